@@ -0,0 +1,697 @@
+//! Code for deciding what monsters do.
+
+use std::collections::HashMap;
+
+use pathfinding::directed::{astar::astar, dijkstra::dijkstra_all};
+use rand::{seq::SliceRandom, Rng};
+use specs::prelude::*;
+
+use crate::{
+    components::{
+        ActionOutcome, CanOpenDoors, Flying, Health, MobAction, Mobile, Monster, Player, Position,
+        Size,
+    },
+    index::PositionIndex,
+    level::{chebyshev, in_bounds, offset, DungeonLevel, DungeonTile, PlayerView},
+    monsters::Behavior,
+    player::find_player,
+    schedule::Schedule,
+    util::GameRng,
+};
+
+/// The fraction of its maximum health below which a cowardly monster
+/// runs away from the player.
+const FLEE_THRESHOLD: f64 = 0.5;
+
+/// How many turns a monster keeps after the player once it can't see
+/// them any more, before giving up.
+const INTEREST_TURNS: u32 = 10;
+
+/// How far from itself a fleeing monster looks for somewhere to run
+/// to.
+const FLEE_RADIUS: i32 = 15;
+
+/// How much getting further from the threat is worth to a fleeing
+/// monster compared to the walk it takes to get there, as a numerator
+/// and denominator.
+const FLEE_FACTOR: (u32, u32) = (6, 5);
+
+#[cfg(test)]
+thread_local! {
+    /// How many paths `find_path` has worked out on this thread, for
+    /// benchmarking how often the path cache saves working one out.
+    static PATHS_FOUND: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// What it costs a monster that can open doors to path through a
+/// closed one: a turn to open it, and another to step through.
+const DOOR_COST: u32 = 2;
+
+/// The directions that monsters can move in, in the order that
+/// pathfinding tries them, so that ties between equally good paths are
+/// always broken the same way.
+const DIRECTIONS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// System for choosing the actions of monsters.
+pub struct MonsterAiSystem;
+
+impl<'a> System<'a> for MonsterAiSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadExpect<'a, Schedule>,
+        WriteStorage<'a, Monster>,
+        ReadStorage<'a, Health>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Flying>,
+        ReadStorage<'a, CanOpenDoors>,
+        WriteStorage<'a, Mobile>,
+        ReadExpect<'a, DungeonLevel>,
+        ReadExpect<'a, PlayerView>,
+        ReadExpect<'a, PositionIndex>,
+        ReadStorage<'a, Size>,
+        WriteExpect<'a, GameRng>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            pos,
+            schedule,
+            mut monsters,
+            health,
+            players,
+            flying,
+            door_openers,
+            mut mobs,
+            level,
+            view,
+            index,
+            sizes,
+            mut rng,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut rng.0;
+
+        // Monsters have no one to go after unless there's exactly one
+        // player.
+        let player_pos: Option<(i32, i32)> = find_player(&entities, &players)
+            .ok()
+            .and_then(|player| pos.get(player))
+            .map(Into::into);
+
+        for (ent, pos, monster, hp, mob) in (&entities, &pos, &mut monsters, &health, &mut mobs)
+            .join()
+            .filter(|(ent, _pos, _monster, _hp, _mob)| schedule.is_acting(*ent))
+        {
+            let map = Surroundings {
+                level: &level,
+                index: &index,
+                health: &health,
+                me: ent,
+                flying: flying.contains(ent),
+                opens_doors: door_openers.contains(ent),
+                size: sizes.get(ent).copied().unwrap_or_default(),
+            };
+
+            let here = pos.into();
+            let seen = player_pos.filter(|&target| view.sees(&level, here, target));
+
+            // Monsters don't go after the player as soon as they see
+            // them, only once they come close, so that a whole level
+            // doesn't descend on them at once. After that they don't
+            // let the player out of their sight if they can help it.
+            match seen {
+                Some(target)
+                    if monster.interest > 0
+                        || map.steps_to(here, target) <= monster.kind.aggro_range() =>
+                {
+                    monster.interest = INTEREST_TURNS;
+                }
+                _ => monster.interest = monster.interest.saturating_sub(1),
+            }
+            let target = seen.filter(|_| monster.interest > 0);
+
+            // A monster that got stuck behind another one last turn
+            // wanders off for a turn, rather than waiting in line, and
+            // has to find a new way around afterwards.
+            let stuck = mob.last_outcome == Some(ActionOutcome::Blocked);
+            let target = target.filter(|_| !stuck);
+            let path = &mut monster.path;
+
+            mob.next_action = match (monster.kind.behavior(), target) {
+                (Behavior::Wandering, _) | (_, None) => {
+                    path.clear();
+                    map.wander(here, rng)
+                }
+                (Behavior::Aggressive, Some(target)) => map.pursue(here, target, path),
+                (Behavior::Cowardly, Some(target)) => {
+                    let hurt = (hp.current as f64) < hp.maximum as f64 * FLEE_THRESHOLD;
+
+                    // Cornered monsters fight back.
+                    match hurt.then(|| map.flee(here, target)).flatten() {
+                        Some(action) => {
+                            path.clear();
+                            action
+                        }
+                        None => map.pursue(here, target, path),
+                    }
+                }
+            };
+        }
+    }
+}
+
+/// The parts of the world that monsters look at when deciding where
+/// to go.
+struct Surroundings<'a, 'b> {
+    level: &'a DungeonLevel,
+    index: &'a PositionIndex,
+    health: &'a ReadStorage<'b, Health>,
+
+    /// The monster doing the looking.
+    me: Entity,
+
+    /// Whether the monster doing the looking can fly.
+    flying: bool,
+
+    /// Whether the monster doing the looking can open closed doors.
+    opens_doors: bool,
+
+    /// How much room the monster doing the looking takes up. Positions
+    /// below are where its upper-left corner would be.
+    size: Size,
+}
+
+impl Surroundings<'_, '_> {
+    /// How much it costs the monster to get into the given position,
+    /// ignoring any creatures in the way, or `None` if it can't. Large
+    /// monsters pay for the worst of the cells they'd cover. Closed
+    /// doors can be got through by monsters that can open them.
+    fn cost(&self, pos: (i32, i32)) -> Option<u32> {
+        self.size
+            .cells(pos)
+            .map(|(x, y)| {
+                if !in_bounds((x, y)) {
+                    None
+                } else if self.opens_doors && self.is_closed_door((x, y)) {
+                    Some(DOOR_COST)
+                } else {
+                    self.level.tile(x, y).move_cost(self.flying)
+                }
+            })
+            .try_fold(0, |worst, cost| Some(u32::max(worst, cost?)))
+    }
+
+    /// Whether the given cell has a closed door in it.
+    fn is_closed_door(&self, (x, y): (i32, i32)) -> bool {
+        self.level.tile(x, y) == &(DungeonTile::Door { open: false })
+    }
+
+    /// The first closed door in the cells the monster would cover at
+    /// the given position, if there is one.
+    fn door_in_way(&self, pos: (i32, i32)) -> Option<(i32, i32)> {
+        self.size
+            .cells(pos)
+            .find(|&cell| in_bounds(cell) && self.is_closed_door(cell))
+    }
+
+    /// Whether the monster can move into the given cell right now,
+    /// ignoring any creatures in the way.
+    fn walkable(&self, cell: (i32, i32)) -> bool {
+        self.cost(cell).is_some() && self.door_in_way(cell).is_none()
+    }
+
+    /// Whether there's another creature in any of the cells the
+    /// monster would cover at the given position.
+    fn occupied(&self, pos: (i32, i32)) -> bool {
+        self.size.cells(pos).any(|(x, y)| {
+            self.index
+                .entities_at(x, y)
+                .iter()
+                .any(|ent| *ent != self.me && self.health.contains(*ent))
+        })
+    }
+
+    /// Whether the monster would be covering `target` at the given
+    /// position.
+    fn covers(&self, pos: (i32, i32), target: (i32, i32)) -> bool {
+        self.size.cells(pos).any(|cell| cell == target)
+    }
+
+    /// The number of steps the monster at the given position needs to
+    /// take before it covers `target`.
+    fn steps_to(&self, (x, y): (i32, i32), target: (i32, i32)) -> i32 {
+        let nearest = (
+            target.0.clamp(x, x + self.size.width - 1),
+            target.1.clamp(y, y + self.size.height - 1),
+        );
+        chebyshev(nearest, target)
+    }
+
+    /// Moves in a random direction, or stays put.
+    fn wander(&self, (x, y): (i32, i32), rng: &mut impl Rng) -> MobAction {
+        let options: Vec<(i32, i32)> = DIRECTIONS
+            .iter()
+            .copied()
+            .filter(|&delta| {
+                let dest = offset((x, y), delta);
+                self.walkable(dest) && !self.occupied(dest)
+            })
+            .collect();
+
+        match options.choose(rng) {
+            Some(&(dx, dy)) if rng.gen_bool(0.5) => MobAction::Move(dx, dy),
+            _ => MobAction::Nop,
+        }
+    }
+
+    /// Takes a step along the cheapest path towards `target`, which
+    /// attacks whatever is at `target` once we're next to it. The path
+    /// is remembered in `cache`, and followed on later turns for as
+    /// long as it still reaches `target` and the way ahead is clear.
+    /// A closed door on the path gets opened first, and stepped
+    /// through on the turn after.
+    fn pursue(
+        &self,
+        from: (i32, i32),
+        target: (i32, i32),
+        cache: &mut Vec<(i32, i32)>,
+    ) -> MobAction {
+        let usable = match (cache.first(), cache.last()) {
+            (Some(&next), Some(&end)) => {
+                self.covers(end, target)
+                    && chebyshev(from, next) == 1
+                    && self.cost(next).is_some()
+                    && (self.covers(next, target) || !self.occupied(next))
+            }
+            _ => false,
+        };
+        if !usable {
+            *cache = self.find_path(from, target).unwrap_or_default();
+        }
+
+        match cache.first().copied() {
+            None => MobAction::Nop,
+            Some(next) => match self.door_in_way(next) {
+                Some(door) => MobAction::Open(door.0 - from.0, door.1 - from.1),
+                None => {
+                    cache.remove(0);
+                    MobAction::Move(next.0 - from.0, next.1 - from.1)
+                }
+            },
+        }
+    }
+
+    /// Finds the cheapest path from `from` towards `target`, returning
+    /// the positions to step onto in order. Like the hallway router in
+    /// `rooms`, this weights each step by the tile being stepped onto,
+    /// so hazards get walked around.
+    fn find_path(&self, from: (i32, i32), target: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        #[cfg(test)]
+        PATHS_FOUND.with(|count| count.set(count.get() + 1));
+
+        astar(
+            &from,
+            |&(x, y)| {
+                DIRECTIONS
+                    .iter()
+                    .map(move |&delta| offset((x, y), delta))
+                    .filter(|&dest| self.covers(dest, target) || !self.occupied(dest))
+                    .filter_map(|dest| self.cost(dest).map(|cost| (dest, cost)))
+                    .collect::<Vec<_>>()
+            },
+            |&node| self.steps_to(node, target) as u32,
+            |&node| self.covers(node, target),
+        )
+        .map(|(path, _cost)| path[1..].to_vec())
+    }
+
+    /// Takes a step away from `threat`, down the slope of a flee map.
+    /// Returns `None` if there's nowhere better to go.
+    ///
+    /// The flee map starts as the cost of getting to each nearby cell
+    /// from the threat, scaled up by `FLEE_FACTOR` and turned upside
+    /// down, so that the further a cell is from the threat the lower
+    /// it sits. Rescanning it lets each cell sink to the lowest value
+    /// that can be reached from it counting the cost of the walk
+    /// there, and since the scale is more than one, somewhere a long
+    /// way off is worth detouring towards, rather than backing into
+    /// the nearest dead end.
+    fn flee(&self, from: (i32, i32), threat: (i32, i32)) -> Option<MobAction> {
+        let steps = |cell: (i32, i32)| {
+            DIRECTIONS
+                .iter()
+                .map(|&delta| offset(cell, delta))
+                .filter(|&dest| chebyshev(dest, from) <= FLEE_RADIUS)
+                .filter_map(|dest| self.cost(dest).map(|cost| (dest, cost)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut distance: HashMap<(i32, i32), u32> = dijkstra_all(&threat, |&cell| steps(cell))
+            .into_iter()
+            .map(|(cell, (_parent, cost))| (cell, cost))
+            .collect();
+        distance.insert(threat, 0);
+
+        // The rescan starts from a made-up cell, `None`, with a step
+        // onto every real cell that costs its inverted distance.
+        let furthest = distance.values().copied().max().unwrap_or(0);
+        let (scale, step_scale) = FLEE_FACTOR;
+        let flee_map = dijkstra_all(&None, |&node| match node {
+            None => distance
+                .iter()
+                .map(|(&cell, &dist)| (Some(cell), scale * (furthest - dist)))
+                .collect::<Vec<_>>(),
+            Some(cell) => steps(cell)
+                .into_iter()
+                .map(|(dest, cost)| (Some(dest), step_scale * cost))
+                .collect(),
+        });
+        let height = |cell| flee_map.get(&Some(cell)).map(|&(_parent, cost)| cost);
+
+        DIRECTIONS
+            .iter()
+            .map(|&delta| offset(from, delta))
+            .filter(|&dest| self.walkable(dest) && !self.occupied(dest))
+            .filter_map(|dest| Some((dest, height(dest)?)))
+            .min_by_key(|&(_dest, dest_height)| dest_height)
+            .filter(|&(_dest, dest_height)| height(from).is_none_or(|here| dest_height < here))
+            .map(|(dest, _height)| MobAction::Move(dest.0 - from.0, dest.1 - from.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        monsters::MonsterKind,
+        systems::{IndexSystem, MobSystem, ViewSystem},
+        testing::{add_player, world_with},
+    };
+
+    /// Runs `f` on the surroundings of a one-tile monster on `level`,
+    /// with nothing else around.
+    fn on_level<R>(level: &str, flying: bool, f: impl FnOnce(&Surroundings) -> R) -> R {
+        let level: DungeonLevel = level.parse().unwrap();
+        let mut world = World::new();
+        world.register::<Health>();
+        let me = world.create_entity().build();
+        let index = PositionIndex::default();
+        let health = world.read_storage::<Health>();
+
+        f(&Surroundings {
+            level: &level,
+            index: &index,
+            health: &health,
+            me,
+            flying,
+            opens_doors: false,
+            size: Size::default(),
+        })
+    }
+
+    /// Asks a monster at `from` on `level` which way it would run from
+    /// `threat`.
+    fn flee_on(level: &str, from: (i32, i32), threat: (i32, i32)) -> Option<MobAction> {
+        on_level(level, false, |map| map.flee(from, threat))
+    }
+
+    #[test]
+    fn flee_steps_away_in_the_open() {
+        let room = "\
+            ---------\n\
+            |.......|\n\
+            |.......|\n\
+            |.......|\n\
+            ---------";
+
+        match flee_on(room, (4, 2), (3, 2)) {
+            Some(MobAction::Move(dx, dy)) => assert_eq!(chebyshev((4 + dx, 2 + dy), (3, 2)), 2),
+            _ => panic!("monster should run away"),
+        }
+    }
+
+    #[test]
+    fn flee_avoids_dead_ends() {
+        // Straight east is further from the threat for now, but it
+        // only goes a few tiles; north leads a long way off.
+        let tee = "\
+            \n      ..........................\
+            \n      .\
+            \n      .\
+            \n      .\
+            \n ..........";
+
+        assert_eq!(flee_on(tee, (6, 5), (1, 5)), Some(MobAction::Move(0, -1)));
+    }
+
+    const LAVA_POOL: &str = "\
+        -----------\n\
+        |...}}}...|\n\
+        |...}}}...|\n\
+        |...}}}...|\n\
+        |.........|\n\
+        -----------";
+
+    #[test]
+    fn paths_go_around_lava() {
+        let level: DungeonLevel = LAVA_POOL.parse().unwrap();
+        let path = on_level(LAVA_POOL, false, |map| map.find_path((2, 1), (8, 1)))
+            .expect("There should be a way around");
+
+        assert_eq!(path.len(), 8);
+        assert_eq!(path.last(), Some(&(8, 1)));
+        assert!(path
+            .iter()
+            .all(|&(x, y)| level.tile(x, y) != &DungeonTile::Lava));
+    }
+
+    #[test]
+    fn flying_paths_cross_lava() {
+        let path = on_level(LAVA_POOL, true, |map| map.find_path((2, 1), (8, 1)))
+            .expect("There should be a way across");
+
+        assert_eq!(path, (3..=8).map(|x| (x, 1)).collect::<Vec<_>>());
+    }
+
+    /// Puts the player at `player_at`, and has `monster` decide what to
+    /// do about it. Returns how interested the monster is in the player
+    /// afterwards, and what it's going to do.
+    fn react(
+        world: &mut World,
+        player: Entity,
+        player_at: (i32, i32),
+        monster: Entity,
+    ) -> (u32, MobAction) {
+        world
+            .write_storage::<Position>()
+            .insert(
+                player,
+                Position {
+                    x: player_at.0,
+                    y: player_at.1,
+                },
+            )
+            .unwrap();
+        {
+            let mut schedule = world.fetch_mut::<Schedule>();
+            let now = schedule.now();
+            schedule.reschedule(monster, now + 1);
+            schedule.advance();
+        }
+        IndexSystem.run_now(world);
+        ViewSystem.run_now(world);
+        MonsterAiSystem.run_now(world);
+
+        let interest = world
+            .read_storage::<Monster>()
+            .get(monster)
+            .unwrap()
+            .interest;
+        let action = world
+            .read_storage::<Mobile>()
+            .get(monster)
+            .unwrap()
+            .next_action;
+        (interest, action)
+    }
+
+    #[test]
+    fn monsters_only_give_chase_up_close() {
+        let mut world = world_with(
+            "\
+            ----------------------\n\
+            |....................|\n\
+            ----------------------",
+        );
+        let zombie = MonsterKind::Zombie.spawn(&mut world, (12, 1));
+        let player = add_player(&mut world, (4, 1));
+
+        // In plain sight, but too far away to bother with.
+        let (interest, _action) = react(&mut world, player, (4, 1), zombie);
+        assert_eq!(interest, 0);
+
+        let (interest, action) = react(&mut world, player, (8, 1), zombie);
+        assert_eq!(interest, INTEREST_TURNS);
+        assert_eq!(action, MobAction::Move(-1, 0));
+
+        // Once it's after the player, backing off doesn't shake it.
+        let (interest, action) = react(&mut world, player, (4, 1), zombie);
+        assert_eq!(interest, INTEREST_TURNS);
+        assert_eq!(action, MobAction::Move(-1, 0));
+        let (_interest, action) = react(&mut world, player, (20, 1), zombie);
+        assert_eq!(action, MobAction::Move(1, 0));
+    }
+
+    /// Has each of `monsters` take a turn, doing whatever it decides
+    /// to do.
+    fn monsters_act(world: &mut World, monsters: &[Entity]) {
+        {
+            let mut schedule = world.fetch_mut::<Schedule>();
+            let now = schedule.now();
+            for &monster in monsters {
+                schedule.reschedule(monster, now + 1);
+            }
+            schedule.advance();
+        }
+        IndexSystem.run_now(world);
+        ViewSystem.run_now(world);
+        MonsterAiSystem.run_now(world);
+        MobSystem.run_now(world);
+        world.maintain();
+    }
+
+    #[test]
+    fn only_door_openers_get_through_closed_doors() {
+        // The player can be seen across the chasms, but the only way
+        // to them is through one of the doors.
+        let mut world = world_with(
+            "\
+            -----------\n\
+            |..:...:..|\n\
+            |..+...+..|\n\
+            -----------",
+        );
+        add_player(&mut world, (5, 1));
+        let goblin = MonsterKind::Goblin.spawn(&mut world, (1, 1));
+        let zombie = MonsterKind::Zombie.spawn(&mut world, (9, 1));
+        assert!(world.read_storage::<CanOpenDoors>().contains(goblin));
+        assert!(!world.read_storage::<CanOpenDoors>().contains(zombie));
+
+        let position = |world: &World, ent: Entity| -> (i32, i32) {
+            world.read_storage::<Position>().get(ent).unwrap().into()
+        };
+        let mut turns = 0;
+        while chebyshev(position(&world, goblin), (5, 1)) > 1 {
+            assert!(turns < 20, "The goblin never got to the player");
+            monsters_act(&mut world, &[goblin, zombie]);
+            assert!(
+                position(&world, zombie).0 > 7,
+                "The zombie got past its door"
+            );
+            turns += 1;
+        }
+
+        assert_eq!(
+            world.fetch::<DungeonLevel>().tile(3, 2),
+            &DungeonTile::Door { open: true }
+        );
+        assert_eq!(
+            world.fetch::<DungeonLevel>().tile(7, 2),
+            &DungeonTile::Door { open: false }
+        );
+    }
+
+    /// Has 50 zombies chase a player who stands still for 20 turns, and
+    /// returns how many paths they worked out and how long they took
+    /// choosing what to do. If `forgetful`, they forget their paths
+    /// before every turn, as they did before paths were cached.
+    fn chase(forgetful: bool) -> (usize, Duration) {
+        let (width, height) = (41, 19);
+        let mut picture = format!("{}\n", "-".repeat(width + 2));
+        for _ in 0..height {
+            picture += &format!("|{}|\n", ".".repeat(width));
+        }
+        picture += &"-".repeat(width + 2);
+        let mut world = world_with(&picture);
+        let player = add_player(&mut world, (21, 10));
+        world
+            .write_storage::<Health>()
+            .get_mut(player)
+            .unwrap()
+            .current = i32::MAX;
+
+        // Spread out in a ring, just close enough to see the player.
+        let zombies: Vec<Entity> = (1..=height)
+            .flat_map(|y| (11..=31).map(move |x| (x, y)))
+            .filter(|&spot| (8..=9).contains(&chebyshev(spot, (21, 10))))
+            .step_by(2)
+            .take(50)
+            .map(|spot| MonsterKind::Zombie.spawn(&mut world, spot))
+            .collect();
+        assert_eq!(zombies.len(), 50);
+        for monster in (&mut world.write_storage::<Monster>()).join() {
+            monster.interest = INTEREST_TURNS;
+        }
+
+        PATHS_FOUND.with(|count| count.set(0));
+        let mut thinking = Duration::ZERO;
+        for _ in 0..20 {
+            if forgetful {
+                for monster in (&mut world.write_storage::<Monster>()).join() {
+                    monster.path.clear();
+                }
+            }
+            {
+                let mut schedule = world.fetch_mut::<Schedule>();
+                let now = schedule.now();
+                for &zombie in &zombies {
+                    schedule.reschedule(zombie, now + 1);
+                }
+                schedule.advance();
+            }
+            IndexSystem.run_now(&world);
+            ViewSystem.run_now(&world);
+            let start = Instant::now();
+            MonsterAiSystem.run_now(&world);
+            thinking += start.elapsed();
+            MobSystem.run_now(&world);
+            world.maintain();
+        }
+
+        (PATHS_FOUND.with(|count| count.get()), thinking)
+    }
+
+    /// Compares how often monsters work out paths to the player with
+    /// and without the path cache. Run with `cargo test --release --
+    /// --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_pursuit_paths() {
+        let (uncached, slow) = chase(true);
+        let (cached, fast) = chase(false);
+        assert!(
+            cached < uncached,
+            "{} paths cached, {} not",
+            cached,
+            uncached
+        );
+        eprintln!(
+            "pursuit over 20 turns: {} paths in {:?} without the cache, {} in {:?} with it",
+            uncached, slow, cached, fast
+        );
+    }
+}
@@ -0,0 +1,1046 @@
+//! Branches of the dungeon, made of levels stacked on top of each
+//! other.
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use pathfinding::directed::bfs::bfs_reach;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use specs::prelude::*;
+
+use crate::{
+    components::{CharRender, Health, Monster, Player, Position, Size},
+    difficulty::Difficulty,
+    events::{Events, GameEvent},
+    index::PositionIndex,
+    level::{
+        chebyshev, in_bounds, CellMemory, DungeonLevel, DungeonTile, LevelExits, PlayerView,
+        SPAWN_SAFE_RADIUS,
+    },
+    markers::Markers,
+    messages::{MessageLog, Severity},
+    monsters::MonsterKind,
+    player::the_player,
+    rooms::{GenError, HallwayWidth, Hallways},
+    stash::Stash,
+    theme::Theme,
+    util::{sub_seed, GameRng},
+    visibility::FovAlgo,
+};
+
+/// The number of levels in the main branch of the dungeon.
+pub const BRANCH_DEPTH: usize = 5;
+
+/// The number of levels in the Caves.
+const CAVES_DEPTH: usize = 3;
+
+/// The index of the level in the main branch with the way down into
+/// the Caves, or of the bottom level if the branch isn't that deep.
+const CAVES_ENTRANCE: usize = 2;
+
+/// Options for how a branch is laid out and behaves.
+pub struct BranchConfig {
+    /// What the branch is called, as in "You climb down the stairs
+    /// into the Dungeon."
+    pub name: &'static str,
+
+    /// The number of levels in the branch.
+    pub depth: usize,
+
+    /// Whether levels are generated from scratch every time the
+    /// player enters them, rather than being left as they were. This
+    /// stops players from farming a level by going up and down the
+    /// stairs.
+    pub regen_on_revisit: bool,
+
+    /// The number of monsters placed on each level, per 100 tiles of
+    /// floor space.
+    pub monster_density: f64,
+
+    /// The number of the player's turns between new monsters showing
+    /// up on their level, or `None` if they only show up when a level is
+    /// first populated.
+    pub spawn_interval: Option<u32>,
+
+    /// The most monsters a level can have before new ones stop showing
+    /// up.
+    pub spawn_cap: usize,
+
+    /// The themes that levels below the top are drawn from. The top
+    /// level always has the default theme.
+    pub themes: &'static [Theme],
+
+    /// How every level works out what's in view.
+    pub fov: FovAlgo,
+
+    /// How many times taller than wide cells count as when working out
+    /// what's in view; see `ViewRadius`.
+    pub view_aspect: i32,
+
+    /// How far away creatures on every level can see.
+    pub view_radius: i32,
+
+    /// How hallways are dug when levels are generated.
+    pub hallways: Hallways,
+}
+
+impl Default for BranchConfig {
+    fn default() -> Self {
+        Self {
+            name: "the Dungeon",
+            depth: BRANCH_DEPTH,
+            regen_on_revisit: false,
+            monster_density: Difficulty::default().monster_density,
+            spawn_interval: None,
+            spawn_cap: 30,
+            themes: Theme::ALL,
+            fov: FovAlgo::default(),
+            view_aspect: 1,
+            view_radius: Difficulty::default().view_radius,
+            hallways: Hallways::default(),
+        }
+    }
+}
+
+/// Which level of which branch something is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct LevelId {
+    /// The index of the branch in the dungeon; the main branch is 0.
+    pub branch: usize,
+
+    /// The index of the level within the branch; the top is 0.
+    pub depth: usize,
+}
+
+/// The level the player is on.
+#[derive(Default)]
+pub struct CurrentLevel(pub LevelId);
+
+/// Which way a staircase leads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StairDirection {
+    Up,
+    Down,
+}
+
+/// How many steps from an upstair the player can start out, if the
+/// upstairs themselves are too close to monsters.
+const START_SEARCH_STEPS: i32 = 3;
+
+/// How many tries we get at finding somewhere else for a monster that's
+/// too close to where the player starts, before giving up on it.
+const RELOCATE_ATTEMPTS: usize = 100;
+
+/// The possible amounts of damage from falling down a chasm.
+const FALL_DAMAGE: RangeInclusive<i32> = 2..=5;
+
+/// A way of getting from one level to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Travel {
+    /// Taking a staircase.
+    Stairs(StairDirection),
+
+    /// Falling down a chasm to the level below.
+    Fall,
+}
+
+/// Set when the player takes a staircase or falls down a chasm, so
+/// that they can be moved to the new level once the turn is over.
+#[derive(Default)]
+pub struct PendingTravel(pub Option<Travel>);
+
+/// A kind of monster being wiped out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Genocide {
+    pub kind: MonsterKind,
+
+    /// Whether it's wiped out on every level of the current branch,
+    /// rather than just the level the player is on.
+    pub whole_branch: bool,
+}
+
+/// Set when the player wipes out a kind of monster, so that it can be
+/// done once the turn is over, when every level can be got at.
+#[derive(Default)]
+pub struct PendingGenocide(pub Option<Genocide>);
+
+/// Every branch of the dungeon.
+pub struct Dungeon {
+    /// The branches; the first is the main one, which the player
+    /// starts at the top of.
+    branches: Vec<DungeonBranch>,
+
+    /// The index of the branch the player is in.
+    current: usize,
+}
+
+/// A stack of levels connected by stairs, from the top down.
+struct DungeonBranch {
+    /// What the branch is called.
+    name: &'static str,
+
+    /// The levels in the branch; the first is the top. The current
+    /// level's copy isn't kept up to date while the player is on it.
+    levels: Vec<DungeonLevel>,
+
+    /// What was left behind on each level the player has visited and
+    /// then left.
+    visits: Vec<Option<Visit>>,
+
+    /// How many times each level has been generated before, not
+    /// counting the layout it has now.
+    generations: Vec<u64>,
+
+    /// The index of the level the player is on, or was on when they
+    /// last left the branch.
+    current: usize,
+
+    /// The seed that the branch's levels are generated from.
+    seed: u64,
+
+    /// The level that the top level's way up leads to, if it isn't
+    /// the way out of the dungeon.
+    parent: Option<LevelId>,
+
+    /// The staircase from this branch down into another one, if there
+    /// is one.
+    side: Option<BranchLink>,
+
+    /// Whether levels are regenerated when revisited.
+    regen_on_revisit: bool,
+
+    /// How crowded levels are with monsters when they're populated.
+    monster_density: f64,
+
+    /// The themes that new levels can be given.
+    themes: &'static [Theme],
+
+    /// How levels work out what's in view.
+    fov: FovAlgo,
+
+    /// How much taller than wide the levels' cells count as.
+    view_aspect: i32,
+
+    /// How far away creatures on the levels can see.
+    view_radius: i32,
+
+    /// How the levels' hallways are dug.
+    hallways: Hallways,
+}
+
+/// A branch staircase, leading from a level of one branch to the top
+/// of another.
+#[derive(Clone, Copy)]
+struct BranchLink {
+    /// The index of the level the staircase is on.
+    level: usize,
+
+    /// The index of the branch it leads to.
+    branch: usize,
+
+    /// What that branch is called.
+    name: &'static str,
+}
+
+/// Where the player ends up on the level they travel to.
+#[derive(Clone, Copy)]
+enum Arrival {
+    Upstair,
+    Downstair,
+    BranchStair,
+
+    /// Anywhere that's free, for falls.
+    Anywhere,
+}
+
+/// The stages of coming up with a level, each of which gets random
+/// numbers of its own.
+#[derive(Clone, Copy)]
+enum Stage {
+    /// Laying out its rooms and hallways.
+    Layout,
+
+    /// Filling it with monsters and items.
+    Population,
+}
+
+/// The state of a level that the player has left.
+struct Visit {
+    /// The entities on the level.
+    stash: Stash,
+
+    /// The player's memory of the level.
+    known_cells: CellMemory,
+    remembered: HashMap<(i32, i32), CharRender>,
+}
+
+impl DungeonBranch {
+    /// Generates a branch as described by `config`, from `seed`. Every
+    /// level below the top has a way up, and every level above the
+    /// bottom has a way down. `parent` is where the top level's way up
+    /// leads, if anywhere, and `side` is the staircase down into
+    /// another branch, if there is one.
+    fn generate(
+        config: &BranchConfig,
+        seed: u64,
+        parent: Option<LevelId>,
+        side: Option<BranchLink>,
+    ) -> Result<Self, GenError> {
+        let mut branch = Self {
+            name: config.name,
+            levels: Vec::new(),
+            visits: Vec::new(),
+            generations: vec![0; config.depth],
+            current: 0,
+            seed,
+            parent,
+            side,
+            regen_on_revisit: config.regen_on_revisit,
+            monster_density: config.monster_density,
+            themes: config.themes,
+            fov: config.fov,
+            view_aspect: config.view_aspect,
+            view_radius: config.view_radius,
+            hallways: config.hallways,
+        };
+
+        for idx in 0..config.depth {
+            let level = branch.generate_level(idx, config.depth)?;
+            branch.levels.push(level);
+            branch.visits.push(None);
+        }
+
+        Ok(branch)
+    }
+
+    /// The random numbers for one stage of coming up with the level at
+    /// index `idx`. They only depend on the branch's seed, which level
+    /// it is and how many times it's been generated before, so a level
+    /// comes out the same way whatever else has happened in the run,
+    /// and without taking any random numbers away from the game.
+    fn level_rng(&self, idx: usize, stage: Stage) -> StdRng {
+        StdRng::seed_from_u64(sub_seed(
+            self.seed,
+            &[idx as u64, self.generations[idx], stage as u64],
+        ))
+    }
+
+    /// Generates the layout of the level at index `idx` of a branch
+    /// `depth` levels deep, and picks its theme.
+    fn generate_level(&self, idx: usize, depth: usize) -> Result<DungeonLevel, GenError> {
+        let rng = &mut self.level_rng(idx, Stage::Layout);
+
+        // The top level's upstair is where the player comes in.
+        let downstairs = if idx + 1 < depth { 1 } else { 0 };
+        let mut level = DungeonLevel::generate(rng, 1, downstairs, self.hallways)?;
+        if idx + 1 == depth {
+            level.fill_chasms();
+        }
+        if idx > 0 || self.parent.is_some() {
+            level.set_theme(self.themes.choose(rng).copied().unwrap_or_default());
+        }
+        level.set_fov(self.fov);
+        level.set_view_aspect(self.view_aspect);
+        level.set_view_radius(self.view_radius);
+        if let Some(side) = self.side.filter(|side| side.level == idx) {
+            level.add_branch_stair(rng, side.name);
+        }
+
+        self.check_stairs(idx, depth, &level)?;
+        Ok(level)
+    }
+
+    /// Makes sure that the level at index `idx` of a branch `depth`
+    /// levels deep connects to the levels above and below it, and to
+    /// any branch that leads off it.
+    fn check_stairs(&self, idx: usize, depth: usize, level: &DungeonLevel) -> Result<(), GenError> {
+        let exits = level.exits();
+        let has_side = self.side.is_some_and(|side| side.level == idx);
+        if idx > 0 && exits.upstairs.is_empty() {
+            Err(GenError::MissingUpstair(idx))
+        } else if idx + 1 < depth && exits.downstairs.is_empty() {
+            Err(GenError::MissingDownstair(idx))
+        } else if has_side != exits.branch_stair.is_some() {
+            Err(GenError::MisplacedBranchStair(idx))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Puts away the level the player is on, including any changes
+    /// that have been made to its layout.
+    fn leave(&mut self, world: &mut World) {
+        self.levels[self.current] = (*world.fetch::<DungeonLevel>()).clone();
+        let (known_cells, remembered) = {
+            let player_ent = the_player(world).expect("There must be one player");
+            let mut players = world.write_storage::<Player>();
+            let player = players.get_mut(player_ent).expect("Player must exist");
+            (
+                std::mem::take(&mut player.known_cells),
+                std::mem::take(&mut player.remembered),
+            )
+        };
+        self.visits[self.current] = Some(Visit {
+            stash: Stash::take(world),
+            known_cells,
+            remembered,
+        });
+    }
+
+    /// Brings out the level with the given ID, which must be in this
+    /// branch, either bringing back what was on it or filling it in
+    /// for the first time. If levels are regenerated on revisits, a
+    /// level the player has been to before is replaced with a brand
+    /// new one instead. Returns the level's exits.
+    fn arrive(&mut self, world: &mut World, id: LevelId) -> LevelExits {
+        let dest = id.depth;
+        self.current = dest;
+        world.insert(CurrentLevel(id));
+        // What was in view on the old level means nothing here.
+        world.insert(PlayerView::default());
+        let visit = self.visits[dest].take();
+        if visit.is_some() && self.regen_on_revisit {
+            // If we can't come up with a new layout, the player gets a
+            // fresh set of monsters and items on the old one. Either
+            // way, their markers no longer mean anything.
+            self.generations[dest] += 1;
+            if let Ok(level) = self.generate_level(dest, self.levels.len()) {
+                self.levels[dest] = level;
+            }
+            world.fetch_mut::<Markers>().clear_level(id);
+        }
+
+        let level = self.levels[dest].clone();
+        let exits = level.exits().clone();
+        match visit.filter(|_| !self.regen_on_revisit) {
+            Some(visit) => {
+                world.insert(level);
+                visit.stash.restore(world);
+
+                let player_ent = the_player(world).expect("There must be one player");
+                let mut players = world.write_storage::<Player>();
+                let player = players.get_mut(player_ent).expect("Player must exist");
+                player.known_cells = visit.known_cells;
+                player.remembered = visit.remembered;
+            }
+            None => {
+                let rng = &mut self.level_rng(dest, Stage::Population);
+                level.populate(world, rng, self.monster_density);
+            }
+        }
+        world.insert(exits.clone());
+
+        exits
+    }
+}
+
+impl Dungeon {
+    /// Generates the main branch as described by `config`, along with
+    /// the Caves, which lead off it partway down. Each branch's levels
+    /// are generated from a seed of its own, worked out from `seed`.
+    pub fn generate(config: &BranchConfig, seed: u64) -> Result<Self, GenError> {
+        let caves_config = BranchConfig {
+            name: "the Caves",
+            depth: CAVES_DEPTH,
+            themes: &[Theme::CAVE],
+            // The Caves are cramped, however grand the rest of the
+            // dungeon is.
+            hallways: Hallways {
+                width: HallwayWidth::Single,
+                ..config.hallways
+            },
+            ..*config
+        };
+        let entrance = CAVES_ENTRANCE.min(config.depth.saturating_sub(1));
+
+        let main = DungeonBranch::generate(
+            config,
+            sub_seed(seed, &[0]),
+            None,
+            Some(BranchLink {
+                level: entrance,
+                branch: 1,
+                name: caves_config.name,
+            }),
+        )?;
+        let caves = DungeonBranch::generate(
+            &caves_config,
+            sub_seed(seed, &[1]),
+            Some(LevelId {
+                branch: 0,
+                depth: entrance,
+            }),
+            None,
+        )?;
+
+        Ok(Self {
+            branches: vec![main, caves],
+            current: 0,
+        })
+    }
+
+    /// Sets up the top level of the main branch in the world, and
+    /// returns where the player should start out.
+    pub fn enter(&mut self, world: &mut World) -> (i32, i32) {
+        self.current = 0;
+        let start = LevelId {
+            branch: 0,
+            depth: 0,
+        };
+        let exits = self.branches[0].arrive(world, start);
+        let spot = Self::starting_spot(world, &exits.upstairs);
+        world.maintain();
+        spot
+    }
+
+    /// Picks where the player starts out: on an upstair if one is clear
+    /// of monsters for `SPAWN_SAFE_RADIUS` tiles around, or else on a
+    /// clear spot a few steps from one. If there's no such spot, the
+    /// player starts out wherever is least crowded, and the monsters
+    /// there are moved somewhere else.
+    fn starting_spot(world: &mut World, upstairs: &[(i32, i32)]) -> (i32, i32) {
+        {
+            let entities = world.entities();
+            let positions = world.read_storage::<Position>();
+            let sizes = world.read_storage::<Size>();
+            world
+                .fetch_mut::<PositionIndex>()
+                .rebuild((&entities, &positions, sizes.maybe()).join());
+        }
+
+        let level = world.fetch::<DungeonLevel>();
+        let monsters = world.read_storage::<Monster>();
+        let mut index = world.fetch_mut::<PositionIndex>();
+        let nearby = |index: &PositionIndex, spot: (i32, i32)| -> Vec<Entity> {
+            index
+                .entities_near(spot, SPAWN_SAFE_RADIUS - 1)
+                .into_iter()
+                .filter(|ent| monsters.contains(*ent))
+                .collect()
+        };
+
+        // The upstairs come first, then the spots around them, nearest
+        // first.
+        let mut candidates: Vec<(i32, i32)> = upstairs.to_vec();
+        for &stair in upstairs {
+            candidates.extend(
+                bfs_reach(stair, |&(x, y)| {
+                    (-1..=1)
+                        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+                        .filter(|&(x, y)| {
+                            in_bounds((x, y))
+                                && chebyshev(stair, (x, y)) <= START_SEARCH_STEPS
+                                && level.tile(x, y).is_navigable()
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .skip(1)
+                .filter(|&(x, y)| {
+                    level.tile(x, y) == &DungeonTile::Floor && index.entities_at(x, y).is_empty()
+                }),
+            );
+        }
+        let start = *candidates
+            .iter()
+            .min_by_key(|&&spot| nearby(&index, spot).len())
+            .expect("Level must have an upstair");
+
+        let sizes = world.read_storage::<Size>();
+        let mut positions = world.write_storage::<Position>();
+        let mut rng = world.fetch_mut::<GameRng>();
+        for ent in nearby(&index, start) {
+            let size = sizes.get(ent).copied().unwrap_or_default();
+            let from: (i32, i32) = positions
+                .get(ent)
+                .expect("Monster must have a position")
+                .into();
+            let spot = (0..RELOCATE_ATTEMPTS)
+                .map(|_| level.empty_square(&mut rng.0))
+                .find(|&spot| {
+                    size.cells(spot).all(|(x, y)| {
+                        in_bounds((x, y))
+                            && level.tile(x, y).is_navigable()
+                            && index.entities_at(x, y).is_empty()
+                            && chebyshev(start, (x, y)) >= SPAWN_SAFE_RADIUS
+                    })
+                });
+            match spot {
+                Some(spot) => {
+                    positions
+                        .insert(ent, Position::from(spot))
+                        .expect("Monster must be alive");
+                    index.move_sized(ent, size, from, spot);
+                }
+                // Better to do without it than to have the player start
+                // out next to it.
+                None => {
+                    index.remove_sized(ent, size, from);
+                    world.entities().delete(ent).expect("Monster must be alive");
+                }
+            }
+        }
+
+        start
+    }
+
+    /// The level the player is on.
+    fn current_level(&self) -> LevelId {
+        LevelId {
+            branch: self.current,
+            depth: self.branches[self.current].current,
+        }
+    }
+
+    /// Removes every monster of a kind from the current level, and from
+    /// what was left behind on the branch's other levels if asked to.
+    pub fn genocide(&mut self, world: &mut World, genocide: Genocide) {
+        let Genocide { kind, whole_branch } = genocide;
+        let doomed: Vec<Entity> = {
+            let entities = world.entities();
+            let monsters = world.read_storage::<Monster>();
+            (&entities, &monsters)
+                .join()
+                .filter(|(_ent, monster)| monster.kind == kind)
+                .map(|(ent, _monster)| ent)
+                .collect()
+        };
+        let mut removed = doomed.len();
+        world
+            .delete_entities(&doomed)
+            .expect("Monsters must be alive");
+
+        if whole_branch {
+            let branch = &mut self.branches[self.current];
+            for visit in branch.visits.iter_mut().flatten() {
+                removed += visit.stash.remove_monsters(kind);
+            }
+        }
+
+        let message = match removed {
+            0 => format!("There were no {}s left to wipe out.", kind.name()),
+            1 => format!("1 {} is wiped out.", kind.name()),
+            n => format!("{} {}s are wiped out.", n, kind.name()),
+        };
+        world.fetch_mut::<MessageLog>().log(message);
+
+        world.maintain();
+    }
+
+    /// How many levels down the player is, counting the top of the
+    /// main branch as 1.
+    pub fn depth(&self) -> usize {
+        let mut level = self.current_level();
+        let mut depth = level.depth + 1;
+        while let Some(parent) = self.branches[level.branch].parent {
+            depth += parent.depth + 1;
+            level = parent;
+        }
+        depth
+    }
+
+    /// Moves the player to another level, putting away everything on
+    /// the level they're leaving. Stairs usually lead to the next
+    /// level up or down the same branch, but branch staircases lead to
+    /// the top of another branch, and going up from there leads back.
+    pub fn travel(&mut self, world: &mut World, travel: Travel) {
+        let from = self.current_level();
+        let branch = &self.branches[from.branch];
+        let player = the_player(world).expect("There must be one player");
+        let (x, y) = world
+            .read_storage::<Position>()
+            .get(player)
+            .expect("Player must have a position")
+            .into();
+        let on_branch_stair = world.fetch::<DungeonLevel>().tile(x, y) == &DungeonTile::BranchStair;
+
+        let other_branch = |branch: usize| LevelId { branch, depth: 0 };
+        let same_branch = |depth: usize| LevelId {
+            branch: from.branch,
+            depth,
+        };
+        let route = match travel {
+            Travel::Stairs(StairDirection::Down) if on_branch_stair => branch
+                .side
+                .map(|side| (other_branch(side.branch), Arrival::Upstair)),
+            Travel::Stairs(StairDirection::Up) if from.depth == 0 => {
+                branch.parent.map(|parent| (parent, Arrival::BranchStair))
+            }
+            Travel::Stairs(StairDirection::Up) => {
+                Some((same_branch(from.depth - 1), Arrival::Downstair))
+            }
+            Travel::Stairs(StairDirection::Down) | Travel::Fall => {
+                let arrival = match travel {
+                    Travel::Fall => Arrival::Anywhere,
+                    _ => Arrival::Upstair,
+                };
+                Some(from.depth + 1)
+                    .filter(|&depth| depth < branch.levels.len())
+                    .map(|depth| (same_branch(depth), arrival))
+            }
+        };
+        let (dest, arrival) = match route {
+            Some(route) => route,
+            None => {
+                world
+                    .fetch_mut::<MessageLog>()
+                    .log("You can't leave the dungeon yet.");
+                return;
+            }
+        };
+
+        self.branches[from.branch].leave(world);
+        world
+            .fetch_mut::<Events>()
+            .publish(GameEvent::LevelChanged { from, to: dest });
+        self.current = dest.branch;
+        let exits = self.branches[dest.branch].arrive(world, dest);
+
+        // The player arrives on the staircase at the other end, or
+        // wherever they happen to land.
+        let arrival = match arrival {
+            Arrival::Upstair => exits.upstairs[0],
+            Arrival::Downstair => exits.downstairs[0],
+            Arrival::BranchStair => exits
+                .branch_stair
+                .expect("Parent level must have a branch staircase"),
+            Arrival::Anywhere => Self::landing_spot(world, &mut world.fetch_mut::<GameRng>().0),
+        };
+        let message = match travel {
+            Travel::Fall => {
+                "You fall through the chasm, and land hard on the level below.".to_string()
+            }
+            _ if dest.branch != from.branch => {
+                let direction = if dest.branch == 0 { "up" } else { "down" };
+                format!(
+                    "You climb {} the stairs into {}.",
+                    direction, self.branches[dest.branch].name
+                )
+            }
+            Travel::Stairs(StairDirection::Up) => "You climb up the stairs.".to_string(),
+            Travel::Stairs(StairDirection::Down) => "You climb down the stairs.".to_string(),
+        };
+        world
+            .write_storage::<Position>()
+            .insert(player, Position::from(arrival))
+            .expect("Player must be alive");
+        let severity = match travel {
+            Travel::Fall => Severity::Combat,
+            Travel::Stairs(_) => Severity::Info,
+        };
+        world.fetch_mut::<MessageLog>().log_with(severity, message);
+
+        if travel == Travel::Fall {
+            let damage = world.fetch_mut::<GameRng>().0.gen_range(FALL_DAMAGE);
+            let mut health = world.write_storage::<Health>();
+            let hp = health.get_mut(player).expect("Player must have health");
+            hp.current -= damage;
+            if hp.current <= 0 {
+                world.fetch_mut::<Events>().publish(GameEvent::EntityDied {
+                    entity: player,
+                    description: "you".to_string(),
+                    killer: None,
+                    cause: "fell down a chasm".to_string(),
+                });
+            }
+        }
+
+        world.maintain();
+    }
+
+    /// Picks a random spot on the current level for something falling
+    /// from above to land on, away from anything else that's there.
+    fn landing_spot(world: &World, rng: &mut impl Rng) -> (i32, i32) {
+        let level = world.fetch::<DungeonLevel>();
+        let positions = world.read_storage::<Position>();
+        loop {
+            let spot = level.empty_square(rng);
+            if !positions.join().any(|pos| <(i32, i32)>::from(pos) == spot) {
+                break spot;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{add_player, empty_world, position, world_with, PLAYER_HEALTH};
+
+    #[test]
+    fn adjacent_levels_have_matching_stairs() {
+        for seed in 0..5 {
+            let branch = DungeonBranch::generate(&BranchConfig::default(), seed, None, None)
+                .expect("Branch should generate");
+            assert_eq!(branch.levels.len(), 5);
+
+            for pair in branch.levels.windows(2) {
+                assert!(!pair[0].exits().downstairs.is_empty());
+                assert!(!pair[1].exits().upstairs.is_empty());
+            }
+            assert!(branch.levels[4].exits().downstairs.is_empty());
+        }
+    }
+
+    #[test]
+    fn levels_without_stairs_are_rejected() {
+        let branch = DungeonBranch::generate(&BranchConfig::default(), 0, None, None)
+            .expect("Branch should generate");
+        let no_way_up: DungeonLevel = "-----\n|.>.|\n-----".parse().unwrap();
+        let no_way_down: DungeonLevel = "-----\n|.<.|\n-----".parse().unwrap();
+
+        assert!(matches!(
+            branch.check_stairs(1, 5, &no_way_up),
+            Err(GenError::MissingUpstair(1))
+        ));
+        assert!(matches!(
+            branch.check_stairs(1, 5, &no_way_down),
+            Err(GenError::MissingDownstair(1))
+        ));
+        assert!(branch.check_stairs(4, 5, &no_way_down).is_ok());
+    }
+
+    #[test]
+    fn levels_only_depend_on_the_seed_and_where_they_are() {
+        let config = BranchConfig::default();
+        let layouts = |seed| {
+            let branch = DungeonBranch::generate(&config, seed, None, None).unwrap();
+            let levels: Vec<String> = branch.levels.iter().map(ToString::to_string).collect();
+            for (idx, level) in levels.iter().enumerate() {
+                let again = branch.generate_level(idx, config.depth).unwrap();
+                assert_eq!(&again.to_string(), level);
+            }
+            levels
+        };
+
+        let first = layouts(7);
+        assert_eq!(layouts(7), first);
+        assert_ne!(layouts(8), first);
+        assert_ne!(first[0], first[1]);
+    }
+
+    /// Sets up a dungeon generated from `config` in a new world, with
+    /// the player at the top.
+    fn start(config: &BranchConfig) -> (World, Dungeon, Entity) {
+        let mut world = empty_world();
+        let mut dungeon = Dungeon::generate(config, 0).unwrap();
+        let start = dungeon.enter(&mut world);
+        let player = add_player(&mut world, start);
+        (world, dungeon, player)
+    }
+
+    /// Goes down, up and down again, returning what the second level
+    /// looked like each time the player was on it.
+    fn down_up_down(config: &BranchConfig) -> (String, String) {
+        let (mut world, mut dungeon, _player) = start(config);
+        let layout = |world: &World| world.fetch::<DungeonLevel>().to_string();
+
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+        let first = layout(&world);
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Up));
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+        assert_eq!(dungeon.current_level().depth, 1);
+        (first, layout(&world))
+    }
+
+    #[test]
+    fn branch_staircases_lead_into_the_caves() {
+        let (mut world, mut dungeon, player) = start(&BranchConfig::default());
+        for _ in 0..CAVES_ENTRANCE {
+            dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+        }
+        assert_eq!(dungeon.current_level().depth, CAVES_ENTRANCE);
+
+        let (x, y) = world
+            .fetch::<DungeonLevel>()
+            .exits()
+            .branch_stair
+            .expect("The Caves should lead off this level");
+        assert_eq!(
+            world.fetch::<DungeonLevel>().describe_tile(x, y),
+            "a staircase to the Caves"
+        );
+        world
+            .write_storage::<Position>()
+            .insert(player, Position { x, y })
+            .unwrap();
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+
+        assert_eq!(
+            world.fetch::<CurrentLevel>().0,
+            LevelId {
+                branch: 1,
+                depth: 0
+            }
+        );
+        assert_eq!(
+            world.fetch::<MessageLog>().unseen().last().unwrap().text,
+            "You climb down the stairs into the Caves."
+        );
+    }
+
+    #[test]
+    fn revisited_levels_are_kept() {
+        let (first, second) = down_up_down(&BranchConfig::default());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn revisited_levels_can_be_regenerated() {
+        let config = BranchConfig {
+            regen_on_revisit: true,
+            ..BranchConfig::default()
+        };
+        let (first, second) = down_up_down(&config);
+        assert_ne!(first, second);
+
+        // The new layout still only depends on the seed.
+        assert_eq!(down_up_down(&config), (first, second));
+    }
+
+    #[test]
+    fn regenerating_levels_leaves_the_game_rng_alone() {
+        let config = BranchConfig {
+            regen_on_revisit: true,
+            ..BranchConfig::default()
+        };
+        let (mut world, mut dungeon, _player) = start(&config);
+        let mut before = world.fetch::<GameRng>().0.clone();
+
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Up));
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+
+        let after = &mut world.fetch_mut::<GameRng>().0;
+        assert_eq!(after.gen::<u64>(), before.gen::<u64>());
+    }
+
+    #[test]
+    fn wounded_monsters_stay_wounded() {
+        let (mut world, mut dungeon, player) = start(&BranchConfig::default());
+
+        let (monster, spot) = {
+            let entities = world.entities();
+            let monsters = world.read_storage::<Monster>();
+            let positions = world.read_storage::<Position>();
+            let (ent, _monster, pos) = (&entities, &monsters, &positions)
+                .join()
+                .next()
+                .expect("Top level should have monsters");
+            (ent, <(i32, i32)>::from(pos))
+        };
+        world
+            .write_storage::<Health>()
+            .get_mut(monster)
+            .unwrap()
+            .current = 1;
+
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Down));
+        assert_eq!(dungeon.current_level().depth, 1);
+        dungeon.travel(&mut world, Travel::Stairs(StairDirection::Up));
+        assert_eq!(dungeon.current_level().depth, 0);
+        assert_eq!(
+            Some(position(&world, player)),
+            world
+                .fetch::<DungeonLevel>()
+                .exits()
+                .downstairs
+                .first()
+                .copied()
+        );
+
+        let entities = world.entities();
+        let monsters = world.read_storage::<Monster>();
+        let positions = world.read_storage::<Position>();
+        let health = world.read_storage::<Health>();
+        let (_ent, _monster, _pos, hp) = (&entities, &monsters, &positions, &health)
+            .join()
+            .find(|(_ent, _monster, pos, _hp)| <(i32, i32)>::from(*pos) == spot)
+            .expect("Monster should be back where it was");
+        assert_eq!(hp.current, 1);
+    }
+
+    #[test]
+    fn falling_lands_on_the_next_level() {
+        let (mut world, mut dungeon, player) = start(&BranchConfig::default());
+
+        dungeon.travel(&mut world, Travel::Fall);
+
+        assert_eq!(dungeon.current_level().depth, 1);
+        let (x, y) = position(&world, player);
+        assert!(world.fetch::<DungeonLevel>().tile(x, y).is_navigable());
+        let hp = world.read_storage::<Health>().get(player).unwrap().current;
+        assert!(FALL_DAMAGE.contains(&(PLAYER_HEALTH - hp)));
+    }
+
+    #[test]
+    fn themes_follow_the_seed() {
+        let themes = |seed| {
+            DungeonBranch::generate(&BranchConfig::default(), seed, None, None)
+                .unwrap()
+                .levels
+                .iter()
+                .map(|level| level.theme().name)
+                .collect::<Vec<_>>()
+        };
+
+        let first = themes(3);
+        assert_eq!(first[0], Theme::DUNGEON.name);
+        assert_eq!(themes(3), first);
+        assert!((0..10).any(|seed| themes(seed) != first));
+    }
+
+    /// The positions of every monster in the world.
+    fn monster_positions(world: &World) -> Vec<(i32, i32)> {
+        let monsters = world.read_storage::<Monster>();
+        let positions = world.read_storage::<Position>();
+        (&monsters, &positions)
+            .join()
+            .map(|(_monster, pos)| pos.into())
+            .collect()
+    }
+
+    #[test]
+    fn crowded_starts_are_cleared() {
+        // A long hall with the upstair at one end, and that end packed
+        // with zombies so that there's nowhere near it to stand.
+        let wall = "-".repeat(60);
+        let row = |first: char| format!("|{}{}|", first, ".".repeat(57));
+        let picture = [wall.clone(), row('<'), row('.'), row('.'), wall].join("\n");
+        let mut world = world_with(&picture);
+        for y in 1..=3 {
+            for x in 1..=12 {
+                if (x, y) != (1, 1) {
+                    MonsterKind::Zombie.spawn(&mut world, (x, y));
+                }
+            }
+        }
+        world.maintain();
+        let before = monster_positions(&world).len();
+
+        let start = Dungeon::starting_spot(&mut world, &[(1, 1)]);
+        world.maintain();
+
+        assert_eq!(start, (1, 1));
+        let after = monster_positions(&world);
+        assert_eq!(after.len(), before);
+        for &monster in &after {
+            assert!(
+                chebyshev(start, monster) >= SPAWN_SAFE_RADIUS,
+                "{:?}",
+                monster
+            );
+        }
+    }
+
+    #[test]
+    fn quiet_upstairs_are_left_alone() {
+        let mut world = world_with(
+            "------------------------\n|<.........<...........|\n------------------------",
+        );
+        MonsterKind::Zombie.spawn(&mut world, (9, 1));
+        world.maintain();
+
+        let start = Dungeon::starting_spot(&mut world, &[(11, 1), (1, 1)]);
+        assert_eq!(start, (1, 1));
+        assert_eq!(monster_positions(&world), [(9, 1)]);
+    }
+}
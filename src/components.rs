@@ -1,8 +1,14 @@
 //! ECS components.
 
+use std::{collections::HashMap, ops::RangeInclusive};
+
 use specs::prelude::*;
 use specs_derive::Component;
 
+use crate::{
+    branch::StairDirection, ids::EntityId, io::Color, level::CellMemory, monsters::MonsterKind,
+};
+
 /// Entities that have a physical position in the world.
 #[derive(Component)]
 pub struct Position {
@@ -11,22 +17,38 @@ pub struct Position {
 }
 
 /// Entities that need to be drawn as a single character.
-#[derive(Component)]
+#[derive(Component, Clone, Copy)]
 pub struct CharRender {
     pub glyph: char,
+    pub color: Color,
 }
 
 /// Entities that users can control.
 #[derive(Component)]
 pub struct Player {
-    /// The list of cells that are known to the player.
-    pub known_cells: Vec<Vec<bool>>,
+    /// The cells on the current level that are known to the player.
+    pub known_cells: CellMemory,
+
+    /// How the objects lying around the level looked when the player
+    /// last saw them, by cell. Creatures move around, so they aren't
+    /// remembered.
+    pub remembered: HashMap<(i32, i32), CharRender>,
+
+    /// How much gold the player has picked up.
+    pub gold: u32,
+}
+
+/// Piles of gold lying on the floor, which players pick up by
+/// stepping on them.
+#[derive(Component)]
+pub struct Gold {
+    pub amount: u32,
 }
 
 /// Entities that take turns periodically.
 #[derive(Component)]
 pub struct TurnTaker {
-    /// Amount of time from now until the next scheduled turn.
+    /// The in-game time of the next scheduled turn.
     pub next: u32,
 
     /// Amount of time between turns.
@@ -38,15 +60,370 @@ pub struct TurnTaker {
 #[derive(Component)]
 pub struct Mobile {
     pub next_action: MobAction,
+
+    /// What happened the last time the mob acted, if it's acted yet.
+    pub last_outcome: Option<ActionOutcome>,
+}
+
+/// Entities that can be picked up and carried around.
+#[derive(Component)]
+pub struct Item {
+    /// What the item is called.
+    pub name: &'static str,
+
+    /// What sort of item it is, which decides which commands work on
+    /// it.
+    pub category: ItemCategory,
+
+    /// What happens when the item is used.
+    pub effect: ItemEffect,
+}
+
+/// The broad sorts of items there are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemCategory {
+    Potion,
+    Scroll,
+    Food,
+    Weapon,
+    Armor,
+}
+
+impl ItemCategory {
+    /// What items of this sort are called, e.g., "potion".
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemCategory::Potion => "potion",
+            ItemCategory::Scroll => "scroll",
+            ItemCategory::Food => "food",
+            ItemCategory::Weapon => "weapon",
+            ItemCategory::Armor => "armor",
+        }
+    }
+
+    /// What the player does with items of this sort, e.g., "read".
+    pub fn verb(&self) -> &'static str {
+        match self {
+            ItemCategory::Potion => "drink",
+            ItemCategory::Scroll => "read",
+            ItemCategory::Food => "eat",
+            ItemCategory::Weapon => "wield",
+            ItemCategory::Armor => "wear",
+        }
+    }
+
+    /// Describes an item of this sort, for use in a message, e.g., "a
+    /// potion".
+    pub fn noun(&self) -> &'static str {
+        match self {
+            ItemCategory::Potion => "a potion",
+            ItemCategory::Scroll => "a scroll",
+            ItemCategory::Food => "food",
+            ItemCategory::Weapon => "a weapon",
+            ItemCategory::Armor => "armor",
+        }
+    }
+}
+
+/// Something that happens when an item is used up.
+#[derive(Clone, Copy)]
+pub enum ItemEffect {
+    /// Moves the user to a random spot on the level.
+    Teleport,
+
+    /// The remains of a dead monster, which restore a little health
+    /// when eaten.
+    Corpse,
+
+    /// Wielded or worn rather than used up; see `Weapon` and `Armor`.
+    Equip,
+
+    /// Shatters when thrown, splashing everyone nearby, or splashes
+    /// just the user when drunk.
+    Splash(Splash),
+
+    /// Wipes out every monster of a kind that the reader picks, on
+    /// every level of the branch they're in.
+    Genocide,
+}
+
+/// What a shattered potion does to everyone it splashes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Splash {
+    /// Burns them.
+    Fire,
+
+    /// Leaves them confused.
+    Confusion,
+}
+
+/// Items that can be wielded as weapons.
+#[derive(Component)]
+pub struct Weapon {
+    /// How much extra damage the weapon adds to its wielder's hits.
+    pub damage: i32,
+}
+
+/// Items that can be worn as armor.
+#[derive(Component)]
+pub struct Armor {
+    /// How much damage the armor takes off each hit on its wearer.
+    pub defense: i32,
+}
+
+/// Creatures bigger than a single cell. Their `Position` is their
+/// upper-left corner, and they take up every cell of the rectangle
+/// from there. Creatures without a size take up just the one cell.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub struct Size {
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Size {
+    /// Whether this is bigger than a single cell.
+    pub fn is_large(&self) -> bool {
+        self.width > 1 || self.height > 1
+    }
+
+    /// Every cell taken up by something of this size with its
+    /// upper-left corner at `anchor`, row by row.
+    pub fn cells(&self, (x, y): (i32, i32)) -> impl Iterator<Item = (i32, i32)> {
+        let width = self.width;
+        (0..self.height).flat_map(move |dy| (0..width).map(move |dx| (x + dx, y + dy)))
+    }
+}
+
+impl Default for Size {
+    /// A single cell.
+    fn default() -> Self {
+        Self {
+            width: 1,
+            height: 1,
+        }
+    }
+}
+
+/// Creatures that fly over hazards on the ground, rather than
+/// walking through them.
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Flying;
+
+/// Creatures that are clever enough to open closed doors that are in
+/// their way, rather than having to go around them.
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct CanOpenDoors;
+
+/// Which side a creature is on. Creatures attack those on other sides
+/// when they bump into them, but not those on their own.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Faction {
+    /// The player, and anything fighting alongside them.
+    Player,
+
+    /// Monsters out to get the player.
+    Hostile,
+
+    /// Creatures that keep to themselves.
+    Neutral,
+}
+
+/// Creatures that give off body heat, which infravision can pick up.
+#[derive(Component, Default)]
+#[storage(NullStorage)]
+pub struct Warm;
+
+/// Creatures that can sense warm creatures nearby by their heat, even
+/// through walls.
+#[derive(Component)]
+pub struct Infravision {
+    /// How far away warm creatures can be sensed.
+    pub radius: i32,
+}
+
+/// Entities that cast colored light on the cells around them.
+#[derive(Component)]
+pub struct LightSource {
+    /// How far the light reaches.
+    pub radius: i32,
+
+    /// The color of the light.
+    pub color: Color,
+
+    /// Whether the light's reach wavers from turn to turn, like a
+    /// torch's.
+    pub flickers: bool,
+}
+
+/// Entities that can carry items.
+#[derive(Component, Default)]
+pub struct Inventory {
+    /// The items being carried, in the order they were picked up, each
+    /// with the letter the player picks it by. An item keeps its letter
+    /// for as long as it's carried.
+    items: Vec<(char, Entity)>,
+}
+
+impl Inventory {
+    /// The letters that items can be given, in the order they're
+    /// handed out; this is also how many items can be carried.
+    const LETTERS: [RangeInclusive<char>; 2] = ['a'..='z', 'A'..='Z'];
+
+    /// Creates an inventory holding the given items.
+    pub fn new(items: impl IntoIterator<Item = Entity>) -> Self {
+        let mut inventory = Self::default();
+        for item in items {
+            inventory.add(item).expect("Too many items to carry");
+        }
+
+        inventory
+    }
+
+    /// Puts an item in the inventory under the first free letter, and
+    /// returns that letter, or `None` if there's no room left.
+    pub fn add(&mut self, item: Entity) -> Option<char> {
+        let letter = Self::LETTERS
+            .into_iter()
+            .flatten()
+            .find(|&letter| self.get(letter).is_none())?;
+        self.items.push((letter, item));
+
+        Some(letter)
+    }
+
+    /// Takes an item out of the inventory, freeing up its letter.
+    pub fn remove(&mut self, item: Entity) {
+        self.items.retain(|&(_letter, other)| other != item);
+    }
+
+    /// Whether the given item is being carried.
+    pub fn contains(&self, item: Entity) -> bool {
+        self.items.iter().any(|&(_letter, other)| other == item)
+    }
+
+    /// Finds the item with the given letter.
+    pub fn get(&self, letter: char) -> Option<Entity> {
+        self.items
+            .iter()
+            .find(|&&(other, _item)| other == letter)
+            .map(|&(_letter, item)| item)
+    }
+
+    /// Every item being carried, with its letter.
+    pub fn items(&self) -> &[(char, Entity)] {
+        &self.items
+    }
+}
+
+/// Entities that can wield a weapon and wear armor. Equipped items
+/// aren't in the entity's `Inventory`.
+#[derive(Component, Default)]
+pub struct Equipment {
+    pub weapon: Option<Entity>,
+    pub armor: Option<Entity>,
+}
+
+/// Entities that can be hurt, and die when their health runs out.
+#[derive(Component)]
+pub struct Health {
+    pub current: i32,
+    pub maximum: i32,
+}
+
+/// Entities that can attack other entities in melee.
+#[derive(Component)]
+pub struct Attack {
+    /// The most damage dealt by a single hit.
+    pub damage: i32,
+
+    /// Whether hits knock the defender back a tile.
+    pub knockback: bool,
+}
+
+/// Entities that shrug off some of the damage from each hit, even
+/// without armor.
+#[derive(Component)]
+pub struct Defense {
+    /// How much damage is taken off each hit.
+    pub armor: i32,
+}
+
+/// Entities with a name of their own, on top of what kind of thing
+/// they are.
+#[derive(Component)]
+pub struct Name {
+    pub name: String,
+}
+
+/// Creatures that stagger around instead of going where they mean to.
+#[derive(Component)]
+pub struct Confused {
+    /// How many more actions the confusion lasts for.
+    pub turns: u32,
+}
+
+/// Creatures that the player has bumped into without being able to
+/// see them. They're drawn where they were found until they move.
+#[derive(Component)]
+pub struct Detected {
+    pub at: (i32, i32),
+}
+
+/// Creatures hurt badly enough that they take longer between turns.
+#[derive(Component)]
+pub struct Injured {
+    /// The creature's usual `TurnTaker::maximum`, from before it was
+    /// slowed down.
+    pub base: u32,
+}
+
+/// Entities controlled by the monster AI.
+#[derive(Component)]
+pub struct Monster {
+    pub kind: MonsterKind,
+
+    /// The rest of the path the monster is following towards its
+    /// target, as the positions to step onto in order, so it doesn't
+    /// have to be searched for again every turn. Empty if there's no
+    /// path worth following.
+    pub path: Vec<(i32, i32)>,
+
+    /// How many more turns the monster keeps after the player once it
+    /// loses sight of them, or 0 if it isn't after them at all.
+    pub interest: u32,
 }
 
 /// Registers every existing component with the given ECS world.
 pub fn register_all(world: &mut World) {
+    world.register::<EntityId>();
     world.register::<Position>();
     world.register::<CharRender>();
     world.register::<Player>();
     world.register::<TurnTaker>();
     world.register::<Mobile>();
+    world.register::<Gold>();
+    world.register::<Item>();
+    world.register::<Inventory>();
+    world.register::<Weapon>();
+    world.register::<Armor>();
+    world.register::<Equipment>();
+    world.register::<Flying>();
+    world.register::<CanOpenDoors>();
+    world.register::<Warm>();
+    world.register::<Faction>();
+    world.register::<Infravision>();
+    world.register::<Size>();
+    world.register::<LightSource>();
+    world.register::<Health>();
+    world.register::<Attack>();
+    world.register::<Defense>();
+    world.register::<Monster>();
+    world.register::<Name>();
+    world.register::<Confused>();
+    world.register::<Injured>();
+    world.register::<Detected>();
 }
 
 impl From<&Position> for (i32, i32) {
@@ -61,12 +438,115 @@ impl From<(i32, i32)> for Position {
     }
 }
 
+/// What actually happened when a mob tried to carry out an action.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionOutcome {
+    /// The action happened, with nothing in particular to report.
+    Done,
+
+    /// The mob moved to a new cell.
+    Moved,
+
+    /// The mob couldn't move because something was in the way.
+    Blocked,
+
+    /// The mob attacked something.
+    Hit,
+
+    /// The action had no effect, e.g., opening a door that's already
+    /// open.
+    Nothing,
+}
+
 /// An action that a mob can perform that takes up a turn.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MobAction {
     /// Do nothing.
     Nop,
 
     /// Physically move by the given vector.
     Move(i32, i32),
+
+    /// Examine the adjacent tiles for hidden features.
+    Search,
+
+    /// Open the door at the given offset from the mob.
+    Open(i32, i32),
+
+    /// Pick up everything on the mob's tile.
+    PickUp,
+
+    /// Use up an item from the mob's inventory.
+    Use(Entity),
+
+    /// Wield a weapon from the mob's inventory, putting away whatever
+    /// it was wielding before.
+    Wield(Entity),
+
+    /// Wear armor from the mob's inventory, taking off whatever it
+    /// was wearing before.
+    Wear(Entity),
+
+    /// Put an equipped item back in the mob's inventory.
+    Unequip(Entity),
+
+    /// Put an item from the mob's inventory down on its tile, along
+    /// with anything else that's already lying there.
+    Drop(Entity),
+
+    /// Throw an item from the mob's inventory towards the given cell.
+    /// It lands there, or in front of whatever's in the way.
+    Throw(Entity, i32, i32),
+
+    /// Jump straight to the given cell, which must be open ground in
+    /// plain sight and close by.
+    Blink(i32, i32),
+
+    /// Take the staircase the mob is standing on.
+    TakeStairs(StairDirection),
+
+    /// Drink from the fountain the mob is standing on.
+    Quaff,
+}
+
+impl MobAction {
+    /// Describes what the mob means to do, for use in messages, e.g.,
+    /// "move north" or "search".
+    pub fn intent(&self) -> String {
+        match self {
+            MobAction::Nop => "wait".to_string(),
+            MobAction::Move(dx, dy) => format!("move {}", direction_name((*dx, *dy))),
+            MobAction::Search => "search".to_string(),
+            MobAction::Open(dx, dy) => {
+                format!("open the door to the {}", direction_name((*dx, *dy)))
+            }
+            MobAction::PickUp => "pick something up".to_string(),
+            MobAction::Use(_) => "use an item".to_string(),
+            MobAction::Wield(_) => "wield a weapon".to_string(),
+            MobAction::Wear(_) => "put on armor".to_string(),
+            MobAction::Unequip(_) => "take something off".to_string(),
+            MobAction::Drop(_) => "drop an item".to_string(),
+            MobAction::Throw(_, _, _) => "throw an item".to_string(),
+            MobAction::Blink(_, _) => "blink".to_string(),
+            MobAction::TakeStairs(StairDirection::Up) => "go up the stairs".to_string(),
+            MobAction::TakeStairs(StairDirection::Down) => "go down the stairs".to_string(),
+            MobAction::Quaff => "drink from the fountain".to_string(),
+        }
+    }
+}
+
+/// The name of the compass direction that a vector points in, e.g.,
+/// "northeast".
+fn direction_name((dx, dy): (i32, i32)) -> &'static str {
+    match (dx.signum(), dy.signum()) {
+        (0, -1) => "north",
+        (1, -1) => "northeast",
+        (1, 0) => "east",
+        (1, 1) => "southeast",
+        (0, 1) => "south",
+        (-1, 1) => "southwest",
+        (-1, 0) => "west",
+        (-1, -1) => "northwest",
+        _ => "nowhere",
+    }
 }
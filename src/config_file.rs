@@ -0,0 +1,279 @@
+//! The config file, which sets options the same way the command line
+//! does, so that they don't have to be given every time.
+//!
+//! The file is a small subset of TOML. Each line is blank, a `#`
+//! comment, or `key = value`, where the key is the name of an option
+//! without its leading dashes (with `-` or `_` between words), and the
+//! value is a quoted string, a whole number, or `true` or `false`.
+//! Options that don't take a value are turned on by `true`. Keys named
+//! `key_` and then a command, e.g. `key_search`, bind that command to
+//! the keys in their value, on top of whichever layout `keys` picks:
+//!
+//! ```toml
+//! difficulty = "hard"
+//! keys = "vi"
+//! theme = "crypt"
+//! key_search = "f"
+//! regen_on_revisit = true
+//! spawn_interval = 200
+//! ```
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::keymap::Command;
+
+/// The options that are turned on or off, rather than given a value.
+const FLAGS: &[&str] = &[
+    "regen-on-revisit",
+    "diagonal-hallways",
+    "wide-hallways",
+    "debug",
+    "ascii",
+    "ghost",
+    "reveal-rooms",
+];
+
+/// The options that are given a value. Those about recording and
+/// replaying the run are left out, since they only make sense for a
+/// single run.
+const SETTINGS: &[&str] = &[
+    "name",
+    "scores",
+    "forget-after",
+    "infravision",
+    "spawn-interval",
+    "difficulty",
+    "keys",
+    "theme",
+    "view-aspect",
+    "fov",
+    "animation-delay",
+];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("line {0} should look like `key = value`")]
+    Syntax(usize),
+
+    #[error("line {0}: there's no option called `{1}`")]
+    UnknownKey(usize, String),
+
+    #[error("line {0}: `{1}` must be true or false")]
+    NotAFlag(usize, String),
+
+    #[error("line {0}: `{1}` must be a quoted string or a number")]
+    NotASetting(usize, String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The config file's usual home: in the user's home directory, or the
+/// current one if they don't have one.
+pub fn default_path() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => Path::new(&home).join(".dungeon_game.toml"),
+        None => PathBuf::from("dungeon_game.toml"),
+    }
+}
+
+/// Reads the config file at `path`, and returns the options it sets as
+/// they'd be given on the command line, in the order the file gives
+/// them.
+pub fn load(path: &Path) -> Result<Vec<String>, ConfigError> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// A value on the right-hand side of a line of the config file.
+enum Value {
+    Flag(bool),
+    Setting(String),
+}
+
+/// Turns the contents of a config file into command-line options.
+fn parse(contents: &str) -> Result<Vec<String>, ConfigError> {
+    let mut options = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let number = idx + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or(ConfigError::Syntax(number))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+        {
+            return Err(ConfigError::Syntax(number));
+        }
+        let option = key.replace('_', "-");
+        let value = parse_value(value.trim()).ok_or(ConfigError::Syntax(number))?;
+
+        if FLAGS.contains(&option.as_str()) {
+            match value {
+                Value::Flag(true) => options.push(format!("--{}", option)),
+                Value::Flag(false) => {}
+                Value::Setting(_) => return Err(ConfigError::NotAFlag(number, key.to_string())),
+            }
+        } else if let Some(command) = option.strip_prefix("key-") {
+            if Command::from_name(command).is_none() {
+                return Err(ConfigError::UnknownKey(number, key.to_string()));
+            }
+            match value {
+                Value::Setting(keys) => {
+                    options.push("--bind".to_string());
+                    options.push(command.to_string());
+                    options.push(keys);
+                }
+                Value::Flag(_) => return Err(ConfigError::NotASetting(number, key.to_string())),
+            }
+        } else if SETTINGS.contains(&option.as_str()) {
+            match value {
+                Value::Setting(value) => {
+                    options.push(format!("--{}", option));
+                    options.push(value);
+                }
+                Value::Flag(_) => return Err(ConfigError::NotASetting(number, key.to_string())),
+            }
+        } else {
+            return Err(ConfigError::UnknownKey(number, key.to_string()));
+        }
+    }
+
+    Ok(options)
+}
+
+/// Reads a value, and the comment after it if there is one, or returns
+/// `None` if it isn't a value.
+fn parse_value(text: &str) -> Option<Value> {
+    let (value, rest) = match text.strip_prefix('"') {
+        Some(quoted) => {
+            let mut value = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        '"' => value.push('"'),
+                        '\\' => value.push('\\'),
+                        _ => return None,
+                    },
+                    ch => value.push(ch),
+                }
+            }
+            (Value::Setting(value), chars.as_str())
+        }
+        None => {
+            let (bare, rest) = text.split_at(text.find('#').unwrap_or(text.len()));
+            let value = match bare.trim() {
+                "true" => Value::Flag(true),
+                "false" => Value::Flag(false),
+                number if number.parse::<i64>().is_ok() => Value::Setting(number.to_string()),
+                _ => return None,
+            };
+            (value, rest)
+        }
+    };
+
+    let rest = rest.trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The options that `contents` sets, which must be valid.
+    fn options(contents: &str) -> Vec<String> {
+        parse(contents).expect("Config should be valid")
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let contents = "\
+            # Settings for a harder game.\n\
+            \n\
+            difficulty = \"hard\" # not too hard\n\
+            \x20  # indented\n\
+            spawn_interval = 200#no space\n\
+            ghost = false\n\
+            debug = true # on";
+        assert_eq!(
+            options(contents),
+            ["--difficulty", "hard", "--spawn-interval", "200", "--debug"]
+        );
+    }
+
+    #[test]
+    fn quoted_strings_keep_what_is_inside() {
+        assert_eq!(
+            options(r#"name = "Bob # the \"Brave\" \\ o/""#),
+            ["--name", r#"Bob # the "Brave" \ o/"#]
+        );
+        assert_eq!(options(r#"name = """#), ["--name", ""]);
+
+        for bad in [
+            r#"name = "Bob"#,
+            r#"name = "Bob" Smith"#,
+            r#"name = "B\ob""#,
+            "name = Bob",
+        ] {
+            assert!(matches!(parse(bad), Err(ConfigError::Syntax(1))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn keys_bind_commands() {
+        assert_eq!(
+            options("keys = \"wasd\"\nkey_search = \"s\"\nkey-pick_up = \",g\""),
+            ["--keys", "wasd", "--bind", "search", "s", "--bind", "pick-up", ",g"]
+        );
+        assert!(matches!(
+            parse("key_search = true"),
+            Err(ConfigError::NotASetting(1, key)) if key == "key_search"
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_line() {
+        assert!(matches!(
+            parse("debug = true\ncolour = \"red\""),
+            Err(ConfigError::UnknownKey(2, key)) if key == "colour"
+        ));
+        assert!(matches!(
+            parse("\n\nkey_dance = \"d\""),
+            Err(ConfigError::UnknownKey(3, key)) if key == "key_dance"
+        ));
+        // Options that only make sense for a single run can't be set.
+        assert!(matches!(
+            parse("seed = 5"),
+            Err(ConfigError::UnknownKey(1, key)) if key == "seed"
+        ));
+        assert!(matches!(
+            parse("ghost = \"yes\""),
+            Err(ConfigError::NotAFlag(1, key)) if key == "ghost"
+        ));
+        assert!(matches!(parse("= 5"), Err(ConfigError::Syntax(1))));
+        assert!(matches!(parse("just words"), Err(ConfigError::Syntax(1))));
+    }
+
+    #[test]
+    fn theme_picks_the_look_of_levels() {
+        assert_eq!(
+            options("theme = \"sewer\"\ndifficulty = \"easy\""),
+            ["--theme", "sewer", "--difficulty", "easy"]
+        );
+        assert!(matches!(
+            parse("theme = true"),
+            Err(ConfigError::NotASetting(1, key)) if key == "theme"
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+//! Difficulty presets, which bundle together the numbers that decide
+//! how hard the game is.
+
+use crate::items::ItemKind;
+
+/// The settings that differ between difficulty levels.
+pub struct Difficulty {
+    /// What the difficulty is called, on the command line and in
+    /// messages.
+    pub name: &'static str,
+
+    /// The amount of health the player starts out with.
+    pub player_health: i32,
+
+    /// The items the player starts out carrying.
+    pub starting_items: &'static [ItemKind],
+
+    /// The number of monsters placed on each level, per 100 tiles of
+    /// floor space.
+    pub monster_density: f64,
+
+    /// How far away creatures can see.
+    pub view_radius: i32,
+
+    /// How long the player waits between turns, in the schedule's time
+    /// units.
+    pub player_turn: u32,
+}
+
+impl Difficulty {
+    /// The names of every difficulty, from easiest to hardest.
+    pub const NAMES: &'static [&'static str] = &["easy", "normal", "hard"];
+
+    /// A gentler game, with more health, some gear to start with and
+    /// fewer monsters.
+    pub fn easy() -> Self {
+        Self {
+            name: "easy",
+            player_health: 30,
+            starting_items: &[
+                ItemKind::Weapon {
+                    name: "dagger",
+                    damage: 1,
+                },
+                ItemKind::Armor {
+                    name: "leather armor",
+                    defense: 1,
+                },
+                ItemKind::TeleportScroll,
+            ],
+            monster_density: 1.0,
+            view_radius: 10,
+            player_turn: 10,
+        }
+    }
+
+    /// The game as it's meant to be played.
+    pub fn normal() -> Self {
+        Self {
+            name: "normal",
+            player_health: 20,
+            starting_items: &[],
+            monster_density: 1.5,
+            view_radius: 10,
+            player_turn: 10,
+        }
+    }
+
+    /// A harsher game, with less health, more monsters and less to see
+    /// them coming by.
+    pub fn hard() -> Self {
+        Self {
+            name: "hard",
+            player_health: 15,
+            starting_items: &[],
+            monster_density: 2.0,
+            view_radius: 8,
+            player_turn: 10,
+        }
+    }
+
+    /// Looks up a difficulty by name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "easy" => Some(Self::easy()),
+            "normal" => Some(Self::normal()),
+            "hard" => Some(Self::hard()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        Self::normal()
+    }
+}
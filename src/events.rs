@@ -0,0 +1,123 @@
+//! Events that happen in the game, for anything that wants to react
+//! to them without the code that caused them knowing about it.
+
+use specs::prelude::*;
+
+use crate::{
+    branch::LevelId,
+    components::{Item, Player},
+    messages::{capitalize, MessageLog, Severity},
+};
+
+/// Something that happened this turn.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    /// An entity moved from one position to another of its own
+    /// accord.
+    EntityMoved {
+        entity: Entity,
+        from: (i32, i32),
+        to: (i32, i32),
+    },
+
+    /// A creature died. The entity may well have been deleted by the
+    /// time anyone hears about it, so its description is kept along
+    /// with it.
+    EntityDied {
+        entity: Entity,
+        description: String,
+        killer: Option<Entity>,
+
+        /// How it died, as in "killed by the goblin", for the
+        /// high-score table.
+        cause: String,
+    },
+
+    /// A creature put an item in its inventory, under the given
+    /// letter.
+    ItemPickedUp {
+        entity: Entity,
+        item: Entity,
+        letter: char,
+    },
+
+    /// The player went from one level of the dungeon to another.
+    LevelChanged { from: LevelId, to: LevelId },
+}
+
+/// The events that have happened since they were last drained.
+#[derive(Default)]
+pub struct Events {
+    events: Vec<GameEvent>,
+}
+
+impl Events {
+    /// Records that an event has happened.
+    pub fn publish(&mut self, event: GameEvent) {
+        self.events.push(event);
+    }
+
+    /// Every event recorded so far, oldest first, without taking them.
+    pub fn pending(&self) -> &[GameEvent] {
+        &self.events
+    }
+
+    /// Takes every event recorded so far, oldest first.
+    pub fn drain(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// System for telling the player about the events that they'd want to
+/// hear about.
+pub struct NarrationSystem;
+
+impl<'a> System<'a> for NarrationSystem {
+    type SystemData = (
+        WriteExpect<'a, Events>,
+        WriteExpect<'a, MessageLog>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Item>,
+    );
+
+    fn run(&mut self, (mut events, mut log, players, items): Self::SystemData) {
+        for event in events.drain() {
+            match event {
+                GameEvent::EntityDied {
+                    entity,
+                    description,
+                    killer,
+                    ..
+                } => {
+                    if players.contains(entity) {
+                        log.log_with(Severity::Combat, "You die...");
+                    } else if killer.is_some_and(|killer| players.contains(killer)) {
+                        log.log_with(Severity::Combat, format!("You kill {}!", description));
+                    } else {
+                        log.log_with(
+                            Severity::Combat,
+                            capitalize(&format!("{} dies.", description)),
+                        );
+                    }
+                }
+
+                GameEvent::ItemPickedUp {
+                    entity,
+                    item,
+                    letter,
+                } => {
+                    if players.contains(entity) {
+                        if let Some(item) = items.get(item) {
+                            log.log_with(
+                                Severity::Pickup,
+                                format!("You pick up the {} ({}).", item.name, letter),
+                            );
+                        }
+                    }
+                }
+
+                GameEvent::EntityMoved { .. } | GameEvent::LevelChanged { .. } => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! IDs for entities that stay the same for as long as the entities
+//! exist, unlike specs' own `Entity` handles, which change whenever an
+//! entity is stashed away and restored, and are reused once it's gone.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use specs::{prelude::*, world::LazyBuilder};
+use specs_derive::Component;
+
+/// An entity's ID. No two entities in a run ever get the same one.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EntityId(pub u64);
+
+/// Resource that hands out entity IDs in order, starting from 0, so
+/// that a run played back from the same seed gives its entities the
+/// same IDs.
+#[derive(Default)]
+pub struct IdAllocator {
+    next: AtomicU64,
+}
+
+impl IdAllocator {
+    /// Takes the next ID.
+    pub fn allocate(&self) -> EntityId {
+        EntityId(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Starts building a new entity in the world, with an ID of its own.
+pub fn create_entity(world: &mut World) -> EntityBuilder<'_> {
+    let id = world.fetch::<IdAllocator>().allocate();
+    world.create_entity().with(id)
+}
+
+/// Starts building a new entity from inside a system, with an ID of
+/// its own. The entity appears once the world is next maintained.
+pub fn create_entity_lazy<'a>(
+    lazy: &'a LazyUpdate,
+    entities: &Entities,
+    ids: &IdAllocator,
+) -> LazyBuilder<'a> {
+    lazy.create_entity(entities).with(ids.allocate())
+}
+
+/// Finds the entity with the given ID, if it's in the world.
+pub fn entity_with_id(world: &World, id: EntityId) -> Option<Entity> {
+    let entities = world.entities();
+    let ids = world.read_storage::<EntityId>();
+    (&entities, &ids)
+        .join()
+        .find(|(_, other)| **other == id)
+        .map(|(ent, _)| ent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::empty_world;
+
+    #[test]
+    fn ids_are_handed_out_in_order() {
+        let mut world = empty_world();
+        let first = create_entity(&mut world).build();
+        let second = create_entity(&mut world).build();
+        world.delete_entity(first).unwrap();
+        let third = create_entity(&mut world).build();
+
+        let ids = world.read_storage::<EntityId>();
+        assert_eq!(ids.get(second), Some(&EntityId(1)));
+        assert_eq!(ids.get(third), Some(&EntityId(2)));
+        drop(ids);
+        assert_eq!(entity_with_id(&world, EntityId(2)), Some(third));
+        assert_eq!(entity_with_id(&world, EntityId(0)), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Spatial index for quickly finding the entities at a position.
+
+use std::collections::HashMap;
+
+use smallvec::SmallVec;
+use specs::prelude::*;
+
+use crate::components::{Position, Size};
+
+/// A lookup table from positions in the level to the entities
+/// standing there.
+#[derive(Default)]
+pub struct PositionIndex {
+    cells: HashMap<(i32, i32), SmallVec<[Entity; 2]>>,
+}
+
+impl PositionIndex {
+    /// Replaces the contents of the index with the given entities.
+    /// Large entities are recorded in every cell they take up.
+    pub fn rebuild<'a>(
+        &mut self,
+        entities: impl Iterator<Item = (Entity, &'a Position, Option<&'a Size>)>,
+    ) {
+        self.cells.clear();
+        for (ent, pos, size) in entities {
+            for cell in size.copied().unwrap_or_default().cells(pos.into()) {
+                self.insert(ent, cell);
+            }
+        }
+    }
+
+    /// Gets the entities at the given position.
+    pub fn entities_at(&self, x: i32, y: i32) -> &[Entity] {
+        self.cells.get(&(x, y)).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// Gets every entity with any part of itself within `radius`
+    /// cells of `center`, each listed once.
+    pub fn entities_near(&self, center: (i32, i32), radius: i32) -> Vec<Entity> {
+        let mut found: Vec<Entity> = Vec::new();
+        for y in center.1 - radius..=center.1 + radius {
+            for x in center.0 - radius..=center.0 + radius {
+                for &ent in self.entities_at(x, y) {
+                    if !found.contains(&ent) {
+                        found.push(ent);
+                    }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Records that an entity is at the given position.
+    pub fn insert(&mut self, ent: Entity, pos: (i32, i32)) {
+        self.cells.entry(pos).or_default().push(ent);
+    }
+
+    /// Records that an entity is no longer at the given position.
+    pub fn remove(&mut self, ent: Entity, pos: (i32, i32)) {
+        if let Some(cell) = self.cells.get_mut(&pos) {
+            cell.retain(|other| *other != ent);
+            if cell.is_empty() {
+                self.cells.remove(&pos);
+            }
+        }
+    }
+
+    /// Records that an entity has moved from one position to
+    /// another.
+    pub fn move_entity(&mut self, ent: Entity, from: (i32, i32), to: (i32, i32)) {
+        self.remove(ent, from);
+        self.insert(ent, to);
+    }
+
+    /// Records that an entity of the given size with its upper-left
+    /// corner at `pos` is no longer there.
+    pub fn remove_sized(&mut self, ent: Entity, size: Size, pos: (i32, i32)) {
+        for cell in size.cells(pos) {
+            self.remove(ent, cell);
+        }
+    }
+
+    /// Records that an entity of the given size has moved its
+    /// upper-left corner from one position to another.
+    pub fn move_sized(&mut self, ent: Entity, size: Size, from: (i32, i32), to: (i32, i32)) {
+        self.remove_sized(ent, size, from);
+        for cell in size.cells(to) {
+            self.insert(ent, cell);
+        }
+    }
+}
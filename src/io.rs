@@ -1,18 +1,55 @@
 //! Pancurses boilerplate code.
 
-use std::process::exit;
+use std::{
+    fmt::Display,
+    panic,
+    process::exit,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use pancurses::{
-    endwin, has_colors, init_pair, initscr, noecho, start_color, ColorPair, Window, COLORS,
-    COLOR_PAIRS,
+    chtype, endwin, has_colors, init_pair, initscr, noecho, start_color, use_default_colors,
+    ColorPair, Input, Window, ACS_LLCORNER, ACS_LRCORNER, ACS_ULCORNER, ACS_URCORNER, A_DIM,
+    COLORS, COLOR_PAIRS,
 };
 use thiserror::Error;
 
+/// The color pair used to draw dimmed text on terminals that don't
+/// support `A_DIM`.
+const DIM_PAIR: u8 = 8;
+
+/// How long each frame of an animation stays on the screen.
+const FRAME_TIME: Duration = Duration::from_millis(30);
+
+/// Whether curses has taken over the terminal, and needs to give it
+/// back before the game exits.
+static CURSES_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the player has pressed Ctrl-C. The signal handler only sets
+/// this, since there's very little that's safe to do inside one; the
+/// game notices it in `check_interrupt`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The exit code for a game stopped by Ctrl-C, as is usual for SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
 /// Initializes the terminal to accept user input, and creates a new
-/// Window.
-pub fn init_window() -> Result<Window, ColorError> {
-    // Create a new window over the terminal.
+/// Screen covering it. Terminals that can't show the game's colors get
+/// a screen that draws in plain text; see `Screen::color_error`.
+pub fn init_window() -> Screen {
+    // Create a new window over the terminal, and make sure we give
+    // the terminal back if the game crashes or is interrupted. Curses
+    // leaves SIGINT alone if it's already being handled, so that has to
+    // come first.
+    catch_interrupts();
     let window = initscr();
+    CURSES_ACTIVE.store(true, Ordering::SeqCst);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
 
     // Enable keypad mode (off by default for historical reasons), so
     // we can read special keycodes other than just characters.
@@ -22,21 +59,375 @@ pub fn init_window() -> Result<Window, ColorError> {
     // upper-left corner of the screen when they type a character.
     noecho();
 
-    // Set up a color palette.
-    init_colors()?;
+    // Set up a color palette, if we can.
+    let colors = init_colors();
+
+    Screen::new(window, colors.err())
+}
+
+/// A double-buffered view of the terminal. Frames are composited in
+/// memory with `put`, then written out all at once by `flush`, which
+/// only touches the cells that changed since the previous frame.
+pub struct Screen {
+    window: Window,
+
+    /// The size of the screen, in cells.
+    size: (usize, usize),
+
+    /// The frame currently being composited.
+    back: Vec<Cell>,
+
+    /// The frame most recently written to the terminal, or `None` if
+    /// nothing has been written yet.
+    front: Option<Vec<Cell>>,
+
+    /// Where to leave the cursor once the frame is written.
+    cursor: (i32, i32),
+
+    /// Whether the terminal can draw dimmed text with `A_DIM`.
+    can_dim: bool,
 
-    Ok(window)
+    /// Why the game's colors couldn't be set up, if they couldn't, in
+    /// which case everything is drawn in plain text.
+    color_error: Option<ColorError>,
+
+    /// Whether to draw box-drawing characters where they make sense,
+    /// rather than plain ASCII.
+    box_drawing: bool,
+}
+
+/// The contents of a single character on the screen.
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    glyph: char,
+    color: Color,
+    dim: bool,
 }
 
-/// Cleans everything up and exits the game.
+impl Cell {
+    /// An empty cell.
+    const BLANK: Self = Self {
+        glyph: ' ',
+        color: Color::White,
+        dim: false,
+    };
+}
+
+impl Screen {
+    /// Creates a new, blank screen covering the whole window, which
+    /// draws in color unless there's a `color_error`.
+    fn new(window: Window, color_error: Option<ColorError>) -> Self {
+        let (rows, cols) = window.get_max_yx();
+        let size = (cols as usize, rows as usize);
+
+        Self {
+            window,
+            size,
+            back: vec![Cell::BLANK; size.0 * size.1],
+            front: None,
+            cursor: (0, 0),
+            can_dim: can_dim(),
+            color_error,
+            box_drawing: true,
+        }
+    }
+
+    /// Creates a screen on a terminal of its own, which draws to
+    /// nowhere and reads `keys` as its input, then acts as though
+    /// stdin was closed. Only one should be in use at a time; see
+    /// `testing::with_screen`.
+    #[cfg(all(test, unix))]
+    pub fn headless(keys: &str) -> Self {
+        // SAFETY: both files are checked before curses is given them,
+        // and `keys` is only read during the call to `fwrite`.
+        unsafe {
+            let input = libc::tmpfile();
+            let output = libc::fopen(c"/dev/null".as_ptr(), c"w".as_ptr());
+            assert!(!input.is_null() && !output.is_null());
+            libc::fwrite(keys.as_ptr().cast(), 1, keys.len(), input);
+            libc::rewind(input);
+            pancurses::newterm(Some("xterm"), output, input);
+        }
+        let window = pancurses::newwin(0, 0, 0, 0);
+        window.keypad(true);
+        noecho();
+
+        Self::new(window, Some(ColorError::NoColors))
+    }
+
+    /// Why the screen is drawing in plain text rather than color, if it
+    /// is.
+    pub fn color_error(&self) -> Option<&ColorError> {
+        self.color_error.as_ref()
+    }
+
+    /// Whether box-drawing characters should be drawn. Box-drawing
+    /// corners put on the screen are drawn with the terminal's
+    /// line-drawing characters.
+    pub fn box_drawing(&self) -> bool {
+        self.box_drawing
+    }
+
+    /// Turns box-drawing characters on or off, e.g., for terminals
+    /// that don't draw them properly.
+    pub fn set_box_drawing(&mut self, enabled: bool) {
+        self.box_drawing = enabled;
+    }
+
+    /// Blanks out the frame being composited.
+    pub fn clear(&mut self) {
+        self.back.fill(Cell::BLANK);
+    }
+
+    /// Sets the character and color at the given cell of the frame
+    /// being composited. Cells outside the screen are ignored.
+    pub fn put(&mut self, x: i32, y: i32, glyph: char, color: Color) {
+        self.put_cell(
+            x,
+            y,
+            Cell {
+                glyph,
+                color,
+                dim: false,
+            },
+        );
+    }
+
+    /// Like `put`, but draws the character dimmer than normal.
+    pub fn put_dim(&mut self, x: i32, y: i32, glyph: char, color: Color) {
+        self.put_cell(
+            x,
+            y,
+            Cell {
+                glyph,
+                color,
+                dim: true,
+            },
+        );
+    }
+
+    fn put_cell(&mut self, x: i32, y: i32, cell: Cell) {
+        if let Some(idx) = self.index(x, y) {
+            self.back[idx] = cell;
+        }
+    }
+
+    /// The character at the given cell of the frame being composited,
+    /// and whether it's dimmed, or `None` if the cell is off the screen.
+    #[cfg(test)]
+    pub fn glyph_at(&self, x: i32, y: i32) -> Option<(char, bool)> {
+        self.index(x, y)
+            .map(|idx| (self.back[idx].glyph, self.back[idx].dim))
+    }
+
+    /// The index of the given cell in a frame, if it's on the screen.
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        ((0..self.size.0 as i32).contains(&x) && (0..self.size.1 as i32).contains(&y))
+            .then(|| y as usize * self.size.0 + x as usize)
+    }
+
+    /// Writes a string into the frame being composited, starting at
+    /// the given cell and continuing to the right.
+    pub fn put_str(&mut self, x: i32, y: i32, text: &str, color: Color) {
+        for (dx, ch) in text.chars().enumerate() {
+            self.put(x + dx as i32, y, ch, color);
+        }
+    }
+
+    /// Sets where the cursor is left once the frame is written.
+    pub fn set_cursor(&mut self, x: i32, y: i32) {
+        self.cursor = (x, y);
+    }
+
+    /// Writes the composited frame out to the terminal.
+    pub fn flush(&mut self) {
+        // Avoid switching styles unless we actually need to.
+        let mut current_style = None;
+
+        for (idx, cell) in self.back.iter().enumerate() {
+            if let Some(front) = &self.front {
+                if front[idx] == *cell {
+                    continue;
+                }
+            }
+
+            if current_style != Some((cell.color, cell.dim)) {
+                self.set_style(cell.color, cell.dim);
+                current_style = Some((cell.color, cell.dim));
+            }
+
+            let glyph = match cell.glyph {
+                '┌' => ACS_ULCORNER(),
+                '┐' => ACS_URCORNER(),
+                '└' => ACS_LLCORNER(),
+                '┘' => ACS_LRCORNER(),
+                glyph => glyph as chtype,
+            };
+            let (x, y) = (idx % self.size.0, idx / self.size.0);
+            self.window.mvaddch(y as _, x as _, glyph);
+        }
+
+        self.front = Some(self.back.clone());
+
+        self.window.mv(self.cursor.1, self.cursor.0);
+        self.window.refresh();
+    }
+
+    /// Sets the color and dimness of the text drawn after this. Without
+    /// colors, only the dimness is kept, and only if the terminal can
+    /// dim text; otherwise dim text looks like any other.
+    fn set_style(&self, color: Color, dim: bool) {
+        // Use the dedicated dim color if we can't dim text normally.
+        let pair = if dim && !self.can_dim {
+            DIM_PAIR
+        } else {
+            color as _
+        };
+
+        let mut attrs: chtype = if self.color_error.is_none() {
+            ColorPair(pair).into()
+        } else {
+            0
+        };
+        if dim && self.can_dim {
+            attrs |= A_DIM;
+        }
+
+        self.window.attrset(attrs);
+    }
+
+    /// Waits for the user to press a key, returning `None` if stdin
+    /// is closed.
+    pub fn getch(&self) -> Option<Input> {
+        self.wait_key(None)
+    }
+
+    /// Waits for the user to press a key, for prompts. Unlike `getch`,
+    /// running out of input is an error, so that a prompt can be given
+    /// up on with `?` all the way back to whoever decides to quit.
+    pub fn read_key(&self) -> Result<Input, InputClosed> {
+        self.getch().ok_or(InputClosed)
+    }
+
+    /// Waits for the user to press a key, giving up after `timeout` if
+    /// one is given. Returns `None` if time runs out or stdin is
+    /// closed.
+    pub fn wait_key(&self, timeout: Option<Duration>) -> Option<Input> {
+        self.window.timeout(match timeout {
+            Some(timeout) => timeout.as_millis().try_into().unwrap_or(i32::MAX),
+            None => -1,
+        });
+
+        let key = self.window.getch();
+        check_interrupt();
+        key
+    }
+
+    /// Keeps what's on the screen there for `time`, so that the player
+    /// can take it in. A key pressed in the meantime cuts the wait
+    /// short, and is left to be read as usual.
+    pub fn pause(&self, time: Duration) {
+        if let Some(key) = self.wait_key(Some(time)) {
+            self.window.ungetch(&key);
+        }
+    }
+
+    /// Shows `glyph` travelling along `path` on top of the last frame,
+    /// e.g., for a thrown projectile. Pressing a key skips the rest of
+    /// the animation.
+    pub fn animate_path(&mut self, path: &[(i32, i32)], glyph: char, color: Color) {
+        for &(x, y) in path {
+            let idx = match self.index(x, y) {
+                Some(idx) => idx,
+                None => continue,
+            };
+
+            let saved = self.back[idx];
+            self.put(x, y, glyph, color);
+            self.flush();
+            self.back[idx] = saved;
+
+            if self.wait_key(Some(FRAME_TIME)).is_some() {
+                break;
+            }
+        }
+
+        self.flush();
+    }
+}
+
+/// Arranges for Ctrl-C to set `INTERRUPTED` instead of killing the
+/// game outright. The handler is installed without `SA_RESTART`, so
+/// that it cuts short any `getch` that's waiting for a key.
+#[cfg(unix)]
+fn catch_interrupts() {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe, and the `sigaction` struct is fully set up
+    // before it's used.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+/// Elsewhere, Ctrl-C keeps doing whatever it does by default.
+#[cfg(not(unix))]
+fn catch_interrupts() {}
+
+/// Exits the game if the player has pressed Ctrl-C since the game
+/// started, giving the terminal back first.
+pub fn check_interrupt() {
+    if interrupted() {
+        restore_terminal();
+        eprintln!("Interrupted.");
+
+        exit(INTERRUPTED_EXIT_CODE)
+    }
+}
+
+/// Whether the player has pressed Ctrl-C since the game started.
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Cleans everything up and exits the game successfully.
 pub fn quit() -> ! {
-    endwin();
+    restore_terminal();
 
     exit(0)
 }
 
+/// Cleans everything up, reports `err`, and exits the game with a
+/// failing exit code. This works whether or not the window has been
+/// set up yet.
+pub fn quit_with_error(err: impl Display) -> ! {
+    restore_terminal();
+    eprintln!("{}", err);
+
+    exit(1)
+}
+
+/// Gives the terminal back from curses, if it's been taken over.
+fn restore_terminal() {
+    if CURSES_ACTIVE.swap(false, Ordering::SeqCst) {
+        endwin();
+    }
+}
+
+/// There's no more input to be had, because stdin has been closed.
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[error("input closed")]
+pub struct InputClosed;
+
 /// The colors on a terminal.
 #[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Color {
     Black = pancurses::COLOR_BLACK as _,
     Red = pancurses::COLOR_RED as _,
@@ -53,34 +444,75 @@ pub enum ColorError {
     #[error("colors not supported")]
     NoColors,
 
+    #[error("colors couldn't be started")]
+    StartFailed,
+
     #[error("too few colors (have {0}, need 8)")]
     NotEnoughColors(u32),
 
-    #[error("too few color slots (have {0}, need 8)")]
+    #[error("too few color slots (have {0}, need 9)")]
     NotEnoughSlots(u32),
 }
 
 fn init_colors() -> Result<(), ColorError> {
-    assert_eq!(start_color(), 0);
     if !has_colors() {
         Err(ColorError::NoColors)
+    } else if start_color() != 0 {
+        Err(ColorError::StartFailed)
     } else if COLORS() < 8 {
         Err(ColorError::NotEnoughColors(COLORS() as _))
-    } else if COLOR_PAIRS() < 8 {
+    } else if COLOR_PAIRS() <= DIM_PAIR as _ {
         Err(ColorError::NotEnoughSlots(COLOR_PAIRS() as _))
     } else {
+        // Draw on the terminal's own background where we can, so that
+        // the game fits in with its theme, and on black otherwise.
+        let background = if use_default_colors() == 0 {
+            -1
+        } else {
+            Color::Black as _
+        };
         for n in 0..8 {
-            init_pair(n, n, Color::Black as _);
+            init_pair(n, n, background);
         }
+        init_pair(DIM_PAIR as _, Color::Blue as _, background);
 
         Ok(())
     }
 }
 
-pub fn set_color(win: &Window, c: Color) {
-    // This gets called for every single character; could be a
-    // performance bottleneck depending on how ncurses implements it.
-    if has_colors() {
-        win.attron(ColorPair(c as _));
+/// Whether the terminal supports dimmed text.
+#[cfg(unix)]
+fn can_dim() -> bool {
+    ncurses::termattrs() & ncurses::A_DIM() != 0
+}
+
+/// Whether the terminal supports dimmed text.
+#[cfg(not(unix))]
+fn can_dim() -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::CURSES_LOCK;
+
+    #[test]
+    fn ctrl_c_only_sets_the_flag() {
+        // Screens check the flag whenever they wait for a key, and
+        // would exit if they saw it set, so none can be in use.
+        let _guard = CURSES_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        catch_interrupts();
+        assert!(!interrupted());
+
+        // SAFETY: the handler installed above only sets the flag.
+        unsafe {
+            libc::raise(libc::SIGINT);
+        }
+        assert!(interrupted());
+
+        INTERRUPTED.store(false, Ordering::SeqCst);
     }
 }
@@ -0,0 +1,179 @@
+//! Definitions of the kinds of items that can be found in the dungeon.
+
+use specs::prelude::*;
+
+use crate::{
+    components::{Armor, CharRender, Item, ItemCategory, ItemEffect, Position, Splash, Weapon},
+    ids::create_entity,
+    io::Color,
+};
+
+/// The kinds of items that exist, not counting corpses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemKind {
+    TeleportScroll,
+    GenocideScroll,
+
+    /// A weapon that adds `damage` to each of its wielder's hits.
+    Weapon {
+        name: &'static str,
+        damage: i32,
+    },
+
+    /// Armor that takes `defense` off each hit on its wearer.
+    Armor {
+        name: &'static str,
+        defense: i32,
+    },
+
+    /// A potion that shatters when thrown, splashing everyone around
+    /// where it lands.
+    Potion {
+        name: &'static str,
+        splash: Splash,
+    },
+}
+
+impl ItemKind {
+    /// Every kind of weapon.
+    pub const WEAPONS: &'static [ItemKind] = &[
+        ItemKind::Weapon {
+            name: "dagger",
+            damage: 1,
+        },
+        ItemKind::Weapon {
+            name: "short sword",
+            damage: 2,
+        },
+        ItemKind::Weapon {
+            name: "mace",
+            damage: 3,
+        },
+    ];
+
+    /// Every kind of armor.
+    pub const ARMORS: &'static [ItemKind] = &[
+        ItemKind::Armor {
+            name: "leather armor",
+            defense: 1,
+        },
+        ItemKind::Armor {
+            name: "ring mail",
+            defense: 2,
+        },
+        ItemKind::Armor {
+            name: "chain mail",
+            defense: 3,
+        },
+    ];
+
+    /// Every kind of potion.
+    pub const POTIONS: &'static [ItemKind] = &[
+        ItemKind::Potion {
+            name: "potion of fire",
+            splash: Splash::Fire,
+        },
+        ItemKind::Potion {
+            name: "potion of confusion",
+            splash: Splash::Confusion,
+        },
+    ];
+
+    /// What the item is called.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ItemKind::TeleportScroll => "scroll of teleportation",
+            ItemKind::GenocideScroll => "scroll of genocide",
+            ItemKind::Weapon { name, .. }
+            | ItemKind::Armor { name, .. }
+            | ItemKind::Potion { name, .. } => name,
+        }
+    }
+
+    /// What sort of item this is.
+    pub fn category(&self) -> ItemCategory {
+        match self {
+            ItemKind::TeleportScroll | ItemKind::GenocideScroll => ItemCategory::Scroll,
+            ItemKind::Weapon { .. } => ItemCategory::Weapon,
+            ItemKind::Armor { .. } => ItemCategory::Armor,
+            ItemKind::Potion { .. } => ItemCategory::Potion,
+        }
+    }
+
+    /// How the item is drawn.
+    pub fn render(&self) -> CharRender {
+        match self {
+            ItemKind::TeleportScroll => CharRender {
+                glyph: '?',
+                color: Color::Magenta,
+            },
+            ItemKind::GenocideScroll => CharRender {
+                glyph: '?',
+                color: Color::Red,
+            },
+            ItemKind::Weapon { .. } => CharRender {
+                glyph: ')',
+                color: Color::Cyan,
+            },
+            ItemKind::Armor { .. } => CharRender {
+                glyph: '[',
+                color: Color::Cyan,
+            },
+            ItemKind::Potion { splash, .. } => CharRender {
+                glyph: '!',
+                color: match splash {
+                    Splash::Fire => Color::Red,
+                    Splash::Confusion => Color::Magenta,
+                },
+            },
+        }
+    }
+
+    /// Creates a new item of this kind that isn't anywhere on the
+    /// level, e.g., for putting straight into an inventory.
+    pub fn create(&self, world: &mut World) -> Entity {
+        let effect = match self {
+            ItemKind::TeleportScroll => ItemEffect::Teleport,
+            ItemKind::GenocideScroll => ItemEffect::Genocide,
+            ItemKind::Weapon { .. } | ItemKind::Armor { .. } => ItemEffect::Equip,
+            ItemKind::Potion { splash, .. } => ItemEffect::Splash(*splash),
+        };
+        let item = create_entity(world)
+            .with(self.render())
+            .with(Item {
+                name: self.name(),
+                category: self.category(),
+                effect,
+            })
+            .build();
+
+        match *self {
+            ItemKind::TeleportScroll | ItemKind::GenocideScroll | ItemKind::Potion { .. } => {}
+            ItemKind::Weapon { damage, .. } => {
+                world
+                    .write_storage::<Weapon>()
+                    .insert(item, Weapon { damage })
+                    .expect("Item was just created");
+            }
+            ItemKind::Armor { defense, .. } => {
+                world
+                    .write_storage::<Armor>()
+                    .insert(item, Armor { defense })
+                    .expect("Item was just created");
+            }
+        }
+
+        item
+    }
+
+    /// Creates a new item of this kind lying at the given position.
+    pub fn spawn(&self, world: &mut World, (x, y): (i32, i32)) -> Entity {
+        let item = self.create(world);
+        world
+            .write_storage::<Position>()
+            .insert(item, Position { x, y })
+            .expect("Item was just created");
+
+        item
+    }
+}
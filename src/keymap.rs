@@ -0,0 +1,521 @@
+//! The mapping from keys to the commands they run.
+
+use pancurses::Input;
+
+/// Something the player can ask to do with a single key press.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Move, or attack whatever's in the way, by the given vector.
+    Move(i32, i32),
+    Wait,
+    Search,
+    Open,
+    PickUp,
+    Drop,
+    GoUp,
+    GoDown,
+    Quaff,
+    Read,
+    Eat,
+    Apply,
+    Wield,
+    Wear,
+    TakeOff,
+    Blink,
+    Throw,
+    Mark,
+    Unmark,
+    Look,
+    Inventory,
+    Map,
+    Help,
+
+    /// Debug command: reveal the whole level.
+    RevealMap,
+
+    /// Debug command: list where the rooms and stairs are.
+    ShowLayout,
+
+    /// Debug command: wipe out a kind of monster.
+    Genocide,
+
+    Quit,
+}
+
+impl Command {
+    /// A short description of the command, for the help screen.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Command::Move(-1, 0) => "Move west",
+            Command::Move(1, 0) => "Move east",
+            Command::Move(0, -1) => "Move north",
+            Command::Move(0, 1) => "Move south",
+            Command::Move(-1, -1) => "Move northwest",
+            Command::Move(1, -1) => "Move northeast",
+            Command::Move(-1, 1) => "Move southwest",
+            Command::Move(1, 1) => "Move southeast",
+            Command::Move(_, _) => "Move",
+            Command::Wait => "Wait a turn",
+            Command::Search => "Search for hidden doors",
+            Command::Open => "Open a door",
+            Command::PickUp => "Pick up items",
+            Command::Drop => "Drop an item",
+            Command::GoUp => "Go up the stairs",
+            Command::GoDown => "Go down the stairs",
+            Command::Quaff => "Drink a potion, or from a fountain",
+            Command::Read => "Read a scroll",
+            Command::Eat => "Eat something",
+            Command::Apply => "Use an item",
+            Command::Wield => "Wield a weapon",
+            Command::Wear => "Wear armor",
+            Command::TakeOff => "Take off equipment",
+            Command::Blink => "Blink to a nearby spot",
+            Command::Throw => "Throw an item",
+            Command::Mark => "Leave a note on the map",
+            Command::Unmark => "Remove a note from the map",
+            Command::Look => "Look at something on the map",
+            Command::Inventory => "List what you're carrying",
+            Command::Map => "Show the map of the level",
+            Command::Help => "Show this help",
+            Command::RevealMap => "Reveal the whole level (debug)",
+            Command::ShowLayout => "List rooms and stairs (debug)",
+            Command::Genocide => "Wipe out a kind of monster (debug)",
+            Command::Quit => "Quit the game",
+        }
+    }
+}
+
+/// The commands that can be bound to keys of the player's choosing,
+/// by name.
+const BINDABLE: &[(&str, Command)] = &[
+    ("move-west", Command::Move(-1, 0)),
+    ("move-south", Command::Move(0, 1)),
+    ("move-north", Command::Move(0, -1)),
+    ("move-east", Command::Move(1, 0)),
+    ("move-northwest", Command::Move(-1, -1)),
+    ("move-northeast", Command::Move(1, -1)),
+    ("move-southwest", Command::Move(-1, 1)),
+    ("move-southeast", Command::Move(1, 1)),
+    ("wait", Command::Wait),
+    ("search", Command::Search),
+    ("open", Command::Open),
+    ("pick-up", Command::PickUp),
+    ("drop", Command::Drop),
+    ("go-up", Command::GoUp),
+    ("go-down", Command::GoDown),
+    ("quaff", Command::Quaff),
+    ("read", Command::Read),
+    ("eat", Command::Eat),
+    ("apply", Command::Apply),
+    ("wield", Command::Wield),
+    ("wear", Command::Wear),
+    ("take-off", Command::TakeOff),
+    ("blink", Command::Blink),
+    ("throw", Command::Throw),
+    ("mark", Command::Mark),
+    ("unmark", Command::Unmark),
+    ("look", Command::Look),
+    ("inventory", Command::Inventory),
+    ("map", Command::Map),
+    ("help", Command::Help),
+    ("quit", Command::Quit),
+];
+
+impl Command {
+    /// Gets a command that can be bound to a key by its name, e.g.,
+    /// "search" or "move-west". Words are split by `-` or `_`. The
+    /// debug commands don't have names, since they can't be rebound.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.replace('_', "-");
+        BINDABLE
+            .iter()
+            .find(|(bindable, _command)| *bindable == name)
+            .map(|(_name, command)| *command)
+    }
+}
+
+/// Which key runs which command.
+pub struct Keymap {
+    /// Every binding, in the order they're listed in the help screen.
+    bindings: Vec<(Input, Command)>,
+}
+
+/// The directions that movement keys are given in, in the order vi
+/// lists them: h, j, k, l, then y, u, b, n.
+const DIRECTIONS: [(i32, i32); 8] = [
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 0),
+    (-1, -1),
+    (1, -1),
+    (-1, 1),
+    (1, 1),
+];
+
+/// The arrow keys for the first four of `DIRECTIONS`.
+const ARROWS: [Input; 4] = [
+    Input::KeyLeft,
+    Input::KeyDown,
+    Input::KeyUp,
+    Input::KeyRight,
+];
+
+impl Keymap {
+    /// The names of the built-in layouts, for `from_name`.
+    pub const NAMES: &'static [&'static str] = &["vi", "wasd", "dvorak"];
+
+    /// Builds a keymap that moves with the given keys, one for each of
+    /// `DIRECTIONS`, as well as the arrow keys, and binds everything
+    /// else as given in `others`.
+    fn new(movement: [char; 8], others: &[(char, Command)]) -> Self {
+        let mut bindings = Vec::new();
+        for (idx, (&key, &(dx, dy))) in movement.iter().zip(DIRECTIONS.iter()).enumerate() {
+            bindings.push((Input::Character(key), Command::Move(dx, dy)));
+            if let Some(arrow) = ARROWS.get(idx) {
+                bindings.push((*arrow, Command::Move(dx, dy)));
+            }
+        }
+        bindings.extend(
+            others
+                .iter()
+                .map(|&(key, command)| (Input::Character(key), command)),
+        );
+
+        Self { bindings }
+    }
+
+    /// The commands bound to the vi-keys, plus the arrow keys.
+    pub fn vi() -> Self {
+        Self::new(
+            ['h', 'j', 'k', 'l', 'y', 'u', 'b', 'n'],
+            &[
+                ('.', Command::Wait),
+                ('5', Command::Wait),
+                ('s', Command::Search),
+                ('o', Command::Open),
+                (',', Command::PickUp),
+                ('d', Command::Drop),
+                ('<', Command::GoUp),
+                ('>', Command::GoDown),
+                ('q', Command::Quaff),
+                ('r', Command::Read),
+                ('e', Command::Eat),
+                ('a', Command::Apply),
+                ('w', Command::Wield),
+                ('W', Command::Wear),
+                ('T', Command::TakeOff),
+                ('z', Command::Blink),
+                ('t', Command::Throw),
+                ('m', Command::Mark),
+                ('U', Command::Unmark),
+                (';', Command::Look),
+                ('i', Command::Inventory),
+                ('M', Command::Map),
+                ('?', Command::Help),
+                ('Q', Command::Quit),
+            ],
+        )
+    }
+
+    /// Moves with w, a, s and d, and q, e, z and c for the diagonals.
+    /// The commands those keys had in the vi layout move elsewhere.
+    pub fn wasd() -> Self {
+        Self::new(
+            ['a', 's', 'w', 'd', 'q', 'e', 'z', 'c'],
+            &[
+                ('.', Command::Wait),
+                ('5', Command::Wait),
+                ('f', Command::Search),
+                ('o', Command::Open),
+                (',', Command::PickUp),
+                ('D', Command::Drop),
+                ('<', Command::GoUp),
+                ('>', Command::GoDown),
+                ('p', Command::Quaff),
+                ('r', Command::Read),
+                ('E', Command::Eat),
+                ('u', Command::Apply),
+                ('v', Command::Wield),
+                ('W', Command::Wear),
+                ('T', Command::TakeOff),
+                ('b', Command::Blink),
+                ('t', Command::Throw),
+                ('m', Command::Mark),
+                ('U', Command::Unmark),
+                (';', Command::Look),
+                ('i', Command::Inventory),
+                ('M', Command::Map),
+                ('?', Command::Help),
+                ('Q', Command::Quit),
+            ],
+        )
+    }
+
+    /// The vi layout with the movement keys moved to where h, j, k, l,
+    /// y, u, b and n are on a QWERTY keyboard, for Dvorak typists.
+    pub fn dvorak() -> Self {
+        Self::new(
+            ['d', 'h', 't', 'n', 'f', 'g', 'x', 'b'],
+            &[
+                ('.', Command::Wait),
+                ('5', Command::Wait),
+                ('s', Command::Search),
+                ('o', Command::Open),
+                (',', Command::PickUp),
+                ('D', Command::Drop),
+                ('<', Command::GoUp),
+                ('>', Command::GoDown),
+                ('q', Command::Quaff),
+                ('r', Command::Read),
+                ('e', Command::Eat),
+                ('a', Command::Apply),
+                ('w', Command::Wield),
+                ('W', Command::Wear),
+                ('T', Command::TakeOff),
+                ('z', Command::Blink),
+                // 't' moves north here.
+                ('y', Command::Throw),
+                ('m', Command::Mark),
+                ('U', Command::Unmark),
+                (';', Command::Look),
+                ('i', Command::Inventory),
+                ('M', Command::Map),
+                ('?', Command::Help),
+                ('Q', Command::Quit),
+            ],
+        )
+    }
+
+    /// Looks up a built-in layout by name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vi" => Some(Self::vi()),
+            "wasd" => Some(Self::wasd()),
+            "dvorak" => Some(Self::dvorak()),
+            _ => None,
+        }
+    }
+
+    /// Binds `command` to each of `keys` instead of the keys it had
+    /// before. The arrow keys stay where they are, as does the
+    /// command's place in the help screen. Anything else that was bound
+    /// to one of `keys` isn't any more.
+    pub fn bind(&mut self, command: Command, keys: &str) {
+        let keys: Vec<Input> = keys.chars().map(Input::Character).collect();
+        let keep = |(key, other): &(Input, Command)| {
+            !keys.contains(key) && (*other != command || !matches!(key, Input::Character(_)))
+        };
+
+        let at = match self
+            .bindings
+            .iter()
+            .position(|(_key, other)| *other == command)
+        {
+            Some(at) => self.bindings[..at]
+                .iter()
+                .filter(|binding| keep(binding))
+                .count(),
+            None => self.bindings.iter().filter(|binding| keep(binding)).count(),
+        };
+        self.bindings.retain(keep);
+        self.bindings
+            .splice(at..at, keys.into_iter().map(|key| (key, command)));
+    }
+
+    /// Binds the debugging commands, which are left out of normal play:
+    /// ^F reveals the level and ^O lists its rooms and stairs.
+    pub fn enable_debug(&mut self) {
+        self.bindings.extend([
+            (Input::Character('\u{6}'), Command::RevealMap),
+            (Input::Character('\u{f}'), Command::ShowLayout),
+            (Input::Character('\u{7}'), Command::Genocide),
+        ]);
+    }
+
+    /// The command bound to a key, if any.
+    pub fn command(&self, key: &Input) -> Option<Command> {
+        self.bindings
+            .iter()
+            .find(|(bound, _command)| bound == key)
+            .map(|(_key, command)| *command)
+    }
+
+    /// The direction a key points in, if it's bound to a movement
+    /// command.
+    pub fn direction(&self, key: &Input) -> Option<(i32, i32)> {
+        match self.command(key) {
+            Some(Command::Move(dx, dy)) => Some((dx, dy)),
+            _ => None,
+        }
+    }
+
+    /// Lists every command that has a key, along with the names of
+    /// all of its keys, in the order the commands were first bound.
+    pub fn commands(&self) -> Vec<(Command, Vec<String>)> {
+        let mut commands: Vec<(Command, Vec<String>)> = Vec::new();
+        for (key, command) in &self.bindings {
+            match commands.iter_mut().find(|(other, _keys)| other == command) {
+                Some((_command, keys)) => keys.push(key_name(key)),
+                None => commands.push((*command, vec![key_name(key)])),
+            }
+        }
+
+        commands
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::vi()
+    }
+}
+
+/// Describes a key for the player, e.g., "k", "^F" or "Up".
+fn key_name(key: &Input) -> String {
+    match key {
+        Input::Character(ch) if ch.is_ascii_control() => {
+            format!("^{}", (*ch as u8 ^ 0x40) as char)
+        }
+        Input::Character(ch) => ch.to_string(),
+        Input::KeyLeft => "Left".to_string(),
+        Input::KeyRight => "Right".to_string(),
+        Input::KeyUp => "Up".to_string(),
+        Input::KeyDown => "Down".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that `movement` moves in each of `DIRECTIONS`, in order.
+    fn assert_moves(keymap: &Keymap, movement: &str) {
+        for (key, &(dx, dy)) in movement.chars().zip(DIRECTIONS.iter()) {
+            assert_eq!(
+                keymap.command(&Input::Character(key)),
+                Some(Command::Move(dx, dy)),
+                "{}",
+                key
+            );
+        }
+        for (arrow, &(dx, dy)) in ARROWS.iter().zip(DIRECTIONS.iter()) {
+            assert_eq!(keymap.direction(arrow), Some((dx, dy)));
+        }
+    }
+
+    /// Checks that no key in the keymap is bound twice.
+    fn assert_unambiguous(keymap: &Keymap) {
+        for (idx, (key, _command)) in keymap.bindings.iter().enumerate() {
+            assert!(
+                keymap.bindings[idx + 1..]
+                    .iter()
+                    .all(|(other, _command)| other != key),
+                "{} is bound twice",
+                key_name(key)
+            );
+        }
+    }
+
+    #[test]
+    fn vi_keys() {
+        let keymap = Keymap::vi();
+        assert_moves(&keymap, "hjklyubn");
+        assert_unambiguous(&keymap);
+        assert_eq!(
+            keymap.command(&Input::Character('s')),
+            Some(Command::Search)
+        );
+        assert_eq!(keymap.command(&Input::Character('t')), Some(Command::Throw));
+    }
+
+    #[test]
+    fn wasd_keys() {
+        let keymap = Keymap::wasd();
+        assert_moves(&keymap, "aswdqezc");
+        assert_unambiguous(&keymap);
+        assert_eq!(
+            keymap.command(&Input::Character('f')),
+            Some(Command::Search)
+        );
+        assert_eq!(keymap.command(&Input::Character('p')), Some(Command::Quaff));
+    }
+
+    #[test]
+    fn dvorak_keys() {
+        let keymap = Keymap::dvorak();
+        assert_moves(&keymap, "dhtnfgxb");
+        assert_unambiguous(&keymap);
+        assert_eq!(keymap.command(&Input::Character('y')), Some(Command::Throw));
+        assert_eq!(keymap.command(&Input::Character('D')), Some(Command::Drop));
+    }
+
+    #[test]
+    fn presets_by_name() {
+        for name in Keymap::NAMES {
+            assert!(Keymap::from_name(name).is_some());
+        }
+        assert!(Keymap::from_name("colemak").is_none());
+    }
+
+    #[test]
+    fn debug_commands_are_off_by_default() {
+        let mut keymap = Keymap::default();
+        let reveal = Input::Character('\u{6}');
+        assert_eq!(keymap.command(&reveal), None);
+
+        keymap.enable_debug();
+        assert_eq!(keymap.command(&reveal), Some(Command::RevealMap));
+        assert_eq!(key_name(&reveal), "^F");
+    }
+
+    #[test]
+    fn commands_by_name() {
+        assert_eq!(Command::from_name("search"), Some(Command::Search));
+        assert_eq!(Command::from_name("pick_up"), Some(Command::PickUp));
+        assert_eq!(
+            Command::from_name("move-northwest"),
+            Some(Command::Move(-1, -1))
+        );
+        assert_eq!(Command::from_name("genocide"), None);
+        assert_eq!(Command::from_name("dance"), None);
+    }
+
+    #[test]
+    fn rebinding_moves_a_command() {
+        let mut keymap = Keymap::vi();
+        let (f, s) = (Input::Character('f'), Input::Character('s'));
+        keymap.bind(Command::Search, "f");
+        assert_eq!(keymap.command(&f), Some(Command::Search));
+        assert_eq!(keymap.command(&s), None);
+        assert_unambiguous(&keymap);
+
+        // Taking another command's key leaves that command without it,
+        // and the arrow keys keep moving.
+        keymap.bind(Command::Move(-1, 0), "sa");
+        assert_eq!(keymap.direction(&s), Some((-1, 0)));
+        assert_eq!(keymap.direction(&Input::Character('a')), Some((-1, 0)));
+        assert_eq!(keymap.command(&Input::Character('h')), None);
+        assert_eq!(keymap.direction(&Input::KeyLeft), Some((-1, 0)));
+        assert_unambiguous(&keymap);
+
+        // The help screen lists commands in the same order as before.
+        let order = |keymap: &Keymap| -> Vec<Command> {
+            keymap
+                .commands()
+                .into_iter()
+                .map(|(command, _keys)| command)
+                .collect()
+        };
+        let before = order(&Keymap::vi());
+        let after = order(&keymap);
+        assert_eq!(
+            after,
+            before
+                .into_iter()
+                .filter(|&command| command != Command::Apply)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,27 +1,269 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    ops::{Range, RangeInclusive},
+    str::FromStr,
+};
 
-use pancurses::Window;
-use rand::Rng;
+use grid::Grid;
+use rand::{seq::SliceRandom, Rng};
 use specs::prelude::*;
+use thiserror::Error;
 
 use crate::{
-    components::{CharRender, Position},
-    io::{set_color, Color},
-    rooms,
-    visibility::{visible, CellVisibility, Lighting},
+    components::{CharRender, Gold, LightSource, Name, Position},
+    difficulty::Difficulty,
+    ids::create_entity,
+    io::{Color, Screen},
+    items::ItemKind,
+    monsters::MonsterKind,
+    rooms::{self, GenError, Hallways},
+    spawner::pack_cells,
+    theme::Theme,
+    util::random_name,
+    visibility::{line_of_fire, CellVisibility, FovAlgo, Lighting, ViewRadius},
 };
 
 /// The size of a dungeon level, in tiles.
 pub const LEVEL_SIZE: (usize, usize) = (80, 24);
 
+/// How many times to try generating a level before giving up.
+const GEN_ATTEMPTS: usize = 5;
+
+/// The number of extra hallways on each level, which give the player
+/// more than one way to get between rooms.
+const HALLWAY_LOOPS: usize = 3;
+
+/// The number of piles of gold scattered around each level.
+const GOLD_PILES: usize = 8;
+
+/// The possible amounts of gold in a single pile.
+const GOLD_PILE_SIZE: Range<u32> = 5..30;
+
+/// The number of scrolls of teleportation lying around each level.
+const TELEPORT_SCROLLS: usize = 2;
+
+/// Probability that a level has a scroll of genocide lying around.
+const GENOCIDE_SCROLL_CHANCE: f64 = 0.1;
+
+/// The number of potions lying around each level.
+const POTIONS: usize = 2;
+
+/// The number of weapons, and separately of suits of armor, lying
+/// around each level.
+const EQUIPMENT_PIECES: usize = 1;
+
+/// The cost of wading through a tile of water, relative to walking
+/// across a floor.
+const WATER_COST: u32 = 3;
+
+/// The number of light sources placed on each level.
+const LIGHT_SOURCES: usize = 4;
+
+/// Probability that a light source is magical rather than a torch.
+const MAGIC_LIGHT_CHANCE: f64 = 0.25;
+
+/// How many tries we get at finding a spot for each monster before
+/// giving up on it.
+const MONSTER_ATTEMPTS: usize = 20;
+
+/// Monsters never start out within this many tiles of an upstair, or
+/// of where the player starts the game, so that the player isn't
+/// ambushed as soon as they arrive.
+pub const SPAWN_SAFE_RADIUS: i32 = 8;
+
+/// Probability that a monster has a name of its own.
+const NAMED_MONSTER_CHANCE: f64 = 0.05;
+
+/// The possible numbers of syllables in a monster's name.
+const NAME_SYLLABLES: RangeInclusive<usize> = 1..=3;
+
+/// The possible numbers of fountains on each level.
+const FOUNTAINS: RangeInclusive<usize> = 0..=2;
+
+/// How many tries we get at finding a spot in a room for each fountain
+/// before giving up on it.
+const FOUNTAIN_ATTEMPTS: usize = 20;
+
+/// Probability that a monster is allowed to start out in a hallway
+/// rather than in a room.
+const HALLWAY_SPAWN_CHANCE: f64 = 0.2;
+
+/// Probability that a floor tile is decorated with something.
+const DECORATION_CHANCE: f64 = 0.04;
+
+/// Whether the given coordinates lie within the bounds of a level.
+pub fn in_bounds((x, y): (i32, i32)) -> bool {
+    (0..LEVEL_SIZE.0 as i32).contains(&x) && (0..LEVEL_SIZE.1 as i32).contains(&y)
+}
+
+/// Moves the given coordinates by `(dx, dy)`, saturating rather than
+/// overflowing at the edges of `i32`.
+pub fn offset((x, y): (i32, i32), (dx, dy): (i32, i32)) -> (i32, i32) {
+    (x.saturating_add(dx), y.saturating_add(dy))
+}
+
+/// The number of steps between two cells, when moving diagonally is
+/// allowed.
+pub fn chebyshev(a: (i32, i32), b: (i32, i32)) -> i32 {
+    i32::max(
+        a.0.saturating_sub(b.0).saturating_abs(),
+        a.1.saturating_sub(b.1).saturating_abs(),
+    )
+}
+
+/// Pulls the given coordinates back inside the bounds of a level.
+pub fn clamp_to_bounds((x, y): (i32, i32)) -> (i32, i32) {
+    (
+        x.clamp(0, LEVEL_SIZE.0 as i32 - 1),
+        y.clamp(0, LEVEL_SIZE.1 as i32 - 1),
+    )
+}
+
+/// A set of cells on a level, packed into one bit per cell.
+#[derive(Clone)]
+pub struct CellSet {
+    /// The bits for each cell, row by row.
+    bits: Vec<u64>,
+}
+
+impl CellSet {
+    /// The bit for the given cell, as an index into `bits` and a mask.
+    /// Panics if the coordinates are out of bounds.
+    fn bit(x: i32, y: i32) -> (usize, u64) {
+        assert!(in_bounds((x, y)), "({}, {}) is out of bounds", x, y);
+        let idx = y as usize * LEVEL_SIZE.0 + x as usize;
+        (idx / 64, 1 << (idx % 64))
+    }
+
+    /// Whether the given cell is in the set. Panics if the coordinates
+    /// are out of bounds.
+    pub fn get(&self, x: i32, y: i32) -> bool {
+        let (word, mask) = Self::bit(x, y);
+        self.bits[word] & mask != 0
+    }
+
+    /// Adds the given cell to the set. Panics if the coordinates are
+    /// out of bounds.
+    pub fn set(&mut self, x: i32, y: i32) {
+        let (word, mask) = Self::bit(x, y);
+        self.bits[word] |= mask;
+    }
+
+    /// Removes the given cell from the set. Panics if the coordinates
+    /// are out of bounds.
+    pub fn unset(&mut self, x: i32, y: i32) {
+        let (word, mask) = Self::bit(x, y);
+        self.bits[word] &= !mask;
+    }
+
+    /// Adds every cell on the level to the set.
+    pub fn set_all(&mut self) {
+        self.bits.fill(u64::MAX);
+    }
+}
+
+impl Default for CellSet {
+    /// An empty set.
+    fn default() -> Self {
+        Self {
+            bits: vec![0; (LEVEL_SIZE.0 * LEVEL_SIZE.1).div_ceil(64)],
+        }
+    }
+}
+
+/// The cells on a level that the player knows about, along with when
+/// they last saw each one, so that memories can fade.
+#[derive(Clone)]
+pub struct CellMemory {
+    /// The cells the player knows about.
+    known: CellSet,
+
+    /// When the player last saw each cell, row by row. Only meaningful
+    /// for cells in `known`.
+    seen_at: Vec<u32>,
+}
+
+impl CellMemory {
+    /// Whether the player knows about the given cell. Panics if the
+    /// coordinates are out of bounds.
+    pub fn get(&self, x: i32, y: i32) -> bool {
+        self.known.get(x, y)
+    }
+
+    /// Notes that the player saw the given cell at time `now`. Panics
+    /// if the coordinates are out of bounds.
+    pub fn see(&mut self, x: i32, y: i32, now: u32) {
+        self.known.set(x, y);
+        self.seen_at[y as usize * LEVEL_SIZE.0 + x as usize] = now;
+    }
+
+    /// Notes that the player saw the whole level at time `now`.
+    pub fn see_all(&mut self, now: u32) {
+        self.known.set_all();
+        self.seen_at.fill(now);
+    }
+
+    /// Forgets every cell that the player hasn't seen for more than
+    /// `decay` time as of `now`, and returns the cells forgotten.
+    pub fn forget(&mut self, now: u32, decay: u32) -> Vec<(i32, i32)> {
+        let mut forgotten = Vec::new();
+        for y in 0..LEVEL_SIZE.1 as i32 {
+            for x in 0..LEVEL_SIZE.0 as i32 {
+                let seen_at = self.seen_at[y as usize * LEVEL_SIZE.0 + x as usize];
+                if self.known.get(x, y) && now.saturating_sub(seen_at) > decay {
+                    self.known.unset(x, y);
+                    forgotten.push((x, y));
+                }
+            }
+        }
+        forgotten
+    }
+}
+
+impl Default for CellMemory {
+    /// Knows nothing.
+    fn default() -> Self {
+        Self {
+            known: CellSet::default(),
+            seen_at: vec![0; LEVEL_SIZE.0 * LEVEL_SIZE.1],
+        }
+    }
+}
+
 /// A single level of the dungeon.
 #[derive(Clone)]
 pub struct DungeonLevel {
     /// The tiles at every position in the level.
     tiles: [[DungeonTile; LEVEL_SIZE.0]; LEVEL_SIZE.1],
 
+    /// The room that each position is inside of, if any.
+    rooms: [[Option<usize>; LEVEL_SIZE.0]; LEVEL_SIZE.1],
+
+    /// What's lying on the floor at each position, for looks only.
+    decorations: [[Option<Decoration>; LEVEL_SIZE.0]; LEVEL_SIZE.1],
+
     /// The locations of the level's exits.
     exits: LevelExits,
+
+    /// Spots where the level's layout calls for treasure.
+    treasure: Vec<(i32, i32)>,
+
+    /// How the level's floors and walls look.
+    theme: Theme,
+
+    /// How it's worked out what can be seen from where.
+    fov: FovAlgo,
+
+    /// How many times taller than wide cells count as when working out
+    /// how far away something is; see `ViewRadius`.
+    view_aspect: i32,
+
+    /// How far away creatures can see.
+    view_radius: i32,
+
+    /// The name of the branch that the level's branch staircase leads
+    /// to, if it has one and we know where it goes.
+    branch_name: Option<&'static str>,
 }
 
 /// The entrances and exits from a level.
@@ -32,6 +274,10 @@ pub struct LevelExits {
 
     /// The location of each of the down-staircases.
     pub downstairs: Vec<(i32, i32)>,
+
+    /// The location of the staircase down into another branch, if
+    /// there is one.
+    pub branch_stair: Option<(i32, i32)>,
 }
 
 /// The smallest measurable independent location in the dungeon,
@@ -42,6 +288,104 @@ pub enum DungeonTile {
     Wall,
     Upstair,
     Downstair,
+
+    /// A staircase down into a different branch of the dungeon.
+    BranchStair,
+
+    /// A door, which blocks movement and sight while closed.
+    Door {
+        open: bool,
+    },
+
+    /// A doorway that looks like a wall until it is found by
+    /// searching.
+    SecretDoor,
+
+    /// Shallow water, which is slow to wade through.
+    Water,
+
+    /// Water too deep to wade through, which only flying creatures
+    /// can cross. It doesn't get in the way of seeing across it.
+    DeepWater,
+
+    /// Molten rock, which only flying creatures can cross.
+    Lava,
+
+    /// A hole in the floor leading down to the next level. Anything
+    /// that can't fly falls in if it steps into it or is knocked
+    /// into it.
+    Chasm,
+
+    /// A fountain that the player can drink from while standing on
+    /// it, until it dries up.
+    Fountain {
+        dry: bool,
+    },
+}
+
+/// Something lying on a floor tile that changes how it looks, but not
+/// how it can be walked across or seen through.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decoration {
+    /// Bits of broken stone.
+    Rubble,
+
+    /// A patch of moss. This can't be ':' like in some other games,
+    /// because that's what chasms look like.
+    Moss,
+}
+
+impl Decoration {
+    /// Every decoration, along with how likely it is compared to the
+    /// others.
+    const WEIGHTED: &'static [(Decoration, u32)] =
+        &[(Decoration::Rubble, 3), (Decoration::Moss, 1)];
+
+    /// Picks a decoration at random.
+    fn random(rng: &mut impl Rng) -> Self {
+        Self::WEIGHTED
+            .choose_weighted(rng, |(_, weight)| *weight)
+            .expect("Decorations must exist")
+            .0
+    }
+
+    /// The glyph that a decorated floor tile is drawn with.
+    pub fn glyph(&self) -> char {
+        match self {
+            Decoration::Rubble => ',',
+            Decoration::Moss => '"',
+        }
+    }
+
+    /// The color that a decorated floor tile is drawn in.
+    pub fn color(&self) -> Color {
+        match self {
+            Decoration::Rubble => Color::Yellow,
+            Decoration::Moss => Color::Green,
+        }
+    }
+
+    /// The decoration drawn with the given glyph, if any.
+    fn from_glyph(glyph: char) -> Option<Self> {
+        match glyph {
+            ',' => Some(Decoration::Rubble),
+            '"' => Some(Decoration::Moss),
+            _ => None,
+        }
+    }
+}
+
+/// Ways that parsing a level from its text form can fail.
+#[derive(Error, Debug)]
+pub enum ParseLevelError {
+    #[error("unknown tile {0:?} at ({1}, {2})")]
+    UnknownTile(char, usize, usize),
+
+    #[error("level is too large (at most {0}x{1})")]
+    TooLarge(usize, usize),
+
+    #[error("level is empty")]
+    Empty,
 }
 
 /// A style for drawing a particular tile in the dungeon.
@@ -65,88 +409,322 @@ impl DungeonTile {
     /// Whether this tile is considered a floor tile, for the purposes
     /// of rendering walls.
     pub fn is_floor(&self) -> bool {
-        match self {
-            DungeonTile::Wall => false,
-            _ => true,
-        }
+        !matches!(self, DungeonTile::Wall | DungeonTile::SecretDoor)
+    }
+
+    /// Whether this tile can be seen through. This is independent of
+    /// whether it can be walked through; see `is_navigable`.
+    pub fn is_transparent(&self) -> bool {
+        self.is_floor() && *self != DungeonTile::Door { open: false }
     }
 
     /// Whether this tile can be traveled through by normal
     /// creatures.
     pub fn is_navigable(&self) -> bool {
-        self.is_floor()
+        self.is_transparent()
+            && !matches!(
+                self,
+                DungeonTile::Lava | DungeonTile::DeepWater | DungeonTile::Chasm
+            )
+    }
+
+    /// How much it costs a creature to move onto this tile, or `None`
+    /// if it can't; flying creatures ignore anything on the ground.
+    pub fn move_cost(&self, flying: bool) -> Option<u32> {
+        match self {
+            _ if flying && self.is_transparent() => Some(1),
+            DungeonTile::Water => Some(WATER_COST),
+            _ if self.is_navigable() => Some(1),
+            _ => None,
+        }
+    }
+
+    /// The color the tile is drawn in.
+    pub fn color(&self) -> Color {
+        match self {
+            DungeonTile::Water | DungeonTile::DeepWater => Color::Blue,
+            DungeonTile::Lava => Color::Red,
+            DungeonTile::Chasm => Color::Magenta,
+            DungeonTile::Fountain { dry: false } => Color::Blue,
+            DungeonTile::BranchStair => Color::Yellow,
+            _ => Color::White,
+        }
     }
 }
 
 impl DungeonLevel {
-    /// Creates a new level with the given set of tiles, upstairs, and
-    /// downstairs.
+    /// Creates a new level with the given set of tiles, room
+    /// membership, upstairs, downstairs, and treasure spots.
     pub fn new(
         tiles: [[DungeonTile; LEVEL_SIZE.0]; LEVEL_SIZE.1],
+        rooms: [[Option<usize>; LEVEL_SIZE.0]; LEVEL_SIZE.1],
         upstairs: Vec<(i32, i32)>,
         downstairs: Vec<(i32, i32)>,
+        treasure: Vec<(i32, i32)>,
     ) -> Self {
         Self {
             tiles,
+            rooms,
+            decorations: [[None; LEVEL_SIZE.0]; LEVEL_SIZE.1],
             exits: LevelExits {
                 upstairs,
                 downstairs,
+                branch_stair: None,
             },
+            treasure,
+            theme: Theme::default(),
+            fov: FovAlgo::default(),
+            view_aspect: 1,
+            view_radius: Difficulty::default().view_radius,
+            branch_name: None,
+        }
+    }
+
+    /// Generates a new level with the given numbers of stairs, and
+    /// hallways dug as given. Generation is retried a few times if it
+    /// fails, after which the last error is returned.
+    pub fn generate(
+        rng: &mut impl Rng,
+        upstairs: usize,
+        downstairs: usize,
+        hallways: Hallways,
+    ) -> Result<Self, GenError> {
+        let mut attempt = 1;
+        loop {
+            match rooms::generate_level(100, rng, upstairs, downstairs, HALLWAY_LOOPS, hallways) {
+                Ok(mut level) => {
+                    level.add_fountains(rng);
+                    level.add_decorations(rng);
+                    break Ok(level);
+                }
+                Err(err) if attempt >= GEN_ATTEMPTS => break Err(err),
+                Err(_) => attempt += 1,
+            }
         }
     }
 
-    /// Creates a new level and registers it with the given world.
-    pub fn generate_level(world: &mut World, rng: &mut impl Rng) -> LevelExits {
-        let level = rooms::generate_level(100, rng, 1, 1);
+    /// Registers the level with the given world, and fills it with
+    /// monsters and treasure. There are `monster_density` monsters for
+    /// every 100 tiles of floor space.
+    pub fn populate(
+        self,
+        world: &mut World,
+        rng: &mut impl Rng,
+        monster_density: f64,
+    ) -> LevelExits {
+        let level = self;
         world.insert(level.clone()); // inefficient but whatever
 
-        // Spawn some zombies in the world.
-        for _ in 0..20 {
+        // Spawn some monsters in the world, preferably in rooms, and
+        // away from where the player comes in. Nothing starts out where
+        // the player can see it as soon as they arrive, either.
+        let entry_rooms: Vec<usize> = level
+            .exits
+            .upstairs
+            .iter()
+            .filter_map(|&(x, y)| level.room_at(x, y))
+            .collect();
+        let mut entry_view: Vec<(i32, i32)> = level
+            .exits
+            .upstairs
+            .iter()
+            .flat_map(|&stair| level.visible_from(stair))
+            .collect();
+        entry_view.sort_unstable();
+        entry_view.dedup();
+        let n_monsters = (level.floor_area() as f64 * monster_density / 100.0).round() as usize;
+        // Large monsters take up more than one cell, so the cells taken
+        // are counted separately from the monsters.
+        let mut taken: Vec<(i32, i32)> = Vec::new();
+        let mut spawned = 0;
+        for _ in 0..n_monsters * MONSTER_ATTEMPTS {
+            if spawned >= n_monsters {
+                break;
+            }
+
             let (x, y) = (
                 rng.gen_range(0..LEVEL_SIZE.0 as _),
                 rng.gen_range(0..LEVEL_SIZE.1 as _),
             );
-            if level.tile(x, y).is_navigable() {
-                world
-                    .create_entity()
-                    .with(Position { x, y })
-                    .with(CharRender { glyph: 'Z' })
-                    .build();
+            let kind = MonsterKind::random(rng);
+            let room = level.room_at(x, y);
+            let near_entry = level
+                .exits
+                .upstairs
+                .iter()
+                .any(|&(sx, sy)| i32::max((x - sx).abs(), (y - sy).abs()) < SPAWN_SAFE_RADIUS);
+
+            let free = |(x, y): (i32, i32)| {
+                in_bounds((x, y))
+                    && level.tile(x, y).is_navigable()
+                    && entry_view.binary_search(&(x, y)).is_err()
+                    && !taken.contains(&(x, y))
+            };
+
+            if kind.size().cells((x, y)).all(free)
+                && !near_entry
+                && room.is_none_or(|room| !entry_rooms.contains(&room))
+                && (room.is_some() || rng.gen_bool(HALLWAY_SPAWN_CHANCE))
+            {
+                let pack = rng.gen_range(kind.pack_size()).min(n_monsters - spawned);
+                for cell in pack_cells((x, y), pack, free) {
+                    let monster = kind.spawn(world, cell);
+                    if rng.gen_bool(NAMED_MONSTER_CHANCE) {
+                        let syllables = rng.gen_range(NAME_SYLLABLES);
+                        let name = random_name(rng, syllables);
+                        world
+                            .write_storage::<Name>()
+                            .insert(monster, Name { name })
+                            .expect("Monster was just created");
+                    }
+                    taken.extend(kind.size().cells(cell));
+                    spawned += 1;
+                }
             }
         }
 
+        // Scatter some gold around too, on top of any that the
+        // layout asks for.
+        let scattered: Vec<(i32, i32)> = (0..GOLD_PILES).map(|_| level.empty_square(rng)).collect();
+        for &(x, y) in level.treasure.iter().chain(scattered.iter()) {
+            create_entity(world)
+                .with(Position { x, y })
+                .with(CharRender {
+                    glyph: '$',
+                    color: Color::Yellow,
+                })
+                .with(Gold {
+                    amount: rng.gen_range(GOLD_PILE_SIZE),
+                })
+                .build();
+        }
+
+        // And a few items.
+        for _ in 0..TELEPORT_SCROLLS {
+            ItemKind::TeleportScroll.spawn(world, level.empty_square(rng));
+        }
+        if rng.gen_bool(GENOCIDE_SCROLL_CHANCE) {
+            ItemKind::GenocideScroll.spawn(world, level.empty_square(rng));
+        }
+        for _ in 0..POTIONS {
+            let potion = ItemKind::POTIONS.choose(rng).expect("Potions must exist");
+            potion.spawn(world, level.empty_square(rng));
+        }
+        for _ in 0..EQUIPMENT_PIECES {
+            let weapon = ItemKind::WEAPONS.choose(rng).expect("Weapons must exist");
+            weapon.spawn(world, level.empty_square(rng));
+            let armor = ItemKind::ARMORS.choose(rng).expect("Armor must exist");
+            armor.spawn(world, level.empty_square(rng));
+        }
+
+        // Light some of the level up, mostly with torches.
+        for _ in 0..LIGHT_SOURCES {
+            let (x, y) = level.empty_square(rng);
+            let light = if rng.gen_bool(MAGIC_LIGHT_CHANCE) {
+                LightSource {
+                    radius: 3,
+                    color: Color::Blue,
+                    flickers: false,
+                }
+            } else {
+                LightSource {
+                    radius: 5,
+                    color: Color::Yellow,
+                    flickers: true,
+                }
+            };
+            create_entity(world)
+                .with(Position { x, y })
+                .with(light)
+                .build();
+        }
+
         level.exits
     }
 
-    /// Draws a level on the display window. Draws only the cells for
-    /// which `filter` returns true; use `|_| true` to draw the whole
-    /// level.
-    pub fn draw(&self, win: &Window, visibility: impl Fn((i32, i32)) -> DrawStyle) {
+    /// Draws a level onto the screen in the level's theme, styling
+    /// each cell according to `visibility`; use `|_| DrawStyle::Visible`
+    /// to draw the whole level. Visible cells of plain-colored tiles are
+    /// tinted by any colored light that `lighting` says falls on them,
+    /// or else by the theme's ambient color.
+    pub fn draw(
+        &self,
+        screen: &mut Screen,
+        visibility: impl Fn((i32, i32)) -> DrawStyle,
+        lighting: impl Fn((i32, i32)) -> Lighting,
+    ) {
         for y in 0..LEVEL_SIZE.1 {
-            win.mv(y as _, 0);
             for x in 0..LEVEL_SIZE.0 {
-                win.addch(match visibility((x as _, y as _)) {
-                    DrawStyle::Undiscovered => ' ',
-                    DrawStyle::Discovered => {
-                        // Using red as a placeholder; black doesn't
-                        // seem to work rn(?)
-                        set_color(win, Color::Red);
-                        self.render_tile(x, y)
+                let tile = self.tiles[y][x];
+                let decoration = self.decoration(x, y);
+                let plain = tile.color() == Color::White && decoration.is_none();
+                let (glyph, color) = match (tile, decoration) {
+                    (DungeonTile::Floor, Some(decoration)) => {
+                        (decoration.glyph(), decoration.color())
                     }
-                    DrawStyle::Visible => {
-                        set_color(win, Color::White);
-                        self.render_tile(x, y)
+                    (DungeonTile::Floor, None) => (self.theme.floor_glyph, self.theme.floor_color),
+                    (DungeonTile::Wall | DungeonTile::SecretDoor, _) => {
+                        let glyph = match self.corner(x, y) {
+                            Some(corner) if screen.box_drawing() => corner.box_glyph(),
+                            _ => self.render_tile(x, y),
+                        };
+                        (glyph, self.theme.wall_color)
                     }
-                });
+                    // On screen, the color is enough to tell it apart
+                    // from an ordinary staircase down.
+                    (DungeonTile::BranchStair, _) => ('>', tile.color()),
+                    _ => (self.render_tile(x, y), tile.color()),
+                };
+                let lit_color = match (lighting((x as _, y as _)), self.theme.ambient) {
+                    (Lighting::Tinted(tint), _) if plain => tint,
+                    (Lighting::Lit, Some(ambient)) if plain => ambient,
+                    _ => color,
+                };
+
+                match visibility((x as _, y as _)) {
+                    DrawStyle::Undiscovered => screen.put(x as _, y as _, ' ', Color::White),
+                    DrawStyle::Discovered => screen.put_dim(x as _, y as _, glyph, color),
+                    DrawStyle::Visible => screen.put(x as _, y as _, glyph, lit_color),
+                }
             }
         }
     }
 
-    /// Renders the tile at the given coordinates.
+    /// Changes how the level looks when it's drawn.
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+    }
+
+    /// How the level looks when it's drawn.
+    pub fn theme(&self) -> Theme {
+        self.theme
+    }
+
+    /// Changes how the level works out what's in view.
+    pub fn set_fov(&mut self, fov: FovAlgo) {
+        self.fov = fov;
+    }
+
+    /// Changes how much taller than wide cells count as when working
+    /// out what's in view.
+    pub fn set_view_aspect(&mut self, aspect: i32) {
+        self.view_aspect = aspect;
+    }
+
+    /// Changes how far away creatures can see.
+    pub fn set_view_radius(&mut self, radius: i32) {
+        self.view_radius = radius;
+    }
+
+    /// Renders the tile at the given coordinates in plain ASCII,
+    /// regardless of the level's theme, as used by the level's text
+    /// form.
     pub fn render_tile(&self, x: usize, y: usize) -> char {
         match self.tiles[y][x] {
-            DungeonTile::Floor => '.',
-            DungeonTile::Wall => {
+            DungeonTile::Floor => self
+                .decoration(x, y)
+                .map_or('.', |decoration| decoration.glyph()),
+            DungeonTile::Wall | DungeonTile::SecretDoor => {
                 // Walls are rendered like so:
                 // - If the wall has any floor tiles to its north or
                 //   south, then it is rendered as '-', because it is
@@ -154,8 +732,10 @@ impl DungeonLevel {
                 // - Otherwise, if the wall has any floor tiles to its
                 //   east or west, then it is rendered as '|'.
                 // - Otherwise, if any floor tiles are diagonally
-                //   adjacent to the wall, then the wall is rendered as
-                //   '+', because it is in the corner of a room.
+                //   adjacent to the wall, then it is in the corner of a
+                //   room, and is rendered as '/' or '\\' depending on
+                //   which corner; see `corner`. Corners used to be '+',
+                //   but that's what closed doors look like.
                 // - Otherwise, no floor tiles are adjacent to the
                 //   wall, therefore it is surrounded by stone and will
                 //   never be discovered by the player, so we don't
@@ -176,15 +756,51 @@ impl DungeonLevel {
                     '-'
                 } else if has_floor(&[(-1, 0), (1, 0)]) {
                     '|'
-                } else if has_floor(&[(-1, -1), (-1, 1), (1, -1), (1, 1)]) {
-                    '+'
+                } else if let Some(corner) = self.corner(x, y) {
+                    corner.ascii_glyph()
                 } else {
                     ' '
                 }
             }
             DungeonTile::Upstair => '<',
             DungeonTile::Downstair => '>',
+            DungeonTile::BranchStair => '*',
+            DungeonTile::Door { open: false } => '+',
+            DungeonTile::Door { open: true } => '\'',
+            DungeonTile::Water => '~',
+            DungeonTile::DeepWater => '=',
+            DungeonTile::Lava => '}',
+            DungeonTile::Chasm => ':',
+            DungeonTile::Fountain { .. } => '{',
+        }
+    }
+
+    /// Works out which corner of a room the wall at the given
+    /// coordinates is, if it's a corner at all: that is, if it has no
+    /// floor straight next to it, but does have floor diagonally next
+    /// to it. The room is on the side of the first diagonal with floor.
+    fn corner(&self, x: usize, y: usize) -> Option<Corner> {
+        let floor_at = |(dx, dy): (i32, i32)| {
+            let (x, y) = (x as i32 + dx, y as i32 + dy);
+            in_bounds((x, y)) && self.tile(x, y).is_floor()
+        };
+        if !matches!(
+            self.tiles[y][x],
+            DungeonTile::Wall | DungeonTile::SecretDoor
+        ) || [(0, -1), (0, 1), (-1, 0), (1, 0)].into_iter().any(floor_at)
+        {
+            return None;
         }
+
+        [
+            ((1, 1), Corner::UpperLeft),
+            ((-1, 1), Corner::UpperRight),
+            ((1, -1), Corner::LowerLeft),
+            ((-1, -1), Corner::LowerRight),
+        ]
+        .into_iter()
+        .find(|&(delta, _corner)| floor_at(delta))
+        .map(|(_delta, corner)| corner)
     }
 
     /// Gets a reference to the tile at the given coordinates. Panics
@@ -193,15 +809,158 @@ impl DungeonLevel {
         &self.tiles[y as usize][x as usize]
     }
 
-    /// Whether a monster standing at `from` can see the contents of cell
-    /// `to`.
-    pub fn can_see(&self, from: (i32, i32), to: (i32, i32)) -> bool {
-        visible(
+    /// The number of tiles on the level that creatures can walk on.
+    pub fn floor_area(&self) -> usize {
+        self.tiles
+            .iter()
+            .flatten()
+            .filter(|tile| tile.is_navigable())
+            .count()
+    }
+
+    /// Finds a random unoccupied (floor) square of the level.
+    pub fn empty_square(&self, rng: &mut impl Rng) -> (i32, i32) {
+        loop {
+            let (x, y) = (
+                rng.gen_range(0..LEVEL_SIZE.0 as _),
+                rng.gen_range(0..LEVEL_SIZE.1 as _),
+            );
+
+            if self.tile(x, y) == &DungeonTile::Floor {
+                break (x, y);
+            }
+        }
+    }
+
+    /// Scatters decorations across the level's floor.
+    fn add_decorations(&mut self, rng: &mut impl Rng) {
+        for y in 0..LEVEL_SIZE.1 {
+            for x in 0..LEVEL_SIZE.0 {
+                if self.tiles[y][x] == DungeonTile::Floor && rng.gen_bool(DECORATION_CHANCE) {
+                    self.decorations[y][x] = Some(Decoration::random(rng));
+                }
+            }
+        }
+    }
+
+    /// What's decorating the floor at the given coordinates, if
+    /// anything. Only floor tiles are ever drawn decorated.
+    pub fn decoration(&self, x: usize, y: usize) -> Option<Decoration> {
+        self.decorations[y][x]
+    }
+
+    /// Puts a few fountains on the floor of the level's rooms.
+    fn add_fountains(&mut self, rng: &mut impl Rng) {
+        let count = rng.gen_range(FOUNTAINS);
+        let mut placed = 0;
+        for _ in 0..count * FOUNTAIN_ATTEMPTS {
+            if placed == count {
+                break;
+            }
+
+            let (x, y) = self.empty_square(rng);
+            if self.room_at(x, y).is_some() {
+                self.set_tile(x, y, DungeonTile::Fountain { dry: false });
+                placed += 1;
+            }
+        }
+    }
+
+    /// Puts a staircase down into the branch called `name` on the
+    /// floor of one of the level's rooms, replacing any it already had.
+    pub fn add_branch_stair(&mut self, rng: &mut impl Rng, name: &'static str) {
+        if let Some((x, y)) = self.exits.branch_stair.take() {
+            self.set_tile(x, y, DungeonTile::Floor);
+        }
+
+        let (x, y) = loop {
+            let (x, y) = self.empty_square(rng);
+            if self.room_at(x, y).is_some() {
+                break (x, y);
+            }
+        };
+        self.set_tile(x, y, DungeonTile::BranchStair);
+        self.exits.branch_stair = Some((x, y));
+        self.branch_name = Some(name);
+    }
+
+    /// What the tile at the given coordinates looks like, for the look
+    /// command, e.g., "a staircase to the Caves". Panics if the
+    /// coordinates are out of bounds.
+    pub fn describe_tile(&self, x: i32, y: i32) -> String {
+        match self.tile(x, y) {
+            DungeonTile::Floor => "the floor".to_string(),
+            DungeonTile::Wall | DungeonTile::SecretDoor => "a wall".to_string(),
+            DungeonTile::Upstair => "a staircase up".to_string(),
+            DungeonTile::Downstair => "a staircase down".to_string(),
+            DungeonTile::BranchStair => match self.branch_name {
+                Some(name) => format!("a staircase to {}", name),
+                None => "a staircase down".to_string(),
+            },
+            DungeonTile::Door { open: true } => "an open door".to_string(),
+            DungeonTile::Door { open: false } => "a closed door".to_string(),
+            DungeonTile::Water => "shallow water".to_string(),
+            DungeonTile::DeepWater => "deep water".to_string(),
+            DungeonTile::Lava => "lava".to_string(),
+            DungeonTile::Chasm => "a chasm".to_string(),
+            DungeonTile::Fountain { dry: false } => "a fountain".to_string(),
+            DungeonTile::Fountain { dry: true } => "a dried-up fountain".to_string(),
+        }
+    }
+
+    /// The name of the branch that the level's branch staircase leads
+    /// to, if it's known.
+    pub fn branch_name(&self) -> Option<&'static str> {
+        self.branch_name
+    }
+
+    /// Replaces every chasm on the level with floor, for levels with
+    /// nothing below them to fall into.
+    pub fn fill_chasms(&mut self) {
+        for tile in self.tiles.iter_mut().flatten() {
+            if *tile == DungeonTile::Chasm {
+                *tile = DungeonTile::Floor;
+            }
+        }
+    }
+
+    /// The locations of the level's exits.
+    pub fn exits(&self) -> &LevelExits {
+        &self.exits
+    }
+
+    /// Replaces the tile at the given coordinates. Panics if the
+    /// coordinates are out of bounds.
+    pub fn set_tile(&mut self, x: i32, y: i32, tile: DungeonTile) {
+        self.tiles[y as usize][x as usize] = tile;
+    }
+
+    /// Puts the given cell in the room with the given index, for
+    /// levels parsed from text, which don't say where the rooms are.
+    /// Panics if the coordinates are out of bounds.
+    #[cfg(test)]
+    pub fn set_room(&mut self, x: i32, y: i32, room: usize) {
+        self.rooms[y as usize][x as usize] = Some(room);
+    }
+
+    /// The index of the room containing the given coordinates, if
+    /// they're inside one. Panics if the coordinates are out of
+    /// bounds.
+    pub fn room_at(&self, x: i32, y: i32) -> Option<usize> {
+        self.rooms[y as usize][x as usize]
+    }
+
+    /// Every cell that a monster standing at `from` can see, in sorted
+    /// order.
+    pub fn visible_from(&self, from: (i32, i32)) -> Vec<(i32, i32)> {
+        self.fov.compute(
             from,
-            to,
-            Some(10),
-            |(x, y)| {
-                if self.tile(x, y).is_navigable() {
+            Some(ViewRadius {
+                radius: self.view_radius,
+                aspect: self.view_aspect,
+            }),
+            |cell| {
+                if in_bounds(cell) && self.tile(cell.0, cell.1).is_transparent() {
                     CellVisibility::Transparent
                 } else {
                     CellVisibility::Blocking
@@ -211,6 +970,26 @@ impl DungeonLevel {
             |(_x, _y)| Lighting::Lit,
         )
     }
+
+    /// Whether a monster standing at `from` can see the contents of cell
+    /// `to`. Use `visible_from` instead when checking lots of cells, or
+    /// `PlayerView` when one of them is where the player is.
+    pub fn can_see(&self, from: (i32, i32), to: (i32, i32)) -> bool {
+        self.visible_from(from).binary_search(&to).is_ok()
+    }
+
+    /// The path that something thrown from `from` towards `to` would
+    /// take, and whether it would get there rather than hitting
+    /// something solid on the way; see `visibility::line_of_fire`.
+    pub fn line_of_fire(&self, from: (i32, i32), to: (i32, i32)) -> (Vec<(i32, i32)>, bool) {
+        line_of_fire(from, to, |cell| {
+            if in_bounds(cell) && self.tile(cell.0, cell.1).is_transparent() {
+                CellVisibility::Transparent
+            } else {
+                CellVisibility::Blocking
+            }
+        })
+    }
 }
 
 impl Display for DungeonLevel {
@@ -226,3 +1005,474 @@ impl Display for DungeonLevel {
         Ok(())
     }
 }
+
+/// Every cell the player can see, worked out once a turn before anyone
+/// acts, so that monsters looking for the player don't each have to
+/// work out what's in view all over again.
+#[derive(Default)]
+pub struct PlayerView {
+    /// Where the player was standing, if there's a player at all.
+    origin: Option<(i32, i32)>,
+
+    /// The cells in view from `origin`, in sorted order.
+    cells: Vec<(i32, i32)>,
+}
+
+impl PlayerView {
+    /// Works out what a player standing at `origin` on `level` can see.
+    pub fn new(level: &DungeonLevel, origin: (i32, i32)) -> Self {
+        Self {
+            origin: Some(origin),
+            cells: level.visible_from(origin),
+        }
+    }
+
+    /// A view from `origin` that takes in nothing at all, as if the
+    /// player were standing in the dark.
+    #[cfg(test)]
+    pub fn blind(origin: (i32, i32)) -> Self {
+        Self {
+            origin: Some(origin),
+            cells: Vec::new(),
+        }
+    }
+
+    /// Whether something standing at `from` on `level` can see the
+    /// contents of cell `to`, as `DungeonLevel::can_see` works out.
+    /// That's looked up in the player's view when `from` is where the
+    /// player was standing, or when `to` is and the level's view is
+    /// symmetric, and otherwise worked out from scratch.
+    pub fn sees(&self, level: &DungeonLevel, from: (i32, i32), to: (i32, i32)) -> bool {
+        match self.origin {
+            Some(origin) if from == origin => self.cells.binary_search(&to).is_ok(),
+            Some(origin) if to == origin && level.fov.is_symmetric() => {
+                self.cells.binary_search(&from).is_ok()
+            }
+            _ => level.can_see(from, to),
+        }
+    }
+}
+
+impl FromStr for DungeonLevel {
+    type Err = ParseLevelError;
+
+    /// Parses a level in the format written by `Display`. Lines
+    /// shorter than the level, and missing lines, are filled in with
+    /// stone.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let grid = parse_grid(s)?;
+        if grid.cols() > LEVEL_SIZE.0 || grid.rows() > LEVEL_SIZE.1 {
+            return Err(ParseLevelError::TooLarge(LEVEL_SIZE.0, LEVEL_SIZE.1));
+        }
+
+        let mut tiles = [[DungeonTile::Wall; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+        let (mut upstairs, mut downstairs) = (Vec::new(), Vec::new());
+        let mut branch_stair = None;
+        for y in 0..grid.rows() {
+            for x in 0..grid.cols() {
+                tiles[y][x] = grid[y][x];
+                match grid[y][x] {
+                    DungeonTile::Upstair => upstairs.push((x as _, y as _)),
+                    DungeonTile::Downstair => downstairs.push((x as _, y as _)),
+                    DungeonTile::BranchStair => branch_stair = Some((x as _, y as _)),
+                    _ => {}
+                }
+            }
+        }
+
+        // The text format doesn't record where the rooms are, or where
+        // branch staircases lead.
+        let rooms = [[None; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+
+        let mut level = Self::new(tiles, rooms, upstairs, downstairs, Vec::new());
+        level.exits.branch_stair = branch_stair;
+        for (y, line) in s.lines().enumerate() {
+            for (x, ch) in line.chars().enumerate() {
+                level.decorations[y][x] = Decoration::from_glyph(ch);
+            }
+        }
+        Ok(level)
+    }
+}
+
+/// The corners of a room, as seen from inside it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Corner {
+    UpperLeft,
+    UpperRight,
+    LowerLeft,
+    LowerRight,
+}
+
+impl Corner {
+    /// The box-drawing character for the corner. `Screen` draws these
+    /// with the terminal's line-drawing characters.
+    fn box_glyph(&self) -> char {
+        match self {
+            Corner::UpperLeft => '┌',
+            Corner::UpperRight => '┐',
+            Corner::LowerLeft => '└',
+            Corner::LowerRight => '┘',
+        }
+    }
+
+    /// The plain ASCII character for the corner, as used in the text
+    /// form of levels and on screens without box drawing.
+    fn ascii_glyph(&self) -> char {
+        match self {
+            Corner::UpperLeft | Corner::LowerRight => '/',
+            Corner::UpperRight | Corner::LowerLeft => '\\',
+        }
+    }
+}
+
+/// Parses a grid of tiles from the character format used to display
+/// levels. The grid is as wide as the longest line, and shorter lines
+/// are filled in with stone.
+///
+/// Secret doors are indistinguishable from walls in this format, and
+/// decorated floor is just floor.
+/// Room corners are written as '/' and '\\' now, but older text used
+/// '+' for them, which is also a closed door; so we treat a '+' as a
+/// door only if it has floor directly next to it (which a corner never
+/// does).
+pub fn parse_grid(s: &str) -> Result<Grid<DungeonTile>, ParseLevelError> {
+    let lines: Vec<&str> = s.lines().collect();
+    let width = lines
+        .iter()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0);
+    if width == 0 {
+        return Err(ParseLevelError::Empty);
+    }
+
+    let mut grid = Grid::init(lines.len(), width, DungeonTile::Wall);
+    let mut plusses = Vec::new();
+    for (y, line) in lines.iter().enumerate() {
+        for (x, ch) in line.chars().enumerate() {
+            grid[y][x] = match ch {
+                '.' | '#' | ',' | '"' => DungeonTile::Floor,
+                '-' | '|' | '/' | '\\' | ' ' => DungeonTile::Wall,
+                '<' => DungeonTile::Upstair,
+                '>' => DungeonTile::Downstair,
+                '*' => DungeonTile::BranchStair,
+                '\'' => DungeonTile::Door { open: true },
+                '~' => DungeonTile::Water,
+                '=' => DungeonTile::DeepWater,
+                '}' => DungeonTile::Lava,
+                ':' => DungeonTile::Chasm,
+                '{' => DungeonTile::Fountain { dry: false },
+                '+' => {
+                    plusses.push((x, y));
+                    DungeonTile::Wall
+                }
+                _ => return Err(ParseLevelError::UnknownTile(ch, x, y)),
+            };
+        }
+    }
+
+    let doors: Vec<(usize, usize)> = plusses
+        .into_iter()
+        .filter(|&(x, y)| {
+            [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                .iter()
+                .map(|(dx, dy)| (x as i32 + dx, y as i32 + dy))
+                .filter(|(x, y)| {
+                    (0..width as i32).contains(x) && (0..lines.len() as i32).contains(y)
+                })
+                .any(|(x, y)| grid[y as usize][x as usize].is_floor())
+        })
+        .collect();
+
+    for (x, y) in doors {
+        grid[y][x] = DungeonTile::Door { open: false };
+    }
+
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{hint::black_box, time::Instant};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        components::{Monster, Position, Size},
+        rooms::Hallways,
+        testing::empty_world,
+    };
+
+    /// A level with nothing on it but a single room of the given size,
+    /// and no stairs.
+    fn one_room(width: usize, height: usize) -> DungeonLevel {
+        let mut tiles = [[DungeonTile::Wall; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+        let mut rooms = [[None; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+        for y in 1..=height {
+            for x in 1..=width {
+                tiles[y][x] = DungeonTile::Floor;
+                rooms[y][x] = Some(0);
+            }
+        }
+        DungeonLevel::new(tiles, rooms, Vec::new(), Vec::new(), Vec::new())
+    }
+
+    #[test]
+    fn monster_count_follows_floor_area() {
+        let density = 2.0;
+        for (width, height) in [(20, 10), (70, 20)] {
+            let level = one_room(width, height);
+            let area = level.floor_area();
+            assert_eq!(area, width * height);
+
+            let mut world = empty_world();
+            level.populate(&mut world, &mut StdRng::seed_from_u64(0), density);
+            let monsters = world.read_storage::<Monster>().count();
+
+            let expected = area as f64 * density / 100.0;
+            assert!(
+                (monsters as f64 - expected).abs() <= expected * 0.1,
+                "{} monsters in {} tiles of floor",
+                monsters,
+                area
+            );
+        }
+    }
+
+    #[test]
+    fn view_radius_limits_what_can_be_seen() {
+        let mut level = one_room(30, 1);
+        let far = level.visible_from((1, 1));
+        level.set_view_radius(5);
+        let near = level.visible_from((1, 1));
+        assert!(near.len() < far.len());
+        assert!(near.iter().all(|&cell| chebyshev((1, 1), cell) <= 5));
+        assert!(near.contains(&(4, 1)));
+    }
+
+    #[test]
+    fn player_view_agrees_with_can_see() {
+        let level: DungeonLevel = "\
+            -----------\n\
+            |.........|\n\
+            |..-......|\n\
+            |.....|...|\n\
+            |.........|\n\
+            -----------"
+            .parse()
+            .unwrap();
+        let player = (1, 1);
+        let view = PlayerView::new(&level, player);
+
+        let floor: Vec<(i32, i32)> = (1..5)
+            .flat_map(|y| (1..10).map(move |x| (x, y)))
+            .filter(|&(x, y)| level.tile(x, y) == &DungeonTile::Floor)
+            .collect();
+        for &cell in &floor {
+            assert_eq!(view.sees(&level, player, cell), level.can_see(player, cell));
+            assert_eq!(view.sees(&level, cell, player), level.can_see(cell, player));
+            assert_eq!(view.sees(&level, cell, (9, 4)), level.can_see(cell, (9, 4)));
+        }
+    }
+
+    /// Times the discovery loop's per-turn work both ways: asking
+    /// `can_see` about every cell on the level, as it once did, and
+    /// working out the whole view once. Run with `cargo test --release
+    /// -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_per_turn_discovery() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let level = DungeonLevel::generate(&mut rng, 1, 1, Hallways::default()).unwrap();
+        let origin = level.exits().upstairs[0];
+        let cells: Vec<(i32, i32)> = (0..LEVEL_SIZE.1 as i32)
+            .flat_map(|y| (0..LEVEL_SIZE.0 as i32).map(move |x| (x, y)))
+            .collect();
+        let turns = 20;
+
+        let start = Instant::now();
+        let mut before = 0;
+        for _ in 0..turns {
+            before += cells
+                .iter()
+                .filter(|&&cell| level.can_see(origin, cell))
+                .count();
+        }
+        let per_cell = start.elapsed() / turns;
+
+        let start = Instant::now();
+        let mut after = 0;
+        for _ in 0..turns {
+            let view = PlayerView::new(&level, origin);
+            after += cells
+                .iter()
+                .filter(|&&cell| view.sees(&level, origin, cell))
+                .count();
+        }
+        let once = start.elapsed() / turns;
+
+        assert_eq!(before, after);
+        eprintln!(
+            "discovery per turn: {:?} cell by cell, {:?} in one pass",
+            per_cell, once
+        );
+    }
+
+    /// Times the discovery loop's bookkeeping of known cells, marking
+    /// what's in view and then going over every cell as forgetting
+    /// does, in a `CellSet` and in one byte per cell as it used to be.
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_known_cells() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let level = DungeonLevel::generate(&mut rng, 1, 1, Hallways::default()).unwrap();
+        let in_view = level.visible_from(level.exits().upstairs[0]);
+        let turns = 10_000;
+
+        let start = Instant::now();
+        let mut bytes = vec![vec![false; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+        let mut before = 0;
+        for _ in 0..turns {
+            for &(x, y) in &in_view {
+                bytes[y as usize][x as usize] = true;
+            }
+            before += bytes.iter().flatten().filter(|&&known| known).count();
+            black_box(&mut bytes);
+        }
+        let per_byte = start.elapsed() / turns;
+
+        let start = Instant::now();
+        let mut bits = CellSet::default();
+        let mut after = 0;
+        for _ in 0..turns {
+            for &(x, y) in &in_view {
+                bits.set(x, y);
+            }
+            for y in 0..LEVEL_SIZE.1 as i32 {
+                for x in 0..LEVEL_SIZE.0 as i32 {
+                    after += bits.get(x, y) as usize;
+                }
+            }
+            black_box(&mut bits);
+        }
+        let per_bit = start.elapsed() / turns;
+
+        assert_eq!(before, after);
+        eprintln!(
+            "known cells per turn: {:?} in {} bytes, {:?} in {} bytes of bits",
+            per_byte,
+            LEVEL_SIZE.0 * LEVEL_SIZE.1,
+            per_bit,
+            bits.bits.len() * 8
+        );
+    }
+
+    #[test]
+    fn one_tile_level_renders() {
+        let level: DungeonLevel = ".".parse().unwrap();
+        assert_eq!(level.floor_area(), 1);
+
+        let text = level.to_string();
+        assert_eq!(text.lines().count(), LEVEL_SIZE.1);
+        assert!(text.starts_with('.'));
+        let reparsed: DungeonLevel = text.parse().unwrap();
+        assert_eq!(reparsed.to_string(), text);
+    }
+
+    #[test]
+    fn empty_level_is_rejected() {
+        assert!(matches!(
+            "".parse::<DungeonLevel>(),
+            Err(ParseLevelError::Empty)
+        ));
+    }
+
+    #[test]
+    fn no_monster_starts_in_view_of_the_upstairs() {
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let level = DungeonLevel::generate(&mut rng, 1, 1, Hallways::default()).unwrap();
+            let mut world = empty_world();
+            let exits = level.clone().populate(&mut world, &mut rng, 10.0);
+            let in_view: Vec<(i32, i32)> = exits
+                .upstairs
+                .iter()
+                .flat_map(|&stair| level.visible_from(stair))
+                .collect();
+
+            let positions = world.read_storage::<Position>();
+            let monsters = world.read_storage::<Monster>();
+            let sizes = world.read_storage::<Size>();
+            let mut count = 0;
+            for (pos, monster, size) in (&positions, &monsters, sizes.maybe()).join() {
+                let size = size.copied().unwrap_or_default();
+                for cell in size.cells(pos.into()) {
+                    assert!(
+                        !in_view.contains(&cell),
+                        "{:?} at {:?} can be seen from the stairs on seed {}",
+                        monster.kind,
+                        cell,
+                        seed
+                    );
+                }
+                count += 1;
+            }
+            assert!(count > 0);
+        }
+    }
+
+    /// Renders the upper-left `width` by `height` cells of the level
+    /// drawn in `picture`, a row at a time.
+    fn render_corner(picture: &str, width: usize, height: usize) -> Vec<String> {
+        let level: DungeonLevel = picture.parse().unwrap();
+        (0..height)
+            .map(|y| (0..width).map(|x| level.render_tile(x, y)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn isolated_wall_renders_as_horizontal_wall() {
+        // Floor above and below wins out over floor to the sides.
+        // Everything off the picture is stone.
+        assert_eq!(
+            render_corner("...\n.|.\n...", 5, 4),
+            ["...| ", ".-.| ", "...| ", "---/ "],
+        );
+    }
+
+    #[test]
+    fn room_corners_render_as_slashes() {
+        assert_eq!(
+            render_corner("-----\n|...|\n|...|\n-----", 6, 5),
+            ["/---\\ ", "|...| ", "|...| ", "\\---/ ", "      "],
+        );
+    }
+
+    #[test]
+    fn hallway_junctions_render_as_walls() {
+        assert_eq!(
+            render_corner("   |.|\n----.----\n.........\n---------", 10, 5),
+            [
+                "   |.|    ",
+                "----.----\\",
+                ".........|",
+                "---------/",
+                "          ",
+            ],
+        );
+        assert_eq!(
+            render_corner("  |.|\n---.---\n.......\n---.---\n  |.|", 8, 6),
+            [
+                "  |.|   ",
+                "---.---\\",
+                ".......|",
+                "---.---/",
+                "  |.|   ",
+                "  \\-/   ",
+            ],
+        );
+    }
+}
@@ -1,18 +1,60 @@
-use components::{register_all, CharRender, MobAction, Mobile, Player, Position, TurnTaker};
-use io::init_window;
-use level::{DungeonLevel, LEVEL_SIZE};
+use std::{collections::HashMap, io::ErrorKind, path::PathBuf, slice, time::Duration};
 
-use player::player_turn;
-use rand::thread_rng;
+use branch::{BranchConfig, Dungeon, PendingGenocide, PendingTravel};
+use components::{
+    register_all, Attack, CharRender, Defense, Equipment, Faction, Health, Infravision, Inventory,
+    MobAction, Mobile, Player, Position, TurnTaker,
+};
+use config_file::ConfigError;
+use difficulty::Difficulty;
+use events::{Events, NarrationSystem};
+use ids::{create_entity, IdAllocator};
+use index::PositionIndex;
+use io::{check_interrupt, init_window, quit_with_error, Color};
+use keymap::{Command, Keymap};
+use level::{CellMemory, PlayerView};
+use markers::Markers;
+use messages::MessageLog;
+
+use player::{game_over, haunt, player_dead, player_turn, render_screen, the_player};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use replay::{run_options, Recorder, Run, Turn};
+use rooms::{HallwayStyle, HallwayWidth};
+use schedule::{GameClock, Schedule};
+use scores::{HighScores, RunStats, ScoreSystem};
+use spawner::Spawner;
 use specs::prelude::*;
-use systems::build_dispatcher;
+use systems::{build_dispatcher, MemoryDecay, RevealRooms};
+use theme::Theme;
+use util::GameRng;
+use visibility::FovAlgo;
 
+mod ai;
+mod branch;
 mod components;
+mod config_file;
+mod difficulty;
+mod events;
+mod ids;
+mod index;
 mod io;
+mod items;
+mod keymap;
 mod level;
+mod markers;
+mod messages;
+mod monsters;
 mod player;
+mod replay;
 mod rooms;
+mod schedule;
+mod scores;
+mod spawner;
+mod stash;
 mod systems;
+#[cfg(test)]
+mod testing;
+mod theme;
 mod util;
 mod visibility;
 
@@ -21,50 +63,345 @@ fn main() {
 
     register_all(&mut world);
 
-    let level = DungeonLevel::generate_level(&mut world, &mut thread_rng());
-    let spawn_pos = level.upstairs[0];
+    let mut config = BranchConfig::default();
+    let mut difficulty = Difficulty::default();
+    let mut keymap = Keymap::default();
+    let mut bindings = Vec::new();
+    let mut debug = false;
+    let mut ascii = false;
+    let mut ghost = false;
+    let mut reveal_rooms = false;
+    let mut animation_delay = None;
+    let mut memory_decay = None;
+    let mut infravision = None;
+    let mut name = std::env::var("USER").unwrap_or_else(|_| "Adventurer".to_string());
+    let mut scores = HighScores::default_path();
+    let mut seed = None;
+    let mut record = None;
+
+    // A replayed run is started with the options it was recorded with,
+    // plus any given now, which had better not change how it plays out.
+    // Otherwise, the options in the config file come first, so that
+    // those on the command line win.
+    let given: Vec<String> = std::env::args().skip(1).collect();
+    let mut replay = None;
+    let mut args = given.clone();
+    if let Some(idx) = given.iter().position(|arg| arg == "--config") {
+        if given.get(idx + 1).is_none() {
+            quit_with_error("--config must be followed by a file name");
+        }
+    }
+    if let Some(idx) = given.iter().position(|arg| arg == "--replay") {
+        let path = match given.get(idx + 1) {
+            Some(path) => path,
+            None => quit_with_error("--replay must be followed by a file name"),
+        };
+        let run = match Run::load(path.as_ref()) {
+            Ok(run) => run,
+            Err(err) => quit_with_error(format!("Error reading {}: {}", path, err)),
+        };
+        args = run.args.clone();
+        args.extend(given[..idx].iter().chain(&given[idx + 2..]).cloned());
+        seed = Some(run.seed);
+        replay = Some(run);
+    } else {
+        let chosen = given
+            .iter()
+            .position(|arg| arg == "--config")
+            .map(|idx| PathBuf::from(&given[idx + 1]));
+        let path = chosen.clone().unwrap_or_else(config_file::default_path);
+        match config_file::load(&path) {
+            Ok(options) => args = options.into_iter().chain(given.iter().cloned()).collect(),
+            // It's fine not to have a config file, unless one was asked
+            // for.
+            Err(ConfigError::Io(err)) if chosen.is_none() && err.kind() == ErrorKind::NotFound => {}
+            Err(err) => quit_with_error(format!("Error reading {}: {}", path.display(), err)),
+        }
+    }
+    let recorded_args = run_options(&args);
 
-    world.insert(level);
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--regen-on-revisit" => config.regen_on_revisit = true,
+            "--diagonal-hallways" => config.hallways.style = HallwayStyle::Diagonal,
+            "--wide-hallways" => config.hallways.width = HallwayWidth::Double,
+            "--debug" => debug = true,
+            "--ascii" => ascii = true,
+            "--ghost" => ghost = true,
+            "--reveal-rooms" => reveal_rooms = true,
+            "--name" => match args.next() {
+                Some(chosen) => name = chosen,
+                None => quit_with_error("--name must be followed by a name"),
+            },
+            "--scores" => match args.next() {
+                Some(path) => scores = path.into(),
+                None => quit_with_error("--scores must be followed by a file name"),
+            },
+            "--forget-after" => match args.next().and_then(|arg| arg.parse().ok()) {
+                Some(decay) => memory_decay = Some(decay),
+                None => quit_with_error("--forget-after must be a number of turns"),
+            },
+            "--infravision" => match args.next().and_then(|arg| arg.parse().ok()) {
+                Some(radius) => infravision = Some(radius),
+                None => quit_with_error("--infravision must be a number of tiles"),
+            },
+            "--spawn-interval" => match args.next().and_then(|arg| arg.parse().ok()) {
+                Some(interval) => config.spawn_interval = Some(interval),
+                None => quit_with_error("--spawn-interval must be a number of turns"),
+            },
+            "--difficulty" => match args.next().as_deref().and_then(Difficulty::from_name) {
+                Some(chosen) => difficulty = chosen,
+                None => quit_with_error(format!(
+                    "--difficulty must be one of: {}",
+                    Difficulty::NAMES.join(", ")
+                )),
+            },
+            "--keys" => match args.next().as_deref().and_then(Keymap::from_name) {
+                Some(chosen) => keymap = chosen,
+                None => quit_with_error(format!(
+                    "--keys must be one of: {}",
+                    Keymap::NAMES.join(", ")
+                )),
+            },
+            "--bind" => match (
+                args.next().as_deref().and_then(Command::from_name),
+                args.next(),
+            ) {
+                (Some(command), Some(keys)) if !keys.is_empty() => bindings.push((command, keys)),
+                _ => quit_with_error("--bind must be followed by a command and the keys for it"),
+            },
+            "--view-aspect" => match args.next().and_then(|arg| arg.parse().ok()) {
+                Some(aspect) if aspect > 0 => config.view_aspect = aspect,
+                _ => quit_with_error("--view-aspect must be a positive number"),
+            },
+            // Levels below the top all get the chosen theme, rather
+            // than one picked at random.
+            "--theme" => match args.next().as_deref().and_then(Theme::from_name) {
+                Some(theme) => config.themes = slice::from_ref(theme),
+                None => quit_with_error(format!(
+                    "--theme must be one of: {}",
+                    Theme::ALL
+                        .iter()
+                        .map(|theme| theme.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            },
+            "--fov" => match args.next().as_deref().and_then(FovAlgo::from_name) {
+                Some(chosen) => config.fov = chosen,
+                None => quit_with_error(format!(
+                    "--fov must be one of: {}",
+                    FovAlgo::NAMES.join(", ")
+                )),
+            },
+            "--animation-delay" => match args.next().and_then(|arg| arg.parse().ok()) {
+                Some(millis) => animation_delay = Some(Duration::from_millis(millis)),
+                None => quit_with_error("--animation-delay must be a number of milliseconds"),
+            },
+            "--seed" => match args.next().and_then(|arg| arg.parse().ok()) {
+                // A replay has to keep the seed it was recorded with.
+                Some(chosen) => seed = seed.or(Some(chosen)),
+                None => quit_with_error("--seed must be a number"),
+            },
+            "--record" => match args.next() {
+                Some(path) => record = Some(path),
+                None => quit_with_error("--record must be followed by a file name"),
+            },
+            // The config file has already been read.
+            "--config" => {
+                args.next();
+            }
+            _ => quit_with_error(format!("Unknown option: {}", arg)),
+        }
+    }
+    config.monster_density = difficulty.monster_density;
+    config.view_radius = difficulty.view_radius;
+    // Keys are bound on top of whichever layout was picked, wherever
+    // the layout was picked.
+    for (command, keys) in &bindings {
+        keymap.bind(*command, keys);
+    }
+    if debug {
+        keymap.enable_debug();
+    }
 
-    world
-        .create_entity()
+    let seed = seed.unwrap_or_else(|| thread_rng().gen());
+    let mut recorder = match record {
+        Some(path) => match Recorder::create(path.as_ref(), seed, &recorded_args) {
+            Ok(recorder) => Some(recorder),
+            Err(err) => quit_with_error(format!("Error creating {}: {}", path, err)),
+        },
+        None => None,
+    };
+
+    // The dungeon's levels are generated from seeds of their own, so
+    // the game's generator is left for what happens on them.
+    world.insert(GameRng(StdRng::seed_from_u64(seed)));
+    world.insert(IdAllocator::default());
+    world.insert(PositionIndex::default());
+    world.insert(PlayerView::default());
+
+    let mut dungeon = match Dungeon::generate(&config, seed) {
+        Ok(dungeon) => dungeon,
+        Err(err) => quit_with_error(format!("Error generating level: {}", err)),
+    };
+    let spawn_pos = dungeon.enter(&mut world);
+
+    world.insert(MessageLog::default());
+    world.insert(Events::default());
+    world.insert(Markers::default());
+    world.insert(RunStats::new(name));
+    world.insert(HighScores::new(scores));
+    world.insert(Schedule::default());
+    world.insert(GameClock::default());
+    world.insert(PendingTravel::default());
+    world.insert(PendingGenocide::default());
+    world.insert(MemoryDecay(memory_decay));
+    world.insert(RevealRooms(reveal_rooms));
+    world.insert(keymap);
+    world.insert(Spawner::new(config.spawn_interval, config.spawn_cap));
+
+    let starting_items: Vec<Entity> = difficulty
+        .starting_items
+        .iter()
+        .map(|kind| kind.create(&mut world))
+        .collect();
+
+    let player = create_entity(&mut world)
         .with(Position::from(spawn_pos))
-        .with(CharRender { glyph: '@' })
+        .with(CharRender {
+            glyph: '@',
+            color: Color::White,
+        })
         .with(Player {
-            known_cells: (0..LEVEL_SIZE.1)
-                .map(|_| (0..LEVEL_SIZE.0).map(|_| false).collect())
-                .collect(),
+            known_cells: CellMemory::default(),
+            remembered: HashMap::new(),
+            gold: 0,
         })
         .with(Mobile {
             next_action: MobAction::Nop,
+            last_outcome: None,
+        })
+        .with(Inventory::new(starting_items))
+        .with(Equipment::default())
+        .with(Health {
+            current: difficulty.player_health,
+            maximum: difficulty.player_health,
         })
+        .with(Attack {
+            damage: 4,
+            knockback: false,
+        })
+        .with(Defense { armor: 0 })
+        .with(Faction::Player)
         .with(TurnTaker {
             next: 0,
-            maximum: 10,
-        })
-        .build();
+            maximum: difficulty.player_turn,
+        });
+    let player = match infravision {
+        Some(radius) => player.with(Infravision { radius }),
+        None => player,
+    };
+    player.build();
+
+    world.fetch_mut::<MessageLog>().log(format!(
+        "Welcome to the dungeon! You are playing on {} difficulty.",
+        difficulty.name
+    ));
 
     let mut dispatcher = build_dispatcher();
 
-    let mut window = match init_window() {
-        Ok(window) => window,
-        Err(err) => {
-            println!("Error initializing window: {}", err);
-            return;
-        }
-    };
+    let mut screen = init_window();
+    screen.set_box_drawing(!ascii);
+    if let Some(err) = screen.color_error() {
+        world.fetch_mut::<MessageLog>().log(format!(
+            "Playing without colors, since your terminal can't show them ({}).",
+            err
+        ));
+    }
 
     loop {
+        // Ctrl-C is usually noticed while waiting for a key, but the
+        // monsters might take a while between the player's turns.
+        check_interrupt();
+
+        let player = match the_player(&world) {
+            Ok(player) => player,
+            Err(err) => quit_with_error(format!("Error finding the player: {}", err)),
+        };
+
+        // The player picks an action right before taking their turn,
+        // so that it happens straight away.
+        let players_turn = world
+            .read_resource::<Schedule>()
+            .up_next()
+            .contains(&player);
+        if players_turn {
+            // Recorded turns are played back without waiting for the
+            // player, who takes over once they run out.
+            match replay.as_mut().and_then(|run| run.next_turn(&world)) {
+                Some(Ok(turn)) => turn.apply(&world),
+                Some(Err(err)) => quit_with_error(format!("Error replaying the run: {}", err)),
+                None => {
+                    if replay.take().is_some() {
+                        world
+                            .fetch_mut::<MessageLog>()
+                            .log("That's the end of the recorded run.");
+                    }
+                    player_turn(&mut world, &mut screen);
+                }
+            }
+
+            if let Some(rec) = recorder.as_mut() {
+                if let Err(err) = rec.record(Turn::taken(&world), &world) {
+                    world
+                        .fetch_mut::<MessageLog>()
+                        .log(format!("The run is no longer being recorded: {}", err));
+                    recorder = None;
+                }
+            }
+        }
+
         dispatcher.dispatch(&world);
+        world.maintain();
+
+        let travel = world.fetch_mut::<PendingTravel>().0.take();
+        if let Some(travel) = travel {
+            dungeon.travel(&mut world, travel);
+            world.fetch_mut::<RunStats>().reached(dungeon.depth());
+        }
+        let genocide = world.fetch_mut::<PendingGenocide>().0.take();
+        if let Some(genocide) = genocide {
+            dungeon.genocide(&mut world, genocide);
+        }
+
+        // Only now, once the player has finished moving between
+        // levels, has everything that can happen this turn happened.
+        ScoreSystem.run_now(&world);
+        NarrationSystem.run_now(&world);
+
+        // The player can be shown what they did before anything else
+        // reacts to it, rather than seeing it all at once when it's
+        // next their turn.
+        if let Some(delay) = animation_delay {
+            let before_monsters = players_turn
+                && !player_dead(&world)
+                && !world
+                    .read_resource::<Schedule>()
+                    .up_next()
+                    .contains(&player);
+            if before_monsters {
+                render_screen(&world, &mut screen);
+                screen.pause(delay);
+            }
+        }
 
-        if (
-            &world.read_storage::<Player>(),
-            &world.read_storage::<TurnTaker>(),
-        )
-            .join()
-            .any(|(_plr, turn)| turn.next == 0)
-        {
-            player_turn(&mut world, &mut window);
+        if player_dead(&world) {
+            if ghost {
+                haunt(&world, &mut screen);
+            }
+            game_over(&world, &mut screen);
         }
     }
 }
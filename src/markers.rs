@@ -0,0 +1,56 @@
+//! Notes that the player leaves on the map for themselves.
+
+use std::collections::HashMap;
+
+use crate::{branch::LevelId, io::Color};
+
+/// The most characters a marker's label can have.
+pub const MAX_LABEL_LEN: usize = 20;
+
+/// The glyph that markers are drawn with on the map.
+pub const MARKER_GLYPH: char = '&';
+
+/// The color that markers are drawn in.
+pub const MARKER_COLOR: Color = Color::Cyan;
+
+/// Every marker the player has placed, keyed by the level it's on and
+/// the cell it marks.
+#[derive(Default)]
+pub struct Markers {
+    labels: HashMap<(LevelId, (i32, i32)), String>,
+}
+
+impl Markers {
+    /// Marks a cell with a label, replacing any marker already there.
+    /// Labels that are too long are cut short.
+    pub fn set(&mut self, level: LevelId, cell: (i32, i32), label: &str) {
+        let label = label.chars().take(MAX_LABEL_LEN).collect();
+        self.labels.insert((level, cell), label);
+    }
+
+    /// Removes the marker from a cell, returning its label if there
+    /// was one.
+    pub fn remove(&mut self, level: LevelId, cell: (i32, i32)) -> Option<String> {
+        self.labels.remove(&(level, cell))
+    }
+
+    /// The label of the marker on a cell, if there is one.
+    pub fn get(&self, level: LevelId, cell: (i32, i32)) -> Option<&str> {
+        self.labels.get(&(level, cell)).map(String::as_str)
+    }
+
+    /// The cells with markers on the given level.
+    pub fn on_level(&self, level: LevelId) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.labels
+            .keys()
+            .filter(move |(marked, _cell)| *marked == level)
+            .map(|(_level, cell)| *cell)
+    }
+
+    /// Removes every marker on a level, for when it's been replaced
+    /// with a new one.
+    pub fn clear_level(&mut self, level: LevelId) {
+        self.labels
+            .retain(|(marked, _cell), _label| *marked != level);
+    }
+}
@@ -0,0 +1,80 @@
+//! The message log, for telling the player what's going on.
+
+use crate::io::Color;
+
+/// What sort of thing a message is about, which decides the color it's
+/// shown in.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Severity {
+    /// Anything that doesn't need to stand out.
+    #[default]
+    Info,
+
+    /// Fighting, and anything else that does damage.
+    Combat,
+
+    /// Picking things up.
+    Pickup,
+}
+
+impl Severity {
+    /// The color that messages of this kind are shown in.
+    pub fn color(self) -> Color {
+        match self {
+            Severity::Info => Color::White,
+            Severity::Combat => Color::Red,
+            Severity::Pickup => Color::Green,
+        }
+    }
+}
+
+/// A single message in the log.
+pub struct Message {
+    pub text: String,
+    pub severity: Severity,
+}
+
+/// A record of all the messages that have been shown to the player.
+#[derive(Default)]
+pub struct MessageLog {
+    /// Every message logged so far, oldest first.
+    messages: Vec<Message>,
+
+    /// The number of messages that the player has already seen.
+    seen: usize,
+}
+
+impl MessageLog {
+    /// Adds a new message to the log, with no particular severity.
+    pub fn log(&mut self, message: impl Into<String>) {
+        self.log_with(Severity::Info, message);
+    }
+
+    /// Adds a new message of the given severity to the log.
+    pub fn log_with(&mut self, severity: Severity, message: impl Into<String>) {
+        self.messages.push(Message {
+            text: message.into(),
+            severity,
+        });
+    }
+
+    /// The messages that have been logged since the player last
+    /// acknowledged the log.
+    pub fn unseen(&self) -> &[Message] {
+        &self.messages[self.seen..]
+    }
+
+    /// Marks every message currently in the log as seen.
+    pub fn mark_seen(&mut self) {
+        self.seen = self.messages.len();
+    }
+}
+
+/// Capitalizes the first letter of a message.
+pub fn capitalize(message: &str) -> String {
+    let mut chars = message.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
@@ -0,0 +1,271 @@
+//! Definitions of the kinds of monsters that inhabit the dungeon.
+
+use std::ops::RangeInclusive;
+
+use rand::{seq::SliceRandom, Rng};
+use specs::prelude::*;
+
+use crate::{
+    components::{
+        Attack, CanOpenDoors, CharRender, Faction, Flying, Health, MobAction, Mobile, Monster,
+        Position, Size, TurnTaker, Warm,
+    },
+    ids::create_entity,
+    io::Color,
+};
+
+/// The kinds of monsters that exist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MonsterKind {
+    Zombie,
+    Goblin,
+    Rat,
+    Bat,
+    Dragon,
+}
+
+/// How a monster acts towards the player.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    /// Chases the player whenever it can see them.
+    Aggressive,
+
+    /// Chases the player, but runs away when badly hurt.
+    Cowardly,
+
+    /// Ignores the player and moves around at random.
+    Wandering,
+}
+
+impl MonsterKind {
+    /// Every kind of monster.
+    pub const ALL: &'static [MonsterKind] = &[
+        MonsterKind::Zombie,
+        MonsterKind::Goblin,
+        MonsterKind::Rat,
+        MonsterKind::Bat,
+        MonsterKind::Dragon,
+    ];
+
+    /// Picks a kind of monster at random, with common kinds turning up
+    /// more often than rare ones.
+    pub fn random(rng: &mut impl Rng) -> Self {
+        *Self::ALL
+            .choose_weighted(rng, |kind| kind.frequency())
+            .expect("Monsters must exist")
+    }
+
+    /// How often the monster turns up, relative to the others.
+    pub fn frequency(&self) -> u32 {
+        match self {
+            MonsterKind::Dragon => 1,
+            _ => 4,
+        }
+    }
+
+    /// What the monster is called in messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MonsterKind::Zombie => "zombie",
+            MonsterKind::Goblin => "goblin",
+            MonsterKind::Rat => "rat",
+            MonsterKind::Bat => "bat",
+            MonsterKind::Dragon => "dragon",
+        }
+    }
+
+    /// What the monster leaves behind when it dies, if anything.
+    pub fn corpse(&self) -> Option<&'static str> {
+        match self {
+            MonsterKind::Zombie => Some("zombie corpse"),
+            MonsterKind::Goblin => Some("goblin corpse"),
+            MonsterKind::Dragon => Some("dragon corpse"),
+            MonsterKind::Rat | MonsterKind::Bat => None,
+        }
+    }
+
+    /// How the monster is drawn.
+    pub fn render(&self) -> CharRender {
+        match self {
+            MonsterKind::Zombie => CharRender {
+                glyph: 'Z',
+                color: Color::Green,
+            },
+            MonsterKind::Goblin => CharRender {
+                glyph: 'g',
+                color: Color::Red,
+            },
+            MonsterKind::Rat => CharRender {
+                glyph: 'r',
+                color: Color::Yellow,
+            },
+            MonsterKind::Bat => CharRender {
+                glyph: 'B',
+                color: Color::Cyan,
+            },
+            MonsterKind::Dragon => CharRender {
+                glyph: 'D',
+                color: Color::Red,
+            },
+        }
+    }
+
+    /// How the monster acts towards the player.
+    pub fn behavior(&self) -> Behavior {
+        match self {
+            MonsterKind::Zombie | MonsterKind::Dragon => Behavior::Aggressive,
+            MonsterKind::Goblin => Behavior::Cowardly,
+            MonsterKind::Rat | MonsterKind::Bat => Behavior::Wandering,
+        }
+    }
+
+    /// Which side the monster is on. Monsters that ignore the player
+    /// don't side with the ones that hunt them, so the two come to
+    /// blows if they get in each other's way.
+    pub fn faction(&self) -> Faction {
+        match self.behavior() {
+            Behavior::Wandering => Faction::Neutral,
+            Behavior::Aggressive | Behavior::Cowardly => Faction::Hostile,
+        }
+    }
+
+    /// How close the player has to come before the monster goes after
+    /// them, if it can see them. Once it's after them it keeps going,
+    /// however far away they get, until it loses track of them.
+    pub fn aggro_range(&self) -> i32 {
+        match self {
+            MonsterKind::Zombie => 5,
+            MonsterKind::Goblin => 7,
+            MonsterKind::Dragon => 8,
+            MonsterKind::Rat | MonsterKind::Bat => 3,
+        }
+    }
+
+    /// How many of the monster turn up together. Only monsters that
+    /// take up a single cell come in packs.
+    pub fn pack_size(&self) -> RangeInclusive<usize> {
+        match self {
+            MonsterKind::Rat => 2..=4,
+            _ => 1..=1,
+        }
+    }
+
+    /// Whether the monster flies over ground hazards.
+    pub fn flying(&self) -> bool {
+        matches!(self, MonsterKind::Bat)
+    }
+
+    /// Whether the monster is alive enough to give off heat.
+    pub fn warm(&self) -> bool {
+        !matches!(self, MonsterKind::Zombie)
+    }
+
+    /// Whether the monster knows how to open doors.
+    pub fn opens_doors(&self) -> bool {
+        matches!(self, MonsterKind::Goblin)
+    }
+
+    /// How much room the monster takes up.
+    pub fn size(&self) -> Size {
+        match self {
+            MonsterKind::Dragon => Size {
+                width: 2,
+                height: 2,
+            },
+            _ => Size::default(),
+        }
+    }
+
+    /// The amount of health the monster starts out with.
+    pub fn max_health(&self) -> i32 {
+        match self {
+            MonsterKind::Zombie => 8,
+            MonsterKind::Goblin => 6,
+            MonsterKind::Rat => 3,
+            MonsterKind::Bat => 2,
+            MonsterKind::Dragon => 12,
+        }
+    }
+
+    /// The most damage the monster can deal in a single hit.
+    pub fn damage(&self) -> i32 {
+        match self {
+            MonsterKind::Zombie | MonsterKind::Dragon => 3,
+            MonsterKind::Goblin => 2,
+            MonsterKind::Rat | MonsterKind::Bat => 1,
+        }
+    }
+
+    /// Whether the monster's hits are heavy enough to knock its
+    /// victims back.
+    pub fn knockback(&self) -> bool {
+        matches!(self, MonsterKind::Zombie)
+    }
+
+    /// The amount of time between the monster's turns.
+    pub fn speed(&self) -> u32 {
+        match self {
+            MonsterKind::Zombie => 15,
+            MonsterKind::Goblin => 10,
+            MonsterKind::Rat => 8,
+            MonsterKind::Bat => 6,
+            MonsterKind::Dragon => 20,
+        }
+    }
+
+    /// Creates a new monster of this kind at the given position.
+    pub fn spawn(&self, world: &mut World, pos: (i32, i32)) -> Entity {
+        self.build(create_entity(world), pos)
+    }
+
+    /// Fills in `builder` with the components of a new monster of this
+    /// kind at the given position. This works with lazy builders too,
+    /// for spawning monsters from inside systems.
+    pub fn build<B: Builder>(&self, builder: B, (x, y): (i32, i32)) -> Entity {
+        let builder = builder
+            .with(Position { x, y })
+            .with(self.render())
+            .with(Monster {
+                kind: *self,
+                path: Vec::new(),
+                interest: 0,
+            })
+            .with(Mobile {
+                next_action: MobAction::Nop,
+                last_outcome: None,
+            })
+            .with(TurnTaker {
+                next: self.speed(),
+                maximum: self.speed(),
+            })
+            .with(Health {
+                current: self.max_health(),
+                maximum: self.max_health(),
+            })
+            .with(Attack {
+                damage: self.damage(),
+                knockback: self.knockback(),
+            })
+            .with(self.faction());
+
+        let builder = match self.size() {
+            size if size.is_large() => builder.with(size),
+            _ => builder,
+        };
+        let builder = if self.opens_doors() {
+            builder.with(CanOpenDoors)
+        } else {
+            builder
+        };
+        let builder = if self.warm() {
+            builder.with(Warm)
+        } else {
+            builder
+        };
+        if self.flying() {
+            builder.with(Flying).build()
+        } else {
+            builder.build()
+        }
+    }
+}
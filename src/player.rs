@@ -1,123 +1,1564 @@
 //! Code for controlling the player, and for I/O.
 
-use pancurses::Window;
-use specs::prelude::*;
+use std::{collections::HashMap, ops::Deref};
+
+use pancurses::Input;
+use rand::{thread_rng, Rng};
+use specs::{prelude::*, storage::MaskedStorage};
+use thiserror::Error;
 
 use crate::{
-    components::{CharRender, MobAction, Mobile, Player, Position},
-    io::quit,
-    level::{DrawStyle, DungeonLevel},
+    branch::{CurrentLevel, Genocide, PendingGenocide, StairDirection},
+    components::{
+        CharRender, Detected, Equipment, Health, Infravision, Injured, Inventory, Item,
+        ItemCategory, ItemEffect, LightSource, MobAction, Mobile, Monster, Player, Position, Warm,
+    },
+    index::PositionIndex,
+    io::{quit, Color, InputClosed, Screen},
+    keymap::{Command, Keymap},
+    level::{
+        chebyshev, clamp_to_bounds, in_bounds, offset, DrawStyle, DungeonLevel, DungeonTile,
+        PlayerView, LEVEL_SIZE,
+    },
+    markers::{Markers, MARKER_COLOR, MARKER_GLYPH, MAX_LABEL_LEN},
+    messages::MessageLog,
+    monsters::MonsterKind,
+    schedule::GameClock,
+    scores::{score, HighScores, RunStats, ScoreEntry},
+    systems::BLINK_RANGE,
+    visibility::Lighting,
 };
 
+/// Probability that a flickering light reaches one cell less far than
+/// usual on any given turn.
+const FLICKER_CHANCE: f64 = 0.3;
+
+/// Why the player couldn't be found.
+#[derive(Error, Debug, PartialEq)]
+pub enum PlayerError {
+    #[error("there is no player in the world")]
+    NoPlayer,
+
+    #[error("there is more than one player in the world")]
+    SeveralPlayers,
+}
+
+/// Finds the player. There's exactly one player at a time: they alone
+/// are controlled from the keyboard, and the screen is drawn from
+/// their point of view. Systems that treat players like any other
+/// creature still join over `Player` as usual.
+///
+/// None of the player's input or output makes sense if there isn't
+/// exactly one player. The game checks for that at the start of every
+/// turn, so code that runs during a turn can count on finding them.
+pub fn the_player(ecs: &World) -> Result<Entity, PlayerError> {
+    find_player(&ecs.entities(), &ecs.read_storage::<Player>())
+}
+
+/// Finds the player, like `the_player`, for systems that already hold
+/// the player storage.
+pub fn find_player<D: Deref<Target = MaskedStorage<Player>>>(
+    entities: &Entities,
+    players: &Storage<Player, D>,
+) -> Result<Entity, PlayerError> {
+    let mut found = (entities, players).join().map(|(ent, _plr)| ent);
+    match (found.next(), found.next()) {
+        (Some(player), None) => Ok(player),
+        (None, _) => Err(PlayerError::NoPlayer),
+        (Some(_), Some(_)) => Err(PlayerError::SeveralPlayers),
+    }
+}
+
+/// Where the player is.
+fn player_pos(ecs: &World) -> (i32, i32) {
+    ecs.read_storage::<Position>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .expect("Player must have a position")
+        .into()
+}
+
 /// Runs a player turn on the ECS, using the given `screen` for input
 /// and output.
 ///
 /// At some point this should maybe become a system rather than a
 /// standalone function.
-pub fn player_turn(ecs: &mut World, screen: &mut Window) {
-    render_screen(ecs, screen);
+pub fn player_turn(ecs: &mut World, screen: &mut Screen) {
+    // Running out of input, even partway through a prompt, is the
+    // user closing stdin, so the game is over.
+    let action = match choose_action(ecs, screen) {
+        Ok(action) => action,
+        Err(InputClosed) => quit(),
+    };
+
+    ecs.write_storage::<Mobile>()
+        .get_mut(the_player(ecs).expect("There must be one player"))
+        .expect("Player must be mobile")
+        .next_action = action;
+}
 
-    let action = loop {
-        let key = screen.getch();
+/// Reads commands from the player until they pick something possible
+/// to do this turn.
+fn choose_action(ecs: &World, screen: &mut Screen) -> Result<MobAction, InputClosed> {
+    loop {
+        render_screen(ecs, screen);
 
-        use pancurses::Input;
-        let action = match key {
-            Some(key) => match key {
-                Input::Character(ch) => match ch {
-                    '.' => Some(MobAction::Nop),
+        let key = screen.read_key()?;
+        ecs.fetch_mut::<MessageLog>().mark_seen();
 
-                    'h' => Some(MobAction::Move(-1, 0)),
-                    'j' => Some(MobAction::Move(0, 1)),
-                    'k' => Some(MobAction::Move(0, -1)),
-                    'l' => Some(MobAction::Move(1, 0)),
+        let command = ecs.fetch::<Keymap>().command(&key);
+        if let Some(command) = command {
+            if let Some(action) = run_command(ecs, screen, command)? {
+                if possible(ecs, &action) {
+                    return Ok(action);
+                }
+            }
+        }
+    }
+}
 
-                    'y' => Some(MobAction::Move(-1, -1)),
-                    'u' => Some(MobAction::Move(1, -1)),
-                    'b' => Some(MobAction::Move(-1, 1)),
-                    'n' => Some(MobAction::Move(1, 1)),
+/// Works out what the player wants to do when they run a command,
+/// asking them for more details if need be. Returns `None` if the
+/// command doesn't take a turn or was cancelled.
+fn run_command(
+    ecs: &World,
+    screen: &mut Screen,
+    command: Command,
+) -> Result<Option<MobAction>, InputClosed> {
+    let action = match command {
+        Command::Move(dx, dy) => {
+            let question = match leads_onto(ecs, (dx, dy)) {
+                Some(DungeonTile::Chasm) => Some("Really jump into the chasm?"),
+                Some(DungeonTile::Lava) => Some("Really step into the lava?"),
+                _ => None,
+            };
+            match question {
+                Some(question) if !confirm(ecs, screen, question)? => None,
+                _ => Some(MobAction::Move(dx, dy)),
+            }
+        }
+        Command::Wait => Some(MobAction::Nop),
+        Command::Search => Some(MobAction::Search),
+        Command::Open => choose_door(ecs, screen)?,
+        Command::PickUp => pick_up(ecs),
+        Command::Drop => choose_item(ecs, screen, "drop")?.map(MobAction::Drop),
+        Command::GoUp => take_stairs(ecs, StairDirection::Up),
+        Command::GoDown => take_stairs(ecs, StairDirection::Down),
+        Command::Quaff => quaff(ecs, screen)?,
+        Command::Read => match choose_category(ecs, screen, ItemCategory::Scroll)? {
+            Some(item) => use_item(ecs, screen, item)?,
+            None => None,
+        },
+        Command::Eat => choose_category(ecs, screen, ItemCategory::Food)?.map(MobAction::Use),
+        Command::Apply => match choose_item(ecs, screen, "apply")? {
+            Some(item) => use_item(ecs, screen, item)?,
+            None => None,
+        },
+        Command::Wield => choose_category(ecs, screen, ItemCategory::Weapon)?.map(MobAction::Wield),
+        Command::Wear => choose_category(ecs, screen, ItemCategory::Armor)?.map(MobAction::Wear),
+        Command::TakeOff => choose_equipped(ecs, screen)?.map(MobAction::Unequip),
+        Command::Blink => choose_blink(ecs, screen)?,
+        Command::Throw => choose_throw(ecs, screen)?,
+        Command::Mark => {
+            place_marker(ecs, screen)?;
+            None
+        }
+        Command::Unmark => {
+            remove_marker(ecs, screen)?;
+            None
+        }
+        Command::Look => {
+            look_at(ecs, screen)?;
+            None
+        }
+        Command::Inventory => {
+            show_inventory(ecs, screen)?;
+            None
+        }
+        Command::Map => {
+            show_map(ecs, screen)?;
+            None
+        }
+        Command::Help => {
+            show_help(ecs, screen)?;
+            None
+        }
+        Command::RevealMap => {
+            reveal_map(ecs);
+            None
+        }
+        Command::ShowLayout => {
+            show_layout(ecs, screen)?;
+            None
+        }
+        Command::Genocide => {
+            let kind = match choose_monster_kind(ecs, screen, "Wipe out which kind of monster?")? {
+                Some(kind) => kind,
+                None => return Ok(None),
+            };
+            let whole_branch = confirm(ecs, screen, "On every level of the branch?")?;
+            ecs.fetch_mut::<PendingGenocide>().0 = Some(Genocide { kind, whole_branch });
 
-                    'q' => quit(),
+            // Like a scroll, this happens once the turn is over, so it
+            // takes one.
+            Some(MobAction::Nop)
+        }
+        Command::Quit => {
+            if confirm(ecs, screen, "Really quit?")? {
+                quit();
+            }
+            None
+        }
+    };
 
-                    _ => None,
-                },
+    Ok(action)
+}
 
-                Input::KeyUp => Some(MobAction::Move(0, -1)),
-                Input::KeyLeft => Some(MobAction::Move(-1, 0)),
-                Input::KeyDown => Some(MobAction::Move(0, 1)),
-                Input::KeyRight => Some(MobAction::Move(1, 0)),
-                _ => None,
-            },
+/// Lists every command and the keys that run it, a page at a time,
+/// until the player has seen them all.
+fn show_help(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let lines: Vec<String> = ecs
+        .fetch::<Keymap>()
+        .commands()
+        .iter()
+        .map(|(command, keys)| format!("{:<16} {}", keys.join(", "), command.describe()))
+        .collect();
+    show_pages(screen, &lines)
+}
 
-            // User closed stdin.
-            None => quit(),
+/// Lists everything the player is carrying or has equipped, with
+/// the letters they're picked by, until the player presses a key.
+fn show_inventory(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let items = ecs.read_storage::<Item>();
+    let describe = |item: Entity| {
+        let item = items.get(item).expect("Carried entity must be an item");
+        format!("{} ({})", item.name, item.category.name())
+    };
+
+    let mut lines: Vec<String> = carried_items(ecs)
+        .into_iter()
+        .map(|(letter, item)| format!("{} - {}", letter, describe(item)))
+        .collect();
+    if let Some(equipment) = ecs
+        .read_storage::<Equipment>()
+        .get(the_player(ecs).expect("There must be one player"))
+    {
+        if let Some(weapon) = equipment.weapon {
+            lines.push(format!("Wielding {}", describe(weapon)));
+        }
+        if let Some(armor) = equipment.armor {
+            lines.push(format!("Wearing {}", describe(armor)));
+        }
+    }
+
+    if lines.is_empty() {
+        ecs.fetch_mut::<MessageLog>()
+            .log("You are carrying nothing.");
+        Ok(())
+    } else {
+        lines.insert(0, "You are carrying:".to_string());
+        show_pages(screen, &lines)
+    }
+}
+
+/// Shows some lines of text a screenful at a time, waiting for a key
+/// after each screen.
+fn show_pages(screen: &mut Screen, lines: &[String]) -> Result<(), InputClosed> {
+    // Leave room at the bottom for the prompt.
+    let pages: Vec<&[String]> = lines.chunks(LEVEL_SIZE.1).collect();
+    for (number, page) in pages.iter().enumerate() {
+        screen.clear();
+        for (y, line) in page.iter().enumerate() {
+            screen.put_str(0, y as _, line, Color::White);
+        }
+
+        let prompt = if number + 1 < pages.len() {
+            format!(
+                "Page {} of {}. Press any key to see more.",
+                number + 1,
+                pages.len()
+            )
+        } else {
+            "Press any key to continue.".to_string()
         };
+        screen.put_str(0, LEVEL_SIZE.1 as _, &prompt, Color::White);
+        screen.set_cursor(0, LEVEL_SIZE.1 as _);
+        screen.flush();
 
-        if let Some(action) = action {
-            if possible(ecs, &action) {
-                break action;
+        screen.read_key()?;
+    }
+
+    Ok(())
+}
+
+/// Debug command: marks the whole level as discovered, so that it's
+/// all drawn from then on, or until it's forgotten.
+fn reveal_map(ecs: &World) {
+    let now = ecs.fetch::<GameClock>().turns;
+    let mut plrs = ecs.write_storage::<Player>();
+    for player in (&mut plrs).join() {
+        player.known_cells.see_all(now);
+    }
+
+    ecs.fetch_mut::<MessageLog>()
+        .log("The layout of the level is revealed.");
+}
+
+/// Debug command: lists where each room and staircase on the level is
+/// in the message log, and shows the list straight away since it's
+/// too long to fit on the message line.
+fn show_layout(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let level = ecs.fetch::<DungeonLevel>();
+
+    // The upper-left and lower-right corners of each room's bounding
+    // box, by room index.
+    type Corners = ((i32, i32), (i32, i32));
+    let mut rooms: Vec<Option<Corners>> = Vec::new();
+    for y in 0..LEVEL_SIZE.1 as i32 {
+        for x in 0..LEVEL_SIZE.0 as i32 {
+            if let Some(room) = level.room_at(x, y) {
+                if rooms.len() <= room {
+                    rooms.resize(room + 1, None);
+                }
+                rooms[room] = Some(match rooms[room] {
+                    Some(((x0, y0), (x1, y1))) => ((x0.min(x), y0.min(y)), (x1.max(x), y1.max(y))),
+                    None => ((x, y), (x, y)),
+                });
             }
         }
-    };
+    }
+
+    let mut log = ecs.fetch_mut::<MessageLog>();
+    log.mark_seen();
+    log.log(format!("Theme: {}.", level.theme().name));
+    for (idx, corners) in rooms.iter().enumerate() {
+        if let Some((ul, lr)) = corners {
+            log.log(format!("Room {} spans {:?} to {:?}.", idx, ul, lr));
+        }
+    }
+    let exits = level.exits();
+    log.log(format!("Upstairs: {:?}.", exits.upstairs));
+    log.log(format!("Downstairs: {:?}.", exits.downstairs));
+    if let Some(stair) = exits.branch_stair {
+        log.log(format!(
+            "Branch staircase: {:?}, to {}.",
+            stair,
+            level.branch_name().unwrap_or("somewhere")
+        ));
+    }
+
+    let lines: Vec<String> = log.unseen().iter().map(|msg| msg.text.clone()).collect();
+    log.mark_seen();
+    show_pages(screen, &lines)
+}
+
+/// Shows everything the player has discovered on the level, until
+/// they press a key. Remembered tiles are drawn dimmed, except for
+/// stairs, which are highlighted so they're easy to find.
+fn show_map(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    screen.clear();
 
     let plrs = ecs.read_storage::<Player>();
-    let mut mobs = ecs.write_storage::<Mobile>();
-    for (_plr, mob) in (&plrs, &mut mobs).join() {
-        mob.next_action = action;
+    let player = plrs
+        .get(the_player(ecs).expect("There must be one player"))
+        .expect("Player must be a player");
+    let (player_x, player_y) = player_pos(ecs);
+
+    // Unlike `render_screen`, this covers the whole level no matter
+    // how much of it fits in the normal view.
+    let level = ecs.fetch::<DungeonLevel>();
+    level.draw(
+        screen,
+        |(x, y)| {
+            if !player.known_cells.get(x, y) {
+                DrawStyle::Undiscovered
+            } else if matches!(
+                level.tile(x, y),
+                DungeonTile::Upstair | DungeonTile::Downstair | DungeonTile::BranchStair
+            ) {
+                DrawStyle::Visible
+            } else {
+                DrawStyle::Discovered
+            }
+        },
+        |_| Lighting::Lit,
+    );
+    let markers = ecs.fetch::<Markers>();
+    for (x, y) in markers.on_level(ecs.fetch::<CurrentLevel>().0) {
+        if player.known_cells.get(x, y) {
+            screen.put(x, y, MARKER_GLYPH, MARKER_COLOR);
+        }
     }
+    screen.put(player_x, player_y, '@', Color::White);
+
+    screen.put_str(
+        0,
+        LEVEL_SIZE.1 as _,
+        "This is what you know of the level. Press any key to continue.",
+        Color::White,
+    );
+    screen.set_cursor(player_x, player_y);
+    screen.flush();
+
+    screen.read_key()?;
+    Ok(())
+}
+
+/// Finds the position, reach and color of every light on the level
+/// this turn.
+fn light_sources(ecs: &World) -> Vec<((i32, i32), i32, Color)> {
+    let mut rng = thread_rng();
+    let lights = ecs.read_storage::<LightSource>();
+    let positions = ecs.read_storage::<Position>();
+
+    (&lights, &positions)
+        .join()
+        .map(|(light, pos)| {
+            let flicker = light.flickers && rng.gen_bool(FLICKER_CHANCE);
+            (pos.into(), light.radius - flicker as i32, light.color)
+        })
+        .collect()
+}
+
+/// Finds every warm creature that the player can sense through
+/// infravision, if they have it, along with how it looks. Walls don't
+/// get in the way of this, unlike ordinary sight.
+fn sensed_by_infravision(ecs: &World) -> Vec<((i32, i32), CharRender)> {
+    let radius = match ecs
+        .read_storage::<Infravision>()
+        .get(the_player(ecs).expect("There must be one player"))
+    {
+        Some(infravision) => infravision.radius,
+        None => return Vec::new(),
+    };
+    let (player_x, player_y) = player_pos(ecs);
+    let renderables = ecs.read_storage::<CharRender>();
+    let positions = ecs.read_storage::<Position>();
+    let warm = ecs.read_storage::<Warm>();
+
+    (&renderables, &positions, &warm)
+        .join()
+        .filter(|(_render, pos, _warm)| {
+            let (dx, dy) = (
+                pos.x.saturating_sub(player_x),
+                pos.y.saturating_sub(player_y),
+            );
+            let dist2 = dx.saturating_mul(dx).saturating_add(dy.saturating_mul(dy));
+            dist2 <= radius.saturating_mul(radius)
+        })
+        .map(|(render, pos, _warm)| (pos.into(), *render))
+        .collect()
+}
+
+/// Whether the player has run out of health.
+pub fn player_dead(ecs: &World) -> bool {
+    ecs.read_storage::<Health>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .is_some_and(|hp| hp.current <= 0)
+}
+
+/// Lets the player drift around the level as a ghost after they've
+/// died, to see what happened to them, until they press Escape or
+/// Enter. The ghost goes through walls and sees the whole level, and
+/// everything on it, whether it was in view or not.
+pub fn haunt(ecs: &World, screen: &mut Screen) {
+    let now = ecs.fetch::<GameClock>().turns;
+    for player in (&mut ecs.write_storage::<Player>()).join() {
+        player.known_cells.see_all(now);
+    }
+    ecs.fetch_mut::<MessageLog>()
+        .log("You rise from your body as a ghost. Look around all you like, then press Escape.");
+
+    let mut ghost = player_pos(ecs);
+    loop {
+        screen.clear();
+        let level = ecs.fetch::<DungeonLevel>();
+        level.draw(screen, |_| DrawStyle::Visible, |_| Lighting::Lit);
+
+        // Objects first, then markers, then creatures, as in
+        // `render_screen`.
+        let renderables = ecs.read_storage::<CharRender>();
+        let positions = ecs.read_storage::<Position>();
+        let creatures = ecs.read_storage::<Health>();
+        for (render, pos, ()) in (&renderables, &positions, !&creatures).join() {
+            screen.put(pos.x, pos.y, render.glyph, render.color);
+        }
+        for (x, y) in ecs
+            .fetch::<Markers>()
+            .on_level(ecs.fetch::<CurrentLevel>().0)
+        {
+            screen.put(x, y, MARKER_GLYPH, MARKER_COLOR);
+        }
+        for (render, pos, _creature) in (&renderables, &positions, &creatures).join() {
+            screen.put(pos.x, pos.y, render.glyph, render.color);
+        }
+
+        let log = ecs.fetch::<MessageLog>();
+        let mut x = 0;
+        for msg in log.unseen() {
+            screen.put_str(x, LEVEL_SIZE.1 as _, &msg.text, msg.severity.color());
+            x += msg.text.chars().count() as i32 + 1;
+        }
+        drop(log);
+
+        // Say what's under the ghost, since it can't be told apart
+        // from what's around it just by looking.
+        let monsters = ecs.read_storage::<Monster>();
+        let items = ecs.read_storage::<Item>();
+        let here: Vec<&str> = ecs
+            .fetch::<PositionIndex>()
+            .entities_at(ghost.0, ghost.1)
+            .iter()
+            .filter_map(|ent| match (monsters.get(*ent), items.get(*ent)) {
+                (Some(monster), _) => Some(monster.kind.name()),
+                (None, Some(item)) => Some(item.name),
+                (None, None) => None,
+            })
+            .collect();
+        if !here.is_empty() {
+            screen.put_str(
+                0,
+                LEVEL_SIZE.1 as i32 + 1,
+                &format!("Here: {}", here.join(", ")),
+                Color::White,
+            );
+        }
+        screen.set_cursor(ghost.0, ghost.1);
+        screen.flush();
+
+        let key = match screen.read_key() {
+            Ok(key) => key,
+            Err(InputClosed) => quit(),
+        };
+        ecs.fetch_mut::<MessageLog>().mark_seen();
+        match (ecs.fetch::<Keymap>().direction(&key), key) {
+            (Some(delta), _) => ghost = clamp_to_bounds(offset(ghost, delta)),
+            (None, Input::Character('\u{1b}' | '\n')) => break,
+            _ => {}
+        }
+    }
+}
+
+/// Shows the player the screen one last time after they've died, then
+/// records their score and shows them the high-score table, and exits
+/// the game once they press a key.
+pub fn game_over(ecs: &World, screen: &mut Screen) -> ! {
+    ecs.fetch_mut::<MessageLog>()
+        .log("Press any key to see the high scores.");
+    render_screen(ecs, screen);
+    if screen.read_key().is_err() {
+        quit();
+    }
+
+    let stats = ecs.fetch::<RunStats>();
+    let gold = ecs
+        .read_storage::<Player>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .map_or(0, |player| player.gold);
+    let entry = ScoreEntry {
+        name: stats.name.clone(),
+        score: score(gold, stats.deepest, ecs.fetch::<GameClock>().turns),
+        depth: stats.deepest,
+        cause: stats.cause.clone().unwrap_or_else(|| "died".to_string()),
+    };
+
+    // If the score can't be saved, show it alongside the ones that
+    // were there already, so the player still sees how they did.
+    let high_scores = ecs.fetch::<HighScores>();
+    let (entries, rank, error) = match high_scores.record(entry.clone()) {
+        Ok((entries, rank)) => (entries, rank, None),
+        Err(err) => {
+            let mut entries = high_scores.load();
+            entries.push(entry);
+            (entries, None, Some(err))
+        }
+    };
+
+    screen.clear();
+    screen.put_str(0, 0, "High scores", Color::White);
+    for (idx, entry) in entries.iter().enumerate() {
+        let line = format!(
+            "{:>2}. {:>6}  {:<16} depth {:>2}, {}",
+            idx + 1,
+            entry.score,
+            entry.name,
+            entry.depth,
+            entry.cause
+        );
+        let yours = rank == Some(idx) || (error.is_some() && idx + 1 == entries.len());
+        let color = if yours { Color::Yellow } else { Color::White };
+        screen.put_str(0, idx as i32 + 2, &line, color);
+    }
+
+    let mut y = entries.len() as i32 + 3;
+    if let Some(err) = error {
+        screen.put_str(
+            0,
+            y,
+            &format!("Couldn't save your score: {}", err),
+            Color::Red,
+        );
+        y += 1;
+    } else if rank.is_none() {
+        screen.put_str(0, y, "Your score didn't make the table.", Color::White);
+        y += 1;
+    }
+    screen.put_str(0, y, "Press any key to exit.", Color::White);
+    screen.set_cursor(0, y);
+    screen.flush();
+    let _ = screen.read_key();
+
+    quit()
+}
+
+/// Uses up an item, first asking the player anything else the item
+/// needs to know, e.g., what a scroll of genocide should wipe out.
+fn use_item(
+    ecs: &World,
+    screen: &mut Screen,
+    item: Entity,
+) -> Result<Option<MobAction>, InputClosed> {
+    let effect = ecs.read_storage::<Item>().get(item).map(|item| item.effect);
+    if let Some(ItemEffect::Genocide) = effect {
+        let kind = match choose_monster_kind(ecs, screen, "What do you want to wipe out?")? {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        ecs.fetch_mut::<PendingGenocide>().0 = Some(Genocide {
+            kind,
+            whole_branch: true,
+        });
+    }
+
+    Ok(Some(MobAction::Use(item)))
+}
+
+/// Asks the player to pick a kind of monster by its glyph.
+fn choose_monster_kind(
+    ecs: &World,
+    screen: &mut Screen,
+    question: &str,
+) -> Result<Option<MonsterKind>, InputClosed> {
+    let choices: Vec<String> = MonsterKind::ALL
+        .iter()
+        .map(|kind| format!("{} {}", kind.render().glyph, kind.name()))
+        .collect();
+    ecs.fetch_mut::<MessageLog>()
+        .log(format!("{} ({})", question, choices.join(", ")));
+    render_screen(ecs, screen);
+
+    let key = screen.read_key()?;
+    ecs.fetch_mut::<MessageLog>().mark_seen();
+
+    let kind = match key {
+        Input::Character(ch) => MonsterKind::ALL
+            .iter()
+            .find(|kind| kind.render().glyph == ch)
+            .copied(),
+        _ => None,
+    };
+    if kind.is_none() {
+        ecs.fetch_mut::<MessageLog>().log("Never mind.");
+    }
+    Ok(kind)
+}
+
+/// Asks the player a yes-or-no question, and returns whether they
+/// said yes. Any key other than 'y' counts as no.
+fn confirm(ecs: &World, screen: &mut Screen, question: &str) -> Result<bool, InputClosed> {
+    ecs.fetch_mut::<MessageLog>()
+        .log(format!("{} (y/n)", question));
+    render_screen(ecs, screen);
+
+    let key = screen.read_key()?;
+    ecs.fetch_mut::<MessageLog>().mark_seen();
+
+    Ok(key == Input::Character('y'))
+}
+
+/// The tile that moving the player by `(dx, dy)` takes them onto, or
+/// `None` if that's off the level.
+fn leads_onto(ecs: &World, (dx, dy): (i32, i32)) -> Option<DungeonTile> {
+    let level = ecs.fetch::<DungeonLevel>();
+    let (x, y) = offset(player_pos(ecs), (dx, dy));
+    in_bounds((x, y)).then(|| *level.tile(x, y))
+}
+
+/// Takes the staircase the player is standing on, if it goes the
+/// right way.
+fn take_stairs(ecs: &World, direction: StairDirection) -> Option<MobAction> {
+    let level = ecs.fetch::<DungeonLevel>();
+    let (x, y) = player_pos(ecs);
+    let (stairs, message): (&[DungeonTile], _) = match direction {
+        StairDirection::Up => (&[DungeonTile::Upstair], "You can't go up here."),
+        StairDirection::Down => (
+            &[DungeonTile::Downstair, DungeonTile::BranchStair],
+            "You can't go down here.",
+        ),
+    };
+
+    if stairs.contains(level.tile(x, y)) {
+        Some(MobAction::TakeStairs(direction))
+    } else {
+        ecs.fetch_mut::<MessageLog>().log(message);
+        None
+    }
+}
+
+/// Drinks from the fountain the player is standing on, if there's
+/// one with any water left and they want to, or else asks them for a
+/// potion to drink.
+fn quaff(ecs: &World, screen: &mut Screen) -> Result<Option<MobAction>, InputClosed> {
+    let (x, y) = player_pos(ecs);
+    let fountain = *ecs.fetch::<DungeonLevel>().tile(x, y);
+    match fountain {
+        DungeonTile::Fountain { dry: false }
+            if confirm(ecs, screen, "Drink from the fountain?")? =>
+        {
+            return Ok(Some(MobAction::Quaff));
+        }
+        DungeonTile::Fountain { dry: true } => {
+            ecs.fetch_mut::<MessageLog>()
+                .log("The fountain has dried up.");
+        }
+        _ => {}
+    }
+
+    Ok(choose_category(ecs, screen, ItemCategory::Potion)?.map(MobAction::Use))
+}
+
+/// Picks a door for the player to open. If there's exactly one closed
+/// door next to the player then that one is chosen; otherwise the
+/// player is asked for a direction. Returns `None` if there's no
+/// door to open.
+fn choose_door(ecs: &World, screen: &mut Screen) -> Result<Option<MobAction>, InputClosed> {
+    let here = player_pos(ecs);
+    let is_door = |(dx, dy): (i32, i32)| {
+        let level = ecs.fetch::<DungeonLevel>();
+        let (x, y) = offset(here, (dx, dy));
+        in_bounds((x, y)) && level.tile(x, y) == &(DungeonTile::Door { open: false })
+    };
+
+    let doors: Vec<(i32, i32)> = (-1..=1)
+        .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+        .filter(|&delta| delta != (0, 0) && is_door(delta))
+        .collect();
+
+    let door = match doors.as_slice() {
+        [] => None,
+        &[door] => Some(door),
+        _ => {
+            ecs.fetch_mut::<MessageLog>().log("In which direction?");
+            render_screen(ecs, screen);
+
+            let key = screen.read_key()?;
+            ecs.fetch_mut::<MessageLog>().mark_seen();
+
+            ecs.fetch::<Keymap>()
+                .direction(&key)
+                .filter(|&delta| is_door(delta))
+        }
+    };
+
+    match door {
+        Some((dx, dy)) => Ok(Some(MobAction::Open(dx, dy))),
+        None => {
+            ecs.fetch_mut::<MessageLog>().log("There is no door there.");
+            Ok(None)
+        }
+    }
+}
+
+/// Lets the player move a cursor around the level to pick a cell,
+/// starting from their own position. Returns `None` if they cancel
+/// with Escape. If `show_line_of_fire` is set, the path that a shot
+/// at the cell would take is drawn too, with an 'X' where it would
+/// stop if something's in the way.
+fn choose_target(
+    ecs: &World,
+    screen: &mut Screen,
+    prompt: &str,
+    show_line_of_fire: bool,
+) -> Result<Option<(i32, i32)>, InputClosed> {
+    let origin = player_pos(ecs);
+    let mut target = origin;
+
+    ecs.fetch_mut::<MessageLog>().log(format!(
+        "{} (move the cursor, then press '.' to choose)",
+        prompt
+    ));
+
+    let choice = loop {
+        render_screen(ecs, screen);
+        if show_line_of_fire {
+            let (path, reached) = ecs.fetch::<DungeonLevel>().line_of_fire(origin, target);
+            for &(x, y) in &path {
+                screen.put(x, y, '*', Color::Yellow);
+            }
+            if !reached {
+                let (x, y) = path.last().copied().unwrap_or(origin);
+                screen.put(x, y, 'X', Color::Red);
+            }
+        }
+        // Say what any marker under the cursor was called, since
+        // there's no room to show it on the map.
+        let level = ecs.fetch::<CurrentLevel>().0;
+        if let Some(label) = ecs.fetch::<Markers>().get(level, target) {
+            screen.put_str(
+                0,
+                LEVEL_SIZE.1 as i32 + 2,
+                &format!("Marked: {}", label),
+                MARKER_COLOR,
+            );
+        }
+        screen.set_cursor(target.0, target.1);
+        screen.flush();
+
+        let key = screen.read_key()?;
+        match (ecs.fetch::<Keymap>().direction(&key), key) {
+            (Some(delta), _) => target = clamp_to_bounds(offset(target, delta)),
+            (None, Input::Character('.' | ',' | '\n')) => break Some(target),
+            (None, Input::Character('\u{1b}')) => break None,
+            _ => {}
+        }
+    };
+    ecs.fetch_mut::<MessageLog>().mark_seen();
+
+    if choice.is_none() {
+        ecs.fetch_mut::<MessageLog>().log("Never mind.");
+    }
+
+    Ok(choice)
+}
+
+/// Asks the player to type a line of text, at most `max_len`
+/// characters long. Returns `None` if they cancel with Escape or
+/// don't type anything.
+fn prompt_text(
+    ecs: &World,
+    screen: &mut Screen,
+    prompt: &str,
+    max_len: usize,
+) -> Result<Option<String>, InputClosed> {
+    ecs.fetch_mut::<MessageLog>().mark_seen();
+
+    let mut text = String::new();
+    let answer = loop {
+        render_screen(ecs, screen);
+        let line = format!("{} {}", prompt, text);
+        screen.put_str(0, LEVEL_SIZE.1 as _, &line, Color::White);
+        screen.set_cursor(line.chars().count() as _, LEVEL_SIZE.1 as _);
+        screen.flush();
+
+        match screen.read_key()? {
+            Input::Character('\n') => break Some(text).filter(|text| !text.is_empty()),
+            Input::Character('\u{1b}') => break None,
+            Input::KeyBackspace | Input::Character('\u{7f}' | '\u{8}') => {
+                text.pop();
+            }
+            Input::Character(ch) if !ch.is_control() && text.chars().count() < max_len => {
+                text.push(ch)
+            }
+            _ => {}
+        }
+    };
+
+    if answer.is_none() {
+        ecs.fetch_mut::<MessageLog>().log("Never mind.");
+    }
+
+    Ok(answer)
+}
+
+/// Asks the player which cell they want to leave a marker on, and what
+/// it should say. Only cells they've discovered can be marked.
+fn place_marker(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let cell = match choose_target(ecs, screen, "Where do you want to leave a marker?", false)? {
+        Some(cell) => cell,
+        None => return Ok(()),
+    };
+
+    let known = ecs
+        .read_storage::<Player>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .expect("Player must be a player")
+        .known_cells
+        .get(cell.0, cell.1);
+    if !known {
+        ecs.fetch_mut::<MessageLog>()
+            .log("You don't know what's there.");
+        return Ok(());
+    }
+
+    let prompt = format!("What should it say? ({} characters at most)", MAX_LABEL_LEN);
+    if let Some(label) = prompt_text(ecs, screen, &prompt, MAX_LABEL_LEN)? {
+        let level = ecs.fetch::<CurrentLevel>().0;
+        ecs.fetch_mut::<Markers>().set(level, cell, &label);
+        ecs.fetch_mut::<MessageLog>()
+            .log(format!("You leave a marker: {}.", label));
+    }
+    Ok(())
+}
+
+/// Asks the player which cell they want to look at, and tells them
+/// what's there: whatever they can see standing in it, or else what
+/// the cell itself is, if they know.
+fn look_at(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let (x, y) = match choose_target(ecs, screen, "What do you want to look at?", false)? {
+        Some(cell) => cell,
+        None => return Ok(()),
+    };
+
+    let message = describe_cell(ecs, (x, y));
+    ecs.fetch_mut::<MessageLog>().log(message);
+    Ok(())
+}
+
+/// What the player knows about the given cell, as a sentence.
+fn describe_cell(ecs: &World, (x, y): (i32, i32)) -> String {
+    let player_ent = the_player(ecs).expect("There must be one player");
+    let known = ecs
+        .read_storage::<Player>()
+        .get(player_ent)
+        .expect("Player must be a player")
+        .known_cells
+        .get(x, y);
+    if !known {
+        return "You don't know what's there.".to_string();
+    }
+
+    let level = ecs.fetch::<DungeonLevel>();
+    if ecs
+        .fetch::<PlayerView>()
+        .sees(&level, player_pos(ecs), (x, y))
+    {
+        let monsters = ecs.read_storage::<Monster>();
+        let items = ecs.read_storage::<Item>();
+        let index = ecs.fetch::<PositionIndex>();
+        let here = index.entities_at(x, y);
+        if here.contains(&player_ent) {
+            return "That's you.".to_string();
+        }
+        if let Some(monster) = here.iter().find_map(|ent| monsters.get(*ent)) {
+            return format!("You see a {} there.", monster.kind.name());
+        }
+        if let Some(item) = here.iter().find_map(|ent| items.get(*ent)) {
+            return format!("You see a {} there.", item.name);
+        }
+    }
+
+    format!("You see {} there.", level.describe_tile(x, y))
+}
+
+/// Asks the player which marker they want to get rid of, and removes
+/// it.
+fn remove_marker(ecs: &World, screen: &mut Screen) -> Result<(), InputClosed> {
+    let cell = match choose_target(ecs, screen, "Which marker do you want to remove?", false)? {
+        Some(cell) => cell,
+        None => return Ok(()),
+    };
+
+    let level = ecs.fetch::<CurrentLevel>().0;
+    let removed = ecs.fetch_mut::<Markers>().remove(level, cell);
+    let message = match removed {
+        Some(label) => format!("You remove the marker: {}.", label),
+        None => "There's no marker there.".to_string(),
+    };
+    ecs.fetch_mut::<MessageLog>().log(message);
+    Ok(())
+}
+
+/// Asks the player where to blink to, and checks that they can get
+/// there: the cell has to be open ground in view, close by, and not
+/// already taken by another creature.
+fn choose_blink(ecs: &World, screen: &mut Screen) -> Result<Option<MobAction>, InputClosed> {
+    let target = match choose_target(ecs, screen, "Where do you want to blink to?", false)? {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+
+    let creatures = ecs.read_storage::<Health>();
+    let level = ecs.fetch::<DungeonLevel>();
+    let index = ecs.fetch::<PositionIndex>();
+    let here = player_pos(ecs);
+
+    let problem = if target == here {
+        Some("You're already there.")
+    } else if chebyshev(here, target) > BLINK_RANGE {
+        Some("That's too far away.")
+    } else if !level.tile(target.0, target.1).is_navigable()
+        || !ecs.fetch::<PlayerView>().sees(&level, here, target)
+    {
+        Some("You can't blink there.")
+    } else if index
+        .entities_at(target.0, target.1)
+        .iter()
+        .any(|ent| creatures.contains(*ent))
+    {
+        Some("There's something in the way.")
+    } else {
+        None
+    };
+
+    match problem {
+        Some(problem) => {
+            ecs.fetch_mut::<MessageLog>().log(problem);
+            Ok(None)
+        }
+        None => Ok(Some(MobAction::Blink(target.0, target.1))),
+    }
+}
+
+/// Asks the player for an item to throw and where to throw it, then
+/// shows it flying there.
+fn choose_throw(ecs: &World, screen: &mut Screen) -> Result<Option<MobAction>, InputClosed> {
+    let item = match choose_item(ecs, screen, "throw")? {
+        Some(item) => item,
+        None => return Ok(None),
+    };
+    let target = match choose_target(ecs, screen, "Where do you want to throw it?", true)? {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+
+    let path = ecs
+        .fetch::<DungeonLevel>()
+        .line_of_fire(player_pos(ecs), target)
+        .0;
+    let render = *ecs
+        .read_storage::<CharRender>()
+        .get(item)
+        .expect("Carried item must be renderable");
+    render_screen(ecs, screen);
+    screen.animate_path(&path, render.glyph, render.color);
+
+    Ok(Some(MobAction::Throw(item, target.0, target.1)))
+}
+
+/// Checks that there's something for the player to pick up, and if
+/// so then picks it up.
+fn pick_up(ecs: &World) -> Option<MobAction> {
+    let items = ecs.read_storage::<Item>();
+    let index = ecs.fetch::<PositionIndex>();
+
+    let (x, y) = player_pos(ecs);
+    let anything_here = index
+        .entities_at(x, y)
+        .iter()
+        .any(|ent| items.contains(*ent));
+
+    if anything_here {
+        Some(MobAction::PickUp)
+    } else {
+        ecs.fetch_mut::<MessageLog>().log("There is nothing here.");
+        None
+    }
+}
+
+/// The items the player is carrying, with their letters, in the order
+/// they were picked up.
+fn carried_items(ecs: &World) -> Vec<(char, Entity)> {
+    ecs.read_storage::<Inventory>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .map(|inventory| inventory.items().to_vec())
+        .unwrap_or_default()
+}
+
+/// Asks the player to pick an item from their inventory, for the
+/// purpose of doing `verb` with it.
+fn choose_item(
+    ecs: &World,
+    screen: &mut Screen,
+    verb: &str,
+) -> Result<Option<Entity>, InputClosed> {
+    choose_from(ecs, screen, verb, carried_items(ecs), |_| true)
+}
+
+/// Asks the player to pick an item of the given category from their
+/// inventory, to do whatever's done with that sort of item. Only items
+/// of the category are offered, but every item keeps its usual letter;
+/// picking something of another category is refused, e.g., "You can't
+/// read a potion."
+fn choose_category(
+    ecs: &World,
+    screen: &mut Screen,
+    category: ItemCategory,
+) -> Result<Option<Entity>, InputClosed> {
+    let is_category = |item: &Item| item.category == category;
+    let item = choose_from(
+        ecs,
+        screen,
+        category.verb(),
+        carried_items(ecs),
+        is_category,
+    )?;
+    Ok(item.filter(|&item| suits_category(ecs, item, category)))
+}
+
+/// Whether an item is of the given category, telling the player they
+/// can't use it that way if it isn't.
+fn suits_category(ecs: &World, item: Entity, category: ItemCategory) -> bool {
+    let items = ecs.read_storage::<Item>();
+    let info = items.get(item).expect("Carried entity must be an item");
+    if info.category != category {
+        ecs.fetch_mut::<MessageLog>().log(format!(
+            "You can't {} {}.",
+            category.verb(),
+            info.category.noun()
+        ));
+    }
+    info.category == category
+}
+
+/// Asks the player to pick one of the items they have equipped, to
+/// take it off or put it away. Equipped items aren't in the inventory,
+/// so they're lettered from 'a' just for this.
+fn choose_equipped(ecs: &World, screen: &mut Screen) -> Result<Option<Entity>, InputClosed> {
+    let equipped: Vec<(char, Entity)> = ecs
+        .read_storage::<Equipment>()
+        .get(the_player(ecs).expect("There must be one player"))
+        .map(|equipment| {
+            ('a'..='z')
+                .zip([equipment.weapon, equipment.armor].into_iter().flatten())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    choose_from(ecs, screen, "take off", equipped, |_| true)
+}
+
+/// Asks the player to pick one of the given lettered items, for the
+/// purpose of doing `verb` with it, on a screen listing the items that
+/// `shown` picks out. The player can still pick an item that isn't
+/// listed by its letter.
+fn choose_from(
+    ecs: &World,
+    screen: &mut Screen,
+    verb: &str,
+    carried: Vec<(char, Entity)>,
+    shown: impl Fn(&Item) -> bool,
+) -> Result<Option<Entity>, InputClosed> {
+    let choices: Vec<String> = {
+        let items = ecs.read_storage::<Item>();
+        carried
+            .iter()
+            .filter_map(|(letter, ent)| {
+                let item = items.get(*ent).expect("Carried entity must be an item");
+                shown(item).then(|| format!("{} - {}", letter, item.name))
+            })
+            .collect()
+    };
+    if choices.is_empty() {
+        ecs.fetch_mut::<MessageLog>()
+            .log(format!("You have nothing to {}.", verb));
+        return Ok(None);
+    }
+
+    screen.clear();
+    screen.put_str(
+        0,
+        0,
+        &format!("What do you want to {}?", verb),
+        Color::White,
+    );
+    for (y, choice) in choices.iter().enumerate() {
+        screen.put_str(2, y as i32 + 2, choice, Color::White);
+    }
+    let prompt = "Press a letter to choose, or any other key to cancel.";
+    let prompt_y = choices.len() as i32 + 3;
+    screen.put_str(0, prompt_y, prompt, Color::White);
+    screen.set_cursor(0, prompt_y);
+    screen.flush();
+
+    let key = screen.read_key()?;
+
+    let choice = match key {
+        Input::Character(ch) => carried
+            .iter()
+            .find(|&&(letter, _item)| letter == ch)
+            .map(|&(_letter, item)| item),
+        _ => None,
+    };
+
+    if choice.is_none() {
+        ecs.fetch_mut::<MessageLog>().log("Never mind.");
+    }
+
+    Ok(choice)
 }
 
 /// Checks whether an action is possible for the player to execute in
 /// the given world.
 fn possible(ecs: &World, action: &MobAction) -> bool {
     match action {
-        MobAction::Nop => true,
+        MobAction::Nop
+        | MobAction::Search
+        | MobAction::Open(_, _)
+        | MobAction::PickUp
+        | MobAction::Use(_)
+        | MobAction::Wield(_)
+        | MobAction::Wear(_)
+        | MobAction::Unequip(_)
+        | MobAction::Drop(_)
+        | MobAction::Throw(_, _, _)
+        | MobAction::Blink(_, _)
+        | MobAction::Quaff
+        | MobAction::TakeStairs(_) => true,
         MobAction::Move(dx, dy) => {
-            let players = ecs.read_storage::<Player>();
-            let positions = ecs.read_storage::<Position>();
             let map = ecs.fetch::<DungeonLevel>();
 
-            (&players, &positions)
-                .join()
-                .all(|(_plr, pos)| map.tile(pos.x + dx, pos.y + dy).is_navigable())
+            // Chasms can't be walked across, but they can be jumped
+            // into, and lava can be waded into by the foolhardy.
+            let (x, y) = offset(player_pos(ecs), (*dx, *dy));
+            in_bounds((x, y))
+                && (map.tile(x, y).is_navigable()
+                    || matches!(map.tile(x, y), DungeonTile::Chasm | DungeonTile::Lava))
         }
     }
 }
 
 /// Renders the state of the world onto the screen.
-fn render_screen(ecs: &mut World, screen: &mut Window) {
-    // Calculate the player's position.
+pub fn render_screen(ecs: &World, screen: &mut Screen) {
+    screen.clear();
+
+    // The screen is drawn from the player's point of view.
+    let player_ent = the_player(ecs).expect("There must be one player");
     let plrs = ecs.read_storage::<Player>();
-    let pos = ecs.read_storage::<Position>();
-    let (_plr, player_pos) = (&plrs, &pos)
-        .join()
-        .next()
-        .expect("Player must have a position");
+    let player = plrs.get(player_ent).expect("Player must be a player");
+    let (player_x, player_y) = player_pos(ecs);
 
     // Draw the base level.
     let level = ecs.fetch::<DungeonLevel>();
-    let known_cells = &plrs.join().next().expect("Player must exist").known_cells;
-    level.draw(screen, |cell| {
-        match level.can_see(player_pos.into(), cell) {
+    let known_cells = &player.known_cells;
+    let view = level.visible_from((player_x, player_y));
+    let in_view = |cell: (i32, i32)| view.binary_search(&cell).is_ok();
+
+    // When several lights reach a cell, the nearest one wins.
+    let mut tints: HashMap<(i32, i32), (i32, Color)> = HashMap::new();
+    for (pos, radius, color) in light_sources(ecs) {
+        for cell in level.visible_from(pos) {
+            let (dx, dy) = (cell.0.saturating_sub(pos.0), cell.1.saturating_sub(pos.1));
+            let dist2 = dx.saturating_mul(dx).saturating_add(dy.saturating_mul(dy));
+            if dist2 < radius.saturating_mul(radius)
+                && tints.get(&cell).is_none_or(|&(best, _color)| dist2 < best)
+            {
+                tints.insert(cell, (dist2, color));
+            }
+        }
+    }
+
+    level.draw(
+        screen,
+        |cell| match in_view(cell) {
             true => DrawStyle::Visible,
             false => {
-                if known_cells[cell.1 as usize][cell.0 as usize] {
+                if known_cells.get(cell.0, cell.1) {
                     DrawStyle::Discovered
                 } else {
                     DrawStyle::Undiscovered
                 }
             }
+        },
+        |cell| match tints.get(&cell) {
+            Some(&(_dist2, color)) => Lighting::Tinted(color),
+            None => Lighting::Lit,
+        },
+    );
+
+    // Draw the objects the player remembers seeing out of view,
+    // then all the renderable entities in view, with creatures on
+    // top of whatever they're standing on.
+    for (&(x, y), render) in player.remembered.iter() {
+        if !in_view((x, y)) {
+            screen.put_dim(x, y, render.glyph, render.color);
         }
-    });
+    }
 
-    // Draw all renderable entities.
     let renderables = ecs.read_storage::<CharRender>();
     let positions = ecs.read_storage::<Position>();
-    for (render, pos) in (&renderables, &positions).join() {
-        screen.mvaddch(pos.y as _, pos.x as _, render.glyph);
+    let creatures = ecs.read_storage::<Health>();
+    for (render, pos, ()) in (&renderables, &positions, !&creatures).join() {
+        if in_view(pos.into()) {
+            screen.put(pos.x, pos.y, render.glyph, render.color);
+        }
+    }
+
+    // Markers go on top of objects, so that the player can find them
+    // again, but underneath any creature standing there.
+    let markers = ecs.fetch::<Markers>();
+    for (x, y) in markers.on_level(ecs.fetch::<CurrentLevel>().0) {
+        if in_view((x, y)) {
+            screen.put(x, y, MARKER_GLYPH, MARKER_COLOR);
+        } else if known_cells.get(x, y) {
+            screen.put_dim(x, y, MARKER_GLYPH, MARKER_COLOR);
+        }
+    }
+    let detected = ecs.read_storage::<Detected>();
+    for (render, pos, _creature, found) in
+        (&renderables, &positions, &creatures, detected.maybe()).join()
+    {
+        if in_view(pos.into()) || found.is_some_and(|found| found.at == pos.into()) {
+            screen.put(pos.x, pos.y, render.glyph, render.color);
+        }
     }
 
+    // Creatures sensed only by their heat are drawn dimly, since the
+    // player can't actually see them.
+    for ((x, y), render) in sensed_by_infravision(ecs) {
+        if !in_view((x, y)) {
+            screen.put_dim(x, y, render.glyph, render.color);
+        }
+    }
+
+    // Draw the new messages, then the status line, below the level.
+    let log = ecs.fetch::<MessageLog>();
+    let mut x = 0;
+    for msg in log.unseen() {
+        screen.put_str(x, LEVEL_SIZE.1 as _, &msg.text, msg.severity.color());
+        x += msg.text.chars().count() as i32 + 1;
+    }
+
+    let equipment = ecs.read_storage::<Equipment>();
+    let items = ecs.read_storage::<Item>();
+    let hp = creatures.get(player_ent).expect("Player must have health");
+    let equipment = equipment
+        .get(player_ent)
+        .expect("Player must have equipment");
+    let mut status = format!(
+        "HP: {}/{}  Gold: {}  Turn: {}",
+        hp.current,
+        hp.maximum,
+        player.gold,
+        ecs.fetch::<GameClock>().turns
+    );
+    if let Some(weapon) = equipment.weapon.and_then(|weapon| items.get(weapon)) {
+        status += &format!("  Wielding: {}", weapon.name);
+    }
+    if ecs.read_storage::<Injured>().contains(player_ent) {
+        status += "  Slowed";
+    }
+    screen.put_str(0, LEVEL_SIZE.1 as i32 + 1, &status, Color::White);
+
     // Leave the cursor on the player's position.
-    screen.mv(player_pos.y, player_pos.x);
+    screen.set_cursor(player_x, player_y);
+
+    screen.flush();
+}
 
-    screen.refresh();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        items::ItemKind,
+        level::DungeonTile,
+        monsters::MonsterKind,
+        schedule::Schedule,
+        systems::{DiscoverySystem, IndexSystem, SearchSystem, ViewSystem},
+        testing::{add_player, with_screen, world_with},
+    };
+
+    #[test]
+    fn there_must_be_exactly_one_player() {
+        let mut world = world_with("-----\n|...|\n-----");
+        assert_eq!(the_player(&world), Err(PlayerError::NoPlayer));
+
+        let player = add_player(&mut world, (1, 1));
+        assert_eq!(the_player(&world), Ok(player));
+
+        add_player(&mut world, (3, 1));
+        assert_eq!(the_player(&world), Err(PlayerError::SeveralPlayers));
+    }
+
+    #[test]
+    fn infravision_senses_warm_creatures_through_walls() {
+        let mut world = world_with(
+            "\
+            -------\n\
+            |.|...|\n\
+            |.|...|\n\
+            -------",
+        );
+        let player = add_player(&mut world, (1, 1));
+        world
+            .write_storage::<Infravision>()
+            .insert(player, Infravision { radius: 4 })
+            .unwrap();
+        let warm = MonsterKind::Goblin.spawn(&mut world, (4, 1));
+        let cold = MonsterKind::Zombie.spawn(&mut world, (4, 2));
+        world.write_storage::<Warm>().insert(warm, Warm).unwrap();
+        world.write_storage::<Warm>().remove(cold);
+
+        let level = world.fetch::<DungeonLevel>();
+        assert!(!level.can_see((1, 1), (4, 1)));
+        assert!(!level.can_see((1, 1), (4, 2)));
+        drop(level);
+
+        let sensed: Vec<(i32, i32)> = sensed_by_infravision(&world)
+            .into_iter()
+            .map(|(pos, _render)| pos)
+            .collect();
+        assert_eq!(sensed, vec![(4, 1)]);
+    }
+
+    #[test]
+    fn reading_a_potion_is_refused() {
+        let mut world = world_with("----\n|..|\n----");
+        let player = add_player(&mut world, (1, 1));
+        let potion = ItemKind::POTIONS[0].create(&mut world);
+        let scroll = ItemKind::TeleportScroll.create(&mut world);
+        {
+            let mut inventories = world.write_storage::<Inventory>();
+            let inventory = inventories.get_mut(player).unwrap();
+            inventory.add(potion);
+            inventory.add(scroll);
+        }
+
+        // The potion isn't offered, but its letter still picks it.
+        let action = with_screen("a", |screen| run_command(&world, screen, Command::Read));
+        assert_eq!(action, Ok(None));
+        assert_eq!(
+            world.fetch::<MessageLog>().unseen().last().unwrap().text,
+            "You can't read a potion."
+        );
+        assert!(carried_items(&world).contains(&('a', potion)));
+    }
+
+    #[test]
+    fn drinking_a_potion_is_allowed() {
+        let mut world = world_with("----\n|..|\n----");
+        add_player(&mut world, (1, 1));
+        let potion = ItemKind::POTIONS[0].create(&mut world);
+
+        assert!(suits_category(&world, potion, ItemCategory::Potion));
+        assert!(world.fetch::<MessageLog>().unseen().is_empty());
+    }
+
+    #[test]
+    fn targeting_gives_up_when_input_runs_out() {
+        let mut world = world_with("------\n|....|\n------");
+        add_player(&mut world, (1, 1));
+
+        let target = with_screen("ll.", |screen| {
+            choose_target(&world, screen, "Where?", true)
+        });
+        assert_eq!(target, Ok(Some((3, 1))));
+
+        for keys in ["", "ll"] {
+            let target = with_screen(keys, |screen| choose_target(&world, screen, "Where?", true));
+            assert_eq!(target, Err(InputClosed));
+        }
+    }
+
+    #[test]
+    fn looking_says_what_is_there() {
+        let mut world = world_with("------\n|...>|\n------");
+        add_player(&mut world, (1, 1));
+        MonsterKind::Goblin.spawn(&mut world, (2, 1));
+        IndexSystem.run_now(&world);
+        ViewSystem.run_now(&world);
+        DiscoverySystem.run_now(&world);
+
+        let look = |keys| {
+            let action = with_screen(keys, |screen| run_command(&world, screen, Command::Look));
+            assert_eq!(action, Ok(None));
+            world
+                .fetch::<MessageLog>()
+                .unseen()
+                .last()
+                .unwrap()
+                .text
+                .clone()
+        };
+        assert_eq!(look("l."), "You see a goblin there.");
+        assert_eq!(look("lll."), "You see a staircase down there.");
+        assert_eq!(look("."), "That's you.");
+    }
+
+    #[test]
+    fn walls_stop_the_player() {
+        let mut world = world_with("-----\n|.:.|\n-----");
+        add_player(&mut world, (1, 1));
+
+        assert!(!possible(&world, &MobAction::Move(-1, 0)));
+        assert!(!possible(&world, &MobAction::Move(0, 1)));
+        assert!(!possible(&world, &MobAction::Move(1, 1)));
+        // Chasms aren't walls; they can be jumped into.
+        assert!(possible(&world, &MobAction::Move(1, 0)));
+        assert_eq!(leads_onto(&world, (1, 0)), Some(DungeonTile::Chasm));
+    }
+
+    /// How the player would see the given cell on the screen right now,
+    /// and whether it'd be dimmed.
+    fn drawn_at(world: &World, cell: (i32, i32)) -> Option<(char, bool)> {
+        with_screen("", |screen| {
+            render_screen(world, screen);
+            screen.glyph_at(cell.0, cell.1)
+        })
+    }
+
+    #[test]
+    fn found_doors_stay_on_the_map() {
+        let mut world = world_with(
+            "\
+            -----\n\
+            |...|\n\
+            |...|\n\
+            |.---\n\
+            |.|\n\
+            |.|\n\
+            ---",
+        );
+        let door = (4, 1);
+        world
+            .fetch_mut::<DungeonLevel>()
+            .set_tile(door.0, door.1, DungeonTile::SecretDoor);
+        let player = add_player(&mut world, (3, 1));
+        DiscoverySystem.run_now(&world);
+        assert_eq!(drawn_at(&world, door), Some(('|', false)));
+
+        world
+            .write_storage::<Mobile>()
+            .get_mut(player)
+            .unwrap()
+            .next_action = MobAction::Search;
+        for _ in 0..100 {
+            if world.fetch::<DungeonLevel>().tile(door.0, door.1) != &DungeonTile::SecretDoor {
+                break;
+            }
+            {
+                let mut schedule = world.fetch_mut::<Schedule>();
+                let now = schedule.now();
+                schedule.reschedule(player, now + 1);
+                schedule.advance();
+            }
+            SearchSystem.run_now(&world);
+        }
+        DiscoverySystem.run_now(&world);
+        assert_eq!(drawn_at(&world, door), Some(('+', false)));
+
+        // Walk down the hallway, out of sight of the door.
+        let away = (1, 5);
+        world
+            .write_storage::<Position>()
+            .insert(
+                player,
+                Position {
+                    x: away.0,
+                    y: away.1,
+                },
+            )
+            .unwrap();
+        assert!(!world.fetch::<DungeonLevel>().can_see(away, door));
+        DiscoverySystem.run_now(&world);
+        assert_eq!(drawn_at(&world, door), Some(('+', true)));
+    }
+
+    #[test]
+    fn lava_can_be_stepped_into() {
+        let mut world = world_with("-----\n|.}.|\n-----");
+        add_player(&mut world, (1, 1));
+
+        assert!(possible(&world, &MobAction::Move(1, 0)));
+        assert_eq!(leads_onto(&world, (1, 0)), Some(DungeonTile::Lava));
+        assert_eq!(leads_onto(&world, (-1, 0)), Some(DungeonTile::Wall));
+    }
 }
@@ -0,0 +1,412 @@
+//! Recording the player's turns to a run file, and playing them back,
+//! so that a run with a bug in it can be reproduced exactly.
+//!
+//! Everything that affects the game draws its random numbers from the
+//! run's seed, so the seed, the options the game was started with and
+//! the player's turns are all it takes to get back to the same state.
+//! A run file is a line of `HEADER`, then a `seed` line, then an `arg`
+//! line for each option, then a `turn` line for each of the player's
+//! turns, as written by `Recorder`.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::SplitWhitespace,
+};
+
+use specs::prelude::*;
+use thiserror::Error;
+
+use crate::{
+    branch::{Genocide, PendingGenocide, StairDirection},
+    components::{MobAction, Mobile},
+    ids::{entity_with_id, EntityId},
+    monsters::MonsterKind,
+    player::the_player,
+};
+
+/// The first line of every run file, which says which version of the
+/// format it's in.
+const HEADER: &str = "dungeon_game run 3";
+
+/// The options that run files don't list: those about recording and
+/// replaying the run rather than the run itself, and the config file,
+/// since the options it sets are listed instead. Each is followed by a
+/// value.
+const REPLAY_OPTIONS: &[&str] = &["--seed", "--record", "--replay", "--config"];
+
+#[derive(Error, Debug)]
+pub enum RunFileError {
+    #[error("not a run file, or from a different version of the game")]
+    NotARunFile,
+
+    #[error("line {0} is broken")]
+    BadLine(usize),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// One of the player's turns.
+#[derive(Clone, Copy)]
+pub struct Turn {
+    /// What the player did.
+    pub action: MobAction,
+
+    /// What the player wiped out, if they did.
+    pub genocide: Option<Genocide>,
+}
+
+impl Turn {
+    /// The turn the player has just chosen to take.
+    pub fn taken(world: &World) -> Self {
+        let action = world
+            .read_storage::<Mobile>()
+            .get(the_player(world).expect("There must be one player"))
+            .expect("Player must be mobile")
+            .next_action;
+        let genocide = world.fetch::<PendingGenocide>().0;
+
+        Self { action, genocide }
+    }
+
+    /// Makes the player take this turn, as if they'd chosen it.
+    pub fn apply(&self, world: &World) {
+        world
+            .write_storage::<Mobile>()
+            .get_mut(the_player(world).expect("There must be one player"))
+            .expect("Player must be mobile")
+            .next_action = self.action;
+        world.fetch_mut::<PendingGenocide>().0 = self.genocide;
+    }
+
+    /// The turn as a line of the run file, without the newline. Items
+    /// are recorded by their `EntityId`, which is the same when playing
+    /// back as it was when recording, since the entities are made in
+    /// the same order.
+    fn to_line(self, world: &World) -> String {
+        let ids = world.read_storage::<EntityId>();
+        let id = |item: Entity| ids.get(item).expect("Item must have an ID").0;
+        let action = match self.action {
+            MobAction::Nop => "nop".to_string(),
+            MobAction::Move(dx, dy) => format!("move {} {}", dx, dy),
+            MobAction::Search => "search".to_string(),
+            MobAction::Open(dx, dy) => format!("open {} {}", dx, dy),
+            MobAction::PickUp => "pickup".to_string(),
+            MobAction::Use(item) => format!("use {}", id(item)),
+            MobAction::Wield(item) => format!("wield {}", id(item)),
+            MobAction::Wear(item) => format!("wear {}", id(item)),
+            MobAction::Unequip(item) => format!("unequip {}", id(item)),
+            MobAction::Drop(item) => format!("drop {}", id(item)),
+            MobAction::Throw(item, x, y) => format!("throw {} {} {}", id(item), x, y),
+            MobAction::Blink(x, y) => format!("blink {} {}", x, y),
+            MobAction::TakeStairs(StairDirection::Up) => "stairs up".to_string(),
+            MobAction::TakeStairs(StairDirection::Down) => "stairs down".to_string(),
+            MobAction::Quaff => "quaff".to_string(),
+        };
+
+        match self.genocide {
+            Some(Genocide { kind, whole_branch }) => format!(
+                "turn {} genocide {} {}",
+                action,
+                kind.name(),
+                if whole_branch { "branch" } else { "level" }
+            ),
+            None => format!("turn {}", action),
+        }
+    }
+
+    /// Reads a turn back from what follows "turn" on a line of the run
+    /// file.
+    fn parse(words: &mut SplitWhitespace, world: &World) -> Option<Self> {
+        let action = match words.next()? {
+            "nop" => MobAction::Nop,
+            "move" => MobAction::Move(number(words)?, number(words)?),
+            "search" => MobAction::Search,
+            "open" => MobAction::Open(number(words)?, number(words)?),
+            "pickup" => MobAction::PickUp,
+            "use" => MobAction::Use(item(words, world)?),
+            "wield" => MobAction::Wield(item(words, world)?),
+            "wear" => MobAction::Wear(item(words, world)?),
+            "unequip" => MobAction::Unequip(item(words, world)?),
+            "drop" => MobAction::Drop(item(words, world)?),
+            "throw" => MobAction::Throw(item(words, world)?, number(words)?, number(words)?),
+            "blink" => MobAction::Blink(number(words)?, number(words)?),
+            "stairs" => MobAction::TakeStairs(match words.next()? {
+                "up" => StairDirection::Up,
+                "down" => StairDirection::Down,
+                _ => return None,
+            }),
+            "quaff" => MobAction::Quaff,
+            _ => return None,
+        };
+
+        let genocide = match words.next() {
+            Some("genocide") => {
+                let name = words.next()?;
+                let kind = *MonsterKind::ALL.iter().find(|kind| kind.name() == name)?;
+                let whole_branch = match words.next()? {
+                    "branch" => true,
+                    "level" => false,
+                    _ => return None,
+                };
+                Some(Genocide { kind, whole_branch })
+            }
+            Some(_) => return None,
+            None => None,
+        };
+
+        Some(Self { action, genocide })
+    }
+}
+
+/// Reads the next word of a line of the run file as a number.
+fn number(words: &mut SplitWhitespace) -> Option<i32> {
+    words.next()?.parse().ok()
+}
+
+/// Reads the next word of a line of the run file as an item's
+/// `EntityId`, and finds the item it belongs to.
+fn item(words: &mut SplitWhitespace, world: &World) -> Option<Entity> {
+    entity_with_id(world, EntityId(words.next()?.parse().ok()?))
+}
+
+/// Picks out the options that a run file records from the ones the
+/// game was started with: everything but those in `REPLAY_OPTIONS`.
+pub fn run_options(args: &[String]) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if REPLAY_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else {
+            kept.push(arg.clone());
+        }
+    }
+    kept
+}
+
+/// Writes the player's turns to a run file as they take them.
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Starts a new run file at `path`, for a run with the given seed
+    /// and options.
+    pub fn create(path: &Path, seed: u64, args: &[String]) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "seed {}", seed)?;
+        for arg in args {
+            writeln!(file, "arg {}", arg)?;
+        }
+        file.flush()?;
+
+        Ok(Self { file })
+    }
+
+    /// Adds a turn to the end of the run file. It's written out
+    /// straight away, so that the file is complete up to the last turn
+    /// even if the game crashes.
+    pub fn record(&mut self, turn: Turn, world: &World) -> io::Result<()> {
+        writeln!(self.file, "{}", turn.to_line(world))?;
+        self.file.flush()
+    }
+}
+
+/// A recorded run, being played back.
+pub struct Run {
+    /// The seed the run's random numbers came from.
+    pub seed: u64,
+
+    /// The options the run was started with.
+    pub args: Vec<String>,
+
+    /// The player's turns that are yet to be played back, each with
+    /// the line of the file it's on.
+    turns: VecDeque<(usize, String)>,
+}
+
+impl Run {
+    /// Reads the run file at `path`.
+    pub fn load(path: &Path) -> Result<Self, RunFileError> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line));
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(RunFileError::NotARunFile);
+        }
+
+        let mut seed = None;
+        let mut args = Vec::new();
+        let mut turns = VecDeque::new();
+        for (number, line) in lines {
+            match line.split_once(' ') {
+                Some(("seed", value)) if seed.is_none() => match value.parse() {
+                    Ok(value) => seed = Some(value),
+                    Err(_) => return Err(RunFileError::BadLine(number)),
+                },
+                Some(("arg", arg)) if turns.is_empty() => args.push(arg.to_string()),
+                Some(("turn", turn)) => turns.push_back((number, turn.to_string())),
+                _ => return Err(RunFileError::BadLine(number)),
+            }
+        }
+
+        Ok(Self {
+            seed: seed.ok_or(RunFileError::NotARunFile)?,
+            args,
+            turns,
+        })
+    }
+
+    /// Takes the next of the player's turns to play back in `world`,
+    /// or `None` once there are none left.
+    pub fn next_turn(&mut self, world: &World) -> Option<Result<Turn, RunFileError>> {
+        let (number, line) = self.turns.pop_front()?;
+        Some(Turn::parse(&mut line.split_whitespace(), world).ok_or(RunFileError::BadLine(number)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        branch::{BranchConfig, Dungeon, PendingTravel},
+        components::Inventory,
+        items::ItemKind,
+        schedule::{GameClock, Schedule},
+        systems::build_dispatcher,
+        testing::{add_player, empty_world, health, position},
+        util::GameRng,
+    };
+
+    /// Makes a world with a player carrying a teleport scroll and a
+    /// genocide scroll, and returns the genocide scroll. If `shifted`,
+    /// an entity that's since been deleted was made first, so the
+    /// entities' handles don't match those of an unshifted world.
+    fn carrying_scrolls(shifted: bool) -> (World, Entity) {
+        let mut world = empty_world();
+        if shifted {
+            let gone = world.create_entity().build();
+            world.delete_entity(gone).unwrap();
+            world.create_entity().build();
+        }
+        let player = add_player(&mut world, (1, 1));
+        let teleport = ItemKind::TeleportScroll.create(&mut world);
+        let genocide = ItemKind::GenocideScroll.create(&mut world);
+        let mut inventories = world.write_storage::<Inventory>();
+        let inventory = inventories.get_mut(player).unwrap();
+        inventory.add(teleport).unwrap();
+        inventory.add(genocide).unwrap();
+        drop(inventories);
+
+        (world, genocide)
+    }
+
+    #[test]
+    fn carried_items_survive_a_round_trip() {
+        let (recorded, scroll) = carrying_scrolls(false);
+        let line = Turn {
+            action: MobAction::Throw(scroll, 3, 4),
+            genocide: None,
+        }
+        .to_line(&recorded);
+
+        let (played_back, scroll) = carrying_scrolls(true);
+        let (word, rest) = line.split_once(' ').unwrap();
+        assert_eq!(word, "turn");
+        let turn = Turn::parse(&mut rest.split_whitespace(), &played_back).unwrap();
+        assert_eq!(turn.action, MobAction::Throw(scroll, 3, 4));
+
+        let inventories = played_back.read_storage::<Inventory>();
+        let player = the_player(&played_back).unwrap();
+        assert_eq!(inventories.get(player).unwrap().get('b'), Some(scroll));
+    }
+
+    #[test]
+    fn unknown_items_are_rejected() {
+        let (world, _) = carrying_scrolls(false);
+        let mut words = "use 99".split_whitespace();
+        assert!(Turn::parse(&mut words, &world).is_none());
+    }
+
+    /// Plays a run on the dungeon generated from `seed`, with the
+    /// player taking the turns `next_turn` gives them until it runs
+    /// out, and returns where the player ended up, their health and the
+    /// number of turns taken.
+    fn play(
+        seed: u64,
+        mut next_turn: impl FnMut(&World) -> Option<Turn>,
+    ) -> ((i32, i32), i32, u32) {
+        let mut world = empty_world();
+        world.insert(GameRng(StdRng::seed_from_u64(seed)));
+        world.insert(PendingGenocide::default());
+        let mut dungeon = Dungeon::generate(&BranchConfig::default(), seed).unwrap();
+        let start = dungeon.enter(&mut world);
+        let player = add_player(&mut world, start);
+        let mut dispatcher = build_dispatcher();
+
+        loop {
+            let players_turn = world.fetch::<Schedule>().up_next().contains(&player);
+            if players_turn {
+                match next_turn(&world) {
+                    Some(turn) => turn.apply(&world),
+                    None => break,
+                }
+            }
+
+            dispatcher.dispatch(&world);
+            world.maintain();
+            let travel = world.fetch_mut::<PendingTravel>().0.take();
+            if let Some(travel) = travel {
+                dungeon.travel(&mut world, travel);
+            }
+            if health(&world, player) <= 0 {
+                break;
+            }
+        }
+
+        let turns = world.fetch::<GameClock>().turns;
+        (position(&world, player), health(&world, player), turns)
+    }
+
+    #[test]
+    fn replaying_a_run_ends_up_in_the_same_place() {
+        let path = env::temp_dir().join(format!("dungeon_game_test_{}.run", process::id()));
+        let seed = 7;
+
+        let mut recorder = Recorder::create(&path, seed, &[]).unwrap();
+        let mut wander = StdRng::seed_from_u64(1);
+        let mut left = 50;
+        let recorded = play(seed, |world| {
+            if left == 0 {
+                return None;
+            }
+            left -= 1;
+            let turn = Turn {
+                action: MobAction::Move(wander.gen_range(-1..=1), wander.gen_range(-1..=1)),
+                genocide: None,
+            };
+            recorder.record(turn, world).unwrap();
+            Some(turn)
+        });
+        drop(recorder);
+
+        let mut run = Run::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(run.seed, seed);
+        let played_back = play(run.seed, |world| run.next_turn(world).map(Result::unwrap));
+
+        assert_eq!(played_back, recorded);
+        assert!(recorded.2 >= 50, "only {} turns went by", recorded.2);
+    }
+}
@@ -17,11 +17,12 @@ use std::ops::Range;
 
 use grid::Grid;
 use pathfinding::directed::astar::astar;
-use rand::Rng;
+use rand::{seq::SliceRandom, Rng};
+use thiserror::Error;
 
 use crate::{
-    level::{DungeonLevel, DungeonTile, LEVEL_SIZE},
-    util::NiceFloat,
+    level::{parse_grid, DungeonLevel, DungeonTile, LevelExits, LEVEL_SIZE},
+    util::connected_components,
 };
 
 /// The possible sizes of a room, on both the x and y axes.
@@ -43,31 +44,264 @@ const ROOM_MARGIN: usize = 2;
 /// wrap around rooms rather than enter them).
 const ROOM_WEIGHT: f64 = 0.2;
 
+/// How far apart, in steps, the centers of two rooms can be for an
+/// extra hallway to be added between them.
+const LOOP_MAX_DISTANCE: usize = 30;
+
 /// Randomness factor to avoid straight lines in hallways.
 const HALLWAY_RANDOMNESS: f64 = 0.6;
 
+/// The cost of digging through a tile of stone with no randomness
+/// applied. Hallway costs are kept as integers scaled by this, rather
+/// than as floats, so that comparing two routes is always exact and
+/// the same seed always digs the same hallways.
+const HALLWAY_COST_SCALE: f64 = 100.0;
+
+/// The cost of a hallway passing through a room, or through another
+/// hallway.
+const ROOM_COST: u32 = (ROOM_WEIGHT * HALLWAY_COST_SCALE) as u32;
+
+/// The range of costs for digging through a tile of stone.
+const MIN_STONE_COST: u32 = ((1.0 - HALLWAY_RANDOMNESS) * HALLWAY_COST_SCALE) as u32;
+const MAX_STONE_COST: u32 = ((1.0 + HALLWAY_RANDOMNESS) * HALLWAY_COST_SCALE) as u32;
+
+/// The least it can cost a hallway to pass through any one tile.
+const MIN_NODE_COST: u32 = if MIN_STONE_COST < ROOM_COST {
+    MIN_STONE_COST
+} else {
+    ROOM_COST
+};
+
+// The hallway search's heuristic is only a lower bound on the real
+// cost, which A* needs in order to find the cheapest route, if every
+// tile costs something to pass through. This catches a
+// HALLWAY_RANDOMNESS of 1.0 or more, or a ROOM_WEIGHT too small to
+// cost anything, before they can quietly break hallway generation.
+const _: () = assert!(
+    MIN_NODE_COST > 0 && MIN_STONE_COST < MAX_STONE_COST,
+    "hallway costs must be positive, with a non-empty range for stone"
+);
+
+/// Probability that a doorway, where a hallway meets a room, is
+/// hidden as a secret door.
+const SECRET_DOOR_CHANCE: f64 = 0.1;
+
+/// Probability that a doorway that isn't secret has a door in it,
+/// rather than being an empty opening.
+const DOOR_CHANCE: f64 = 0.5;
+
+/// Probability that a door starts out closed.
+const CLOSED_DOOR_CHANCE: f64 = 0.5;
+
+/// Probability that any given room is replaced with a vault.
+const VAULT_CHANCE: f64 = 0.05;
+
+/// Hand-designed rooms that are occasionally placed instead of plain
+/// rectangular ones. Each layout includes the walls surrounding the
+/// room, and '$' marks a spot where treasure is placed. The center of
+/// each vault's interior must be navigable, since that's where
+/// hallways lead to.
+const VAULTS: &[&[&str]] = &[
+    &[
+        "---------",
+        "|.......|",
+        "|.|...|.|",
+        "|...$...|",
+        "|.|...|.|",
+        "|.......|",
+        "---------",
+    ],
+    &[
+        "-----------",
+        "|.........|",
+        "|.-------.|",
+        "|.|.....|.|",
+        "|.|..$..+.|",
+        "|.|.....|.|",
+        "|.-------.|",
+        "|.........|",
+        "-----------",
+    ],
+    &[
+        "-----------",
+        "|.........|",
+        "|.~~~~~~~.|",
+        "|.~}}$}}~.|",
+        "|.~~~~~~~.|",
+        "|.........|",
+        "-----------",
+    ],
+    &[
+        "-----------",
+        "|.........|",
+        "|..~~~~~..|",
+        "|.~==$==~.|",
+        "|..~~~~~..|",
+        "|.........|",
+        "-----------",
+    ],
+    &[
+        "-----------",
+        "|.........|",
+        "|.:::.:::.|",
+        "|.:::$:::.|",
+        "|.:::.:::.|",
+        "|.........|",
+        "-----------",
+    ],
+    &[
+        "-------", //
+        "|.....|", "|.$.$.|", "|.....|", "-------",
+    ],
+];
+
+/// Ways that generating a level can fail.
+#[derive(Error, Debug)]
+pub enum GenError {
+    #[error("can't generate a {0}x{1} level")]
+    EmptyRegion(usize, usize),
+
+    #[error("no rooms could be placed")]
+    NoRooms,
+
+    #[error("couldn't place stairs (have {0} reachable floor tiles, need {1})")]
+    NoStairs(usize, usize),
+
+    #[error("level {0} has no way up")]
+    MissingUpstair(usize),
+
+    #[error("level {0} has no way down")]
+    MissingDownstair(usize),
+
+    #[error("level {0} has a branch staircase in the wrong place")]
+    MisplacedBranchStair(usize),
+}
+
+/// Which ways hallways can run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HallwayStyle {
+    /// Hallways only run straight across or down, turning at right
+    /// angles.
+    #[default]
+    Orthogonal,
+
+    /// Hallways can also cut diagonally through the stone, away from
+    /// rooms.
+    Diagonal,
+}
+
+/// How wide hallways are.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HallwayWidth {
+    /// Hallways are a single tile across.
+    #[default]
+    Single,
+
+    /// Hallways are two tiles across where there's room for it, and
+    /// narrow down to one near rooms, whose walls are left whole.
+    Double,
+}
+
+/// How the hallways between rooms are dug.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Hallways {
+    /// Which ways they run.
+    pub style: HallwayStyle,
+
+    /// How wide they are.
+    pub width: HallwayWidth,
+}
+
+/// A freshly generated level.
+pub struct Layout {
+    /// The tiles making up the level.
+    pub grid: Grid<DungeonTile>,
+
+    /// The locations of the level's exits.
+    pub exits: LevelExits,
+
+    /// Spots where treasure should be placed.
+    pub treasure: Vec<(i32, i32)>,
+
+    /// The index of the room that each tile is inside of, if any.
+    /// Hallways and walls aren't part of any room.
+    pub rooms: Grid<Option<usize>>,
+}
+
 /// Generates a grid of the given size containing rooms connected by
-/// passages.
+/// passages, with `loops` extra passages between nearby rooms on top
+/// of the ones needed to connect everything. Sizes with no tiles at
+/// all are rejected outright.
 pub fn generate(
     n_rooms: usize,
     size: (usize, usize),
     rng: &mut impl Rng,
     upstairs: usize,
     downstairs: usize,
-) -> (Grid<DungeonTile>, Vec<(i32, i32)>, Vec<(i32, i32)>) {
+    loops: usize,
+    hallways: Hallways,
+) -> Result<Layout, GenError> {
+    if size.0 == 0 || size.1 == 0 {
+        return Err(GenError::EmptyRegion(size.0, size.1));
+    }
+
     let mut grid = Grid::init(size.1, size.0, DungeonTile::Wall);
-    let rooms = RoomBounds::generate(n_rooms, size, rng);
+    let vaults = Vault::load_all();
+    let rooms = RoomBounds::generate(n_rooms, size, &vaults, rng);
+    if rooms.is_empty() {
+        return Err(GenError::NoRooms);
+    }
 
-    for room in rooms.iter() {
+    let mut treasure = Vec::new();
+    let mut room_ids = Grid::init(size.1, size.0, None);
+    for (id, room) in rooms.iter().enumerate() {
         for (x, y) in room.tiles() {
-            grid[y][x] = DungeonTile::Floor;
+            room_ids[y][x] = Some(id);
+        }
+
+        match room.vault {
+            None => {
+                for (x, y) in room.tiles() {
+                    grid[y][x] = DungeonTile::Floor;
+                }
+            }
+            Some(idx) => {
+                // Vault layouts include their walls, so they start one
+                // tile up and to the left of the room's interior.
+                let vault = &vaults[idx];
+                let (x0, y0) = (room.ul_corner.0 - 1, room.ul_corner.1 - 1);
+                for y in 0..vault.tiles.rows() {
+                    for x in 0..vault.tiles.cols() {
+                        grid[y0 + y][x0 + x] = vault.tiles[y][x];
+                    }
+                }
+
+                treasure.extend(
+                    vault
+                        .treasure
+                        .iter()
+                        .map(|(x, y)| ((x0 + x) as i32, (y0 + y) as i32)),
+                );
+            }
         }
     }
 
-    add_hallways(&mut grid, &rooms, rng);
-    let (upstairs, downstairs) = add_stairs(&mut grid, upstairs, downstairs, rng);
+    add_hallways(&mut grid, &rooms, loops, hallways, rng);
 
-    (grid, upstairs, downstairs)
+    // Hallways connect every room to the first, so anywhere that can
+    // be reached from inside it is part of the level proper.
+    let start = rooms[0]
+        .tiles()
+        .find(|&(x, y)| is_passable(grid[y][x]))
+        .unwrap_or_else(|| rooms[0].center());
+    let exits = add_stairs(&mut grid, start, upstairs, downstairs, rng)?;
+
+    Ok(Layout {
+        grid,
+        exits,
+        treasure,
+        rooms: room_ids,
+    })
 }
 
 /// Generates a grid of the statically-known level size.
@@ -76,12 +310,21 @@ pub fn generate_level(
     rng: &mut impl Rng,
     upstairs: usize,
     downstairs: usize,
-) -> DungeonLevel {
+    loops: usize,
+    hallways: Hallways,
+) -> Result<DungeonLevel, GenError> {
     // FIXME: This function is atrocious. We do an allocation here
     // when we theoretically doesn't need to (we get a heap-allocated
     // Grid back, when we know statically that it's LEVEL_SIZE so we
     // could allocate it on the stack)...
-    let (grid, upstairs, downstairs) = generate(n_rooms, LEVEL_SIZE, rng, upstairs, downstairs);
+    let Layout {
+        grid,
+        exits,
+        treasure,
+        rooms,
+    } = generate(
+        n_rooms, LEVEL_SIZE, rng, upstairs, downstairs, loops, hallways,
+    )?;
 
     // ...and then we use a pointless default of DungeonTile::Floor
     // here then copy in the real data from `grid`.
@@ -93,13 +336,71 @@ pub fn generate_level(
         *slot = value;
     }
 
-    DungeonLevel::new(data, upstairs, downstairs)
+    let mut room_data = [[None; LEVEL_SIZE.0]; LEVEL_SIZE.1];
+    for (value, slot) in Iterator::zip(
+        rooms.into_vec().into_iter(),
+        room_data.iter_mut().flat_map(|elem| elem.iter_mut()),
+    ) {
+        *slot = value;
+    }
+
+    Ok(DungeonLevel::new(
+        data,
+        room_data,
+        exits.upstairs,
+        exits.downstairs,
+        treasure,
+    ))
+}
+
+/// A hand-designed room layout.
+struct Vault {
+    /// The vault's tiles, including the walls around it.
+    tiles: Grid<DungeonTile>,
+
+    /// The spots where treasure is placed, relative to the upper-left
+    /// corner of `tiles`.
+    treasure: Vec<(usize, usize)>,
+}
+
+impl Vault {
+    /// Parses every layout in `VAULTS`.
+    fn load_all() -> Vec<Self> {
+        VAULTS
+            .iter()
+            .map(|layout| {
+                let treasure = layout
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(y, row)| {
+                        row.chars()
+                            .enumerate()
+                            .filter(|(_x, ch)| *ch == '$')
+                            .map(move |(x, _ch)| (x, y))
+                    })
+                    .collect();
+                let tiles = parse_grid(&layout.join("\n").replace('$', "."))
+                    .expect("Vault layouts must be valid");
+
+                Self { tiles, treasure }
+            })
+            .collect()
+    }
+
+    /// The size of the vault, not counting its walls.
+    fn interior_size(&self) -> (usize, usize) {
+        (self.tiles.cols() - 2, self.tiles.rows() - 2)
+    }
 }
 
 /// The bounding box of a room.
-struct RoomBounds {
+pub struct RoomBounds {
     ul_corner: (usize, usize),
     size: (usize, usize),
+
+    /// The index of the vault this room is filled with, if it isn't
+    /// just a plain room.
+    vault: Option<usize>,
 }
 
 impl RoomBounds {
@@ -111,9 +412,19 @@ impl RoomBounds {
         (y_min..y_max).flat_map(move |y| (x_min..x_max).map(move |x| (x, y)))
     }
 
+    /// Returns whether the given tile is contained within the room.
+    pub fn contains(&self, (x, y): (usize, usize)) -> bool {
+        (self.ul_corner.0..self.ul_corner.0 + self.size.0).contains(&x)
+            && (self.ul_corner.1..self.ul_corner.1 + self.size.1).contains(&y)
+    }
+
     /// Returns whether the two rooms are overlapping, i.e., there
-    /// exists at least one tile that is contained in both rooms.
+    /// exists at least one tile that is contained in both rooms. Rooms
+    /// whose edges only touch don't overlap.
     pub fn intersects(&self, other: &Self) -> bool {
+        // Whether the half-open ranges share a value: once `a` is the
+        // one starting first, that's whenever it ends past the start
+        // of `b`.
         fn range_overlapping(a: Range<usize>, b: Range<usize>) -> bool {
             if a.start > b.start {
                 range_overlapping(b, a)
@@ -132,8 +443,12 @@ impl RoomBounds {
     }
 
     /// Returns whether the two rooms are within distance `dist` of
-    /// one another or intersecting.
+    /// one another or intersecting, i.e., there are fewer than `dist`
+    /// tiles between them both across and up and down. Rooms exactly
+    /// `dist` tiles apart aren't near.
     pub fn near(&self, other: &Self, dist: usize) -> bool {
+        // Stretching both rooms right and down by `dist` closes up a
+        // gap of less than `dist` on whichever side it's on.
         RoomBounds {
             size: (self.size.0 + dist, self.size.1 + dist),
             ..*self
@@ -145,21 +460,48 @@ impl RoomBounds {
     }
 
     /// Generates bounds for a set of at most `n_rooms` nonoverlapping
-    /// rooms within a region of size `region_size`.
-    fn generate(n_rooms: usize, region_size: (usize, usize), rng: &mut impl Rng) -> Vec<Self> {
+    /// rooms within a region of size `region_size`, some of which may
+    /// be filled with `vaults`.
+    fn generate(
+        n_rooms: usize,
+        region_size: (usize, usize),
+        vaults: &[Vault],
+        rng: &mut impl Rng,
+    ) -> Vec<Self> {
         let mut v: Vec<Self> = Vec::new();
 
         for _ in 0..n_rooms {
-            let size = (
-                rng.gen_range(ROOM_SIZE_LIMITS),
-                rng.gen_range(ROOM_SIZE_LIMITS),
-            );
-            let ul_corner = (
-                rng.gen_range(ROOM_MARGIN..region_size.0 - size.0 - ROOM_MARGIN),
-                rng.gen_range(ROOM_MARGIN..region_size.1 - size.1 - ROOM_MARGIN),
-            );
+            let vault = if rng.gen_bool(VAULT_CHANCE) {
+                Some(rng.gen_range(0..vaults.len()))
+            } else {
+                None
+            };
+            let size = match vault {
+                Some(idx) => vaults[idx].interior_size(),
+                None => (
+                    rng.gen_range(ROOM_SIZE_LIMITS),
+                    rng.gen_range(ROOM_SIZE_LIMITS),
+                ),
+            };
+
+            // Skip rooms too big to fit in the region at all.
+            let x_max = region_size.0.checked_sub(size.0 + ROOM_MARGIN);
+            let y_max = region_size.1.checked_sub(size.1 + ROOM_MARGIN);
+            let (x_range, y_range) = match (x_max, y_max) {
+                (Some(x_max), Some(y_max)) => (ROOM_MARGIN..x_max, ROOM_MARGIN..y_max),
+                _ => continue,
+            };
+            if x_range.is_empty() || y_range.is_empty() {
+                continue;
+            }
 
-            let new_room = Self { ul_corner, size };
+            let ul_corner = (rng.gen_range(x_range), rng.gen_range(y_range));
+
+            let new_room = Self {
+                ul_corner,
+                size,
+                vault,
+            };
             if v.iter()
                 .all(|room| !room.near(&new_room, ROOM_MIN_DISTANCE))
             {
@@ -180,109 +522,544 @@ impl RoomBounds {
 }
 
 /// Adds a set of hallways connecting the given rooms to a dungeon.
-fn add_hallways(grid: &mut Grid<DungeonTile>, rooms: &[RoomBounds], rng: &mut impl Rng) {
+fn add_hallways(
+    grid: &mut Grid<DungeonTile>,
+    rooms: &[RoomBounds],
+    loops: usize,
+    hallways: Hallways,
+    rng: &mut impl Rng,
+) {
     // How hard we try to avoid traveling through stone at a pair of
     // coordinates.
     let mut stone_weights = Grid::new(grid.rows(), grid.cols());
     for elem in stone_weights.iter_mut() {
-        *elem = rng.gen_range(1.0 - HALLWAY_RANDOMNESS..1.0 + HALLWAY_RANDOMNESS);
+        *elem = rng.gen_range(MIN_STONE_COST..MAX_STONE_COST);
+    }
+
+    // Make hallways between pairs of adjacent rooms, which connects
+    // everything up...
+    for pair in rooms.windows(2) {
+        dig_hallway(
+            grid,
+            rooms,
+            &stone_weights,
+            &pair[0],
+            &pair[1],
+            hallways,
+            rng,
+        );
     }
 
+    // ...then add a few more between rooms that are close together,
+    // so that there's more than one way to get around.
+    for (a, b) in loop_pairs(rooms, loops, rng) {
+        dig_hallway(
+            grid,
+            rooms,
+            &stone_weights,
+            &rooms[a],
+            &rooms[b],
+            hallways,
+            rng,
+        );
+    }
+}
+
+/// Picks up to `loops` pairs of rooms, by index, to dig extra
+/// hallways between. They're close together, but weren't next to each
+/// other in `rooms`, so they aren't already joined by a hallway.
+fn loop_pairs(rooms: &[RoomBounds], loops: usize, rng: &mut impl Rng) -> Vec<(usize, usize)> {
+    let mut candidates: Vec<(usize, usize)> = (0..rooms.len())
+        .flat_map(|a| (a + 2..rooms.len()).map(move |b| (a, b)))
+        .filter(|&(a, b)| {
+            let (from, to) = (rooms[a].center(), rooms[b].center());
+            from.0.abs_diff(to.0) + from.1.abs_diff(to.1) <= LOOP_MAX_DISTANCE
+        })
+        .collect();
+    candidates.shuffle(rng);
+    candidates.truncate(loops);
+    candidates
+}
+
+/// Digs a hallway between the centers of two rooms, following the
+/// cheapest path through `stone_weights`.
+///
+/// Diagonal hallways only take diagonal steps where neither end is in
+/// a room or right next to one, so that they never cut across the
+/// corner of a room and every doorway is walked through straight on.
+/// Wide hallways get a second lane alongside every step outside of a
+/// room, on whichever side has stone to spare, but never right next to
+/// a room, so that they can't open up its walls, nor within
+/// `ROOM_MARGIN` of the edge of the map.
+fn dig_hallway(
+    grid: &mut Grid<DungeonTile>,
+    rooms: &[RoomBounds],
+    stone_weights: &Grid<u32>,
+    from: &RoomBounds,
+    to: &RoomBounds,
+    hallways: Hallways,
+    rng: &mut impl Rng,
+) {
     let size = (grid.cols(), grid.rows());
+    let (from, to) = (&from.center(), &to.center());
+    // Neighbors are always tried in the same order, so that ties
+    // between equally cheap routes are broken the same way every
+    // time.
+    let neighbors: &[(isize, isize)] = match hallways.style {
+        HallwayStyle::Orthogonal => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+        HallwayStyle::Diagonal => &[
+            (-1, 0),
+            (1, 0),
+            (0, -1),
+            (0, 1),
+            (-1, -1),
+            (1, -1),
+            (-1, 1),
+            (1, 1),
+        ],
+    };
 
-    // Make hallways between pairs of adjacent rooms.
-    for rooms in rooms.windows(2) {
-        let (from, to) = (&rooms[0].center(), &rooms[1].center());
-        let neighbors = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+    let in_room = |tile: (usize, usize)| rooms.iter().any(|room| room.contains(tile));
+    let near_room = |(x, y): (isize, isize)| {
+        [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .any(|(dx, dy)| {
+                let (x, y) = (x + dx, y + dy);
+                x >= 0 && y >= 0 && in_room((x as usize, y as usize))
+            })
+    };
 
-        let (path, _weight) = astar(
-            from,
-            |node| {
-                let (x, y) = (node.0 as isize, node.1 as isize);
-                neighbors
-                    .iter()
-                    .map(move |(dx, dy)| (x + dx, y + dy))
-                    .filter_map(|(x, y)| {
-                        if (0..size.0 as isize).contains(&x) && (0..size.1 as isize).contains(&y) {
-                            Some((
-                                (x as usize, y as usize),
-                                NiceFloat(match grid[y as usize][x as usize] {
-                                    DungeonTile::Wall => stone_weights[y as usize][x as usize],
-                                    _ => ROOM_WEIGHT,
-                                }),
-                            ))
-                        } else {
-                            None
-                        }
-                    })
-            },
-            |node| {
-                // For A* to work correctly, the heuristic here must
-                // be smaller than the actual cost to travel from
-                // `node` to `to`, which means we need to know the
-                // minimum possible cost from `node` to `to`.
-
-                // The minimum possible cost to travel through a
-                // single node if it's a wall is 1.0 -
-                // HALLWAY_RANDOMNESS, and if it's a hallway then it's
-                // ROOM_WEIGHT.
-                let min_node_cost = f64::min(1.0 - HALLWAY_RANDOMNESS, ROOM_WEIGHT);
-
-                // And since hallways don't travel diagonally, the
-                // minimum number of nodes to travel through is the
-                // sum of the horizontal and vertical distance.
-                let dx = node.0 as isize - to.0 as isize;
-                let dy = node.1 as isize - to.1 as isize;
-                let min_dist = dx.abs() + dy.abs();
-
-                NiceFloat(min_dist as f64 * min_node_cost)
-            },
-            |node| node == to,
-        )
-        .expect("Grid is connected therefore should be navigable");
+    // The cost of a hallway passing through a tile.
+    let cost = |(x, y): (usize, usize)| match grid[y][x] {
+        DungeonTile::Wall => stone_weights[y][x],
+        _ => ROOM_COST,
+    };
+
+    // For A* to work correctly, the heuristic must never be more than
+    // the actual cost to travel from `node` to `to`. Every tile costs
+    // at least MIN_NODE_COST to pass through, and the fewest tiles to
+    // pass through is the sum of the horizontal and vertical distance,
+    // or just the larger of the two if hallways can travel
+    // diagonally.
+    let heuristic = |node: &(usize, usize)| {
+        let (dx, dy) = (node.0.abs_diff(to.0), node.1.abs_diff(to.1));
+        let min_dist = match hallways.style {
+            HallwayStyle::Orthogonal => dx + dy,
+            HallwayStyle::Diagonal => dx.max(dy),
+        };
+
+        min_dist as u32 * MIN_NODE_COST
+    };
+
+    let (path, weight) = astar(
+        from,
+        |node| {
+            let (x, y) = (node.0 as isize, node.1 as isize);
+            neighbors
+                .iter()
+                .filter(move |&&(dx, dy)| {
+                    dx == 0 || dy == 0 || !(near_room((x, y)) || near_room((x + dx, y + dy)))
+                })
+                .map(move |(dx, dy)| (x + dx, y + dy))
+                .filter_map(|(x, y)| {
+                    if (0..size.0 as isize).contains(&x) && (0..size.1 as isize).contains(&y) {
+                        let cell = (x as usize, y as usize);
+                        Some((cell, cost(cell)))
+                    } else {
+                        None
+                    }
+                })
+        },
+        heuristic,
+        |node| node == to,
+    )
+    .expect("Grid is connected therefore should be navigable");
+
+    // Make sure the heuristic really was a lower bound on what's left
+    // to pay at every step, since A* can't tell us if it wasn't.
+    if cfg!(debug_assertions) {
+        let mut remaining = weight;
+        for &node in &path {
+            debug_assert!(
+                heuristic(&node) <= remaining,
+                "hallway heuristic overestimates the cost from {:?}",
+                node
+            );
+            if node != *from {
+                remaining -= cost(node);
+            }
+        }
+        debug_assert_eq!(remaining, 0);
+    }
+
+    // A doorway is a tile we're cutting out of the stone that
+    // lies right next to the interior of a room along the path.
+    let doorways: Vec<(usize, usize)> = path
+        .windows(2)
+        .filter_map(|step| match (in_room(step[0]), in_room(step[1])) {
+            (true, false) => Some(step[1]),
+            (false, true) => Some(step[0]),
+            _ => None,
+        })
+        .collect();
+
+    for &(x, y) in &path {
+        if grid[y][x] == DungeonTile::Wall {
+            grid[y][x] = if !doorways.contains(&(x, y)) {
+                DungeonTile::Floor
+            } else if rng.gen_bool(SECRET_DOOR_CHANCE) {
+                DungeonTile::SecretDoor
+            } else if rng.gen_bool(DOOR_CHANCE) {
+                DungeonTile::Door {
+                    open: !rng.gen_bool(CLOSED_DOOR_CHANCE),
+                }
+            } else {
+                DungeonTile::Floor
+            };
+        }
+    }
+
+    if hallways.width == HallwayWidth::Double {
+        let next_to_room = |(x, y): (usize, usize)| {
+            (y.saturating_sub(1)..=y + 1)
+                .any(|y| (x.saturating_sub(1)..=x + 1).any(|x| in_room((x, y))))
+        };
+        let away_from_edge = |(x, y): (isize, isize)| {
+            (ROOM_MARGIN as isize..(size.0 - ROOM_MARGIN) as isize).contains(&x)
+                && (ROOM_MARGIN as isize..(size.1 - ROOM_MARGIN) as isize).contains(&y)
+        };
+
+        for step in path.windows(2) {
+            let (prev, (x, y)) = (step[0], step[1]);
+            if in_room((x, y)) || doorways.contains(&(x, y)) {
+                continue;
+            }
 
-        for (x, y) in path {
-            if grid[y][x] == DungeonTile::Wall {
-                grid[y][x] = DungeonTile::Floor;
+            // The second lane runs beside the first, below horizontal
+            // steps and to the right of the rest if it can.
+            let side: (isize, isize) = if prev.1 == y { (0, 1) } else { (1, 0) };
+            for sign in [1, -1] {
+                let lane = (x as isize + sign * side.0, y as isize + sign * side.1);
+                if !away_from_edge(lane) {
+                    continue;
+                }
+                let lane = (lane.0 as usize, lane.1 as usize);
+                match grid[lane.1][lane.0] {
+                    DungeonTile::Floor => break,
+                    DungeonTile::Wall if !next_to_room(lane) => {
+                        grid[lane.1][lane.0] = DungeonTile::Floor;
+                        break;
+                    }
+                    _ => {}
+                }
             }
         }
     }
 }
 
-/// Adds staircases leading upwards and downwards to the level.
+/// Adds staircases leading upwards and downwards to the level, on
+/// floor tiles that can be reached from `start`, so that stairs never
+/// end up cut off from the rest of the level.
 fn add_stairs(
     grid: &mut Grid<DungeonTile>,
+    start: (usize, usize),
     n_upstairs: usize,
     n_downstairs: usize,
     rng: &mut impl Rng,
-) -> (Vec<(i32, i32)>, Vec<(i32, i32)>) {
-    let (mut upstairs, mut downstairs) = (
-        Vec::with_capacity(n_upstairs),
-        Vec::with_capacity(n_downstairs),
-    );
-
-    for _ in 0..n_upstairs {
-        let (x, y) = empty_square(grid, rng);
-        upstairs.push((x, y));
-        grid[y as usize][x as usize] = DungeonTile::Upstair;
+) -> Result<LevelExits, GenError> {
+    let mut floor: Vec<(usize, usize)> = connected_components(grid, |&tile| is_passable(tile))
+        .into_iter()
+        .find(|component| component.contains(&start))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&(x, y)| grid[y][x] == DungeonTile::Floor)
+        .collect();
+    if floor.len() < n_upstairs + n_downstairs {
+        return Err(GenError::NoStairs(floor.len(), n_upstairs + n_downstairs));
     }
 
-    for _ in 0..n_downstairs {
-        let (x, y) = empty_square(grid, rng);
-        downstairs.push((x, y));
+    floor.shuffle(rng);
+    let to_exits = |tiles: &[(usize, usize)]| -> Vec<(i32, i32)> {
+        tiles.iter().map(|&(x, y)| (x as i32, y as i32)).collect()
+    };
+    let upstairs = to_exits(&floor[..n_upstairs]);
+    let downstairs = to_exits(&floor[n_upstairs..n_upstairs + n_downstairs]);
+
+    for &(x, y) in &upstairs {
+        grid[y as usize][x as usize] = DungeonTile::Upstair;
+    }
+    for &(x, y) in &downstairs {
         grid[y as usize][x as usize] = DungeonTile::Downstair;
     }
 
-    (upstairs, downstairs)
+    Ok(LevelExits {
+        upstairs,
+        downstairs,
+        branch_stair: None,
+    })
 }
 
-/// Finds an unoccupied (floor) square of the level.
-fn empty_square(grid: &Grid<DungeonTile>, rng: &mut impl Rng) -> (i32, i32) {
-    loop {
-        let (x, y) = (rng.gen_range(0..grid.cols()), rng.gen_range(0..grid.rows()));
+/// Whether a walking creature can get through a tile, at least once
+/// any doors on it have been found and opened.
+fn is_passable(tile: DungeonTile) -> bool {
+    tile.is_navigable() || matches!(tile, DungeonTile::Door { .. } | DungeonTile::SecretDoor)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn tiny_region_has_no_rooms() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let result = generate(10, (5, 5), &mut rng, 1, 1, 0, Hallways::default());
+        assert!(matches!(result, Err(GenError::NoRooms)));
+    }
+
+    #[test]
+    fn one_tile_region_has_no_rooms() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let result = generate(10, (1, 1), &mut rng, 1, 1, 0, Hallways::default());
+        assert!(matches!(result, Err(GenError::NoRooms)));
+    }
+
+    #[test]
+    fn empty_region_is_rejected() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let result = generate(10, (0, 20), &mut rng, 1, 1, 0, Hallways::default());
+        assert!(matches!(result, Err(GenError::EmptyRegion(0, 20))));
+    }
+
+    /// A plain room with its upper-left corner at the given position.
+    fn room(x: usize, y: usize, width: usize, height: usize) -> RoomBounds {
+        RoomBounds {
+            ul_corner: (x, y),
+            size: (width, height),
+            vault: None,
+        }
+    }
+
+    #[test]
+    fn rooms_overlap_only_when_they_share_a_tile() {
+        let a = room(0, 0, 5, 5);
+        for (b, overlapping) in [
+            (room(4, 4, 5, 5), true),
+            (room(1, 1, 2, 2), true),
+            (room(5, 0, 5, 5), false),
+            (room(0, 5, 5, 5), false),
+            (room(5, 5, 1, 1), false),
+        ] {
+            assert_eq!(a.intersects(&b), overlapping, "{:?}", b.ul_corner);
+            assert_eq!(b.intersects(&a), overlapping, "{:?}", b.ul_corner);
+        }
+    }
+
+    #[test]
+    fn rooms_are_near_when_the_gap_is_small() {
+        let a = room(0, 0, 5, 5);
+        // Four tiles between them, then three.
+        for (b, near) in [
+            (room(9, 0, 5, 5), false),
+            (room(8, 0, 5, 5), true),
+            (room(0, 9, 5, 5), false),
+            (room(0, 8, 5, 5), true),
+            // Close across, but too far apart up and down.
+            (room(6, 9, 5, 5), false),
+            (room(2, 2, 5, 5), true),
+        ] {
+            assert_eq!(a.near(&b, 4), near, "{:?}", b.ul_corner);
+            assert_eq!(b.near(&a, 4), near, "{:?}", b.ul_corner);
+        }
+    }
+
+    #[test]
+    fn loops_join_nearby_rooms() {
+        // Five rooms in a row, ten tiles apart, so that rooms two or
+        // three apart in the list are close enough to be looped.
+        let rooms: Vec<RoomBounds> = (0..5).map(|i| room(i * 10, 0, 4, 4)).collect();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let pairs = loop_pairs(&rooms, 3, &mut rng);
+        assert_eq!(pairs.len(), 3);
+        for &(a, b) in &pairs {
+            assert!((2..=3).contains(&(b - a)));
+        }
+
+        let mut all = loop_pairs(&rooms, 10, &mut rng);
+        all.sort();
+        assert_eq!(all, [(0, 2), (0, 3), (1, 3), (1, 4), (2, 4)]);
+
+        assert!(loop_pairs(&rooms, 0, &mut rng).is_empty());
+    }
+
+    /// A small room on the left, cut off from a bigger one on the right.
+    const POCKETS: &str = "\
+        ------------\n\
+        |...||.....|\n\
+        |...||.....|\n\
+        |...||.....|\n\
+        -----|.....|\n\
+        -----------";
+
+    #[test]
+    fn stairs_are_reachable() {
+        let mut grid = parse_grid(POCKETS).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let exits = add_stairs(&mut grid, (1, 1), 2, 2, &mut rng).unwrap();
+
+        for &(x, y) in exits.upstairs.iter().chain(&exits.downstairs) {
+            assert!((1..=3).contains(&x) && (1..=3).contains(&y));
+        }
+        assert_eq!(exits.upstairs.len(), 2);
+        assert_eq!(exits.downstairs.len(), 2);
+    }
+
+    #[test]
+    fn too_few_reachable_tiles_for_stairs() {
+        let mut grid = parse_grid(POCKETS).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let result = add_stairs(&mut grid, (1, 1), 5, 5, &mut rng);
+        assert!(matches!(result, Err(GenError::NoStairs(9, 10))));
+    }
+
+    #[test]
+    fn same_seed_same_layout() {
+        let styles = [
+            Hallways::default(),
+            Hallways {
+                style: HallwayStyle::Diagonal,
+                width: HallwayWidth::Double,
+            },
+        ];
+        for hallways in styles {
+            let layout = || {
+                let mut rng = StdRng::seed_from_u64(42);
+                generate(100, LEVEL_SIZE, &mut rng, 1, 1, 3, hallways).unwrap()
+            };
+            let (first, second) = (layout(), layout());
+
+            assert!(first.grid.iter().eq(second.grid.iter()));
+            assert_eq!(first.rooms, second.rooms);
+            assert_eq!(first.exits.upstairs, second.exits.upstairs);
+            assert_eq!(first.exits.downstairs, second.exits.downstairs);
+            assert_eq!(first.treasure, second.treasure);
+        }
+    }
+
+    #[test]
+    fn all_floor_is_reachable_from_the_upstairs() {
+        for style in [HallwayStyle::Orthogonal, HallwayStyle::Diagonal] {
+            let hallways = Hallways {
+                style,
+                ..Hallways::default()
+            };
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let layout = generate(100, LEVEL_SIZE, &mut rng, 1, 1, 3, hallways).unwrap();
+                let (x, y) = layout.exits.upstairs[0];
+                let start = (x as usize, y as usize);
+                let reachable = connected_components(&layout.grid, |&tile| is_passable(tile))
+                    .into_iter()
+                    .find(|component| component.contains(&start))
+                    .unwrap();
+
+                for y in 0..layout.grid.rows() {
+                    for x in 0..layout.grid.cols() {
+                        if layout.grid[y][x] == DungeonTile::Floor {
+                            assert!(
+                                reachable.contains(&(x, y)),
+                                "{:?} is cut off with {:?} hallways on seed {}",
+                                (x, y),
+                                style,
+                                seed
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-        if grid[y][x] == DungeonTile::Floor {
-            break (x as _, y as _);
+    #[test]
+    fn wide_hallways_connect_every_room() {
+        for style in [HallwayStyle::Orthogonal, HallwayStyle::Diagonal] {
+            let hallways = Hallways {
+                style,
+                width: HallwayWidth::Double,
+            };
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let layout = generate(100, LEVEL_SIZE, &mut rng, 1, 1, 3, hallways).unwrap();
+                let components = connected_components(&layout.grid, |&tile| is_passable(tile));
+                let component_of = |cell: (usize, usize)| {
+                    components
+                        .iter()
+                        .position(|component| component.contains(&cell))
+                };
+
+                let mut rooms: Vec<(usize, Option<usize>)> = Vec::new();
+                for y in 0..layout.grid.rows() {
+                    for x in 0..layout.grid.cols() {
+                        let room = match layout.rooms[y][x] {
+                            Some(room) if is_passable(layout.grid[y][x]) => room,
+                            _ => continue,
+                        };
+                        if !rooms.iter().any(|&(other, _)| other == room) {
+                            rooms.push((room, component_of((x, y))));
+                        }
+                    }
+                }
+
+                assert!(rooms.len() > 1, "only one room on seed {}", seed);
+                for &(room, component) in &rooms {
+                    assert_eq!(
+                        component, rooms[0].1,
+                        "room {} is cut off from room {} with {:?} hallways on seed {}",
+                        room, rooms[0].0, style, seed
+                    );
+                }
+            }
+        }
+    }
+
+    /// Digs an orthogonal hallway between two 3x3 rooms through stone
+    /// that's as cheap as it can be, and returns how many tiles of
+    /// stone it cut through.
+    fn hallway_length(a: RoomBounds, b: RoomBounds) -> usize {
+        let mut grid = Grid::init(12, 30, DungeonTile::Wall);
+        for tile in a.tiles().chain(b.tiles()) {
+            grid[tile.1][tile.0] = DungeonTile::Floor;
         }
+        let stone_weights = Grid::init(12, 30, MIN_STONE_COST);
+        let mut rng = StdRng::seed_from_u64(0);
+        let rooms = [a, b];
+        dig_hallway(
+            &mut grid,
+            &rooms,
+            &stone_weights,
+            &rooms[0],
+            &rooms[1],
+            Hallways::default(),
+            &mut rng,
+        );
+
+        (0..grid.rows())
+            .flat_map(|y| (0..grid.cols()).map(move |x| (x, y)))
+            .filter(|&tile| !rooms.iter().any(|room| room.contains(tile)))
+            .filter(|&(x, y)| grid[y][x] != DungeonTile::Wall)
+            .count()
+    }
+
+    #[test]
+    fn cheapest_stone_still_gives_shortest_hallway() {
+        // With every tile of stone costing as little as possible, the
+        // heuristic is as close to the real cost as it gets, so this
+        // is where an overestimate would first send A* the long way.
+        assert_eq!(hallway_length(room(2, 3, 3, 3), room(20, 3, 3, 3)), 15);
+        assert_eq!(hallway_length(room(2, 2, 3, 3), room(20, 6, 3, 3)), 17);
     }
 }
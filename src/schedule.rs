@@ -0,0 +1,96 @@
+//! The turn schedule, which decides who acts when.
+
+use std::collections::{BTreeMap, HashSet};
+
+use specs::prelude::*;
+
+/// How many turns the player has taken. This is how long the game has
+/// gone on for as far as the player can tell, whereas the `Schedule`'s
+/// time passes more or less quickly between their turns depending on
+/// how fast they are. It's moved on by `TimeSystem` whenever the
+/// player takes a turn, and by nothing else.
+#[derive(Default)]
+pub struct GameClock {
+    pub turns: u32,
+}
+
+/// A priority queue of entities, ordered by the time at which they
+/// next take a turn.
+#[derive(Default)]
+pub struct Schedule {
+    /// The current in-game time.
+    now: u32,
+
+    /// The entities waiting for a turn, keyed by the time of that
+    /// turn. Entities scheduled for the same time act together.
+    queue: BTreeMap<u32, Vec<Entity>>,
+
+    /// Every entity that's somewhere in `queue`.
+    scheduled: HashSet<Entity>,
+
+    /// The entities taking their turn right now.
+    acting: Vec<Entity>,
+}
+
+impl Schedule {
+    /// The current in-game time.
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    /// Whether the entity is already waiting for a turn.
+    pub fn contains(&self, ent: Entity) -> bool {
+        self.scheduled.contains(&ent)
+    }
+
+    /// Schedules the entity to take a turn at the given time, or right
+    /// away if that time has already passed.
+    pub fn insert(&mut self, ent: Entity, time: u32) {
+        let time = time.max(self.now);
+        if self.scheduled.insert(ent) {
+            self.queue.entry(time).or_default().push(ent);
+        }
+    }
+
+    /// Moves an entity that's already waiting for a turn to a new
+    /// time, as with `insert`.
+    pub fn reschedule(&mut self, ent: Entity, time: u32) {
+        if self.scheduled.remove(&ent) {
+            self.queue.retain(|_time, ents| {
+                ents.retain(|other| *other != ent);
+                !ents.is_empty()
+            });
+        }
+        self.insert(ent, time);
+    }
+
+    /// The entities that will take the next turn, in the order they
+    /// were scheduled.
+    pub fn up_next(&self) -> &[Entity] {
+        self.queue
+            .first_key_value()
+            .map(|(_time, ents)| ents.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Advances time to the next scheduled turn, and returns the
+    /// entities taking it. The caller is responsible for scheduling
+    /// them again.
+    pub fn advance(&mut self) -> &[Entity] {
+        self.acting.clear();
+        if let Some((time, ents)) = self.queue.pop_first() {
+            self.now = time;
+            for ent in &ents {
+                self.scheduled.remove(ent);
+            }
+            self.acting = ents;
+        }
+
+        &self.acting
+    }
+
+    /// Whether the entity is taking its turn right now.
+    pub fn is_acting(&self, ent: Entity) -> bool {
+        self.acting.contains(&ent)
+    }
+}
@@ -0,0 +1,264 @@
+//! The high-score table, kept in a file between runs.
+
+use std::{
+    cmp::Reverse,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use specs::prelude::*;
+use thiserror::Error;
+
+use crate::{
+    components::Player,
+    events::{Events, GameEvent},
+};
+
+/// The most entries the high-score file keeps; anything below them
+/// falls off the bottom.
+pub const MAX_SCORES: usize = 10;
+
+/// Points for each piece of gold the player was carrying.
+const POINTS_PER_GOLD: u32 = 1;
+
+/// Points for each level of the dungeon the player got down to.
+const POINTS_PER_DEPTH: u32 = 50;
+
+/// The number of turns the player has to survive for each point.
+const TURNS_PER_POINT: u32 = 10;
+
+/// How many times to try taking the lock on the score file before
+/// giving up, and how long to wait between tries.
+const LOCK_ATTEMPTS: u32 = 20;
+const LOCK_RETRY: Duration = Duration::from_millis(50);
+
+/// One finished run in the high-score table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreEntry {
+    /// Whose run it was.
+    pub name: String,
+
+    /// How well they did.
+    pub score: u32,
+
+    /// The deepest level they reached, counting the top as 1.
+    pub depth: usize,
+
+    /// How the run ended, as in "killed by the goblin".
+    pub cause: String,
+}
+
+impl ScoreEntry {
+    /// The entry as a line of the score file, without the newline.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.score, self.depth, self.name, self.cause
+        )
+    }
+
+    /// Reads an entry back from a line of the score file, or `None` if
+    /// it's not one.
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let score = fields.next()?.parse().ok()?;
+        let depth = fields.next()?.parse().ok()?;
+        let name = fields.next()?.to_string();
+        let cause = fields.next()?.to_string();
+        if fields.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            name,
+            score,
+            depth,
+            cause,
+        })
+    }
+}
+
+/// Makes text safe to store as one field of a line in the score file.
+fn field(text: &str) -> String {
+    text.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Works out the score for a run.
+pub fn score(gold: u32, depth: usize, turns: u32) -> u32 {
+    gold * POINTS_PER_GOLD + depth as u32 * POINTS_PER_DEPTH + turns / TURNS_PER_POINT
+}
+
+#[derive(Error, Debug)]
+pub enum ScoreError {
+    #[error("{0} is locked by another game; if none is running, delete it")]
+    Locked(PathBuf),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The high-score file, which several games might share.
+pub struct HighScores {
+    path: PathBuf,
+}
+
+impl HighScores {
+    /// The high-score file at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The high-score file's usual home: in the user's home directory,
+    /// or the current one if they don't have one.
+    pub fn default_path() -> PathBuf {
+        match std::env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".dungeon_game_scores"),
+            None => PathBuf::from("dungeon_game_scores"),
+        }
+    }
+
+    /// The entries in the file, best first. A missing file has no
+    /// entries, and so does a broken one, which gets replaced the next
+    /// time a score is recorded.
+    pub fn load(&self) -> Vec<ScoreEntry> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(_) => return Vec::new(),
+        };
+
+        let entries: Option<Vec<ScoreEntry>> = contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ScoreEntry::from_line)
+            .collect();
+        let mut entries = entries.unwrap_or_default();
+        entries.sort_by_key(|entry| Reverse(entry.score));
+        entries.truncate(MAX_SCORES);
+        entries
+    }
+
+    /// Adds an entry to the file, and returns the entries now in it,
+    /// along with where the new one ended up if it made the cut.
+    pub fn record(
+        &self,
+        entry: ScoreEntry,
+    ) -> Result<(Vec<ScoreEntry>, Option<usize>), ScoreError> {
+        let _lock = ScoreLock::take(&self.path)?;
+        let entry = ScoreEntry {
+            name: field(&entry.name),
+            cause: field(&entry.cause),
+            ..entry
+        };
+
+        let mut entries = self.load();
+        // Ties go to whoever got there first.
+        let rank = entries
+            .iter()
+            .position(|other| other.score < entry.score)
+            .unwrap_or(entries.len());
+        entries.insert(rank, entry);
+        entries.truncate(MAX_SCORES);
+
+        // Write the new table out to the side and move it into place,
+        // so that the old one is never left half-overwritten.
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut temp = File::create(&temp_path)?;
+        for entry in &entries {
+            writeln!(temp, "{}", entry.to_line())?;
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        let rank = Some(rank).filter(|&rank| rank < entries.len());
+        Ok((entries, rank))
+    }
+}
+
+/// Stops two games from rewriting the score file at once. The lock is
+/// a file next to the score file, which exists for as long as this is
+/// held.
+struct ScoreLock {
+    path: PathBuf,
+}
+
+impl ScoreLock {
+    /// Takes the lock on the score file at `scores`, waiting a little
+    /// while if another game has it.
+    fn take(scores: &Path) -> Result<Self, ScoreError> {
+        let mut path = scores.to_path_buf().into_os_string();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        for _ in 0..LOCK_ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self { path }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => thread::sleep(LOCK_RETRY),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(ScoreError::Locked(path))
+    }
+}
+
+impl Drop for ScoreLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// What's needed to fill in the player's entry in the high-score table
+/// once the run is over.
+pub struct RunStats {
+    /// The player's name.
+    pub name: String,
+
+    /// The deepest level the player has reached, counting the top as
+    /// 1.
+    pub deepest: usize,
+
+    /// How the player died, once they have.
+    pub cause: Option<String>,
+}
+
+impl RunStats {
+    /// The stats for a run that's just starting, on the top level.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            deepest: 1,
+            cause: None,
+        }
+    }
+
+    /// Notes that the player has made it to a level at the given depth.
+    pub fn reached(&mut self, depth: usize) {
+        self.deepest = self.deepest.max(depth);
+    }
+}
+
+/// System for noting down how the player's run ended. This has to run
+/// before `NarrationSystem`, which drains the events it reads.
+pub struct ScoreSystem;
+
+impl<'a> System<'a> for ScoreSystem {
+    type SystemData = (
+        ReadExpect<'a, Events>,
+        WriteExpect<'a, RunStats>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(&mut self, (events, mut stats, players): Self::SystemData) {
+        for event in events.pending() {
+            if let GameEvent::EntityDied { entity, cause, .. } = event {
+                if players.contains(*entity) && stats.cause.is_none() {
+                    stats.cause = Some(cause.clone());
+                }
+            }
+        }
+    }
+}
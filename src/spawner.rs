@@ -0,0 +1,223 @@
+//! Code for bringing new monsters onto the level as time goes on, so
+//! that resting in one spot isn't free.
+
+use pathfinding::directed::bfs::bfs_reach;
+use rand::Rng;
+use specs::prelude::*;
+
+use crate::{
+    components::{Monster, Player, Position},
+    ids::{create_entity_lazy, IdAllocator},
+    index::PositionIndex,
+    level::{in_bounds, DungeonLevel},
+    monsters::MonsterKind,
+    schedule::GameClock,
+    util::GameRng,
+};
+
+/// How many tries we get at finding somewhere out of sight for a new
+/// monster before giving up until the next spawn.
+const SPAWN_ATTEMPTS: usize = 20;
+
+/// Picks out cells for a pack of up to `count` monsters, starting with
+/// `seed` and spreading out from there through cells that `free` says
+/// have room, so that the pack sticks together. The nearest cells come
+/// first. There are fewer than `count` if the pack is hemmed in, and
+/// none if there's no room at `seed` itself.
+pub fn pack_cells(
+    seed: (i32, i32),
+    count: usize,
+    free: impl Fn((i32, i32)) -> bool,
+) -> Vec<(i32, i32)> {
+    if !free(seed) {
+        return Vec::new();
+    }
+
+    bfs_reach(seed, |&(x, y)| {
+        (-1..=1)
+            .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            .filter(|&cell| free(cell))
+            .collect::<Vec<_>>()
+    })
+    .take(count)
+    .collect()
+}
+
+/// When new monsters show up, if at all.
+pub struct Spawner {
+    /// The number of the player's turns between new monsters, or
+    /// `None` if monsters are only placed when a level is first
+    /// populated.
+    interval: Option<u32>,
+
+    /// The most monsters there can be on a level before spawning
+    /// stops.
+    cap: usize,
+
+    /// The `GameClock` turn on which the next monster is due.
+    next: u32,
+}
+
+impl Spawner {
+    /// Creates a spawner that brings in a monster every `interval`
+    /// turns, as long as there are fewer than `cap` on the level.
+    pub fn new(interval: Option<u32>, cap: usize) -> Self {
+        Self {
+            interval,
+            cap,
+            next: interval.unwrap_or(0),
+        }
+    }
+}
+
+/// System for periodically spawning monsters somewhere the player
+/// can't see.
+pub struct SpawnerSystem;
+
+impl<'a> System<'a> for SpawnerSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Monster>,
+        ReadExpect<'a, GameClock>,
+        ReadExpect<'a, DungeonLevel>,
+        ReadExpect<'a, PositionIndex>,
+        WriteExpect<'a, Spawner>,
+        Read<'a, LazyUpdate>,
+        ReadExpect<'a, IdAllocator>,
+        WriteExpect<'a, GameRng>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            players,
+            positions,
+            monsters,
+            clock,
+            level,
+            index,
+            mut spawner,
+            lazy,
+            ids,
+            mut rng,
+        ): Self::SystemData,
+    ) {
+        let interval = match spawner.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if clock.turns < spawner.next {
+            return;
+        }
+        spawner.next = clock.turns.saturating_add(interval);
+
+        let count = monsters.join().count();
+        if count >= spawner.cap {
+            return;
+        }
+
+        let in_view: Vec<(i32, i32)> = (&players, &positions)
+            .join()
+            .flat_map(|(_plr, pos)| level.visible_from(pos.into()))
+            .collect();
+
+        let rng = &mut rng.0;
+        for _ in 0..SPAWN_ATTEMPTS {
+            let kind = MonsterKind::random(rng);
+            let (x, y) = level.empty_square(rng);
+            let free = |(x, y): (i32, i32)| {
+                in_bounds((x, y))
+                    && level.tile(x, y).is_navigable()
+                    && index.entities_at(x, y).is_empty()
+                    && !in_view.contains(&(x, y))
+            };
+            if kind.size().cells((x, y)).all(free) {
+                let pack = rng.gen_range(kind.pack_size()).min(spawner.cap - count);
+                for cell in pack_cells((x, y), pack, free) {
+                    kind.build(create_entity_lazy(&lazy, &entities, &ids), cell);
+                }
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        level::chebyshev,
+        schedule::Schedule,
+        testing::{add_player, world_with},
+    };
+
+    #[test]
+    fn packs_stick_together() {
+        let pack = pack_cells((5, 5), 4, |_cell| true);
+        assert_eq!(pack.len(), 4);
+        assert_eq!(pack[0], (5, 5));
+        for (i, &cell) in pack.iter().enumerate() {
+            assert!(!pack[..i].contains(&cell), "{:?} is taken twice", cell);
+            assert_eq!(chebyshev(cell, (5, 5)), if i == 0 { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn packs_stay_out_of_taken_cells() {
+        // A corridor running right from the seed, with its second cell
+        // already taken, so only the seed itself is reachable.
+        let free = |(x, y): (i32, i32)| y == 0 && (0..10).contains(&x) && x != 1;
+        assert_eq!(pack_cells((0, 0), 4, free), [(0, 0)]);
+
+        // Further along there's room for the whole pack, in a line
+        // with the seed as near the middle as it can be.
+        let pack = pack_cells((5, 0), 4, free);
+        assert_eq!(pack.len(), 4);
+        assert!([(4, 0), (5, 0), (6, 0)]
+            .iter()
+            .all(|cell| pack.contains(cell)));
+        assert!(pack.iter().all(|&cell| chebyshev(cell, (5, 0)) <= 2));
+
+        assert!(pack_cells((1, 0), 4, free).is_empty());
+    }
+
+    #[test]
+    fn spawns_go_by_the_clock() {
+        // The player is shut away on their own, so there's plenty of
+        // room out of their sight.
+        let mut world = world_with(
+            "\
+            ---\n\
+            |.|\n\
+            ---------\n\
+            |.......|\n\
+            |.......|\n\
+            ---------",
+        );
+        let player = add_player(&mut world, (1, 1));
+        world.insert(Spawner::new(Some(3), 50));
+        let monsters_on_turn = |world: &mut World, turn: u32| {
+            world.fetch_mut::<GameClock>().turns = turn;
+            SpawnerSystem.run_now(world);
+            world.maintain();
+            world.read_storage::<Monster>().count()
+        };
+
+        // However much time passes between the player's turns, only
+        // the turns themselves count.
+        {
+            let mut schedule = world.fetch_mut::<Schedule>();
+            schedule.reschedule(player, 1000);
+            schedule.advance();
+        }
+        assert_eq!(monsters_on_turn(&mut world, 2), 0);
+
+        let first = monsters_on_turn(&mut world, 3);
+        assert!(first > 0);
+        assert_eq!(monsters_on_turn(&mut world, 5), first);
+        assert!(monsters_on_turn(&mut world, 6) > first);
+    }
+}
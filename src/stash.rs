@@ -0,0 +1,369 @@
+//! Code for taking a level's entities out of the world while the
+//! player is somewhere else, and putting them back when they return.
+
+use specs::prelude::*;
+
+use crate::{
+    components::{
+        Armor, Attack, CanOpenDoors, CharRender, Confused, Defense, Faction, Flying, Gold, Health,
+        Item, LightSource, MobAction, Mobile, Monster, Name, Player, Position, Size, TurnTaker,
+        Warm, Weapon,
+    },
+    ids::EntityId,
+    monsters::MonsterKind,
+    schedule::Schedule,
+};
+
+/// Everything that was on a level when the player left it.
+pub struct Stash {
+    entities: Vec<StashedEntity>,
+}
+
+/// The components of a single entity that's been taken out of the
+/// world.
+struct StashedEntity {
+    /// The entity's ID, which it keeps when it's restored, so that
+    /// anything that refers to it by ID still finds it.
+    id: Option<EntityId>,
+    position: Position,
+    render: Option<CharRender>,
+    gold: Option<Gold>,
+    item: Option<Item>,
+    weapon: Option<Weapon>,
+    armor: Option<Armor>,
+    health: Option<Health>,
+    attack: Option<Attack>,
+    defense: Option<Defense>,
+    monster: Option<Monster>,
+    faction: Option<Faction>,
+    name: Option<Name>,
+    mobile: bool,
+    turn: Option<TurnTaker>,
+    flying: bool,
+    warm: bool,
+    size: Option<Size>,
+    light: Option<LightSource>,
+    can_open_doors: bool,
+    confused: Option<Confused>,
+}
+
+impl Stash {
+    /// Takes every entity on the current level out of the world.
+    /// Players, and anything that isn't lying on the level (e.g.,
+    /// items that someone is carrying), are left alone. Time stands
+    /// still for the stashed entities until they're restored.
+    pub fn take(world: &mut World) -> Self {
+        let now = world.fetch::<Schedule>().now();
+        let on_level: Vec<Entity> = {
+            let entities = world.entities();
+            let positions = world.read_storage::<Position>();
+            let players = world.read_storage::<Player>();
+            (&entities, &positions, !&players)
+                .join()
+                .map(|(ent, _pos, ())| ent)
+                .collect()
+        };
+
+        let entities = on_level
+            .into_iter()
+            .map(|ent| {
+                let stashed = StashedEntity {
+                    id: take(world, ent),
+                    position: take(world, ent).expect("Entity must have a position"),
+                    render: take(world, ent),
+                    gold: take(world, ent),
+                    item: take(world, ent),
+                    weapon: take(world, ent),
+                    armor: take(world, ent),
+                    health: take(world, ent),
+                    attack: take(world, ent),
+                    defense: take(world, ent),
+                    monster: take(world, ent),
+                    faction: take(world, ent),
+                    name: take(world, ent),
+                    mobile: take::<Mobile>(world, ent).is_some(),
+                    turn: take::<TurnTaker>(world, ent).map(|turn| TurnTaker {
+                        next: turn.next.saturating_sub(now),
+                        ..turn
+                    }),
+                    flying: take::<Flying>(world, ent).is_some(),
+                    warm: take::<Warm>(world, ent).is_some(),
+                    size: take(world, ent),
+                    light: take(world, ent),
+                    can_open_doors: take::<CanOpenDoors>(world, ent).is_some(),
+                    confused: take(world, ent),
+                };
+                world.delete_entity(ent).expect("Entity must be alive");
+
+                stashed
+            })
+            .collect();
+
+        Self { entities }
+    }
+
+    /// Throws away every monster of the given kind in the stash, and
+    /// returns how many there were.
+    pub fn remove_monsters(&mut self, kind: MonsterKind) -> usize {
+        let before = self.entities.len();
+        self.entities.retain(|stashed| {
+            stashed
+                .monster
+                .as_ref()
+                .is_none_or(|monster| monster.kind != kind)
+        });
+        before - self.entities.len()
+    }
+
+    /// Puts everything in the stash back into the world.
+    pub fn restore(self, world: &mut World) {
+        let now = world.fetch::<Schedule>().now();
+        for stashed in self.entities {
+            let mut builder = world.create_entity().with(stashed.position);
+            if let Some(id) = stashed.id {
+                builder = builder.with(id);
+            }
+            if let Some(render) = stashed.render {
+                builder = builder.with(render);
+            }
+            if let Some(gold) = stashed.gold {
+                builder = builder.with(gold);
+            }
+            if let Some(item) = stashed.item {
+                builder = builder.with(item);
+            }
+            if let Some(weapon) = stashed.weapon {
+                builder = builder.with(weapon);
+            }
+            if let Some(armor) = stashed.armor {
+                builder = builder.with(armor);
+            }
+            if let Some(health) = stashed.health {
+                builder = builder.with(health);
+            }
+            if let Some(attack) = stashed.attack {
+                builder = builder.with(attack);
+            }
+            if let Some(defense) = stashed.defense {
+                builder = builder.with(defense);
+            }
+            if let Some(monster) = stashed.monster {
+                // Nor do the paths it was following.
+                builder = builder.with(Monster {
+                    path: Vec::new(),
+                    ..monster
+                });
+            }
+            if let Some(faction) = stashed.faction {
+                builder = builder.with(faction);
+            }
+            if let Some(name) = stashed.name {
+                builder = builder.with(name);
+            }
+            if stashed.mobile {
+                // Whatever the mob was about to do no longer makes
+                // sense.
+                builder = builder.with(Mobile {
+                    next_action: MobAction::Nop,
+                    last_outcome: None,
+                });
+            }
+            if let Some(turn) = stashed.turn {
+                builder = builder.with(TurnTaker {
+                    next: now + turn.next,
+                    ..turn
+                });
+            }
+            if stashed.flying {
+                builder = builder.with(Flying);
+            }
+            if stashed.warm {
+                builder = builder.with(Warm);
+            }
+            if let Some(size) = stashed.size {
+                builder = builder.with(size);
+            }
+            if let Some(light) = stashed.light {
+                builder = builder.with(light);
+            }
+            if stashed.can_open_doors {
+                builder = builder.with(CanOpenDoors);
+            }
+            if let Some(confused) = stashed.confused {
+                builder = builder.with(confused);
+            }
+            builder.build();
+        }
+    }
+}
+
+/// Removes a component from an entity, returning it if it was there.
+fn take<T: Component>(world: &World, ent: Entity) -> Option<T> {
+    world.write_storage::<T>().remove(ent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        components::{ItemCategory, ItemEffect},
+        io::Color,
+        testing::empty_world,
+    };
+
+    #[test]
+    fn everything_survives_a_round_trip() {
+        let mut world = empty_world();
+        world
+            .create_entity()
+            .with(EntityId(17))
+            .with(Position { x: 3, y: 4 })
+            .with(CharRender {
+                glyph: 'Z',
+                color: Color::Green,
+            })
+            .with(Health {
+                current: 3,
+                maximum: 8,
+            })
+            .with(Attack {
+                damage: 2,
+                knockback: true,
+            })
+            .with(Defense { armor: 1 })
+            .with(Monster {
+                kind: MonsterKind::Zombie,
+                path: vec![(4, 4)],
+                interest: 5,
+            })
+            .with(Faction::Hostile)
+            .with(Name {
+                name: "Bob".to_string(),
+            })
+            .with(Mobile {
+                next_action: MobAction::Nop,
+                last_outcome: None,
+            })
+            .with(TurnTaker {
+                next: 7,
+                maximum: 12,
+            })
+            .with(Flying)
+            .with(Warm)
+            .with(Size {
+                width: 2,
+                height: 1,
+            })
+            .with(LightSource {
+                radius: 3,
+                color: Color::Red,
+                flickers: true,
+            })
+            .with(CanOpenDoors)
+            .with(Confused { turns: 4 })
+            .build();
+        world
+            .create_entity()
+            .with(Position { x: 5, y: 6 })
+            .with(Gold { amount: 9 })
+            .with(Item {
+                name: "sword",
+                category: ItemCategory::Weapon,
+                effect: ItemEffect::Equip,
+            })
+            .with(Weapon { damage: 3 })
+            .with(Armor { defense: 2 })
+            .build();
+
+        let stash = Stash::take(&mut world);
+        world.maintain();
+        assert_eq!(world.read_storage::<Position>().count(), 0);
+        stash.restore(&mut world);
+        world.maintain();
+
+        let entities = world.entities();
+        let positions = world.read_storage::<Position>();
+        let find = |x, y| {
+            (&entities, &positions)
+                .join()
+                .find(|(_, pos)| (pos.x, pos.y) == (x, y))
+                .map(|(ent, _)| ent)
+                .expect("Entity should be back where it was")
+        };
+
+        let monster = find(3, 4);
+        assert_eq!(
+            world.read_storage::<EntityId>().get(monster),
+            Some(&EntityId(17))
+        );
+        assert_eq!(
+            world
+                .read_storage::<CharRender>()
+                .get(monster)
+                .unwrap()
+                .glyph,
+            'Z'
+        );
+        assert_eq!(
+            world.read_storage::<Health>().get(monster).unwrap().current,
+            3
+        );
+        assert!(
+            world
+                .read_storage::<Attack>()
+                .get(monster)
+                .unwrap()
+                .knockback
+        );
+        assert_eq!(
+            world.read_storage::<Defense>().get(monster).unwrap().armor,
+            1
+        );
+        let monsters = world.read_storage::<Monster>();
+        let stashed = monsters.get(monster).unwrap();
+        assert_eq!(stashed.kind, MonsterKind::Zombie);
+        assert_eq!(stashed.interest, 5);
+        assert_eq!(
+            world.read_storage::<Faction>().get(monster),
+            Some(&Faction::Hostile)
+        );
+        assert_eq!(
+            world.read_storage::<Name>().get(monster).unwrap().name,
+            "Bob"
+        );
+        assert!(world.read_storage::<Mobile>().contains(monster));
+        let turns = world.read_storage::<TurnTaker>();
+        let turn = turns.get(monster).unwrap();
+        assert_eq!((turn.next, turn.maximum), (7, 12));
+        assert!(world.read_storage::<Flying>().contains(monster));
+        assert!(world.read_storage::<Warm>().contains(monster));
+        assert_eq!(
+            world.read_storage::<Size>().get(monster),
+            Some(&Size {
+                width: 2,
+                height: 1
+            })
+        );
+        assert!(
+            world
+                .read_storage::<LightSource>()
+                .get(monster)
+                .unwrap()
+                .flickers
+        );
+        assert!(world.read_storage::<CanOpenDoors>().contains(monster));
+        assert_eq!(
+            world.read_storage::<Confused>().get(monster).unwrap().turns,
+            4
+        );
+
+        let item = find(5, 6);
+        assert_eq!(world.read_storage::<Gold>().get(item).unwrap().amount, 9);
+        assert_eq!(
+            world.read_storage::<Item>().get(item).unwrap().name,
+            "sword"
+        );
+        assert_eq!(world.read_storage::<Weapon>().get(item).unwrap().damage, 3);
+        assert_eq!(world.read_storage::<Armor>().get(item).unwrap().defense, 2);
+    }
+}
@@ -1,24 +1,191 @@
 //! ECS systems.
 
-use specs::prelude::*;
+use std::ops::{Deref, RangeInclusive};
+
+use rand::Rng;
+use specs::{prelude::*, storage::MaskedStorage};
 
 use crate::{
-    components::{MobAction, Mobile, Player, Position, TurnTaker},
-    level::DungeonLevel,
+    ai::MonsterAiSystem,
+    branch::{PendingTravel, Travel},
+    components::{
+        ActionOutcome, Armor, Attack, CharRender, Confused, Defense, Detected, Equipment, Faction,
+        Flying, Gold, Health, Injured, Inventory, Item, ItemCategory, ItemEffect, MobAction,
+        Mobile, Monster, Name, Player, Position, Size, Splash, TurnTaker, Weapon,
+    },
+    events::{Events, GameEvent},
+    ids::{create_entity_lazy, IdAllocator},
+    index::PositionIndex,
+    io::Color,
+    level::{
+        chebyshev, clamp_to_bounds, in_bounds, offset, DungeonLevel, DungeonTile, PlayerView,
+        LEVEL_SIZE,
+    },
+    messages::{capitalize, MessageLog, Severity},
+    player::find_player,
+    schedule::{GameClock, Schedule},
+    spawner::SpawnerSystem,
+    util::GameRng,
 };
 
-/// System for ticking the turn counter on every entity; this system
-/// implements the relationship between real-world time and in-game
-/// time.
+/// Probability that searching finds a particular hidden feature
+/// adjacent to the searcher.
+const SEARCH_CHANCE: f64 = 1.0 / 3.0;
+
+/// Damage dealt to a creature that's knocked back into something
+/// solid.
+const COLLISION_DAMAGE: i32 = 2;
+
+/// The furthest a mob can blink in one go.
+pub const BLINK_RANGE: i32 = 6;
+
+/// Probability that a confused creature staggers in a random direction
+/// instead of the one it meant to move in.
+const STAGGER_CHANCE: f64 = 0.5;
+
+/// The possible amounts of health restored by drinking from a
+/// fountain.
+const FOUNTAIN_HEALING: RangeInclusive<i32> = 3..=8;
+
+/// The possible numbers of actions that confusion from a fountain
+/// lasts for.
+const FOUNTAIN_CONFUSION: RangeInclusive<u32> = 5..=10;
+
+/// Probability that a fountain dries up after each drink.
+const FOUNTAIN_DRY_CHANCE: f64 = 1.0 / 3.0;
+
+/// How much health eating a corpse restores.
+const CORPSE_HEALING: RangeInclusive<i32> = 1..=3;
+
+/// How many tries a scroll of teleportation gets at finding an empty
+/// square, before it fizzles.
+const TELEPORT_ATTEMPTS: usize = 100;
+
+/// How far from where it lands a thrown potion splashes.
+const SPLASH_RADIUS: i32 = 1;
+
+/// Damage dealt to each creature splashed by a potion of fire.
+const SPLASH_FIRE_DAMAGE: RangeInclusive<i32> = 2..=6;
+
+/// How much damage a creature takes for each action it ends in lava.
+const LAVA_DAMAGE: RangeInclusive<i32> = 5..=10;
+
+/// How many actions a potion of confusion leaves each creature it
+/// splashes confused for.
+const SPLASH_CONFUSION: RangeInclusive<u32> = 4..=8;
+
+/// The fraction of their maximum health below which players are
+/// slowed down by their wounds.
+const INJURY_THRESHOLD: f64 = 0.25;
+
+/// How much longer injured players take between turns.
+const INJURY_DELAY: u32 = 5;
+
+/// System for advancing in-game time to the next scheduled turn; the
+/// entities taking that turn are rescheduled `maximum` time units
+/// later.
 pub struct TimeSystem;
 
 impl<'a> System<'a> for TimeSystem {
-    type SystemData = WriteStorage<'a, TurnTaker>;
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, TurnTaker>,
+        WriteExpect<'a, Schedule>,
+        WriteExpect<'a, GameClock>,
+        ReadStorage<'a, Player>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut turn_takers, mut schedule, mut clock, players): Self::SystemData,
+    ) {
+        // Pick up any turn takers that were created since last time.
+        for (ent, turn) in (&entities, &turn_takers).join() {
+            if !schedule.contains(ent) {
+                schedule.insert(ent, turn.next);
+            }
+        }
 
-    fn run(&mut self, mut turn_takers: Self::SystemData) {
-        for ent in (&mut turn_takers).join() {
-            ent.next = ent.next.checked_sub(1).unwrap_or(ent.maximum);
+        let acting = schedule.advance().to_vec();
+        let now = schedule.now();
+        if acting.iter().any(|ent| players.contains(*ent)) {
+            clock.turns += 1;
         }
+
+        // Entities that died or stopped taking turns while they were
+        // waiting just drop out of the schedule.
+        for ent in acting {
+            if let Some(turn) = turn_takers.get_mut(ent) {
+                turn.next = now + turn.maximum;
+                schedule.insert(ent, turn.next);
+            }
+        }
+    }
+}
+
+/// System for catching entities that have somehow ended up outside
+/// the level, and putting them back inside it.
+pub struct BoundsSystem;
+
+impl<'a> System<'a> for BoundsSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        WriteExpect<'a, MessageLog>,
+    );
+
+    fn run(&mut self, (entities, mut pos, mut log): Self::SystemData) {
+        for (ent, pos) in (&entities, &mut pos).join() {
+            let cell = (pos.x, pos.y);
+            if !in_bounds(cell) {
+                // This is always a bug somewhere else. It goes in the
+                // log rather than on stderr, which would scribble all
+                // over the screen.
+                log.log(format!(
+                    "Bug: entity {} was out of bounds at {:?}; putting it back.",
+                    ent.id(),
+                    cell
+                ));
+                *pos = clamp_to_bounds(cell).into();
+            }
+        }
+    }
+}
+
+/// System for rebuilding the index of which entities are where.
+pub struct IndexSystem;
+
+impl<'a> System<'a> for IndexSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadStorage<'a, Size>,
+        WriteExpect<'a, PositionIndex>,
+    );
+
+    fn run(&mut self, (entities, pos, sizes, mut index): Self::SystemData) {
+        index.rebuild((&entities, &pos, sizes.maybe()).join());
+    }
+}
+
+/// System for working out what the player can see before anyone acts.
+pub struct ViewSystem;
+
+impl<'a> System<'a> for ViewSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Position>,
+        ReadExpect<'a, DungeonLevel>,
+        WriteExpect<'a, PlayerView>,
+    );
+
+    fn run(&mut self, (entities, players, pos, level, mut view): Self::SystemData) {
+        let player = find_player(&entities, &players).ok();
+        *view = match player.and_then(|player| pos.get(player)) {
+            Some(pos) => PlayerView::new(&level, pos.into()),
+            None => PlayerView::default(),
+        };
     }
 }
 
@@ -27,57 +194,1743 @@ pub struct MobSystem;
 
 impl<'a> System<'a> for MobSystem {
     type SystemData = (
+        Entities<'a>,
         WriteStorage<'a, Position>,
-        ReadStorage<'a, TurnTaker>,
+        (WriteExpect<'a, Schedule>, WriteStorage<'a, TurnTaker>),
         WriteStorage<'a, Mobile>,
+        WriteStorage<'a, Player>,
+        ReadStorage<'a, Gold>,
+        ReadStorage<'a, Item>,
+        (WriteExpect<'a, DungeonLevel>, WriteExpect<'a, PlayerView>),
+        WriteExpect<'a, MessageLog>,
+        WriteExpect<'a, PositionIndex>,
+        WriteStorage<'a, Health>,
+        ReadStorage<'a, Attack>,
+        ReadStorage<'a, Defense>,
+        ReadStorage<'a, Equipment>,
+        ReadStorage<'a, Weapon>,
+        ReadStorage<'a, Armor>,
+        ReadStorage<'a, Monster>,
+        ReadStorage<'a, Name>,
+        ReadStorage<'a, Flying>,
+        (Read<'a, LazyUpdate>, ReadExpect<'a, IdAllocator>),
+        WriteExpect<'a, PendingTravel>,
+        (WriteStorage<'a, Confused>, WriteStorage<'a, Detected>),
+        ReadStorage<'a, Size>,
+        WriteExpect<'a, Events>,
+        WriteExpect<'a, GameRng>,
+        ReadStorage<'a, Faction>,
     );
 
-    fn run(&mut self, (mut pos, turn, mut mob): Self::SystemData) {
-        for (pos, _turn, mob) in (&mut pos, &turn, &mut mob)
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut pos,
+            (mut schedule, mut turn_takers),
+            mut mobs,
+            mut players,
+            gold,
+            items,
+            (mut level, mut view),
+            mut log,
+            mut index,
+            mut health,
+            attack,
+            defense,
+            equipment,
+            weapons,
+            armors,
+            monsters,
+            names,
+            flying,
+            (lazy, ids),
+            mut travel,
+            (mut confused, mut detected),
+            sizes,
+            mut events,
+            mut rng,
+            factions,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut rng.0;
+        let mut moved = Vec::new();
+
+        // Equipment adds to a creature's own combat stats.
+        let weapon_damage = |ent: Entity| {
+            equipment
+                .get(ent)
+                .and_then(|equipment| equipment.weapon)
+                .and_then(|weapon| weapons.get(weapon))
+                .map_or(0, |weapon| weapon.damage)
+        };
+        let armor_defense = |ent: Entity| {
+            let worn = equipment
+                .get(ent)
+                .and_then(|equipment| equipment.armor)
+                .and_then(|armor| armors.get(armor))
+                .map_or(0, |armor| armor.defense);
+            defense.get(ent).map_or(0, |defense| defense.armor) + worn
+        };
+
+        let size_of = |ent: Entity| sizes.get(ent).copied().unwrap_or_default();
+
+        // Mobs can push each other around, so we look positions up as
+        // we go rather than holding on to them.
+        let acting: Vec<Entity> = (&entities, &pos, &mobs)
             .join()
-            .filter(|(_pos, turn, _mob)| turn.next == 0)
-        {
-            match mob.next_action {
+            .filter(|(ent, _pos, _mob)| schedule.is_acting(*ent))
+            .map(|(ent, _pos, _mob)| ent)
+            .collect();
+
+        // Mobs that fall out of the level are deleted straight away, but
+        // linger in the storages until the world is maintained.
+        let mut fallen = Vec::new();
+
+        for ent in acting {
+            // Mobs killed or lost earlier this turn don't get to act.
+            if health.get(ent).is_some_and(|hp| hp.current <= 0) || fallen.contains(&ent) {
+                continue;
+            }
+
+            let here: (i32, i32) = pos.get(ent).expect("Mob must have a position").into();
+            let mob = mobs.get_mut(ent).expect("Mob must be mobile");
+            let mut action = mob.next_action;
+            let mut outcome = ActionOutcome::Done;
+
+            // Confused mobs sometimes stagger off somewhere other than
+            // where they meant to go, and get a little clearer-headed
+            // with every action.
+            if let Some(confusion) = confused.get_mut(ent) {
+                if matches!(action, MobAction::Move(_, _)) && rng.gen_bool(STAGGER_CHANCE) {
+                    let (dx, dy) = loop {
+                        let delta = (rng.gen_range(-1..=1), rng.gen_range(-1..=1));
+                        if delta != (0, 0) {
+                            break delta;
+                        }
+                    };
+                    let dest = offset(here, (dx, dy));
+                    if in_bounds(dest) && level.tile(dest.0, dest.1).is_navigable() {
+                        action = MobAction::Move(dx, dy);
+                        if players.contains(ent) {
+                            log.log("You stagger around in confusion.");
+                        }
+                    }
+                }
+
+                confusion.turns = confusion.turns.saturating_sub(1);
+                if confusion.turns == 0 {
+                    confused.remove(ent);
+                    if players.contains(ent) {
+                        log.log("You feel less confused now.");
+                    }
+                }
+            }
+
+            match action {
                 MobAction::Nop => {}
                 MobAction::Move(dx, dy) => {
-                    pos.x = pos.x + dx;
-                    pos.y = pos.y + dy;
+                    let from = here;
+                    let dest = offset(here, (dx, dy));
+
+                    // Moving into another creature attacks it, unless
+                    // they're on the same side. The player trades
+                    // places with their allies, but anyone else is just
+                    // blocked by theirs. After an attack the mover
+                    // stays where it is, even if the target dies or is
+                    // knocked back; it takes another move to step into
+                    // the space. Large creatures bump into anything in
+                    // the way of any of their cells.
+                    let size = size_of(ent);
+                    let target = size
+                        .cells(dest)
+                        .flat_map(|(x, y)| index.entities_at(x, y).iter().copied())
+                        .find(|other| *other != ent && health.contains(*other));
+                    let mover_flies = flying.contains(ent);
+                    let fits = !size.is_large()
+                        || size.cells(dest).all(|cell| {
+                            in_bounds(cell)
+                                && level.tile(cell.0, cell.1).move_cost(mover_flies).is_some()
+                        });
+
+                    let allied = |target: Entity| {
+                        factions.get(ent).is_some() && factions.get(ent) == factions.get(target)
+                    };
+                    let swaps = |target: Entity| {
+                        players.contains(ent)
+                            && !size.is_large()
+                            && !size_of(target).is_large()
+                            && level.tile(dest.0, dest.1).move_cost(mover_flies).is_some()
+                            && level
+                                .tile(from.0, from.1)
+                                .move_cost(flying.contains(target))
+                                .is_some()
+                    };
+
+                    match target {
+                        Some(target) if allied(target) && swaps(target) => {
+                            pos.insert(ent, Position::from(dest))
+                                .expect("Mob must be alive");
+                            pos.insert(target, Position::from(from))
+                                .expect("Target must be alive");
+                            index.move_entity(ent, from, dest);
+                            index.move_entity(target, dest, from);
+                            outcome = ActionOutcome::Moved;
+                            events.publish(GameEvent::EntityMoved {
+                                entity: ent,
+                                from,
+                                to: dest,
+                            });
+                            events.publish(GameEvent::EntityMoved {
+                                entity: target,
+                                from: dest,
+                                to: from,
+                            });
+                            log.log(format!(
+                                "You swap places with {}.",
+                                describe(target, &players, &monsters, &names)
+                            ));
+                            moved.push((ent, dest));
+                        }
+                        Some(target) if allied(target) => {
+                            outcome = ActionOutcome::Blocked;
+                        }
+                        Some(target) => {
+                            outcome = ActionOutcome::Hit;
+                            // Whatever the player bumps into out of
+                            // sight at least gets noticed.
+                            if players.contains(ent) && !view.sees(&level, from, dest) {
+                                log.log("You bump into something!");
+                                detected
+                                    .insert(target, Detected { at: dest })
+                                    .expect("Target must be alive");
+                            }
+                            let damage = match attack.get(ent) {
+                                Some(attack) => {
+                                    rng.gen_range(1..=attack.damage + weapon_damage(ent))
+                                }
+                                None => 0,
+                            };
+                            let damage = (damage - armor_defense(target)).max(0);
+                            health
+                                .get_mut(target)
+                                .expect("Target must have health")
+                                .current -= damage;
+
+                            let attacker = describe(ent, &players, &monsters, &names);
+                            let victim = describe(target, &players, &monsters, &names);
+                            if players.contains(ent) {
+                                log.log_with(Severity::Combat, format!("You hit {}.", victim));
+                            } else {
+                                log.log_with(
+                                    Severity::Combat,
+                                    capitalize(&format!("{} hits {}.", attacker, victim)),
+                                );
+                            }
+                            if damage == 0 {
+                                let whose = if players.contains(target) {
+                                    "your"
+                                } else {
+                                    "its"
+                                };
+                                log.log_with(
+                                    Severity::Combat,
+                                    format!("The blow glances off {} armor.", whose),
+                                );
+                            }
+
+                            // Heavy hitters shove whatever survives
+                            // their blows back a step, or into
+                            // whatever's behind it, as long as it's
+                            // small enough to be shoved.
+                            let knockback = attack.get(ent).is_some_and(|attack| attack.knockback)
+                                && !size_of(target).is_large();
+                            let mut fell = false;
+                            if knockback && health.get(target).is_some_and(|hp| hp.current > 0) {
+                                let behind = offset(dest, (dx, dy));
+                                let obstacle = index
+                                    .entities_at(behind.0, behind.1)
+                                    .iter()
+                                    .copied()
+                                    .find(|other| health.contains(*other));
+                                let is = if players.contains(target) {
+                                    "are"
+                                } else {
+                                    "is"
+                                };
+
+                                let behind_tile =
+                                    in_bounds(behind).then(|| *level.tile(behind.0, behind.1));
+                                let target_flies = flying.contains(target);
+
+                                if behind_tile == Some(DungeonTile::Chasm)
+                                    && obstacle.is_none()
+                                    && !target_flies
+                                {
+                                    pos.insert(target, Position::from(behind))
+                                        .expect("Target must be alive");
+                                    index.move_entity(target, dest, behind);
+                                    fell = true;
+                                    log.log_with(
+                                        Severity::Combat,
+                                        capitalize(&format!(
+                                            "{} {} knocked into the chasm!",
+                                            victim, is
+                                        )),
+                                    );
+                                } else if behind_tile
+                                    .is_none_or(|tile| tile.move_cost(target_flies).is_none())
+                                {
+                                    health
+                                        .get_mut(target)
+                                        .expect("Target must have health")
+                                        .current -= COLLISION_DAMAGE;
+                                    log.log_with(
+                                        Severity::Combat,
+                                        capitalize(&format!(
+                                            "{} {} knocked into the wall!",
+                                            victim, is
+                                        )),
+                                    );
+                                } else if let Some(obstacle) = obstacle {
+                                    health
+                                        .get_mut(target)
+                                        .expect("Target must have health")
+                                        .current -= COLLISION_DAMAGE;
+                                    log.log_with(
+                                        Severity::Combat,
+                                        capitalize(&format!(
+                                            "{} {} knocked into {}!",
+                                            victim,
+                                            is,
+                                            describe(obstacle, &players, &monsters, &names)
+                                        )),
+                                    );
+                                } else {
+                                    pos.insert(target, Position::from(behind))
+                                        .expect("Target must be alive");
+                                    index.move_entity(target, dest, behind);
+                                    log.log_with(
+                                        Severity::Combat,
+                                        capitalize(&format!("{} {} knocked back!", victim, is)),
+                                    );
+                                }
+                            }
+
+                            let target_pos: (i32, i32) =
+                                pos.get(target).expect("Target must have a position").into();
+                            let target_hp = health.get(target).expect("Target must have health");
+                            if target_hp.current <= 0 {
+                                events.publish(GameEvent::EntityDied {
+                                    entity: target,
+                                    description: victim,
+                                    killer: Some(ent),
+                                    cause: format!("killed by {}", attacker),
+                                });
+                                if !players.contains(target) {
+                                    if let Some(name) =
+                                        monsters.get(target).and_then(|m| m.kind.corpse())
+                                    {
+                                        leave_corpse(&lazy, &entities, &ids, name, target_pos);
+                                    }
+
+                                    index.remove_sized(target, size_of(target), target_pos);
+                                    entities.delete(target).expect("Target must be alive");
+                                }
+                            } else if fell {
+                                // Only the player has a level below to
+                                // land on; monsters are gone for good.
+                                if players.contains(target) {
+                                    travel.0 = Some(Travel::Fall);
+                                } else {
+                                    index.remove(target, target_pos);
+                                    entities.delete(target).expect("Target must be alive");
+                                    fallen.push(target);
+                                }
+                            }
+                        }
+                        // Large creatures can't squeeze into spaces
+                        // too small for them.
+                        None if !fits => {
+                            outcome = ActionOutcome::Blocked;
+                        }
+                        None => {
+                            pos.insert(ent, Position::from(dest))
+                                .expect("Mob must be alive");
+                            index.move_sized(ent, size, from, dest);
+                            outcome = ActionOutcome::Moved;
+                            events.publish(GameEvent::EntityMoved {
+                                entity: ent,
+                                from,
+                                to: dest,
+                            });
+
+                            moved.push((ent, dest));
+                        }
+                    }
+                }
+
+                MobAction::Blink(x, y) => {
+                    let dest = (x, y);
+                    let occupied = index
+                        .entities_at(x, y)
+                        .iter()
+                        .any(|other| health.contains(*other));
+                    if chebyshev(here, dest) <= BLINK_RANGE
+                        && in_bounds(dest)
+                        && level.tile(x, y).is_navigable()
+                        && !occupied
+                        && view.sees(&level, here, dest)
+                    {
+                        pos.insert(ent, Position::from(dest))
+                            .expect("Mob must be alive");
+                        index.move_entity(ent, here, dest);
+                        events.publish(GameEvent::EntityMoved {
+                            entity: ent,
+                            from: here,
+                            to: dest,
+                        });
+                        moved.push((ent, dest));
+                        outcome = ActionOutcome::Moved;
+                        if players.contains(ent) {
+                            log.log("You blink away.");
+                        }
+                    } else {
+                        outcome = ActionOutcome::Blocked;
+                    }
+                }
+
+                // Handled by `SearchSystem`.
+                MobAction::Search => {}
+
+                // Handled by `ItemSystem`.
+                MobAction::PickUp
+                | MobAction::Use(_)
+                | MobAction::Wield(_)
+                | MobAction::Wear(_)
+                | MobAction::Unequip(_)
+                | MobAction::Drop(_)
+                | MobAction::Throw(_, _, _) => {}
+
+                // Only players can go to other levels, since only the
+                // player's level is in the world; the move itself
+                // happens once the turn is over.
+                MobAction::TakeStairs(direction) => {
+                    if players.contains(ent) {
+                        travel.0 = Some(Travel::Stairs(direction));
+                    }
+                }
+
+                // Only the player drinks from fountains.
+                MobAction::Quaff => {
+                    let is_player = players.contains(ent);
+                    if level.tile(here.0, here.1) == &(DungeonTile::Fountain { dry: false }) {
+                        match rng.gen_range(0..3) {
+                            0 => {
+                                if let Some(hp) = health.get_mut(ent) {
+                                    hp.current = (hp.current + rng.gen_range(FOUNTAIN_HEALING))
+                                        .min(hp.maximum);
+                                }
+                                if is_player {
+                                    log.log("The cool water refreshes you.");
+                                }
+                            }
+                            1 => {
+                                confused
+                                    .insert(
+                                        ent,
+                                        Confused {
+                                            turns: rng.gen_range(FOUNTAIN_CONFUSION),
+                                        },
+                                    )
+                                    .expect("Mob must be alive");
+                                if is_player {
+                                    log.log("The water tastes strange, and your head spins.");
+                                }
+                            }
+                            _ => {
+                                if is_player {
+                                    log.log("The water tastes like ordinary water.");
+                                }
+                            }
+                        }
+
+                        if rng.gen_bool(FOUNTAIN_DRY_CHANCE) {
+                            level.set_tile(here.0, here.1, DungeonTile::Fountain { dry: true });
+                            if is_player {
+                                log.log("The fountain dries up!");
+                            }
+                        }
+                    } else {
+                        outcome = ActionOutcome::Nothing;
+                    }
+                }
+
+                MobAction::Open(dx, dy) => {
+                    let (x, y) = offset(here, (dx, dy));
+                    if level.tile(x, y) == &(DungeonTile::Door { open: false }) {
+                        level.set_tile(x, y, DungeonTile::Door { open: true });
+                        // What the player can see through the doorway
+                        // is worked out afresh until the next turn.
+                        *view = PlayerView::default();
+                        if players.contains(ent) {
+                            log.log("The door opens.");
+                        } else {
+                            log.log(capitalize(&format!(
+                                "{} opens a door.",
+                                describe(ent, &players, &monsters, &names)
+                            )));
+                        }
+                    } else {
+                        outcome = ActionOutcome::Nothing;
+                    }
+                }
+            }
+
+            // Wading through difficult terrain takes as much longer
+            // than usual as it's costlier to cross. The mob has
+            // already been scheduled as though it were an ordinary
+            // step, so the delay goes on top of that.
+            if outcome == ActionOutcome::Moved && matches!(action, MobAction::Move(_, _)) {
+                let mover_flies = flying.contains(ent);
+                let here = pos.get(ent).expect("Mob must have a position").into();
+                let cost = size_of(ent)
+                    .cells(here)
+                    .filter_map(|(x, y)| level.tile(x, y).move_cost(mover_flies))
+                    .max()
+                    .unwrap_or(1);
+                if let Some(turn) = turn_takers.get_mut(ent) {
+                    if cost > 1 {
+                        turn.next += (cost - 1) * turn.maximum;
+                        schedule.reschedule(ent, turn.next);
+                    }
+                }
+            }
+
+            // Whatever is underfoot takes effect at the end of a move,
+            // and just as much when the mob waits where it is.
+            let standing: (i32, i32) = pos.get(ent).expect("Mob must have a position").into();
+            if matches!(action, MobAction::Nop | MobAction::Move(_, _)) && !flying.contains(ent) {
+                let is_player = players.contains(ent);
+                match level.tile(standing.0, standing.1) {
+                    DungeonTile::Chasm => {
+                        moved.retain(|(other, _)| *other != ent);
+                        if is_player {
+                            if matches!(action, MobAction::Move(_, _)) {
+                                log.log("You jump into the chasm!");
+                            } else {
+                                log.log("You fall into the chasm!");
+                            }
+                            travel.0 = Some(Travel::Fall);
+                        } else {
+                            index.remove(ent, standing);
+                            entities.delete(ent).expect("Mob must be alive");
+                            fallen.push(ent);
+                        }
+                    }
+                    DungeonTile::Lava => {
+                        let victim = describe(ent, &players, &monsters, &names);
+                        let is = if is_player { "are" } else { "is" };
+                        log.log_with(
+                            Severity::Combat,
+                            capitalize(&format!("{} {} burned by the lava!", victim, is)),
+                        );
+                        let hp = health.get_mut(ent).expect("Mob must have health");
+                        hp.current -= rng.gen_range(LAVA_DAMAGE);
+                        if hp.current <= 0 {
+                            moved.retain(|(other, _)| *other != ent);
+                            events.publish(GameEvent::EntityDied {
+                                entity: ent,
+                                description: victim,
+                                killer: None,
+                                cause: "burned to death in lava".to_string(),
+                            });
+                            if !is_player {
+                                index.remove_sized(ent, size_of(ent), standing);
+                                entities.delete(ent).expect("Mob must be alive");
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // Let the player know when things didn't go as planned.
+            if players.contains(ent) {
+                match outcome {
+                    ActionOutcome::Blocked => log.log(format!(
+                        "You try to {}, but something is in the way.",
+                        action.intent()
+                    )),
+                    ActionOutcome::Nothing => log.log(format!(
+                        "You try to {}, but nothing happens.",
+                        action.intent()
+                    )),
+                    ActionOutcome::Done | ActionOutcome::Moved | ActionOutcome::Hit => {}
                 }
             }
 
             mob.next_action = MobAction::Nop;
+            mob.last_outcome = Some(outcome);
+        }
+
+        // Creatures the player bumped into are lost track of again
+        // once they move.
+        let lost: Vec<Entity> = (&entities, &detected, &pos)
+            .join()
+            .filter(|(_ent, found, pos)| found.at != (*pos).into())
+            .map(|(ent, _found, _pos)| ent)
+            .collect();
+        for ent in lost {
+            detected.remove(ent);
+        }
+
+        // Players automatically pick up any gold they step on, and
+        // are told about anything else that's there.
+        for (ent, dest) in moved {
+            if let Some(player) = players.get_mut(ent) {
+                let piles: Vec<Entity> = index
+                    .entities_at(dest.0, dest.1)
+                    .iter()
+                    .copied()
+                    .filter(|other| gold.contains(*other))
+                    .collect();
+
+                for pile_ent in piles {
+                    player.gold += gold.get(pile_ent).expect("Pile must be gold").amount;
+                    index.remove(pile_ent, dest);
+                    entities
+                        .delete(pile_ent)
+                        .expect("Gold pile must still be alive");
+                }
+
+                match level.tile(dest.0, dest.1) {
+                    DungeonTile::Upstair => log.log("There is a staircase up here."),
+                    DungeonTile::Downstair => log.log("There is a staircase down here."),
+                    DungeonTile::BranchStair => match level.branch_name() {
+                        Some(name) => {
+                            log.log(format!("There is a staircase down to {} here.", name))
+                        }
+                        None => log.log("There is a staircase down here."),
+                    },
+                    DungeonTile::Fountain { dry: false } => log.log("There is a fountain here."),
+                    DungeonTile::Fountain { dry: true } => {
+                        log.log("There is a dried-up fountain here.")
+                    }
+                    _ => {}
+                }
+
+                let here: Vec<&Item> = index
+                    .entities_at(dest.0, dest.1)
+                    .iter()
+                    .filter_map(|other| items.get(*other))
+                    .collect();
+                match here.as_slice() {
+                    [] => {}
+                    [item] => log.log(format!("You see the {} here.", item.name)),
+                    _ => log.log("There are several items here."),
+                }
+            }
+        }
+    }
+}
+
+/// Creates a corpse with the given name at `(x, y)`. The corpse
+/// appears once the world is next maintained.
+fn leave_corpse(
+    lazy: &LazyUpdate,
+    entities: &Entities,
+    ids: &IdAllocator,
+    name: &'static str,
+    (x, y): (i32, i32),
+) {
+    create_entity_lazy(lazy, entities, ids)
+        .with(Position { x, y })
+        .with(CharRender {
+            // Non-bold yellow shows up as brown on most terminals.
+            glyph: '%',
+            color: Color::Yellow,
+        })
+        .with(Item {
+            name,
+            category: ItemCategory::Food,
+            effect: ItemEffect::Corpse,
+        })
+        .build();
+}
+
+/// Describes an entity for use in a message, e.g., "the zombie",
+/// "the goblin Xurth" or "you".
+fn describe<D: Deref<Target = MaskedStorage<Player>>>(
+    ent: Entity,
+    players: &Storage<Player, D>,
+    monsters: &ReadStorage<Monster>,
+    names: &ReadStorage<Name>,
+) -> String {
+    if players.contains(ent) {
+        "you".to_string()
+    } else if let Some(monster) = monsters.get(ent) {
+        match names.get(ent) {
+            Some(name) => format!("the {} {}", monster.kind.name(), name.name),
+            None => format!("the {}", monster.kind.name()),
+        }
+    } else {
+        "something".to_string()
+    }
+}
+
+/// System for rolling detection of hidden features around mobs that
+/// have chosen to search.
+pub struct SearchSystem;
+
+impl<'a> System<'a> for SearchSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Position>,
+        ReadExpect<'a, Schedule>,
+        ReadStorage<'a, Mobile>,
+        ReadStorage<'a, Player>,
+        WriteExpect<'a, DungeonLevel>,
+        WriteExpect<'a, MessageLog>,
+        WriteExpect<'a, GameRng>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, pos, schedule, mob, players, mut level, mut log, mut rng): Self::SystemData,
+    ) {
+        let rng = &mut rng.0;
+
+        for (ent, pos, _mob) in (&entities, &pos, &mob).join().filter(|(ent, _pos, mob)| {
+            schedule.is_acting(*ent) && matches!(mob.next_action, MobAction::Search)
+        }) {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (x, y) = offset(pos.into(), (dx, dy));
+                    if in_bounds((x, y))
+                        && level.tile(x, y) == &DungeonTile::SecretDoor
+                        && rng.gen_bool(SEARCH_CHANCE)
+                    {
+                        level.set_tile(x, y, DungeonTile::Door { open: false });
+                        if players.contains(ent) {
+                            log.log("You find a hidden door.");
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// System for picking up and using items.
+pub struct ItemSystem;
+
+impl<'a> System<'a> for ItemSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Position>,
+        ReadExpect<'a, Schedule>,
+        ReadStorage<'a, Mobile>,
+        WriteStorage<'a, Inventory>,
+        ReadStorage<'a, Item>,
+        WriteStorage<'a, Equipment>,
+        ReadStorage<'a, Weapon>,
+        ReadStorage<'a, Armor>,
+        ReadStorage<'a, Player>,
+        ReadExpect<'a, DungeonLevel>,
+        ReadExpect<'a, PlayerView>,
+        WriteExpect<'a, MessageLog>,
+        WriteExpect<'a, PositionIndex>,
+        WriteStorage<'a, Health>,
+        WriteStorage<'a, Confused>,
+        ReadStorage<'a, Monster>,
+        ReadStorage<'a, Name>,
+        ReadStorage<'a, Size>,
+        Read<'a, LazyUpdate>,
+        ReadExpect<'a, IdAllocator>,
+        WriteExpect<'a, Events>,
+        WriteExpect<'a, GameRng>,
+    );
+
+    fn run(
+        &mut self,
+        (
+            entities,
+            mut pos,
+            schedule,
+            mob,
+            mut inventories,
+            items,
+            mut equipment,
+            weapons,
+            armors,
+            players,
+            level,
+            view,
+            mut log,
+            mut index,
+            mut health,
+            mut confused,
+            monsters,
+            names,
+            sizes,
+            lazy,
+            ids,
+            mut events,
+            mut rng,
+        ): Self::SystemData,
+    ) {
+        let rng = &mut rng.0;
+
+        let actions: Vec<(Entity, MobAction)> = (&entities, &mob)
+            .join()
+            .filter(|(ent, _mob)| schedule.is_acting(*ent))
+            .map(|(ent, mob)| (ent, mob.next_action))
+            .collect();
+
+        // The potions that have been thrown or drunk, and who each one
+        // splashes.
+        let mut splashes = Vec::new();
+
+        for (ent, action) in actions {
+            let inventory = match inventories.get_mut(ent) {
+                Some(inventory) => inventory,
+                None => continue,
+            };
+
+            match action {
+                MobAction::PickUp => {
+                    let here: (i32, i32) = pos.get(ent).expect("Mob must have a position").into();
+                    let found: Vec<Entity> = index
+                        .entities_at(here.0, here.1)
+                        .iter()
+                        .copied()
+                        .filter(|other| items.contains(*other))
+                        .collect();
+
+                    for item in found {
+                        let name = items.get(item).expect("Item must be an item").name;
+                        match inventory.add(item) {
+                            Some(letter) => {
+                                pos.remove(item);
+                                index.remove(item, here);
+                                events.publish(GameEvent::ItemPickedUp {
+                                    entity: ent,
+                                    item,
+                                    letter,
+                                });
+                            }
+                            None => {
+                                if players.contains(ent) {
+                                    log.log(format!("You have no room for the {}.", name));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                MobAction::Use(item) => {
+                    if !inventory.contains(item) {
+                        continue;
+                    }
+                    let item_info = items.get(item).expect("Used entity must be an item");
+                    if let ItemEffect::Equip = item_info.effect {
+                        if players.contains(ent) {
+                            log.log(format!(
+                                "You can't use the {} like that; try wielding or wearing it.",
+                                item_info.name
+                            ));
+                        }
+                        continue;
+                    }
+
+                    inventory.remove(item);
+                    if players.contains(ent) {
+                        log.log(format!(
+                            "You {} the {}.",
+                            item_info.category.verb(),
+                            item_info.name
+                        ));
+                    }
+
+                    match item_info.effect {
+                        ItemEffect::Teleport => {
+                            let from: (i32, i32) =
+                                pos.get(ent).expect("Mob must have a position").into();
+                            let to = (0..TELEPORT_ATTEMPTS)
+                                .map(|_| level.empty_square(rng))
+                                .find(|&(x, y)| index.entities_at(x, y).is_empty());
+
+                            match to {
+                                Some(to) => {
+                                    pos.insert(ent, Position::from(to))
+                                        .expect("Mob must be alive");
+                                    index.move_entity(ent, from, to);
+
+                                    if players.contains(ent) {
+                                        log.log("You find yourself somewhere else.");
+                                    }
+                                }
+                                None => {
+                                    if players.contains(ent) {
+                                        log.log("You feel a brief tug, and then nothing.");
+                                    }
+                                }
+                            }
+                        }
+
+                        ItemEffect::Corpse => {
+                            if let Some(hp) = health.get_mut(ent) {
+                                hp.current =
+                                    (hp.current + rng.gen_range(CORPSE_HEALING)).min(hp.maximum);
+                            }
+                            if players.contains(ent) {
+                                log.log("You feel a little better.");
+                            }
+                        }
+                        ItemEffect::Splash(splash) => splashes.push((splash, vec![ent])),
+
+                        // The reader picked what to wipe out when they
+                        // read it, and it's wiped out once the turn is
+                        // over; see `Dungeon::genocide`.
+                        ItemEffect::Genocide => {}
+
+                        // Handled above, since it isn't used up.
+                        ItemEffect::Equip => {}
+                    }
+
+                    entities.delete(item).expect("Used item must be alive");
+                }
+
+                MobAction::Wield(item) | MobAction::Wear(item) => {
+                    let equipment = match equipment.get_mut(ent) {
+                        Some(equipment) => equipment,
+                        None => continue,
+                    };
+                    let (slot, fits, verb) = match action {
+                        MobAction::Wield(_) => {
+                            (&mut equipment.weapon, weapons.contains(item), "wield")
+                        }
+                        _ => (&mut equipment.armor, armors.contains(item), "put on"),
+                    };
+                    if !inventory.contains(item) || !fits {
+                        continue;
+                    }
+
+                    // There's always room for the old item, in the
+                    // slot the new one leaves behind.
+                    inventory.remove(item);
+                    if let Some(old) = slot.replace(item) {
+                        inventory.add(old).expect("Inventory must have room");
+                    }
+                    if players.contains(ent) {
+                        let name = items.get(item).expect("Item must be an item").name;
+                        log.log(format!("You {} the {}.", verb, name));
+                    }
+                }
+
+                MobAction::Drop(item) => {
+                    if !inventory.contains(item) {
+                        continue;
+                    }
+
+                    // Mobs only ever stand on open ground, but items
+                    // dropped into a wall would be lost for good.
+                    let here: (i32, i32) = pos.get(ent).expect("Mob must have a position").into();
+                    if !level.tile(here.0, here.1).is_navigable() {
+                        if players.contains(ent) {
+                            log.log("There's no room to put anything down here.");
+                        }
+                        continue;
+                    }
+
+                    inventory.remove(item);
+                    pos.insert(item, Position::from(here))
+                        .expect("Dropped item must be alive");
+                    index.insert(item, here);
+
+                    if players.contains(ent) {
+                        let name = items.get(item).expect("Item must be an item").name;
+                        log.log(format!("You drop the {}.", name));
+                    }
+                }
+
+                MobAction::Throw(item, x, y) => {
+                    if !inventory.contains(item) {
+                        continue;
+                    }
+
+                    let here: (i32, i32) = pos.get(ent).expect("Mob must have a position").into();
+                    let (path, reached) = level.line_of_fire(here, (x, y));
+                    let landing = path.last().copied().unwrap_or(here);
+
+                    inventory.remove(item);
+                    let item_info = items.get(item).expect("Item must be an item");
+                    if players.contains(ent) {
+                        log.log(format!("You throw the {}.", item_info.name));
+                    }
+
+                    // Potions shatter wherever they land, rather than
+                    // dropping to the ground.
+                    if let ItemEffect::Splash(splash) = item_info.effect {
+                        log.log(capitalize(&format!("The {} shatters!", item_info.name)));
+                        entities.delete(item).expect("Thrown item must be alive");
+
+                        let splashed: Vec<Entity> = index
+                            .entities_near(landing, SPLASH_RADIUS)
+                            .into_iter()
+                            .filter(|&other| health.contains(other))
+                            .filter(|&other| {
+                                let at = pos
+                                    .get(other)
+                                    .expect("Splashed entity must have a position");
+                                view.sees(&level, landing, at.into())
+                            })
+                            .collect();
+                        if splashed.is_empty() {
+                            log.log("It doesn't splash anyone.");
+                        }
+
+                        splashes.push((splash, splashed));
+                        continue;
+                    }
+
+                    pos.insert(item, Position::from(landing))
+                        .expect("Thrown item must be alive");
+                    index.insert(item, landing);
+
+                    if players.contains(ent) && !reached {
+                        log.log("It hits something solid and drops to the ground.");
+                    }
+                }
+
+                MobAction::Unequip(item) => {
+                    let equipment = match equipment.get_mut(ent) {
+                        Some(equipment) => equipment,
+                        None => continue,
+                    };
+                    let (slot, verb) = if equipment.weapon == Some(item) {
+                        (&mut equipment.weapon, "put away")
+                    } else if equipment.armor == Some(item) {
+                        (&mut equipment.armor, "take off")
+                    } else {
+                        continue;
+                    };
+
+                    let name = items.get(item).expect("Item must be an item").name;
+                    match inventory.add(item) {
+                        Some(letter) => {
+                            *slot = None;
+                            if players.contains(ent) {
+                                log.log(format!("You {} the {} ({}).", verb, name, letter));
+                            }
+                        }
+                        None => {
+                            if players.contains(ent) {
+                                log.log(format!("You have no room to carry the {}.", name));
+                            }
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        // Splashes land once everyone's done acting, whether the
+        // potion was thrown or drunk.
+        for (splash, splashed) in splashes {
+            for target in splashed {
+                let victim = describe(target, &players, &monsters, &names);
+                let is = if players.contains(target) {
+                    "are"
+                } else {
+                    "is"
+                };
+                match splash {
+                    Splash::Fire => {
+                        let hp = health.get_mut(target).expect("Target must have health");
+                        hp.current -= rng.gen_range(SPLASH_FIRE_DAMAGE);
+                        log.log_with(
+                            Severity::Combat,
+                            capitalize(&format!("{} {} burned!", victim, is)),
+                        );
+                    }
+                    Splash::Confusion => {
+                        let turns = rng.gen_range(SPLASH_CONFUSION);
+                        confused
+                            .insert(target, Confused { turns })
+                            .expect("Target must be alive");
+                        log.log(capitalize(&format!("{} {} confused!", victim, is)));
+                    }
+                }
+
+                let target_pos: (i32, i32) =
+                    pos.get(target).expect("Target must have a position").into();
+                if health.get(target).is_some_and(|hp| hp.current <= 0) {
+                    events.publish(GameEvent::EntityDied {
+                        entity: target,
+                        description: victim,
+                        killer: None,
+                        cause: "burned to death".to_string(),
+                    });
+                    if !players.contains(target) {
+                        if let Some(name) = monsters.get(target).and_then(|m| m.kind.corpse()) {
+                            leave_corpse(&lazy, &entities, &ids, name, target_pos);
+                        }
+
+                        let size = sizes.get(target).copied().unwrap_or_default();
+                        index.remove_sized(target, size, target_pos);
+                        entities.delete(target).expect("Target must be alive");
+                    }
+                }
+            }
         }
     }
 }
 
-/// System for updating player-discovered cells.
+/// System for slowing players down while they're badly hurt, and
+/// bringing them back up to speed once they've healed.
+pub struct InjurySystem;
+
+impl<'a> System<'a> for InjurySystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Player>,
+        ReadStorage<'a, Health>,
+        WriteStorage<'a, TurnTaker>,
+        WriteStorage<'a, Injured>,
+        WriteExpect<'a, Schedule>,
+        WriteExpect<'a, MessageLog>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, players, health, mut turns, mut injured, mut schedule, mut log): Self::SystemData,
+    ) {
+        for (ent, _plr, hp, turn) in (&entities, &players, &health, &mut turns).join() {
+            let hurt = (hp.current as f64) < INJURY_THRESHOLD * hp.maximum as f64;
+            if hurt && !injured.contains(ent) && hp.current > 0 {
+                // The turn they're already waiting for stays put; the
+                // ones after it come more slowly.
+                injured
+                    .insert(ent, Injured { base: turn.maximum })
+                    .expect("Player must be alive");
+                turn.maximum += INJURY_DELAY;
+                log.log_with(Severity::Combat, "Your wounds slow you down.");
+            } else if !hurt {
+                if let Some(Injured { base }) = injured.remove(ent) {
+                    // Don't keep them waiting any longer than their
+                    // usual speed would.
+                    turn.maximum = base;
+                    let soonest = schedule.now() + turn.maximum;
+                    if turn.next > soonest {
+                        turn.next = soonest;
+                        schedule.reschedule(ent, soonest);
+                    }
+                    log.log("You feel able to move freely again.");
+                }
+            }
+        }
+    }
+}
+
+/// How many turns the player remembers cells they've stopped being
+/// able to see for, before they have to be explored again; `None` if
+/// they're remembered forever.
+#[derive(Default)]
+pub struct MemoryDecay(pub Option<u32>);
+
+/// Whether seeing any of a room's floor shows the player the walls
+/// all the way around it, rather than just the parts in view. What's
+/// inside the room still has to be seen to be known.
+#[derive(Default)]
+pub struct RevealRooms(pub bool);
+
+/// System for updating player-discovered cells, and what players
+/// remember seeing in them.
 pub struct DiscoverySystem;
 
 impl<'a> System<'a> for DiscoverySystem {
     type SystemData = (
         WriteStorage<'a, Player>,
         ReadStorage<'a, Position>,
+        ReadStorage<'a, CharRender>,
+        ReadStorage<'a, Mobile>,
         ReadExpect<'a, DungeonLevel>,
+        ReadExpect<'a, PositionIndex>,
+        ReadExpect<'a, GameClock>,
+        ReadExpect<'a, MemoryDecay>,
+        ReadExpect<'a, RevealRooms>,
     );
 
-    fn run(&mut self, (mut players, position, level): Self::SystemData) {
+    fn run(
+        &mut self,
+        (mut players, position, renderables, mobs, level, index, clock, decay, reveal_rooms): Self::SystemData,
+    ) {
+        let now = clock.turns;
         for (player, pos) in (&mut players, &position).join() {
-            for (y, row) in player.known_cells.iter_mut().enumerate() {
-                for (x, known) in row.iter_mut().enumerate() {
-                    if level.can_see(pos.into(), (x as _, y as _)) {
-                        *known = true;
+            let mut rooms_seen = Vec::new();
+            for cell in level.visible_from(pos.into()) {
+                player.known_cells.see(cell.0, cell.1, now);
+                if let Some(room) = level.room_at(cell.0, cell.1) {
+                    if !rooms_seen.contains(&room) {
+                        rooms_seen.push(room);
+                    }
+                }
+
+                let object = index
+                    .entities_at(cell.0, cell.1)
+                    .iter()
+                    .rev()
+                    .filter(|ent| !mobs.contains(**ent))
+                    .find_map(|ent| renderables.get(*ent));
+                match object {
+                    Some(render) => player.remembered.insert(cell, *render),
+                    None => player.remembered.remove(&cell),
+                };
+            }
+
+            // A room's walls are the cells next to it that aren't part
+            // of it.
+            if reveal_rooms.0 && !rooms_seen.is_empty() {
+                for y in 0..LEVEL_SIZE.1 as i32 {
+                    for x in 0..LEVEL_SIZE.0 as i32 {
+                        let room = match level.room_at(x, y) {
+                            Some(room) if rooms_seen.contains(&room) => room,
+                            _ => continue,
+                        };
+                        for dy in -1..=1 {
+                            for dx in -1..=1 {
+                                let (x, y) = offset((x, y), (dx, dy));
+                                if in_bounds((x, y)) && level.room_at(x, y) != Some(room) {
+                                    player.known_cells.see(x, y, now);
+                                }
+                            }
+                        }
                     }
                 }
             }
+
+            if let MemoryDecay(Some(decay)) = *decay {
+                for cell in player.known_cells.forget(now, decay) {
+                    player.remembered.remove(&cell);
+                }
+            }
         }
     }
 }
 
-/// Creates a Dispatcher with every system set up.
+/// Creates a Dispatcher with every system set up. Each system depends
+/// on the one before it, so that they always run in this order: time
+/// moves on, the index is brought up to date, what the player can see
+/// is worked out, everyone acts, and only then does the player see
+/// where things ended up.
 pub fn build_dispatcher() -> Dispatcher<'static, 'static> {
     DispatcherBuilder::new()
         .with(TimeSystem, "time", &[])
-        .with(MobSystem, "mobs", &[])
-        .with(DiscoverySystem, "discovery", &[])
+        .with(BoundsSystem, "bounds", &["time"])
+        .with(IndexSystem, "index", &["bounds"])
+        .with(ViewSystem, "view", &["index"])
+        .with(MonsterAiSystem, "ai", &["view"])
+        .with(SearchSystem, "search", &["ai"])
+        .with(ItemSystem, "items", &["search"])
+        .with(MobSystem, "mobs", &["items"])
+        .with(InjurySystem, "injury", &["mobs"])
+        .with(SpawnerSystem, "spawner", &["injury"])
+        .with(DiscoverySystem, "discovery", &["spawner"])
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        items::ItemKind,
+        monsters::MonsterKind,
+        testing::{add_player, health, position, take_turn, world_with, PLAYER_HEALTH},
+    };
+
+    /// Adds a zombie at the given position that hits for exactly 1
+    /// damage, and knocks its victims back.
+    fn add_zombie(world: &mut World, pos: (i32, i32)) -> Entity {
+        let zombie = MonsterKind::Zombie.spawn(world, pos);
+        world
+            .write_storage::<Attack>()
+            .insert(
+                zombie,
+                Attack {
+                    damage: 1,
+                    knockback: true,
+                },
+            )
+            .expect("Zombie must be alive");
+        zombie
+    }
+
+    #[test]
+    fn knockback_into_wall_hurts_without_moving() {
+        let mut world = world_with("------\n|....|\n------");
+        let player = add_player(&mut world, (1, 1));
+        let zombie = add_zombie(&mut world, (2, 1));
+
+        take_turn(&mut world, zombie, MobAction::Move(-1, 0));
+
+        assert_eq!(position(&world, player), (1, 1));
+        assert_eq!(health(&world, player), PLAYER_HEALTH - 1 - COLLISION_DAMAGE);
+    }
+
+    #[test]
+    fn knockback_into_open_space_moves() {
+        let mut world = world_with("------\n|....|\n------");
+        let player = add_player(&mut world, (2, 1));
+        let zombie = add_zombie(&mut world, (3, 1));
+
+        take_turn(&mut world, zombie, MobAction::Move(-1, 0));
+
+        assert_eq!(position(&world, player), (1, 1));
+        assert_eq!(health(&world, player), PLAYER_HEALTH - 1);
+    }
+
+    #[test]
+    fn bounds_system_puts_entities_back() {
+        let mut world = world_with("----\n|..|\n----");
+        let player = add_player(&mut world, (i32::MAX, i32::MIN));
+
+        BoundsSystem.run_now(&world);
+
+        assert!(in_bounds(position(&world, player)));
+        assert_eq!(world.fetch::<MessageLog>().unseen().len(), 1);
+    }
+
+    #[test]
+    fn knockback_into_chasm_drops_the_player() {
+        let mut world = world_with("------\n|:...|\n------");
+        let player = add_player(&mut world, (2, 1));
+        let zombie = add_zombie(&mut world, (3, 1));
+
+        take_turn(&mut world, zombie, MobAction::Move(-1, 0));
+
+        assert_eq!(position(&world, player), (1, 1));
+        assert!(matches!(
+            world.fetch::<PendingTravel>().0,
+            Some(Travel::Fall)
+        ));
+    }
+
+    #[test]
+    fn knockback_into_chasm_loses_monsters() {
+        let mut world = world_with("------\n|:...|\n------");
+        let player = add_player(&mut world, (3, 1));
+        world
+            .write_storage::<Attack>()
+            .insert(
+                player,
+                Attack {
+                    damage: 1,
+                    knockback: true,
+                },
+            )
+            .unwrap();
+        let zombie = MonsterKind::Zombie.spawn(&mut world, (2, 1));
+
+        take_turn(&mut world, player, MobAction::Move(-1, 0));
+
+        assert!(!world.is_alive(zombie));
+        assert!(world.fetch::<PendingTravel>().0.is_none());
+    }
+
+    #[test]
+    fn bumping_attacks_without_moving() {
+        let mut world = world_with("------\n|....|\n------");
+        let player = add_player(&mut world, (1, 1));
+        let zombie = MonsterKind::Zombie.spawn(&mut world, (2, 1));
+
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+
+        assert_eq!(position(&world, player), (1, 1));
+        assert_eq!(position(&world, zombie), (2, 1));
+        assert_eq!(health(&world, zombie), MonsterKind::Zombie.max_health() - 1);
+        assert_eq!(
+            world
+                .read_storage::<Mobile>()
+                .get(player)
+                .unwrap()
+                .last_outcome,
+            Some(ActionOutcome::Hit)
+        );
+    }
+
+    /// What happened the last time `ent` acted.
+    fn last_outcome(world: &World, ent: Entity) -> Option<ActionOutcome> {
+        world
+            .read_storage::<Mobile>()
+            .get(ent)
+            .unwrap()
+            .last_outcome
+    }
+
+    #[test]
+    fn bumping_swaps_with_allies_and_hits_enemies() {
+        let mut world = world_with("-------\n|.....|\n-------");
+        let player = add_player(&mut world, (2, 1));
+        let ally = MonsterKind::Rat.spawn(&mut world, (1, 1));
+        world
+            .write_storage::<Faction>()
+            .insert(ally, Faction::Player)
+            .unwrap();
+        let enemy = MonsterKind::Zombie.spawn(&mut world, (3, 1));
+
+        take_turn(&mut world, player, MobAction::Move(-1, 0));
+        assert_eq!(position(&world, player), (1, 1));
+        assert_eq!(position(&world, ally), (2, 1));
+        assert_eq!(health(&world, ally), MonsterKind::Rat.max_health());
+        assert_eq!(last_outcome(&world, player), Some(ActionOutcome::Moved));
+
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        assert_eq!(position(&world, player), (2, 1));
+        assert_eq!(position(&world, enemy), (3, 1));
+        assert_eq!(health(&world, enemy), MonsterKind::Zombie.max_health() - 1);
+        assert_eq!(last_outcome(&world, player), Some(ActionOutcome::Hit));
+    }
+
+    #[test]
+    fn discovery_sees_from_where_the_player_moved_to() {
+        let mut world = world_with(
+            "\
+            ------\n\
+            |....|\n\
+            ----.|\n\
+            \x20  |.|\n\
+            \x20  |.|\n\
+            \x20  ---",
+        );
+        let player = add_player(&mut world, (1, 1));
+        let (before, after) = {
+            let level = world.fetch::<DungeonLevel>();
+            (level.visible_from((1, 1)), level.visible_from((2, 1)))
+        };
+        assert!(after.iter().any(|cell| !before.contains(cell)));
+
+        world
+            .write_storage::<Mobile>()
+            .get_mut(player)
+            .unwrap()
+            .next_action = MobAction::Move(1, 0);
+        build_dispatcher().dispatch(&world);
+        world.maintain();
+
+        assert_eq!(position(&world, player), (2, 1));
+        let players = world.read_storage::<Player>();
+        let known = &players.get(player).unwrap().known_cells;
+        for &(x, y) in &after {
+            assert!(known.get(x, y), "{:?} should be known", (x, y));
+        }
+    }
+
+    #[test]
+    fn large_monsters_block_corridors() {
+        // The dragon fills the corridor from wall to wall, so there's
+        // no way past it on either side.
+        let mut world = world_with("---------\n|.......|\n|.......|\n---------");
+        let dragon = MonsterKind::Dragon.spawn(&mut world, (4, 1));
+        let rat = MonsterKind::Rat.spawn(&mut world, (3, 1));
+
+        for (from, delta) in [
+            ((3, 1), (1, 0)),
+            ((3, 1), (1, 1)),
+            ((3, 2), (1, -1)),
+            ((3, 2), (1, 0)),
+        ] {
+            world
+                .write_storage::<Position>()
+                .insert(rat, Position::from(from))
+                .unwrap();
+            take_turn(&mut world, rat, MobAction::Move(delta.0, delta.1));
+            assert_eq!(position(&world, rat), from);
+        }
+        assert_eq!(position(&world, dragon), (4, 1));
+    }
+
+    /// Sets the player's health and lets `InjurySystem` react to it,
+    /// returning the time between their turns afterwards.
+    fn turn_time_at(world: &mut World, player: Entity, current: i32) -> u32 {
+        world
+            .write_storage::<Health>()
+            .get_mut(player)
+            .unwrap()
+            .current = current;
+        InjurySystem.run_now(world);
+        world
+            .read_storage::<TurnTaker>()
+            .get(player)
+            .unwrap()
+            .maximum
+    }
+
+    #[test]
+    fn wounds_slow_the_player_until_healed() {
+        let mut world = world_with("----\n|..|\n----");
+        let player = add_player(&mut world, (1, 1));
+
+        assert_eq!(turn_time_at(&mut world, player, PLAYER_HEALTH), 10);
+        assert_eq!(turn_time_at(&mut world, player, 4), 10 + INJURY_DELAY);
+        assert!(world.read_storage::<Injured>().contains(player));
+
+        // Getting hurt more doesn't pile on more delay.
+        assert_eq!(turn_time_at(&mut world, player, 2), 10 + INJURY_DELAY);
+
+        assert_eq!(turn_time_at(&mut world, player, 15), 10);
+        assert!(!world.read_storage::<Injured>().contains(player));
+    }
+
+    /// Moves the clock on to `turn`, puts the player at `pos`, and lets
+    /// them look around.
+    fn look_around_at(world: &mut World, player: Entity, pos: (i32, i32), turn: u32) {
+        world
+            .write_storage::<Position>()
+            .insert(player, Position { x: pos.0, y: pos.1 })
+            .unwrap();
+        world.fetch_mut::<GameClock>().turns = turn;
+        IndexSystem.run_now(world);
+        DiscoverySystem.run_now(world);
+    }
+
+    #[test]
+    fn unseen_cells_are_forgotten_after_the_decay() {
+        // Two rooms that can't see into each other.
+        let mut world = world_with("--------\n|..||..|\n--------");
+        world.insert(MemoryDecay(Some(10)));
+        let player = add_player(&mut world, (1, 1));
+        world
+            .create_entity()
+            .with(Position { x: 2, y: 1 })
+            .with(CharRender {
+                glyph: '$',
+                color: Color::Yellow,
+            })
+            .build();
+        let knows = |world: &World, (x, y): (i32, i32)| {
+            let players = world.read_storage::<Player>();
+            let player = players.get(player).unwrap();
+            (
+                player.known_cells.get(x, y),
+                player.remembered.contains_key(&(x, y)),
+            )
+        };
+
+        look_around_at(&mut world, player, (1, 1), 0);
+        assert_eq!(knows(&world, (2, 1)), (true, true));
+
+        look_around_at(&mut world, player, (5, 1), 10);
+        assert_eq!(knows(&world, (2, 1)), (true, true));
+        assert_eq!(knows(&world, (5, 1)), (true, false));
+
+        look_around_at(&mut world, player, (5, 1), 11);
+        assert_eq!(knows(&world, (2, 1)), (false, false));
+        assert_eq!(knows(&world, (1, 1)), (false, false));
+        assert_eq!(knows(&world, (6, 1)), (true, false));
+
+        // Going back has the player see it all again.
+        look_around_at(&mut world, player, (1, 1), 30);
+        assert_eq!(knows(&world, (2, 1)), (true, true));
+        assert_eq!(knows(&world, (6, 1)), (false, false));
+    }
+
+    #[test]
+    fn clock_ticks_once_per_player_turn() {
+        let mut world = world_with("-----\n|...|\n-----");
+        let player = add_player(&mut world, (1, 1));
+        let bat = MonsterKind::Bat.spawn(&mut world, (3, 1));
+        world
+            .write_storage::<TurnTaker>()
+            .insert(
+                bat,
+                TurnTaker {
+                    next: 0,
+                    maximum: 3,
+                },
+            )
+            .unwrap();
+
+        let mut player_turns = 0;
+        let mut bat_turns = 0;
+        for _ in 0..20 {
+            TimeSystem.run_now(&world);
+            let schedule = world.fetch::<Schedule>();
+            player_turns += schedule.is_acting(player) as u32;
+            bat_turns += schedule.is_acting(bat) as u32;
+            assert_eq!(world.fetch::<GameClock>().turns, player_turns);
+        }
+
+        // The bat acts about three times for each of the player's turns,
+        // and the clock only follows the player.
+        assert!(bat_turns > 2 * player_turns);
+        assert!(player_turns >= 4);
+    }
+
+    #[test]
+    fn lava_burns_whoever_is_in_it() {
+        let mut world = world_with("-----\n|.}.|\n-----");
+        let player = add_player(&mut world, (1, 1));
+
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        assert_eq!(position(&world, player), (2, 1));
+        let burned = PLAYER_HEALTH - health(&world, player);
+        assert!(LAVA_DAMAGE.contains(&burned), "{}", burned);
+
+        // Staying put is no safer.
+        let before = health(&world, player);
+        take_turn(&mut world, player, MobAction::Nop);
+        let burned = before - health(&world, player);
+        assert!(LAVA_DAMAGE.contains(&burned), "{}", burned);
+
+        let before = health(&world, player);
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        take_turn(&mut world, player, MobAction::Nop);
+        assert_eq!(health(&world, player), before);
+    }
+
+    /// Has the player take the given action, and returns how much
+    /// later that pushed their next turn back.
+    fn delay_from(world: &mut World, player: Entity, action: MobAction) -> u32 {
+        let next = |world: &World| world.read_storage::<TurnTaker>().get(player).unwrap().next;
+        let before = next(world);
+        take_turn(world, player, action);
+        next(world) - before
+    }
+
+    #[test]
+    fn wading_delays_the_next_turn() {
+        let mut world = world_with("-----\n|.~.|\n-----");
+        let player = add_player(&mut world, (1, 1));
+        let cost = DungeonTile::Water.move_cost(false).unwrap();
+
+        assert_eq!(
+            delay_from(&mut world, player, MobAction::Move(1, 0)),
+            (cost - 1) * 10
+        );
+        assert_eq!(position(&world, player), (2, 1));
+
+        // Climbing back out onto dry land is an ordinary step.
+        assert_eq!(delay_from(&mut world, player, MobAction::Move(1, 0)), 0);
+        assert_eq!(position(&world, player), (3, 1));
+    }
+
+    #[test]
+    fn seeing_a_room_reveals_its_walls() {
+        let mut world = world_with(
+            "     -------\n\
+             \x20    |.....|\n\
+             \x20....'.....|\n\
+             \x20    |.....|\n\
+             \x20    -------",
+        );
+        {
+            let mut level = world.fetch_mut::<DungeonLevel>();
+            for y in 1..=3 {
+                for x in 6..=10 {
+                    level.set_room(x, y, 0);
+                }
+            }
+        }
+        let player = add_player(&mut world, (2, 2));
+        let walls: Vec<(i32, i32)> = (0..=4)
+            .flat_map(|y| (5..=11).map(move |x| (x, y)))
+            .filter(|&(x, y)| !(6..=10).contains(&x) || !(1..=3).contains(&y))
+            .collect();
+        let known = |world: &World, (x, y): (i32, i32)| {
+            world
+                .read_storage::<Player>()
+                .get(player)
+                .unwrap()
+                .known_cells
+                .get(x, y)
+        };
+
+        // From out in the corridor, the wall the door is in is side
+        // on, so most of it can't be seen.
+        look_around_at(&mut world, player, (2, 2), 0);
+        assert!(!known(&world, (5, 0)));
+        assert!(!known(&world, (5, 4)));
+
+        world.insert(RevealRooms(true));
+        look_around_at(&mut world, player, (2, 2), 1);
+        for &wall in &walls {
+            assert!(known(&world, wall), "{:?}", wall);
+        }
+        // What's inside the room still has to be seen.
+        assert!(!known(&world, (6, 1)));
+    }
+
+    #[test]
+    fn bumping_into_unseen_creatures_detects_them() {
+        let mut world = world_with("------\n|....|\n------");
+        let player = add_player(&mut world, (1, 1));
+        let goblin = MonsterKind::Goblin.spawn(&mut world, (2, 1));
+        let found = |world: &World| world.read_storage::<Detected>().get(goblin).map(|d| d.at);
+
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        assert_eq!(found(&world), None);
+        assert!(world
+            .fetch::<MessageLog>()
+            .unseen()
+            .iter()
+            .all(|msg| msg.text != "You bump into something!"));
+
+        world.insert(PlayerView::blind((1, 1)));
+        take_turn(&mut world, player, MobAction::Move(1, 0));
+        assert_eq!(found(&world), Some((2, 1)));
+        let log = world.fetch::<MessageLog>();
+        let texts: Vec<&str> = log.unseen().iter().map(|msg| msg.text.as_str()).collect();
+        assert!(texts.contains(&"You bump into something!"), "{:?}", texts);
+        drop(log);
+
+        // Once it wanders off, it's lost track of again.
+        take_turn(&mut world, goblin, MobAction::Move(1, 0));
+        assert_eq!(found(&world), None);
+    }
+
+    #[test]
+    fn opening_a_door_refreshes_the_view() {
+        let mut world = world_with("-------\n|..+..|\n-------");
+        let player = add_player(&mut world, (2, 1));
+        ViewSystem.run_now(&world);
+        let sees_past = |world: &World| {
+            let level = world.fetch::<DungeonLevel>();
+            world.fetch::<PlayerView>().sees(&level, (2, 1), (5, 1))
+        };
+        assert!(!sees_past(&world));
+
+        take_turn(&mut world, player, MobAction::Open(1, 0));
+        assert_eq!(position(&world, player), (2, 1));
+        assert!(sees_past(&world));
+    }
+
+    #[test]
+    fn teleporting_fizzles_with_nowhere_to_go() {
+        let mut world = world_with("---\n|.|\n---");
+        let player = add_player(&mut world, (1, 1));
+        let scroll = ItemKind::TeleportScroll.create(&mut world);
+        world
+            .write_storage::<Inventory>()
+            .get_mut(player)
+            .unwrap()
+            .add(scroll);
+        world
+            .write_storage::<Mobile>()
+            .get_mut(player)
+            .unwrap()
+            .next_action = MobAction::Use(scroll);
+        build_dispatcher().dispatch(&world);
+        world.maintain();
+
+        assert_eq!(position(&world, player), (1, 1));
+        assert!(world
+            .fetch::<MessageLog>()
+            .unseen()
+            .iter()
+            .any(|msg| msg.text == "You feel a brief tug, and then nothing."));
+    }
+}
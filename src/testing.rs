@@ -0,0 +1,148 @@
+//! Helpers for setting up worlds to run systems in, for tests.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use rand::{rngs::StdRng, SeedableRng};
+use specs::prelude::*;
+
+use crate::{
+    branch::{CurrentLevel, PendingTravel},
+    components::{
+        register_all, Attack, CharRender, Equipment, Faction, Health, Inventory, MobAction, Mobile,
+        Player, Position, TurnTaker,
+    },
+    events::Events,
+    ids::{create_entity, IdAllocator},
+    index::PositionIndex,
+    io::{Color, Screen},
+    keymap::Keymap,
+    level::{CellMemory, DungeonLevel, PlayerView},
+    markers::Markers,
+    messages::MessageLog,
+    schedule::{GameClock, Schedule},
+    spawner::Spawner,
+    systems::{IndexSystem, MemoryDecay, MobSystem, RevealRooms},
+    util::GameRng,
+};
+
+/// Held by whoever's using curses, since it only drives one terminal
+/// at a time.
+pub static CURSES_LOCK: Mutex<()> = Mutex::new(());
+
+/// How much health players made by `add_player` start out with.
+pub const PLAYER_HEALTH: i32 = 20;
+
+/// Makes a world on the level drawn in `level`, with every component
+/// registered and the resources that the systems need.
+pub fn world_with(level: &str) -> World {
+    let mut world = empty_world();
+    world.insert::<DungeonLevel>(level.parse().expect("Test level must be valid"));
+    world
+}
+
+/// Makes a world like `world_with` does, but without a level, for
+/// tests that set one up some other way.
+pub fn empty_world() -> World {
+    let mut world = World::new();
+    register_all(&mut world);
+
+    world.insert(GameRng(StdRng::seed_from_u64(0)));
+    world.insert(IdAllocator::default());
+    world.insert(PositionIndex::default());
+    world.insert(PlayerView::default());
+    world.insert(MessageLog::default());
+    world.insert(Events::default());
+    world.insert(Markers::default());
+    world.insert(Schedule::default());
+    world.insert(GameClock::default());
+    world.insert(PendingTravel::default());
+    world.insert(CurrentLevel::default());
+    world.insert(Keymap::default());
+    world.insert(MemoryDecay(None));
+    world.insert(RevealRooms(false));
+    world.insert(Spawner::new(None, 0));
+
+    world
+}
+
+/// Adds a player at the given position, who hits for exactly 1 damage.
+pub fn add_player(world: &mut World, (x, y): (i32, i32)) -> Entity {
+    create_entity(world)
+        .with(Position { x, y })
+        .with(CharRender {
+            glyph: '@',
+            color: Color::White,
+        })
+        .with(Player {
+            known_cells: CellMemory::default(),
+            remembered: HashMap::new(),
+            gold: 0,
+        })
+        .with(Mobile {
+            next_action: MobAction::Nop,
+            last_outcome: None,
+        })
+        .with(Inventory::new(Vec::new()))
+        .with(Equipment::default())
+        .with(Health {
+            current: PLAYER_HEALTH,
+            maximum: PLAYER_HEALTH,
+        })
+        .with(Attack {
+            damage: 1,
+            knockback: false,
+        })
+        .with(Faction::Player)
+        .with(TurnTaker {
+            next: 0,
+            maximum: 10,
+        })
+        .build()
+}
+
+/// Has `ent` take `action` right now, along with anyone else who was
+/// due to act now anyway.
+pub fn take_turn(world: &mut World, ent: Entity, action: MobAction) {
+    IndexSystem.run_now(world);
+    world
+        .write_storage::<Mobile>()
+        .get_mut(ent)
+        .expect("Entity must be mobile")
+        .next_action = action;
+    {
+        let mut schedule = world.fetch_mut::<Schedule>();
+        let now = schedule.now();
+        schedule.reschedule(ent, now);
+        schedule.advance();
+    }
+    MobSystem.run_now(world);
+    world.maintain();
+}
+
+/// The position of the given entity.
+pub fn position(world: &World, ent: Entity) -> (i32, i32) {
+    world
+        .read_storage::<Position>()
+        .get(ent)
+        .expect("Entity must have a position")
+        .into()
+}
+
+/// The current health of the given entity.
+pub fn health(world: &World, ent: Entity) -> i32 {
+    world
+        .read_storage::<Health>()
+        .get(ent)
+        .expect("Entity must have health")
+        .current
+}
+
+/// Runs `f` on a headless screen that reads `keys` as its input. Curses
+/// only drives one terminal at a time, so this waits for anyone else
+/// that's using one to finish first.
+pub fn with_screen<T>(keys: &str, f: impl FnOnce(&mut Screen) -> T) -> T {
+    let _guard = CURSES_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    f(&mut Screen::headless(keys))
+}
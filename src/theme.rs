@@ -0,0 +1,76 @@
+//! Themes that change how a level's tiles look, to give levels a bit
+//! of character.
+
+use crate::io::Color;
+
+/// How a level's floors and walls are drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Theme {
+    /// What the theme is called.
+    pub name: &'static str,
+
+    /// The glyph used for floor tiles.
+    pub floor_glyph: char,
+
+    /// The color of floor tiles.
+    pub floor_color: Color,
+
+    /// The color of walls.
+    pub wall_color: Color,
+
+    /// The color cast over plain-colored tiles in view that no light
+    /// source reaches, if any.
+    pub ambient: Option<Color>,
+}
+
+impl Theme {
+    /// The plain ASCII look that levels have always had.
+    pub const DUNGEON: Theme = Theme {
+        name: "dungeon",
+        floor_glyph: '.',
+        floor_color: Color::White,
+        wall_color: Color::White,
+        ambient: None,
+    };
+
+    /// Rough, earthy caverns.
+    pub const CAVE: Theme = Theme {
+        name: "cave",
+        floor_glyph: ',',
+        floor_color: Color::Yellow,
+        wall_color: Color::Yellow,
+        ambient: None,
+    };
+
+    /// Cold halls of the dead.
+    pub const CRYPT: Theme = Theme {
+        name: "crypt",
+        floor_glyph: '.',
+        floor_color: Color::White,
+        wall_color: Color::Magenta,
+        ambient: Some(Color::Blue),
+    };
+
+    /// Damp tunnels.
+    pub const SEWER: Theme = Theme {
+        name: "sewer",
+        floor_glyph: '.',
+        floor_color: Color::Green,
+        wall_color: Color::Green,
+        ambient: Some(Color::Green),
+    };
+
+    /// Every theme.
+    pub const ALL: &'static [Theme] = &[Theme::DUNGEON, Theme::CAVE, Theme::CRYPT, Theme::SEWER];
+
+    /// Gets a theme by name.
+    pub fn from_name(name: &str) -> Option<&'static Theme> {
+        Self::ALL.iter().find(|theme| theme.name == name)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::DUNGEON
+    }
+}
@@ -1,54 +1,184 @@
 //! Miscellanous utility functions and types used in other files.
 
-use std::ops::Add;
+use std::cmp::Reverse;
 
-use float_ord::FloatOrd;
-use pathfinding::num_traits::Zero;
+use grid::Grid;
+use pathfinding::directed::bfs::bfs_reach;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng};
 
-/// A somewhat more well-behaved floating point type, used in
-/// pathfinding. Fully ordered, implements Eq, and has a defined zero
-/// element.
-#[derive(Clone, Copy)]
-pub struct NiceFloat(pub f64);
+use crate::messages::capitalize;
 
-impl PartialEq for NiceFloat {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
-    }
+/// The random number generator behind everything that happens in the
+/// game, seeded at the start of the run so that the run can be played
+/// back exactly. Anything that's only for show, like torches
+/// flickering, should use `thread_rng` instead, or else it throws
+/// replays off.
+pub struct GameRng(pub StdRng);
+
+/// Works out a seed of its own for one part of the run, such as a
+/// level, from the run's seed and some numbers that say which part it
+/// is. The same numbers always give the same seed, on every platform
+/// and version of Rust, and different numbers give unrelated seeds.
+pub fn sub_seed(seed: u64, parts: &[u64]) -> u64 {
+    // Each step is SplitMix64.
+    parts.iter().fold(seed, |acc, part| {
+        let mut z = (acc ^ part).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
 }
 
-impl Eq for NiceFloat {}
+/// Consonant sounds that can start a syllable of a generated name.
+const NAME_ONSETS: &[&str] = &[
+    "b", "d", "g", "k", "l", "m", "n", "r", "s", "t", "v", "x", "z", "th", "sh", "gr", "kr",
+];
+
+/// Vowel sounds that make up the middle of a syllable.
+const NAME_VOWELS: &[&str] = &["a", "e", "i", "o", "u", "ai", "ou"];
 
-impl PartialOrd for NiceFloat {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+/// Sounds that can end a name; the empty string is listed several
+/// times so that most names end on a vowel-ish sound.
+const NAME_CODAS: &[&str] = &["", "", "", "n", "r", "k", "sh", "rth", "g"];
+
+/// Generates a pronounceable pseudo-random name with the given number
+/// of syllables, e.g., "Xurth" or "Gramelok". The name only depends
+/// on the state of `rng`, so a seeded generator always gives the same
+/// names back. Names always have at least one syllable.
+pub fn random_name(rng: &mut impl Rng, syllables: usize) -> String {
+    let mut name = String::new();
+    for _ in 0..syllables.max(1) {
+        name += NAME_ONSETS.choose(rng).expect("Onsets must exist");
+        name += NAME_VOWELS.choose(rng).expect("Vowels must exist");
     }
+    name += NAME_CODAS.choose(rng).expect("Codas must exist");
+
+    capitalize(&name)
 }
 
-impl Ord for NiceFloat {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // TODO: This is the /only/ use of FloatOrd in the program at
-        // this point. It's kinda silly to bring in a dependency for
-        // this; we should probably just implement the logic here
-        // instead.
-        FloatOrd(self.0).cmp(&FloatOrd(other.0))
+/// Splits the cells of `grid` that `passable` accepts into groups that
+/// can be got between by moving from cell to neighboring cell,
+/// diagonals included. The biggest group comes first; groups of the
+/// same size come in the order of their top-left-most cell.
+pub fn connected_components<T>(
+    grid: &Grid<T>,
+    passable: impl Fn(&T) -> bool,
+) -> Vec<Vec<(usize, usize)>> {
+    let mut seen = Grid::init(grid.rows(), grid.cols(), false);
+    let mut components = Vec::new();
+    for y in 0..grid.rows() {
+        for x in 0..grid.cols() {
+            if seen[y][x] || !passable(&grid[y][x]) {
+                continue;
+            }
+
+            let component: Vec<(usize, usize)> = bfs_reach((x, y), |&(x, y)| {
+                let (x, y) = (x as isize, y as isize);
+                (-1..=1)
+                    .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+                    .filter_map(|(x, y)| Some((usize::try_from(x).ok()?, usize::try_from(y).ok()?)))
+                    .filter(|&(x, y)| x < grid.cols() && y < grid.rows() && passable(&grid[y][x]))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+            for &(x, y) in &component {
+                seen[y][x] = true;
+            }
+            components.push(component);
+        }
     }
+
+    components.sort_by_key(|component| Reverse(component.len()));
+    components
 }
 
-impl Add for NiceFloat {
-    type Output = Self;
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
 
-    fn add(self, rhs: Self) -> Self::Output {
-        Self(self.0 + rhs.0)
+    #[test]
+    fn sub_seeds_are_stable() {
+        // The first output of SplitMix64 seeded with 0.
+        assert_eq!(sub_seed(0, &[0]), 0xe220_a839_7b1d_cdaf);
+        assert_eq!(sub_seed(42, &[1, 2]), 0xf081_f507_e13d_31de);
+        assert_eq!(sub_seed(42, &[]), 42);
+
+        assert_ne!(sub_seed(42, &[1, 2]), sub_seed(42, &[2, 1]));
+        assert_ne!(sub_seed(42, &[1, 2]), sub_seed(43, &[1, 2]));
+    }
+
+    #[test]
+    fn names_are_deterministic() {
+        let names = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (1..=4)
+                .map(|syllables| random_name(&mut rng, syllables))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(7), names(7));
+        assert_ne!(names(7), names(8));
+    }
+
+    #[test]
+    fn names_use_only_name_sounds() {
+        let letters: String = NAME_ONSETS
+            .iter()
+            .chain(NAME_VOWELS)
+            .chain(NAME_CODAS)
+            .flat_map(|sound| sound.chars())
+            .collect();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for syllables in 0..5 {
+            let name = random_name(&mut rng, syllables);
+            let mut chars = name.chars();
+            let first = chars.next().expect("Names can't be empty");
+            assert!(first.is_uppercase());
+            assert!(letters.contains(first.to_ascii_lowercase()));
+            assert!(chars.all(|ch| letters.contains(ch)), "{}", name);
+
+            // Every syllable has a vowel sound in it.
+            let vowels = name
+                .to_lowercase()
+                .split(|ch: char| !"aeiou".contains(ch))
+                .filter(|run| !run.is_empty())
+                .count();
+            assert_eq!(vowels, syllables.max(1), "{}", name);
+        }
+    }
+
+    /// A grid of the characters in `rows`, which must all be the same
+    /// length.
+    fn char_grid(rows: &[&str]) -> Grid<char> {
+        Grid::from_vec(rows.concat().chars().collect(), rows[0].len())
+    }
+
+    /// The sizes of the components of the `.` cells in `rows`.
+    fn component_sizes(rows: &[&str]) -> Vec<usize> {
+        connected_components(&char_grid(rows), |&ch| ch == '.')
+            .iter()
+            .map(Vec::len)
+            .collect()
     }
-}
 
-impl Zero for NiceFloat {
-    fn zero() -> Self {
-        Self(0.0)
+    #[test]
+    fn components_are_counted_and_sorted() {
+        assert_eq!(component_sizes(&["#####", "#####"]), Vec::<usize>::new());
+        assert_eq!(component_sizes(&["....", "...."]), [8]);
+        assert_eq!(component_sizes(&[".#...", ".#...", ".#..."]), [9, 3]);
+        // Cells that only touch at the corners are still connected.
+        assert_eq!(component_sizes(&[".#.#.", "#.#.#", ".#.#."]), [8]);
+        assert_eq!(component_sizes(&["..#.", "####", ".#.#"]), [2, 1, 1, 1]);
     }
 
-    fn is_zero(&self) -> bool {
-        *self == Self::zero()
+    #[test]
+    fn ties_go_to_the_top_left_first() {
+        let components =
+            connected_components(&char_grid(&["..#..", "#####", "..#.."]), |&ch| ch == '.');
+        let firsts: Vec<(usize, usize)> = components.iter().map(|component| component[0]).collect();
+        assert_eq!(firsts, [(0, 0), (3, 0), (0, 2), (3, 2)]);
+        assert!(components.iter().all(|component| component.len() == 2));
     }
 }
@@ -1,5 +1,9 @@
 //! Code for determining which cells the player and monsters can see.
 
+use std::collections::HashSet;
+
+use crate::io::Color;
+
 /// The light transmission properties of a cell in the world.
 #[derive(Debug, PartialEq)]
 pub enum CellVisibility {
@@ -12,53 +16,399 @@ pub enum CellVisibility {
 }
 
 /// How well-lit a cell is.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Lighting {
     // /// Monsters can only see in this cell if the cell is immediately
     // /// adjacent to the monster.
     // Dark,
     /// Monsters can see in this cell from far away.
     Lit,
+
+    /// Like `Lit`, but the light is colored, and tints whatever it
+    /// falls on.
+    Tinted(Color),
+}
+
+/// How far a monster can see.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewRadius {
+    /// How far the view reaches, in cell widths.
+    pub radius: i32,
+
+    /// How many times taller than they are wide cells are. Distances
+    /// up and down count for that much more, so that on a terminal,
+    /// where cells are usually about twice as tall as they're wide, a
+    /// view can look round rather than squashed. 1 treats cells as
+    /// square.
+    pub aspect: i32,
+}
+
+impl ViewRadius {
+    /// Whether a cell `(dx, dy)` away from the viewer is close enough
+    /// to see.
+    fn reaches(&self, (dx, dy): (i32, i32)) -> bool {
+        // Saturate rather than overflowing, so that far-off cells are
+        // just out of range.
+        let dy = dy.saturating_mul(self.aspect);
+        dx.saturating_mul(dx).saturating_add(dy.saturating_mul(dy))
+            < self.radius.saturating_mul(self.radius)
+    }
+}
+
+/// Whether `cell` is close enough to `origin` to be seen from there;
+/// every cell is, if there's no radius.
+fn in_radius(radius: Option<ViewRadius>, origin: (i32, i32), cell: (i32, i32)) -> bool {
+    let delta = (
+        cell.0.saturating_sub(origin.0),
+        cell.1.saturating_sub(origin.1),
+    );
+    radius.is_none_or(|radius| radius.reaches(delta))
+}
+
+/// The ways of working out which cells are in view.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FovAlgo {
+    /// Traces a line to every cell in range, as `visible` does. Views
+    /// aren't always symmetric, and walls can hide cells just around
+    /// a corner that shadowcasting would show.
+    LineTrace,
+
+    /// Symmetric shadowcasting, as `field_of_view` does.
+    #[default]
+    Shadowcast,
+}
+
+impl FovAlgo {
+    /// The names of the algorithms that can be picked with `from_name`.
+    pub const NAMES: &'static [&'static str] = &["line", "shadowcast"];
+
+    /// Gets an algorithm by name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "line" => Some(Self::LineTrace),
+            "shadowcast" => Some(Self::Shadowcast),
+            _ => None,
+        }
+    }
+
+    /// Whether anything that can see a cell from another can always be
+    /// seen from that cell in turn.
+    pub fn is_symmetric(self) -> bool {
+        matches!(self, Self::Shadowcast)
+    }
+
+    /// Finds every cell that a monster standing at `origin` can see,
+    /// in sorted order, under the same assumptions as `visible`.
+    ///
+    /// `cell_map` must report cells outside the map as `Blocking`, or
+    /// this may not terminate when `radius` is `None`.
+    pub fn compute(
+        self,
+        origin: (i32, i32),
+        radius: Option<ViewRadius>,
+        cell_map: impl Fn((i32, i32)) -> CellVisibility,
+        light_map: impl Fn((i32, i32)) -> Lighting,
+    ) -> Vec<(i32, i32)> {
+        match self {
+            Self::LineTrace => line_trace(origin, radius, cell_map, light_map),
+            Self::Shadowcast => field_of_view(origin, radius, cell_map, light_map),
+        }
+    }
+}
+
+/// Finds every cell that a monster standing at `origin` can see by
+/// calling `visible` on each cell that might be in view. Every cell
+/// along a line of sight is next to the one before it and transparent,
+/// so only cells reachable from `origin` through transparent cells, or
+/// walls next to those, need checking.
+fn line_trace(
+    origin: (i32, i32),
+    radius: Option<ViewRadius>,
+    cell_map: impl Fn((i32, i32)) -> CellVisibility,
+    light_map: impl Fn((i32, i32)) -> Lighting,
+) -> Vec<(i32, i32)> {
+    let in_range = |cell: (i32, i32)| in_radius(radius, origin, cell);
+
+    let mut candidates = HashSet::from([origin]);
+    let mut frontier = vec![origin];
+    while let Some((x, y)) = frontier.pop() {
+        if (x, y) != origin && cell_map((x, y)) == CellVisibility::Blocking {
+            continue;
+        }
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let next = (x + dx, y + dy);
+                if in_range(next) && candidates.insert(next) {
+                    frontier.push(next);
+                }
+            }
+        }
+    }
+
+    let mut seen: Vec<(i32, i32)> = candidates
+        .into_iter()
+        .filter(|&cell| cell == origin || visible(origin, cell, radius, &cell_map, &light_map))
+        .collect();
+    seen.sort_unstable();
+    seen
+}
+
+/// Finds every cell that a monster standing at `origin` can see,
+/// under the same assumptions as `visible`, using symmetric
+/// shadowcasting: if a monster at A can see B, then a monster at B can
+/// see A. This visits each cell in range at most once, so it's much
+/// cheaper than calling `visible` on every cell. The cells come back
+/// in sorted order.
+///
+/// `cell_map` must report cells outside the map as `Blocking`, or this
+/// may not terminate when `radius` is `None`.
+pub fn field_of_view(
+    origin: (i32, i32),
+    radius: Option<ViewRadius>,
+    cell_map: impl Fn((i32, i32)) -> CellVisibility,
+    light_map: impl Fn((i32, i32)) -> Lighting,
+) -> Vec<(i32, i32)> {
+    let mut seen = vec![origin];
+
+    // Each quadrant is scanned outwards one row at a time, where
+    // `depth` is the distance from `origin` along the quadrant's axis
+    // and `col` is the offset across it.
+    let quadrants: [Transform; 4] = [
+        |(x, y), depth, col| (x + col, y - depth),
+        |(x, y), depth, col| (x + depth, y + col),
+        |(x, y), depth, col| (x + col, y + depth),
+        |(x, y), depth, col| (x - depth, y + col),
+    ];
+
+    for transform in quadrants {
+        let cell = |depth: i32, col: i32| transform(origin, depth, col);
+        let blocking =
+            |depth: i32, col: i32| cell_map(cell(depth, col)) == CellVisibility::Blocking;
+        let in_range = |depth: i32, col: i32| in_radius(radius, origin, cell(depth, col));
+
+        let mut rows = vec![Row {
+            depth: 1,
+            start: Slope::new(-1, 1),
+            end: Slope::new(1, 1),
+        }];
+
+        while let Some(mut row) = rows.pop() {
+            // The middle of a row is the part of it closest to the
+            // origin, whether the row runs across or down.
+            if !in_range(row.depth, 0) {
+                continue;
+            }
+
+            let mut prev_blocking = None;
+            for col in row.start.round_up(row.depth)..=row.end.round_down(row.depth) {
+                let is_blocking = blocking(row.depth, col);
+                if (is_blocking || row.is_symmetric(col))
+                    && in_range(row.depth, col)
+                    && matches!(
+                        light_map(cell(row.depth, col)),
+                        Lighting::Lit | Lighting::Tinted(_)
+                    )
+                {
+                    seen.push(cell(row.depth, col));
+                }
+
+                match (prev_blocking, is_blocking) {
+                    // Coming out from behind an obstacle narrows the
+                    // view from the left.
+                    (Some(true), false) => row.start = Slope::of(row.depth, col),
+
+                    // Running into one means we need to look past its
+                    // edge on the next row.
+                    (Some(false), true) => rows.push(Row {
+                        depth: row.depth + 1,
+                        start: row.start,
+                        end: Slope::of(row.depth, col),
+                    }),
+
+                    _ => {}
+                }
+                prev_blocking = Some(is_blocking);
+            }
+
+            if prev_blocking == Some(false) {
+                rows.push(Row {
+                    depth: row.depth + 1,
+                    ..row
+                });
+            }
+        }
+    }
+
+    // Cells on the diagonals get scanned by two quadrants, and sorting
+    // lets callers binary search the result.
+    seen.sort_unstable();
+    seen.dedup();
+    seen
+}
+
+/// Maps a `(depth, col)` pair in one quadrant of a field of view to a
+/// cell on the map, given the origin of the field.
+type Transform = fn((i32, i32), i32, i32) -> (i32, i32);
+
+/// A row of cells being scanned by `field_of_view`.
+#[derive(Clone, Copy)]
+struct Row {
+    /// How far the row is from the origin.
+    depth: i32,
+
+    /// The slopes bounding the part of the row that's in view.
+    start: Slope,
+    end: Slope,
+}
+
+impl Row {
+    /// Whether the cell at `col` in this row lies within the row's
+    /// bounds, when measured from its center.
+    fn is_symmetric(&self, col: i32) -> bool {
+        self.start.times_le(self.depth, col) && self.end.times_ge(self.depth, col)
+    }
+}
+
+/// A rational slope, `num / den`, with `den` always positive; using
+/// integers rather than floats keeps the edges of shadows exact.
+#[derive(Clone, Copy)]
+struct Slope {
+    num: i32,
+    den: i32,
+}
+
+impl Slope {
+    fn new(num: i32, den: i32) -> Self {
+        Self { num, den }
+    }
+
+    /// The slope from the origin to the left edge of the cell at `col`
+    /// in the row at `depth`.
+    fn of(depth: i32, col: i32) -> Self {
+        Self::new(2 * col - 1, 2 * depth)
+    }
+
+    /// `depth * self`, rounded to the nearest integer, with ties
+    /// rounded up.
+    fn round_up(&self, depth: i32) -> i32 {
+        (2 * depth * self.num + self.den).div_euclid(2 * self.den)
+    }
+
+    /// `depth * self`, rounded to the nearest integer, with ties
+    /// rounded down.
+    fn round_down(&self, depth: i32) -> i32 {
+        -(self.den - 2 * depth * self.num).div_euclid(2 * self.den)
+    }
+
+    /// Whether `depth * self <= col`.
+    fn times_le(&self, depth: i32, col: i32) -> bool {
+        depth * self.num <= col * self.den
+    }
+
+    /// Whether `depth * self >= col`.
+    fn times_ge(&self, depth: i32, col: i32) -> bool {
+        depth * self.num >= col * self.den
+    }
 }
 
 /// Calculates whether a monster standing at `origin` can see the
-/// contents of cell `cell`. We assume the monster can see `radius`
-/// cells away at best (None for unlimited range), that `cell_map`
-/// represents whether a cell transmits light, and that `light_map`
-/// represents how well-lit a cell is.
+/// contents of cell `cell`, by tracing a line between them. We assume
+/// the monster can see as far as `radius` at best (None for unlimited
+/// range), that `cell_map` represents whether a cell transmits light,
+/// and that `light_map` represents how well-lit a cell is.
 pub fn visible(
     origin: (i32, i32),
     cell: (i32, i32),
-    radius: Option<i32>,
+    radius: Option<ViewRadius>,
     cell_map: impl Fn((i32, i32)) -> CellVisibility,
     light_map: impl Fn((i32, i32)) -> Lighting,
 ) -> bool {
-    let dx = cell.0 - origin.0;
-    let dy = cell.1 - origin.1;
-
-    radius
-        .map(|radius| dx * dx + dy * dy < radius * radius)
-        .unwrap_or(true)
-        && (light_map(cell) == Lighting::Lit)
+    in_radius(radius, origin, cell)
+        && matches!(light_map(cell), Lighting::Lit | Lighting::Tinted(_))
         && (line(origin, cell).all(|tile| cell_map(tile) == CellVisibility::Transparent))
 }
 
+/// Traces the path that a projectile would take from `origin` towards
+/// `target`, stopping short of the first cell that `cell_map` says is
+/// blocking. Returns the cells the projectile passes through, not
+/// counting `origin`, and whether it gets all the way to `target`.
+/// The path follows `bresenham` rather than the line that `visible`
+/// traces, so it differs from what's in view around corners: a cell
+/// can be in view without there being a clear shot at it.
+pub fn line_of_fire(
+    origin: (i32, i32),
+    target: (i32, i32),
+    cell_map: impl Fn((i32, i32)) -> CellVisibility,
+) -> (Vec<(i32, i32)>, bool) {
+    let mut path = Vec::new();
+    if origin == target {
+        return (path, true);
+    }
+
+    for cell in bresenham(origin, target).skip(1) {
+        if cell_map(cell) == CellVisibility::Blocking {
+            return (path, false);
+        }
+        path.push(cell);
+    }
+
+    (path, true)
+}
+
+/// Constructs an iterator over the cells that a straight line from
+/// `start` to `end` passes through, by Bresenham's algorithm, which
+/// steps through the cells you'd expect to see a projectile cross.
+/// Unlike `line`, both `start` and `end` are included.
+pub fn bresenham(start: (i32, i32), end: (i32, i32)) -> impl Iterator<Item = (i32, i32)> {
+    let dx = (end.0 - start.0).abs();
+    let dy = -(end.1 - start.1).abs();
+    let step = ((end.0 - start.0).signum(), (end.1 - start.1).signum());
+
+    // `error` keeps track of how far the cells we've picked are from
+    // the true line, scaled up so that it stays a whole number.
+    std::iter::successors(Some((start, dx + dy)), move |&((x, y), error)| {
+        if (x, y) == end {
+            return None;
+        }
+
+        let (mut x, mut y, mut next_error) = (x, y, error);
+        if 2 * error >= dy {
+            next_error += dy;
+            x += step.0;
+        }
+        if 2 * error <= dx {
+            next_error += dx;
+            y += step.1;
+        }
+        Some(((x, y), next_error))
+    })
+    .map(|(cell, _error)| cell)
+}
+
 /// Constructs an iterator over the cells in a straight line from
 /// `start` to `end`. The line will include `start`, but not `end`.
+/// This is the line used for working out what's in view; projectiles
+/// follow `bresenham` instead.
 fn line(start: (i32, i32), end: (i32, i32)) -> Box<dyn Iterator<Item = (i32, i32)>> {
     // We could use a dedicated iterator type here eventually and
     // avoid the `Box` allocations, but I'm gonna assume it's not a
     // significant problem until proven otherwise.
 
-    let dx = end.0 - start.0;
-    let dy = end.1 - start.1;
+    let dx = end.0.saturating_sub(start.0);
+    let dy = end.1.saturating_sub(start.1);
 
     // Transform the world so we're working from left to right, with
     // slope magnitude less than 1.
-    if dx.abs() < dy.abs() {
+    if dx.unsigned_abs() < dy.unsigned_abs() {
         Box::new(line((start.1, start.0), (end.1, end.0)).map(|(x, y)| (y, x)))
     } else if dx < 0 {
-        Box::new(line((-start.0, start.1), (-end.0, end.1)).map(|(x, y)| (-x, y)))
+        Box::new(
+            line(
+                (start.0.saturating_neg(), start.1),
+                (end.0.saturating_neg(), end.1),
+            )
+            .map(|(x, y)| (-x, y)),
+        )
     } else {
         // Move the destination over by 0.5 cells on each axis, to
         // navigate to the corner rather than the center of the target
@@ -74,10 +424,10 @@ fn line(start: (i32, i32), end: (i32, i32)) -> Box<dyn Iterator<Item = (i32, i32
 
         // Now use float math to step along the line, one cell at a
         // time.
-        let slope = dy as f64 / dx as f64;
+        let slope = dy / dx;
         Box::new(
             std::iter::successors(Some((start.0, start.1 as f64)), move |&(x, y)| {
-                Some((x + 1, y + slope))
+                Some((x.checked_add(1)?, y + slope))
             })
             // Add 0.5 here to round to nearest rather than rounding
             // towards zero (eliminates some bias).
@@ -86,3 +436,187 @@ fn line(start: (i32, i32), end: (i32, i32)) -> Box<dyn Iterator<Item = (i32, i32
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: Option<ViewRadius> = Some(ViewRadius {
+        radius: 10,
+        aspect: 2,
+    });
+
+    fn open(_cell: (i32, i32)) -> CellVisibility {
+        CellVisibility::Transparent
+    }
+
+    fn lit(_cell: (i32, i32)) -> Lighting {
+        Lighting::Lit
+    }
+
+    #[test]
+    fn far_corners_are_out_of_range() {
+        let corners = [
+            (i32::MIN, i32::MIN),
+            (i32::MIN, i32::MAX),
+            (i32::MAX, i32::MIN),
+            (i32::MAX, i32::MAX),
+        ];
+        for &origin in &corners {
+            for &cell in &corners {
+                assert_eq!(visible(origin, cell, RADIUS, open, lit), origin == cell);
+            }
+        }
+    }
+
+    #[test]
+    fn neighbors_are_visible_at_the_edges() {
+        assert!(visible((i32::MAX, 0), (i32::MAX - 1, 0), RADIUS, open, lit));
+        assert!(visible(
+            (i32::MAX - 1, i32::MAX - 1),
+            (i32::MAX - 2, i32::MAX - 1),
+            RADIUS,
+            open,
+            lit
+        ));
+    }
+
+    #[test]
+    fn huge_radii_dont_overflow() {
+        let radius = Some(ViewRadius {
+            radius: i32::MAX,
+            aspect: i32::MAX,
+        });
+        assert!(!in_radius(radius, (i32::MIN, 0), (i32::MAX, 0)));
+        assert!(in_radius(radius, (0, 0), (1, 0)));
+    }
+
+    /// A map of cells from the given picture, where `#` blocks light
+    /// and everything off the picture does too.
+    fn map(rows: &'static [&'static str]) -> impl Fn((i32, i32)) -> CellVisibility {
+        move |(x, y)| {
+            let ch = usize::try_from(y)
+                .ok()
+                .and_then(|y| rows.get(y))
+                .and_then(|row| row.chars().nth(usize::try_from(x).ok()?));
+            match ch {
+                Some('.') => CellVisibility::Transparent,
+                _ => CellVisibility::Blocking,
+            }
+        }
+    }
+
+    #[test]
+    fn algorithms_agree_in_open_rooms() {
+        let room = map(&[
+            "#########",
+            "#.......#",
+            "#.......#",
+            "#.......#",
+            "#.......#",
+            "#########",
+        ]);
+        for origin in [(1, 1), (4, 2), (7, 4)] {
+            assert_eq!(
+                FovAlgo::LineTrace.compute(origin, RADIUS, &room, lit),
+                FovAlgo::Shadowcast.compute(origin, RADIUS, &room, lit),
+            );
+        }
+    }
+
+    #[test]
+    fn algorithms_differ_around_corners() {
+        // Looking down the hallway, line tracing just makes out the cell
+        // around the corner, though that cell can't see back. Symmetric
+        // shadowcasting shows neither.
+        let corner = map(&[
+            "#######", //
+            "#.....#", "#.#####", "#.#", "###",
+        ]);
+        let (eye, around) = ((5, 1), (1, 2));
+
+        assert!(FovAlgo::LineTrace
+            .compute(eye, None, &corner, lit)
+            .contains(&around));
+        assert!(!FovAlgo::LineTrace
+            .compute(around, None, &corner, lit)
+            .contains(&eye));
+
+        assert!(!FovAlgo::Shadowcast
+            .compute(eye, None, &corner, lit)
+            .contains(&around));
+        assert!(!FovAlgo::Shadowcast
+            .compute(around, None, &corner, lit)
+            .contains(&eye));
+    }
+
+    #[test]
+    fn tall_cells_squash_the_view() {
+        let radius = |aspect| Some(ViewRadius { radius: 10, aspect });
+        for algo in [FovAlgo::LineTrace, FovAlgo::Shadowcast] {
+            let square = algo.compute((0, 0), radius(1), open, lit);
+            let tall = algo.compute((0, 0), radius(2), open, lit);
+            let extent = |cells: &[(i32, i32)]| {
+                let widest = cells.iter().map(|&(x, _)| x.abs()).max();
+                let tallest = cells.iter().map(|&(_, y)| y.abs()).max();
+                (widest, tallest)
+            };
+
+            assert_eq!(square.len(), 305);
+            assert_eq!(extent(&square), (Some(9), Some(9)));
+            assert_eq!(tall.len(), 147);
+            assert_eq!(extent(&tall), (Some(9), Some(4)));
+            assert!(tall.iter().all(|cell| square.contains(cell)));
+        }
+    }
+
+    /// Checks that the Bresenham line from `start` to `end` is `expected`.
+    fn assert_line(start: (i32, i32), end: (i32, i32), expected: &[(i32, i32)]) {
+        let line: Vec<(i32, i32)> = bresenham(start, end).collect();
+        assert_eq!(line, expected, "{:?} to {:?}", start, end);
+    }
+
+    #[test]
+    fn bresenham_matches_known_lines() {
+        assert_line((0, 0), (0, 0), &[(0, 0)]);
+        assert_line((0, 0), (3, 0), &[(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_line((2, 2), (2, -1), &[(2, 2), (2, 1), (2, 0), (2, -1)]);
+        assert_line((0, 0), (3, 3), &[(0, 0), (1, 1), (2, 2), (3, 3)]);
+        assert_line(
+            (0, 0),
+            (6, 4),
+            &[(0, 0), (1, 1), (2, 1), (3, 2), (4, 3), (5, 3), (6, 4)],
+        );
+        assert_line(
+            (0, 0),
+            (2, 5),
+            &[(0, 0), (0, 1), (1, 2), (1, 3), (2, 4), (2, 5)],
+        );
+        assert_line(
+            (0, 0),
+            (5, -2),
+            &[(0, 0), (1, 0), (2, -1), (3, -1), (4, -2), (5, -2)],
+        );
+        assert_line(
+            (1, 1),
+            (-4, -1),
+            &[(1, 1), (0, 1), (-1, 0), (-2, 0), (-3, -1), (-4, -1)],
+        );
+    }
+
+    #[test]
+    fn shots_stop_short_of_walls() {
+        let hall = map(&["#######", "#.....#", "###.###", "#######"]);
+        assert_eq!(
+            line_of_fire((1, 1), (5, 1), &hall),
+            (vec![(2, 1), (3, 1), (4, 1), (5, 1)], true)
+        );
+        // This one dips into the gap in the wall, then hits the wall
+        // beyond it.
+        assert_eq!(
+            line_of_fire((1, 1), (5, 2), &hall),
+            (vec![(2, 1), (3, 2)], false)
+        );
+        assert_eq!(line_of_fire((3, 2), (3, 2), &hall), (vec![], true));
+    }
+}